@@ -0,0 +1,177 @@
+//! Async client for the ALICE Cloud Gateway device API: connect, sync, transform and mesh.
+//!
+//! Requests are retried on transport errors and on 429/502/503/504, waiting for the server's
+//! `X-Suggested-Interval-Ms` or `Retry-After` when given and backing off exponentially
+//! otherwise. `connect`, `sync` and `sync_transaction` carry an `Idempotency-Key` that stays
+//! the same across the retries of one call, so a retried request the gateway already applied
+//! is replayed, not repeated. Mesh creation is not idempotent and is only retried when the gateway cannot have
+//! acted on it (connection refused, 429, 503).
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), alice_gateway_client::Error> {
+//! use alice_gateway_client::{Client, SyncRequest};
+//! let gw = Client::new("https://gateway.example.com").api_key("agk_...");
+//! let conn = gw.connect("sensor-7", Some("sdf-stream")).await?;
+//! gw.sync(&SyncRequest { connection_id: conn.connection_id, sdf_delta: Some(serde_json::json!({ "objects": {} })), ..Default::default() }).await?;
+//! # Ok(()) }
+//! ```
+
+pub use alice_gateway_types::*;
+
+use reqwest::{header::HeaderMap, Method, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy { pub max_attempts: u32, pub base_backoff: Duration, pub max_backoff: Duration }
+
+impl Default for RetryPolicy {
+    fn default() -> Self { RetryPolicy { max_attempts: 5, base_backoff: Duration::from_millis(200), max_backoff: Duration::from_secs(10) } }
+}
+
+impl RetryPolicy {
+    /// No retries: every call makes exactly one attempt.
+    pub fn none() -> Self { RetryPolicy { max_attempts: 1, ..Self::default() } }
+    fn backoff(&self, attempt: u32) -> Duration { self.base_backoff.saturating_mul(1 << attempt.min(16)).min(self.max_backoff) }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or the response could not be read.
+    Http(reqwest::Error),
+    /// The gateway answered with a non-2xx status.
+    Api { status: StatusCode, body: ErrorBody },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "gateway request failed: {e}"),
+            Error::Api { status, body } => {
+                write!(f, "gateway returned {status}: {}", body.error)?;
+                if let Some(d) = &body.details { write!(f, " ({d})")?; }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> { match self { Error::Http(e) => Some(e), Error::Api { .. } => None } }
+}
+
+impl From<reqwest::Error> for Error { fn from(e: reqwest::Error) -> Self { Error::Http(e) } }
+
+impl Error {
+    /// The gateway's machine-readable rejection code, e.g. `duplicate_sequence`.
+    pub fn code(&self) -> Option<&str> { match self { Error::Api { body, .. } => body.code.as_deref(), Error::Http(_) => None } }
+}
+
+/// How safe a call is to repeat.
+#[derive(Clone, Copy, PartialEq)]
+enum Retry { Always, IfNotApplied }
+
+#[derive(Clone)]
+pub struct Client { http: reqwest::Client, base: String, headers: HeaderMap, retry: RetryPolicy }
+
+impl Client {
+    pub fn new(base_url: &str) -> Self { Self::with_http(reqwest::Client::new(), base_url) }
+
+    /// Uses a preconfigured reqwest client (timeouts, TLS client certificates, proxies).
+    pub fn with_http(http: reqwest::Client, base_url: &str) -> Self {
+        Client { http, base: base_url.trim_end_matches('/').to_string(), headers: HeaderMap::new(), retry: RetryPolicy::default() }
+    }
+
+    /// Authenticates with a tenant API key (`X-API-Key`).
+    pub fn api_key(self, key: &str) -> Self { self.header("x-api-key", key) }
+    /// Authenticates with a bearer token.
+    pub fn bearer(self, token: &str) -> Self { self.header("authorization", &format!("Bearer {token}")) }
+    /// Scopes requests to a tenant when talking to the engine directly, without the api-gateway.
+    pub fn tenant(self, tenant: &str) -> Self { self.header("x-tenant-id", tenant) }
+    pub fn retry(mut self, policy: RetryPolicy) -> Self { self.retry = policy; self }
+
+    fn header(mut self, name: &'static str, value: &str) -> Self {
+        // Values that are not valid header text are dropped rather than failing every request later.
+        if let Ok(v) = value.parse() { self.headers.insert(name, v); }
+        self
+    }
+
+    /// Connects with a fixed protocol, or lets the gateway choose when `protocol` is `None`.
+    pub async fn connect(&self, device_id: &str, protocol: Option<&str>) -> Result<ConnectResponse, Error> {
+        self.connect_with(&ConnectRequest { device_id: device_id.into(), protocol: protocol.map(Into::into), ..Default::default() }).await
+    }
+
+    pub async fn connect_with(&self, req: &ConnectRequest) -> Result<ConnectResponse, Error> {
+        self.send(Method::POST, "/api/v1/gateway/connect", Some(req), Some(uuid::Uuid::new_v4().to_string()), Retry::Always).await
+    }
+
+    pub async fn sync(&self, req: &SyncRequest) -> Result<SyncResponse, Error> {
+        self.send(Method::POST, "/api/v1/gateway/sync", Some(req), Some(uuid::Uuid::new_v4().to_string()), Retry::Always).await
+    }
+
+    /// Applies several connections' deltas atomically. A rolled-back transaction is still `Ok`;
+    /// check `status` and the per-sync results.
+    pub async fn sync_transaction(&self, req: &SyncTransactionRequest) -> Result<SyncTransactionResponse, Error> {
+        self.send(Method::POST, "/api/v1/gateway/sync/transaction", Some(req), Some(uuid::Uuid::new_v4().to_string()), Retry::Always).await
+    }
+
+    pub async fn disconnect(&self, connection_id: &str) -> Result<(), Error> {
+        self.send::<(), serde_json::Value>(Method::DELETE, &format!("/api/v1/gateway/connections/{connection_id}"), None, None, Retry::Always).await.map(drop)
+    }
+
+    /// The delivery receipt named by a sync's `delivery_id`: per-destination status with timestamps.
+    pub async fn get_delivery(&self, delivery_id: &str) -> Result<serde_json::Value, Error> {
+        self.send::<(), _>(Method::GET, &format!("/api/v1/gateway/deliveries/{delivery_id}"), None, None, Retry::Always).await
+    }
+
+    pub async fn transform(&self, req: &TransformRequest) -> Result<TransformResponse, Error> {
+        self.send(Method::POST, "/api/v1/gateway/transform", Some(req), None, Retry::Always).await
+    }
+
+    pub async fn create_mesh(&self, req: &MeshRequest) -> Result<MeshResponse, Error> {
+        self.send(Method::POST, "/api/v1/gateway/mesh", Some(req), None, Retry::IfNotApplied).await
+    }
+
+    /// The stored mesh, including measured links; returned as JSON since its shape is richer than `MeshResponse`.
+    pub async fn get_mesh(&self, mesh_id: &str) -> Result<serde_json::Value, Error> {
+        self.send::<(), _>(Method::GET, &format!("/api/v1/gateway/mesh/{mesh_id}"), None, None, Retry::Always).await
+    }
+
+    async fn send<B: Serialize, T: DeserializeOwned>(&self, method: Method, path: &str, body: Option<&B>, idempotency_key: Option<String>, retry: Retry) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            let mut req = self.http.request(method.clone(), format!("{}{path}", self.base)).headers(self.headers.clone());
+            if let Some(b) = body { req = req.json(b); }
+            if let Some(k) = &idempotency_key { req = req.header("idempotency-key", k); }
+            attempt += 1;
+            let last = attempt >= self.retry.max_attempts;
+            let resp = match req.send().await {
+                Ok(r) => r,
+                Err(e) if !last && (retry == Retry::Always || e.is_connect()) => { tokio::time::sleep(self.retry.backoff(attempt - 1)).await; continue }
+                Err(e) => return Err(e.into()),
+            };
+            let status = resp.status();
+            if status.is_success() {
+                // DELETE answers 204; let unit-like targets deserialize from `null`.
+                let bytes = resp.bytes().await?;
+                return serde_json::from_slice(if bytes.is_empty() { b"null" } else { &bytes }).map_err(|e| Error::Api { status, body: ErrorBody { error: "unreadable response".into(), code: None, details: Some(e.to_string()), violations: Vec::new(), suggested_interval_ms: None, suggested_region: None } });
+            }
+            let retryable = match status {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+                StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => retry == Retry::Always,
+                _ => false,
+            };
+            let wait = server_wait(resp.headers());
+            let body = resp.json::<ErrorBody>().await.unwrap_or_else(|_| ErrorBody { error: status.canonical_reason().unwrap_or("error").into(), code: None, details: None, violations: Vec::new(), suggested_interval_ms: None, suggested_region: None });
+            if !retryable || last { return Err(Error::Api { status, body }); }
+            tokio::time::sleep(wait.unwrap_or_else(|| self.retry.backoff(attempt - 1)).min(self.retry.max_backoff)).await;
+        }
+    }
+}
+
+/// `X-Suggested-Interval-Ms` is more precise than `Retry-After`, so it wins when both are sent.
+fn server_wait(h: &HeaderMap) -> Option<Duration> {
+    let num = |name: &str| h.get(name).and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse::<u64>().ok());
+    num("x-suggested-interval-ms").map(Duration::from_millis).or_else(|| num("retry-after").map(Duration::from_secs))
+}
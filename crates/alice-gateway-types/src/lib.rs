@@ -0,0 +1,144 @@
+//! Wire types of the ALICE Cloud Gateway device API (`/api/v1/gateway/...`), shared by the
+//! core engine and `alice-gateway-client` so firmware and server agree on one definition.
+//! Optional request fields are omitted when unset; the engine treats absent and `null` alike.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Longest `pipeline` a transform may run.
+pub const MAX_PIPELINE: usize = 8;
+/// Most syncs one transaction may carry.
+pub const MAX_TRANSACTION: usize = 64;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConnectRequest {
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub protocol: Option<String>,
+    /// Protocols the device can speak, most preferred first; the gateway picks one. Versioned ids
+    /// (`sdf-stream@1`) get the highest version offered that the gateway also speaks.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub accept_protocols: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub priority: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub standby_region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub home_region: Option<String>,
+    /// Token from an earlier connect; resumes that session (`status: "resumed"`) if it is still
+    /// open or was closed within the grace period, else a new session is opened.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub resume_token: Option<String>,
+    /// One-time token from pre-registration; the first connect of a provisioned device presents it.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub enrollment_token: Option<String>,
+    /// Credential bound at enrollment; every later connect of the device presents it.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub device_credential: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectResponse {
+    pub connection_id: String, pub device_id: String, pub protocol: String, pub region: String, pub endpoint: String, pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub standby: Option<StandbyInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub relayed_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub resume_token: Option<String>,
+    /// Returned once, by the connect that enrolled the device; store it for later connects.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub device_credential: Option<String>,
+}
+
+/// Pre-registered session in `standby_region`; present its token to `/failover`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StandbyInfo { pub region: String, pub endpoint: String, pub resume_token: String }
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub connection_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub sdf_delta: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub timestamp: Option<String>,
+    /// Strictly increasing per connection when set; replays are rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub sequence: Option<u64>,
+    /// `high`, `normal` (the default) or `low`; under load, higher priorities are admitted first.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub priority: Option<String>,
+    /// End-to-end encrypted payload, sent instead of `sdf_delta`.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub envelope: Option<Envelope>,
+    /// Registered schema version `sdf_delta` conforms to; the protocol's latest when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub schema_version: Option<u32>,
+}
+
+/// Ciphertext the gateway relays and stores without decrypting. `ciphertext`, `nonce` and `tag`
+/// are base64; `key_id` names the tenant-held key and `alg` the AEAD (e.g. `A256GCM`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub key_id: String, pub alg: String, pub ciphertext: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub nonce: Option<String>,
+    pub tag: String,
+}
+
+/// `delivery_id` names the sync's delivery receipt (`GET /deliveries/:id`); transactional syncs have none.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub sync_id: String, pub status: String, pub objects_synced: u32, pub sdf_bytes_transferred: u64, pub latency_ms: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub delivery_id: Option<String>,
+}
+
+/// Deltas for several connections (one each), applied all together or not at all.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SyncTransactionRequest { pub syncs: Vec<SyncRequest> }
+
+/// `status` is `committed` or `rolled_back`; either way every sync has a result, in request order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncTransactionResponse { pub transaction_id: String, pub status: String, pub results: Vec<TransactionResult>, pub latency_ms: f64 }
+
+/// `status` is `committed`, `failed` (this sync caused the rollback) or `aborted` (rolled back
+/// because another sync failed).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionResult {
+    pub connection_id: String, pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub sync_id: Option<String>,
+    #[serde(default)] pub objects_synced: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub error: Option<ErrorBody>,
+}
+
+/// Either a single `source_protocol -> target_protocol` hop or a `pipeline` of protocols, run hop
+/// by hop through canonical SDF (source and target then default to its ends).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TransformRequest {
+    #[serde(default, skip_serializing_if = "String::is_empty")] pub source_protocol: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")] pub target_protocol: String,
+    pub payload: Value,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")] pub pipeline: Vec<String>,
+    /// Registered schema version of the source protocol `payload` conforms to; the latest when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub schema_version: Option<u32>,
+}
+
+impl TransformRequest {
+    /// The protocols the payload passes through, in order.
+    pub fn chain(&self) -> Vec<&str> {
+        if self.pipeline.is_empty() { vec![&self.source_protocol, &self.target_protocol] } else { self.pipeline.iter().map(String::as_str).collect() }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransformResponse { pub transform_id: String, pub source: String, pub target: String, pub output: Value, pub elapsed_us: u128, pub stages: Vec<TransformStage> }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransformStage { pub from: String, pub to: String, pub elapsed_us: u64 }
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MeshRequest { pub devices: Vec<String>, #[serde(default, skip_serializing_if = "Option::is_none")] pub topology: Option<String> }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MeshResponse { pub mesh_id: String, pub devices: usize, pub topology: String, pub connections: Vec<MeshConnection>, pub status: String }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MeshConnection { pub from: String, pub to: String, pub latency_ms: f64 }
+
+/// Body of every non-2xx engine response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub error: String,
+    /// Machine-readable reason, e.g. `backpressure`, `duplicate_sequence`, `migrating`.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub details: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")] pub violations: Vec<Violation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub suggested_interval_ms: Option<u32>,
+    /// Healthy region to connect to instead, on `region_unavailable`.
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub suggested_region: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Violation { pub field: String, pub message: String }
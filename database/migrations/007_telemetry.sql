@@ -0,0 +1,19 @@
+-- ALICE Cloud Gateway: time-series telemetry (TELEMETRY_BACKEND=timescale)
+CREATE TABLE IF NOT EXISTS telemetry_points (
+    time TIMESTAMPTZ NOT NULL,
+    tenant TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    value DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_telemetry_points_lookup ON telemetry_points(tenant, metric, time DESC);
+
+-- Promote to a hypertable only where the TimescaleDB extension is installed.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_available_extensions WHERE name = 'timescaledb') THEN
+        CREATE EXTENSION IF NOT EXISTS timescaledb;
+        PERFORM create_hypertable('telemetry_points', 'time', if_not_exists => TRUE);
+    END IF;
+END $$;
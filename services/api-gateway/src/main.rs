@@ -1,22 +1,30 @@
 use axum::{
     body::Body,
-    extract::{Request, State},
-    http::{HeaderMap, StatusCode},
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
     middleware::{self, Next},
     response::{Json, Response},
     routing::{any, get},
     Router,
 };
 use dashmap::DashMap;
+use hyper_util::rt::TokioIo;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Instant;
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
 
 struct AppState {
     core_url: String,
+    /// Where the engine's internal surface (`/internal/keys/verify`) is served.
+    core_internal_url: String,
     jwt_secret: String,
+    /// Sent as `X-Gateway-Secret` so the engine trusts the identity headers set here.
+    shared_secret: Option<HeaderValue>,
+    /// Take the client address from `X-Forwarded-For` (behind a trusted load balancer).
+    trust_forwarded_for: bool,
     rate_limiters: DashMap<String, TokenBucket>,
     start_time: Instant,
 }
@@ -51,7 +59,21 @@ struct Err { error: String, #[serde(skip_serializing_if = "Option::is_none")] de
 struct LicenseInfo { license: String, source_code: String, notice: String }
 
 #[derive(Deserialize, Serialize, Clone)]
-struct Claims { sub: String, email: Option<String>, role: Option<String>, exp: usize }
+struct Claims { sub: String, email: Option<String>, #[serde(default)] app_metadata: AppMetadata, exp: usize }
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+struct AppMetadata { gateway_role: Option<String> }
+
+/// Roles a user token may carry; platform admin is only ever the engine's `ADMIN_TOKEN`.
+const TOKEN_ROLES: &[&str] = &["viewer", "operator", "tenant-admin"];
+
+/// The gateway role of a user token, from `app_metadata.gateway_role`, which users cannot set
+/// themselves. The `role` claim belongs to the identity provider (Supabase puts
+/// `authenticated` there) and is ignored; a missing or unknown gateway role gets `viewer`.
+fn gateway_role(claims: &Claims) -> &'static str {
+    let role = claims.app_metadata.gateway_role.as_deref().map(str::to_ascii_lowercase);
+    TOKEN_ROLES.iter().copied().find(|r| Some(*r) == role.as_deref()).unwrap_or("viewer")
+}
 
 #[tokio::main]
 async fn main() {
@@ -62,18 +84,23 @@ async fn main() {
         )
         .init();
     let env = |k: &str, d: &str| std::env::var(k).unwrap_or_else(|_| d.into());
+    let core_url = env("CORE_ENGINE_URL", "http://core-engine:8081");
     let state = Arc::new(AppState {
-        core_url: env("CORE_ENGINE_URL", "http://core-engine:8081"),
+        core_internal_url: std::env::var("CORE_ENGINE_INTERNAL_URL").ok().filter(|u| !u.is_empty()).unwrap_or_else(|| core_url.clone()),
+        core_url,
         jwt_secret: env("JWT_SECRET", "dev-secret-change-me"),
+        shared_secret: std::env::var("GATEWAY_SHARED_SECRET").ok().filter(|s| !s.is_empty()).and_then(|s| s.parse().ok()),
+        trust_forwarded_for: env("TRUST_FORWARDED_FOR", "false") == "true",
         rate_limiters: DashMap::new(),
         start_time: Instant::now(),
     });
-    let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
+    let cors = cors_from_env();
     let public = Router::new()
         .route("/health", get(health))
         .route("/license", get(license_handler));
     let api = Router::new()
-        .route("/api/v1/{*p}", any(proxy_core))
+        .route("/api/v1/*p", any(proxy_core))
+        .route("/api/v2/*p", any(proxy_core))
         .layer(middleware::from_fn_with_state(state.clone(), auth_mw))
         .layer(middleware::from_fn_with_state(state.clone(), rate_mw));
     let app = Router::new()
@@ -85,7 +112,18 @@ async fn main() {
     let addr = std::env::var("GATEWAY_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".into());
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     tracing::info!("API Gateway on {addr}");
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+}
+
+/// Browser origins allowed by `CORS_ALLOWED_ORIGINS` (comma-separated); none when unset.
+fn cors_from_env() -> CorsLayer {
+    let origins: Vec<HeaderValue> = std::env::var("CORS_ALLOWED_ORIGINS").unwrap_or_default().split(',').map(str::trim).filter(|o| !o.is_empty()).filter_map(|o| o.parse().ok()).collect();
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::IF_MODIFIED_SINCE, header::IF_NONE_MATCH, header::HeaderName::from_static("idempotency-key"), header::HeaderName::from_static("x-grpc-web"), header::HeaderName::from_static("x-user-agent")])
+        .expose_headers([header::ETAG, header::HeaderName::from_static("grpc-status"), header::HeaderName::from_static("grpc-message")])
+        .allow_credentials(true)
 }
 
 async fn health(State(s): State<Arc<AppState>>) -> Json<Health> {
@@ -107,8 +145,18 @@ async fn license_handler() -> (HeaderMap, Json<LicenseInfo>) {
 }
 
 async fn auth_mw(
-    State(s): State<Arc<AppState>>, mut req: Request, next: Next,
+    State(s): State<Arc<AppState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, mut req: Request, next: Next,
 ) -> Result<Response, (StatusCode, Json<Err>)> {
+    // Never trust a client-supplied tenant, role, scopes, actor or address; all are derived below.
+    req.headers_mut().remove("x-gateway-secret");
+    if let Some(v) = &s.shared_secret { req.headers_mut().insert("x-gateway-secret", v.clone()); }
+    req.headers_mut().remove("x-tenant-id");
+    req.headers_mut().remove("x-gateway-role");
+    req.headers_mut().remove("x-gateway-scopes");
+    req.headers_mut().remove("x-gateway-actor");
+    req.headers_mut().remove("x-gateway-client-ip");
+    let forwarded = s.trust_forwarded_for.then(|| req.headers().get("x-forwarded-for").and_then(|h| h.to_str().ok()).and_then(|f| f.split(',').next()).map(|ip| ip.trim().to_string())).flatten();
+    if let Ok(v) = forwarded.unwrap_or_else(|| peer.ip().to_string()).parse() { req.headers_mut().insert("x-gateway-client-ip", v); }
     let auth = req.headers().get("Authorization").and_then(|h| h.to_str().ok()).map(|s| s.to_string());
     let api_key = req.headers().get("X-API-Key").and_then(|h| h.to_str().ok()).map(|s| s.to_string());
     if let Some(a) = &auth {
@@ -120,18 +168,42 @@ async fn auth_mw(
                 &jsonwebtoken::DecodingKey::from_secret(s.jwt_secret.as_bytes()),
                 &val,
             ) {
-                Ok(data) => { req.extensions_mut().insert(data.claims); return Ok(next.run(req).await); }
+                Ok(data) => {
+                    if let Ok(v) = data.claims.sub.parse() { req.headers_mut().insert("x-tenant-id", v); }
+                    req.headers_mut().insert("x-gateway-role", HeaderValue::from_static(gateway_role(&data.claims)));
+                    if let Ok(v) = format!("user:{}", data.claims.email.as_deref().unwrap_or(&data.claims.sub)).parse() { req.headers_mut().insert("x-gateway-actor", v); }
+                    req.extensions_mut().insert(data.claims);
+                    return Ok(next.run(req).await);
+                }
                 Err(e) => return Err((StatusCode::UNAUTHORIZED, Json(Err { error: "Invalid token".into(), details: Some(e.to_string()) }))),
             }
         }
     }
-    if api_key.is_some() {
-        req.extensions_mut().insert(Claims { sub: "api-key-user".into(), email: None, role: Some("api".into()), exp: usize::MAX });
+    if let Some(key) = api_key {
+        let owner = verify_api_key(&s.core_internal_url, &key).await?;
+        if let Ok(v) = owner.tenant.parse() { req.headers_mut().insert("x-tenant-id", v); }
+        if let Ok(v) = owner.role.parse() { req.headers_mut().insert("x-gateway-role", v); }
+        if let Some(Ok(v)) = owner.scopes.map(|s| s.join(",").parse()) { req.headers_mut().insert("x-gateway-scopes", v); }
+        if let Ok(v) = format!("key:{}", key.chars().take(12).collect::<String>()).parse() { req.headers_mut().insert("x-gateway-actor", v); }
+        req.extensions_mut().insert(Claims { sub: owner.tenant, email: None, app_metadata: AppMetadata::default(), exp: usize::MAX });
         return Ok(next.run(req).await);
     }
     Err((StatusCode::UNAUTHORIZED, Json(Err { error: "Auth required".into(), details: Some("Provide Bearer token or X-API-Key".into()) })))
 }
 
+#[derive(Deserialize)]
+struct KeyOwner { tenant: String, role: String, scopes: Option<Vec<String>> }
+
+async fn verify_api_key(core_url: &str, key: &str) -> Result<KeyOwner, (StatusCode, Json<Err>)> {
+    let resp = reqwest::Client::new().post(format!("{core_url}/internal/keys/verify")).json(&serde_json::json!({ "key": key })).send().await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, Json(Err { error: "Upstream unavailable".into(), details: Some(e.to_string()) })))?;
+    if !resp.status().is_success() {
+        return Err((StatusCode::UNAUTHORIZED, Json(Err { error: "Invalid API key".into(), details: None })));
+    }
+    resp.json().await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, Json(Err { error: "Read fail".into(), details: Some(e.to_string()) })))
+}
+
 async fn rate_mw(
     State(s): State<Arc<AppState>>, req: Request, next: Next,
 ) -> Result<Response, (StatusCode, Json<Err>)> {
@@ -144,7 +216,18 @@ async fn rate_mw(
     Ok(next.run(req).await)
 }
 
+/// Headers that describe one connection and are not forwarded to the next.
+const HOP_BY_HOP: &[&str] = &["host", "connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade"];
+
+fn upstream_err(e: impl ToString) -> (StatusCode, Json<Err>) {
+    (StatusCode::BAD_GATEWAY, Json(Err { error: "Upstream unavailable".into(), details: Some(e.to_string()) }))
+}
+
+/// Proxies `req` to the engine and streams the response back chunk by chunk, so SSE, NDJSON
+/// exports and gRPC-Web streams reach the client as the engine writes them. Upgrade requests (WebSocket) are
+/// handed to [`forward_upgrade`].
 async fn forward(url: &str, req: Request) -> Result<Response, (StatusCode, Json<Err>)> {
+    if req.headers().contains_key(header::UPGRADE) { return forward_upgrade(url, req).await; }
     let client = reqwest::Client::new();
     let path = req.uri().path().to_owned();
     let q = req.uri().query().map(|q| format!("?{q}")).unwrap_or_default();
@@ -153,16 +236,45 @@ async fn forward(url: &str, req: Request) -> Result<Response, (StatusCode, Json<
     let body = axum::body::to_bytes(req.into_body(), 50 * 1024 * 1024).await
         .map_err(|e| (StatusCode::BAD_REQUEST, Json(Err { error: "Body read fail".into(), details: Some(e.to_string()) })))?;
     let mut r = client.request(method, format!("{url}{path}{q}"));
-    for (k, v) in hdrs.iter() { if k != "host" { r = r.header(k, v); } }
-    let resp = r.body(body).send().await
-        .map_err(|e| (StatusCode::BAD_GATEWAY, Json(Err { error: "Upstream unavailable".into(), details: Some(e.to_string()) })))?;
+    for (k, v) in hdrs.iter() { if !HOP_BY_HOP.contains(&k.as_str()) { r = r.header(k, v); } }
+    let resp = r.body(body).send().await.map_err(upstream_err)?;
     let st = StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-    let rh = resp.headers().clone();
-    let rb = resp.bytes().await
-        .map_err(|e| (StatusCode::BAD_GATEWAY, Json(Err { error: "Read fail".into(), details: Some(e.to_string()) })))?;
     let mut b = Response::builder().status(st);
-    for (k, v) in rh.iter() { b = b.header(k, v); }
-    b.body(Body::from(rb))
+    for (k, v) in resp.headers().iter() { if !HOP_BY_HOP.contains(&k.as_str()) { b = b.header(k, v); } }
+    let chunks = futures_util::stream::try_unfold(resp, |mut resp| async move { Ok::<_, reqwest::Error>(resp.chunk().await?.map(|c| (c, resp))) });
+    b.body(Body::from_stream(chunks))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, Json(Err { error: "Build fail".into(), details: Some(e.to_string()) })))
+}
+
+/// Replays an upgrade request to the engine over its own HTTP/1.1 connection and, once the
+/// engine switches protocols, splices the two upgraded connections together. Needs an
+/// `http://` `CORE_ENGINE_URL`.
+async fn forward_upgrade(url: &str, mut req: Request) -> Result<Response, (StatusCode, Json<Err>)> {
+    let authority = url.strip_prefix("http://").map(|a| a.trim_end_matches('/'))
+        .ok_or_else(|| upstream_err("upgrades are only proxied to an http:// CORE_ENGINE_URL"))?;
+    let addr = if authority.contains(':') { authority.to_string() } else { format!("{authority}:80") };
+    let tcp = tokio::net::TcpStream::connect(&addr).await.map_err(upstream_err)?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tcp)).await.map_err(upstream_err)?;
+    tokio::spawn(async move { if let Err(e) = conn.with_upgrades().await { tracing::debug!("upgraded upstream connection closed: {e}"); } });
+    let mut upstream = hyper::Request::builder().method(req.method()).uri(req.uri().path_and_query().map_or("/", |p| p.as_str()));
+    for (k, v) in req.headers().iter() { if k != "host" { upstream = upstream.header(k, v); } }
+    let upstream = upstream.header(header::HOST, authority).body(Body::empty())
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(Err { error: "Bad upgrade request".into(), details: Some(e.to_string()) })))?;
+    let mut resp = sender.send_request(upstream).await.map_err(upstream_err)?;
+    let st = resp.status();
+    let mut b = Response::builder().status(st);
+    for (k, v) in resp.headers().iter() { b = b.header(k, v); }
+    if st == StatusCode::SWITCHING_PROTOCOLS {
+        let (client, engine) = (hyper::upgrade::on(&mut req), hyper::upgrade::on(&mut resp));
+        tokio::spawn(async move {
+            match tokio::try_join!(client, engine) {
+                Ok((client, engine)) => { let _ = tokio::io::copy_bidirectional(&mut TokioIo::new(client), &mut TokioIo::new(engine)).await; }
+                Err(e) => tracing::debug!("upgrade failed: {e}"),
+            }
+        });
+        return b.body(Body::empty()).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, Json(Err { error: "Build fail".into(), details: Some(e.to_string()) })));
+    }
+    b.body(Body::new(resp.into_body()))
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, Json(Err { error: "Build fail".into(), details: Some(e.to_string()) })))
 }
 
@@ -171,3 +283,47 @@ async fn proxy_core(
 ) -> Result<Response, (StatusCode, Json<Err>)> {
     forward(&s.core_url, req).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(payload: serde_json::Value) -> Claims { serde_json::from_value(payload).unwrap() }
+
+    #[test]
+    fn supabase_token_without_gateway_role_is_a_viewer() {
+        let c = claims(serde_json::json!({ "sub": "t1", "email": "a@example.com", "role": "authenticated", "exp": 1 }));
+        assert_eq!(gateway_role(&c), "viewer");
+    }
+
+    #[test]
+    fn gateway_role_comes_from_app_metadata() {
+        for role in ["viewer", "operator", "tenant-admin", "Operator"] {
+            let c = claims(serde_json::json!({ "sub": "t1", "role": "authenticated", "app_metadata": { "gateway_role": role }, "exp": 1 }));
+            assert_eq!(gateway_role(&c), role.to_ascii_lowercase());
+        }
+    }
+
+    #[test]
+    fn unknown_or_admin_gateway_role_is_a_viewer() {
+        for role in ["admin", "superuser", ""] {
+            let c = claims(serde_json::json!({ "sub": "t1", "app_metadata": { "gateway_role": role }, "exp": 1 }));
+            assert_eq!(gateway_role(&c), "viewer");
+        }
+    }
+
+    #[test]
+    fn role_claim_alone_grants_nothing() {
+        let c = claims(serde_json::json!({ "sub": "t1", "role": "tenant-admin", "app_metadata": { "provider": "email" }, "exp": 1 }));
+        assert_eq!(gateway_role(&c), "viewer");
+    }
+
+    #[test]
+    fn signed_token_decodes_to_its_gateway_role() {
+        let secret = b"test-secret";
+        let payload = serde_json::json!({ "sub": "t1", "role": "authenticated", "app_metadata": { "gateway_role": "operator" }, "exp": usize::MAX });
+        let token = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &payload, &jsonwebtoken::EncodingKey::from_secret(secret)).unwrap();
+        let data = jsonwebtoken::decode::<Claims>(&token, &jsonwebtoken::DecodingKey::from_secret(secret), &jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256)).unwrap();
+        assert_eq!(gateway_role(&data.claims), "operator");
+    }
+}
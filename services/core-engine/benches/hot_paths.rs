@@ -0,0 +1,14 @@
+//! Criterion suite over the gateway's sync and transform hot paths; the cases are shared with
+//! `POST /admin/debug/bench`. Run with `cargo bench`, or `cargo bench -- codec` for one group.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use gateway_engine::bench;
+
+fn hot_paths(c: &mut Criterion) {
+    for mut case in bench::cases() {
+        c.benchmark_group(case.group).bench_function(case.name.clone(), |b| b.iter(|| case.run()));
+    }
+}
+
+criterion_group!(benches, hot_paths);
+criterion_main!(benches);
@@ -0,0 +1,101 @@
+//! Structured access log for SIEM shipping: one JSON line per request with route, tenant,
+//! device, status, latency and byte counts. Enabled by `ACCESS_LOG` (`stdout` or a file path
+//! to append to). `ACCESS_LOG_SAMPLE_RATE` (0.0-1.0, default 1.0) samples successful requests;
+//! 5xx responses are always logged. With `ACCESS_LOG_PAYLOADS=true` JSON request bodies up to
+//! 64 KiB are included, with every field named in `ACCESS_LOG_REDACT` replaced by `"[redacted]"`
+//! at any depth.
+
+use crate::events::now_ms;
+use crate::{AppState, Tenant};
+use axum::{
+    body::{to_bytes, Body, HttpBody},
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{json, Value};
+use std::io::{LineWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const MAX_LOGGED_BODY: u64 = 64 * 1024;
+const DEFAULT_REDACT: &str = "api_key,key,password,secret,token,authorization,credentials";
+
+pub struct AccessLog { sink: Option<Mutex<Box<dyn Write + Send>>>, sample_rate: f64, payloads: bool, redact: Vec<String> }
+
+impl AccessLog {
+    pub fn from_env() -> Self {
+        let sink: Option<Box<dyn Write + Send>> = match std::env::var("ACCESS_LOG").ok().filter(|v| !v.is_empty()) {
+            None => None,
+            Some(t) if t == "stdout" => Some(Box::new(LineWriter::new(std::io::stdout()))),
+            Some(path) => match std::fs::OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(f) => Some(Box::new(LineWriter::new(f))),
+                Err(e) => { tracing::warn!("Access log disabled, cannot open {path}: {e}"); None }
+            },
+        };
+        let sample_rate = std::env::var("ACCESS_LOG_SAMPLE_RATE").ok().and_then(|v| v.parse().ok()).unwrap_or(1.0f64).clamp(0.0, 1.0);
+        let payloads = std::env::var("ACCESS_LOG_PAYLOADS").is_ok_and(|v| v == "true" || v == "1");
+        let redact = std::env::var("ACCESS_LOG_REDACT").unwrap_or_else(|_| DEFAULT_REDACT.into()).split(',').map(|f| f.trim().to_ascii_lowercase()).filter(|f| !f.is_empty()).collect();
+        AccessLog { sink: sink.map(Mutex::new), sample_rate, payloads, redact }
+    }
+
+    fn sampled(&self) -> bool { self.sample_rate >= 1.0 || (uuid::Uuid::new_v4().as_u128() % 10_000) as f64 / 10_000.0 < self.sample_rate }
+
+    fn redact(&self, v: &mut Value) {
+        match v {
+            Value::Object(map) => for (k, v) in map.iter_mut() {
+                if self.redact.contains(&k.to_ascii_lowercase()) { *v = Value::String("[redacted]".into()); } else { self.redact(v); }
+            },
+            Value::Array(items) => items.iter_mut().for_each(|v| self.redact(v)),
+            _ => {}
+        }
+    }
+
+    fn write(&self, line: &Value) {
+        let Some(sink) = &self.sink else { return };
+        // A failing sink must not fail the request; the next line simply tries again.
+        let _ = writeln!(sink.lock().unwrap(), "{line}");
+    }
+}
+
+/// The device a request concerns: a `:device_id` path segment, else `device_id` in the body,
+/// else the device behind a `connection_id` (path or body).
+pub(crate) fn device_of(s: &AppState, path: &str, route: Option<&str>, body: Option<&Value>) -> Option<String> {
+    let param = |name: &str| route.and_then(|r| r.split('/').zip(path.split('/')).find(|(t, _)| t.strip_prefix(':') == Some(name)).map(|(_, v)| v.to_string()));
+    if let Some(d) = param("device_id").or_else(|| body.and_then(|b| b["device_id"].as_str()).map(str::to_owned)) { return Some(d); }
+    let conn = body.and_then(|b| b["connection_id"].as_str()).map(str::to_owned).or_else(|| route.filter(|r| r.contains("/connections/:id")).and_then(|_| param("id")))?;
+    s.connections.lock().unwrap().get(&conn).map(|c| c.device_id.clone())
+}
+
+pub async fn access_log_mw(State(s): State<Arc<AppState>>, Tenant(tenant): Tenant, req: Request, next: Next) -> Response {
+    let log = &s.access_log;
+    if log.sink.is_none() { return next.run(req).await; }
+    let started = Instant::now();
+    let (method, path) = (req.method().to_string(), req.uri().path().to_string());
+    let route = req.extensions().get::<MatchedPath>().map(|m| m.as_str().to_string());
+    let bytes_in = req.body().size_hint().exact().or_else(|| req.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse().ok()));
+    let is_json = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(|ct| ct.starts_with("application/json"));
+    // Only bodies of known, small size are buffered, so body limits further in still apply.
+    let (req, body) = if is_json && bytes_in.is_some_and(|n| n > 0 && n <= MAX_LOGGED_BODY) {
+        let (parts, b) = req.into_parts();
+        let bytes = to_bytes(b, MAX_LOGGED_BODY as usize).await.unwrap_or_default();
+        let parsed = serde_json::from_slice::<Value>(&bytes).ok();
+        (Request::from_parts(parts, Body::from(bytes)), parsed)
+    } else { (req, None) };
+    let device = device_of(&s, &path, route.as_deref(), body.as_ref());
+
+    let resp = next.run(req).await;
+    let status = resp.status();
+    if !status.is_server_error() && !log.sampled() { return resp; }
+    let bytes_out = resp.body().size_hint().exact().or_else(|| resp.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse().ok()));
+    let mut line = json!({
+        "ts_ms": now_ms(), "method": method, "route": route, "path": path, "tenant": tenant, "device": device,
+        "status": status.as_u16(), "latency_ms": started.elapsed().as_secs_f64() * 1000.0, "bytes_in": bytes_in, "bytes_out": bytes_out,
+    });
+    if log.payloads {
+        if let Some(mut b) = body { log.redact(&mut b); line["payload"] = b; }
+    }
+    log.write(&line);
+    resp
+}
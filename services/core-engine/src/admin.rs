@@ -0,0 +1,241 @@
+//! Operator API mounted at `/admin`, guarded by the `ADMIN_TOKEN` bearer token.
+//! Without `ADMIN_TOKEN` configured every admin route answers 503.
+
+use crate::apikeys::{self, IssuedKey, KeyInfo};
+use crate::audit::Actor;
+use crate::protocols::{CanaryRequest, CanaryStatus, PluginSpec};
+use crate::rbac::{Admin, Require, Role};
+use crate::resilience::BreakerSnapshot;
+use crate::{api_err, uploads, usage, ApiError, AppState, ProtocolInfo};
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{Json, Response},
+    routing::{delete, get, post, put},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub struct DisconnectReport { device_id: String, connections_closed: usize }
+
+#[derive(Serialize)]
+pub struct TenantClearReport { tenant: String, connections: usize, webhooks: usize, alert_rules: usize, api_keys: usize, shadows: usize, uploads: usize, schedules: usize, sync_records: usize, groups: usize, jobs: usize, geofence_policies: usize, routed_objects: usize, parked_sessions: usize, anomaly_baselines: usize, provisioned_devices: usize, schema_versions: usize, soft_deleted: usize, transform_rules: usize,
+    meshes: usize, standbys: usize, dead_letters: usize, usage_buckets: usize, envelopes: usize, telemetry_points: u64,
+    /// Why the telemetry backend could not delete the tenant's points, if it could not.
+    #[serde(skip_serializing_if = "Option::is_none")] telemetry_error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RotateQuery { role: Option<Role> }
+
+#[derive(Deserialize, Serialize)]
+pub struct MaintenanceMode { enabled: bool }
+
+#[derive(Serialize)]
+pub struct Diagnostics {
+    version: String, uptime_secs: u64, maintenance: bool, telemetry_backend: String,
+    connections: usize, standbys: usize, webhooks: usize, alert_rules: usize, firing_alerts: usize, api_keys: usize, event_subscribers: usize,
+    total_connections: u64, total_syncs: u64, total_transforms: u64, bytes_relayed: u64,
+}
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/devices/:device_id/disconnect", post(force_disconnect))
+        .route("/tenants/:tenant/state", delete(clear_tenant))
+        .route("/tenants/:tenant/keys", get(list_keys))
+        .route("/tenants/:tenant/keys/rotate", post(rotate_keys))
+        .route("/tenants/:tenant/keys/:prefix", delete(revoke_key))
+        .route("/tenants/:tenant/quota", put(crate::usage::set_quota))
+        .route("/tenants/:tenant/bandwidth", put(crate::shaping::set_limits))
+        .route("/tenants/:tenant/encryption", put(crate::envelope::set_required))
+        .route("/tenants/:tenant/encryption-keys", get(crate::keystore::list))
+        .route("/tenants/:tenant/encryption-keys/rotate", post(crate::keystore::rotate))
+        .route("/tenants/:tenant/provisioning", put(crate::provisioning::set_required))
+        .route("/protocols/:name", put(register_protocol).delete(remove_protocol))
+        .route("/protocols/:name/canary", get(canary_status).put(set_canary).delete(rollback_canary))
+        .route("/protocols/:name/canary/promote", post(promote_canary))
+        .route("/maintenance", get(get_maintenance).put(set_maintenance))
+        .route("/maintenance/windows", get(crate::maintenance::list).post(crate::maintenance::schedule))
+        .route("/maintenance/windows/:id", delete(crate::maintenance::cancel))
+        .route("/chaos/faults", get(crate::chaos::list).post(crate::chaos::create).delete(crate::chaos::clear))
+        .route("/chaos/faults/:id", delete(crate::chaos::remove))
+        .route("/diagnostics", get(diagnostics))
+        .route("/recovery", get(crate::wal::recovery))
+        .route("/debug/bench", post(crate::bench::run))
+        .route("/cors", get(crate::cors::view))
+        .route("/breakers", get(breakers))
+        .route("/breakers/:name/reset", post(reset_breaker))
+        .route("/audit", get(crate::audit::query))
+        .route("/audit/verify", get(crate::audit::verify))
+        .route("/routing/rules", get(crate::routing::list_rules).post(crate::routing::create_rule))
+        .route("/routing/rules/:id", delete(crate::routing::remove_rule))
+        .layer(middleware::from_fn(admin_auth_mw))
+}
+
+pub(crate) fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn admin_auth_mw(mut req: Request, next: Next) -> Result<Response, ApiError> {
+    let Ok(expected) = std::env::var("ADMIN_TOKEN") else { return Err(api_err(StatusCode::SERVICE_UNAVAILABLE, "Admin API disabled", Some("ADMIN_TOKEN is not configured".into()))); };
+    let given = req.headers().get("Authorization").and_then(|h| h.to_str().ok()).and_then(|a| a.strip_prefix("Bearer "));
+    match given {
+        Some(t) if token_matches(t, &expected) => {
+            req.extensions_mut().insert(Role::Admin);
+            Ok(next.run(req).await)
+        }
+        _ => Err(api_err(StatusCode::UNAUTHORIZED, "Admin auth required", Some("Provide Bearer ADMIN_TOKEN".into()))),
+    }
+}
+
+/// Applied to every non-admin route: while maintenance mode is on they answer 503, and during a
+/// scheduled maintenance window writes from the tenants it covers do.
+pub async fn maintenance_mw(State(s): State<Arc<AppState>>, req: Request, next: Next) -> Result<Response, ApiError> {
+    if s.maintenance.load(Ordering::Relaxed) && !matches!(req.uri().path(), "/health" | "/health/live" | "/health/ready" | "/metrics") {
+        return Err(api_err(StatusCode::SERVICE_UNAVAILABLE, "Maintenance in progress", None));
+    }
+    if crate::maintenance::writes(req.method(), req.uri().path()) { crate::maintenance::check(&s, crate::tenant::of(req.headers()))?; }
+    Ok(next.run(req).await)
+}
+
+async fn force_disconnect(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(device_id): Path<String>) -> Json<DisconnectReport> {
+    let ids: Vec<String> = s.connections.lock().unwrap().iter().filter(|(_, c)| c.device_id == device_id).map(|(id, _)| id.clone()).collect();
+    let connections_closed = ids.iter().filter_map(|id| s.drop_connection(id)).count();
+    tracing::info!(%device_id, connections_closed, "admin force-disconnect");
+    s.audit.record(&actor, "admin.device.disconnect", None, Some(&device_id), serde_json::json!({ "connections_closed": connections_closed }));
+    Json(DisconnectReport { device_id, connections_closed })
+}
+
+async fn clear_tenant(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(tenant): Path<String>) -> Json<TenantClearReport> {
+    // Standbys and envelopes also go with their connections, so count them first.
+    let standbys = { let mut sb = s.standbys.lock().unwrap(); let n = sb.len(); sb.retain(|_, x| x.tenant != tenant); n - sb.len() };
+    let envelopes = s.encryption.remove_tenant(&tenant);
+    let ids: Vec<String> = s.connections.lock().unwrap().iter().filter(|(_, c)| c.tenant == tenant).map(|(id, _)| id.clone()).collect();
+    let connections = ids.iter().filter_map(|id| s.drop_connection(id)).count();
+    let webhooks = { let mut w = s.webhooks.lock().unwrap(); let n = w.len(); w.retain(|_, h| h.tenant != tenant); n - w.len() };
+    let alert_rules = { let mut r = s.alert_rules.lock().unwrap(); let n = r.len(); r.retain(|_, a| a.tenant != tenant); n - r.len() };
+    let api_keys = { let mut k = s.api_keys.lock().unwrap(); let n = k.len(); k.retain(|_, a| a.tenant != tenant); n - k.len() };
+    let shadows = { let mut sh = s.shadows.lock().unwrap(); let n = sh.len(); sh.retain(|(t, _), _| *t != tenant); n - sh.len() };
+    let uploads = uploads::remove_tenant(&s, &tenant);
+    s.outbox.remove_tenant(&tenant);
+    let sync_records = s.sync_log.remove_tenant(&tenant);
+    let groups = s.groups.remove_tenant(&tenant);
+    let jobs = s.jobs.remove_tenant(&tenant);
+    let geofence_policies = s.geofence.remove_tenant(&tenant);
+    let routed_objects = s.routing.remove_tenant(&tenant);
+    let parked_sessions = s.resume.remove_tenant(&tenant);
+    let anomaly_baselines = s.anomalies.remove_tenant(&tenant);
+    let provisioned_devices = s.provisioning.remove_tenant(&tenant);
+    let transform_rules = s.unit_rules.remove_tenant(&tenant);
+    let schema_versions = s.schemas.remove_tenant(&tenant);
+    let soft_deleted = s.trash.remove_tenant(&tenant);
+    let schedules = { let mut j = s.schedules.lock().unwrap(); let n = j.len(); j.retain(|_, x| x.tenant != tenant); n - j.len() };
+    let meshes = { let mut m = s.meshes.lock().unwrap(); let n = m.len(); m.retain(|_, x| x.tenant != tenant); n - m.len() };
+    let dead_letters = s.dlq.remove_tenant(&tenant);
+    let usage_buckets = usage::remove_tenant(&s, &tenant);
+    s.stats.lock().unwrap().tenants.remove(&tenant);
+    let (telemetry_points, telemetry_error) = match s.telemetry.remove_tenant(&tenant).await {
+        Ok(n) => (n, None),
+        Err(e) => { tracing::warn!(%tenant, "telemetry not cleared: {e}"); (0, Some(e)) }
+    };
+    tracing::info!(%tenant, connections, webhooks, alert_rules, api_keys, shadows, uploads, schedules, sync_records, groups, jobs, geofence_policies, routed_objects, parked_sessions, anomaly_baselines, provisioned_devices, schema_versions, soft_deleted, transform_rules, meshes, standbys, dead_letters, usage_buckets, envelopes, telemetry_points, "admin cleared tenant state");
+    s.audit.record(&actor, "admin.tenant.clear", Some(&tenant), None, serde_json::json!({ "connections": connections, "webhooks": webhooks, "alert_rules": alert_rules, "api_keys": api_keys, "shadows": shadows, "uploads": uploads, "schedules": schedules, "sync_records": sync_records, "groups": groups, "jobs": jobs, "geofence_policies": geofence_policies, "routed_objects": routed_objects, "parked_sessions": parked_sessions, "anomaly_baselines": anomaly_baselines, "provisioned_devices": provisioned_devices, "schema_versions": schema_versions, "soft_deleted": soft_deleted, "transform_rules": transform_rules, "meshes": meshes, "standbys": standbys, "dead_letters": dead_letters, "usage_buckets": usage_buckets, "envelopes": envelopes, "telemetry_points": telemetry_points, "telemetry_error": telemetry_error }));
+    Json(TenantClearReport { tenant, connections, webhooks, alert_rules, api_keys, shadows, uploads, schedules, sync_records, groups, jobs, geofence_policies, routed_objects, parked_sessions, anomaly_baselines, provisioned_devices, schema_versions, soft_deleted, transform_rules, meshes, standbys, dead_letters, usage_buckets, envelopes, telemetry_points, telemetry_error })
+}
+
+async fn rotate_keys(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(tenant): Path<String>, Query(q): Query<RotateQuery>) -> Json<IssuedKey> {
+    let issued = apikeys::rotate(&s, &tenant, q.role.unwrap_or(Role::TenantAdmin));
+    tracing::info!(%tenant, prefix = %issued.prefix, role = issued.role.as_str(), "admin rotated API key");
+    s.audit.record(&actor, "admin.apikey.rotate", Some(&tenant), Some(&issued.prefix), serde_json::json!({ "role": issued.role }));
+    Json(issued)
+}
+
+async fn list_keys(State(s): State<Arc<AppState>>, _: Require<Admin>, Path(tenant): Path<String>) -> Json<Vec<KeyInfo>> {
+    Json(apikeys::list(&s, &tenant))
+}
+
+async fn revoke_key(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path((tenant, prefix)): Path<(String, String)>) -> Result<StatusCode, ApiError> {
+    if !apikeys::revoke(&s, &tenant, &prefix) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown API key", Some(prefix))); }
+    tracing::info!(%tenant, %prefix, "admin revoked API key");
+    s.audit.record(&actor, "admin.apikey.revoke", Some(&tenant), Some(&prefix), Value::Null);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn register_protocol(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(name): Path<String>, Json(mut spec): Json<PluginSpec>) -> Result<Json<ProtocolInfo>, ApiError> {
+    spec.name = name;
+    let info = s.protocols.register(spec).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid protocol plugin", Some(e)))?;
+    tracing::info!(protocol = %info.name, "admin registered protocol");
+    s.audit.record(&actor, "admin.protocol.register", None, Some(&info.name), Value::Null);
+    Ok(Json(info))
+}
+
+async fn remove_protocol(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(name): Path<String>) -> Result<StatusCode, ApiError> {
+    if !s.protocols.remove(&name) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown protocol", Some(name))); }
+    tracing::info!(protocol = %name, "admin removed protocol");
+    s.audit.record(&actor, "admin.protocol.remove", None, Some(&name), Value::Null);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_canary(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(name): Path<String>, Json(req): Json<CanaryRequest>) -> Result<Json<CanaryStatus>, ApiError> {
+    let status = s.protocols.set_canary(&name, req.spec, req.percent).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid canary", Some(e)))?;
+    tracing::info!(protocol = %name, percent = req.percent, "admin set protocol canary");
+    s.audit.record(&actor, "admin.protocol.canary", None, Some(&name), serde_json::json!({ "percent": req.percent }));
+    Ok(Json(status))
+}
+
+async fn canary_status(State(s): State<Arc<AppState>>, _: Require<Admin>, Path(name): Path<String>) -> Result<Json<CanaryStatus>, ApiError> {
+    s.protocols.canary_status(&name).map(Json).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "No canary for protocol", Some(name)))
+}
+
+async fn promote_canary(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(name): Path<String>) -> Result<Json<ProtocolInfo>, ApiError> {
+    let status = s.protocols.canary_status(&name);
+    let info = s.protocols.promote(&name).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "No canary for protocol", Some(name.clone())))?;
+    tracing::info!(protocol = %name, "admin promoted protocol canary");
+    s.audit.record(&actor, "admin.protocol.promote", None, Some(&name), serde_json::to_value(status).unwrap_or_default());
+    Ok(Json(info))
+}
+
+async fn rollback_canary(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(name): Path<String>) -> Result<StatusCode, ApiError> {
+    let status = s.protocols.canary_status(&name);
+    if !s.protocols.rollback(&name) { return Err(api_err(StatusCode::NOT_FOUND, "No canary for protocol", Some(name))); }
+    tracing::warn!(protocol = %name, "admin rolled back protocol canary");
+    s.audit.record(&actor, "admin.protocol.rollback", None, Some(&name), serde_json::to_value(status).unwrap_or_default());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_maintenance(State(s): State<Arc<AppState>>, _: Require<Admin>) -> Json<MaintenanceMode> {
+    Json(MaintenanceMode { enabled: s.maintenance.load(Ordering::Relaxed) })
+}
+
+async fn set_maintenance(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Json(m): Json<MaintenanceMode>) -> Json<MaintenanceMode> {
+    s.maintenance.store(m.enabled, Ordering::Relaxed);
+    tracing::warn!(enabled = m.enabled, "maintenance mode toggled");
+    s.audit.record(&actor, "admin.maintenance.set", None, None, serde_json::json!({ "enabled": m.enabled }));
+    Json(m)
+}
+
+async fn diagnostics(State(s): State<Arc<AppState>>, _: Require<Admin>) -> Json<Diagnostics> {
+    let (total_connections, total_syncs, total_transforms, bytes_relayed) = { let st = s.stats.lock().unwrap(); (st.total_connections, st.total_syncs, st.total_transforms, st.bytes_relayed) };
+    let (alert_rules, firing_alerts) = { let r = s.alert_rules.lock().unwrap(); (r.len(), r.values().filter(|a| a.firing).count()) };
+    Json(Diagnostics {
+        version: env!("CARGO_PKG_VERSION").into(), uptime_secs: s.start_time.elapsed().as_secs(), maintenance: s.maintenance.load(Ordering::Relaxed), telemetry_backend: s.telemetry.name().into(),
+        connections: s.connections.lock().unwrap().len(), standbys: s.standbys.lock().unwrap().len(), webhooks: s.webhooks.lock().unwrap().len(), alert_rules, firing_alerts,
+        api_keys: s.api_keys.lock().unwrap().len(), event_subscribers: s.hub.counts().subscribers,
+        total_connections, total_syncs, total_transforms, bytes_relayed,
+    })
+}
+
+async fn breakers(State(s): State<Arc<AppState>>, _: Require<Admin>) -> Json<Vec<BreakerSnapshot>> { Json(s.breakers.snapshots()) }
+
+async fn reset_breaker(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(name): Path<String>) -> Result<Json<BreakerSnapshot>, ApiError> {
+    let b = s.breakers.find(&name).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown breaker", Some(name.clone())))?;
+    b.reset();
+    tracing::info!(breaker = %name, "admin reset circuit breaker");
+    s.audit.record(&actor, "admin.breaker.reset", None, Some(&name), Value::Null);
+    Ok(Json(b.snapshot()))
+}
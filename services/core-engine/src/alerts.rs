@@ -0,0 +1,140 @@
+//! Declarative alert rules evaluated in-process against the gateway's own counters, billing
+//! usage and tenant telemetry rollups. Every metric is the rule's tenant's alone. Breaches and
+//! recoveries are emitted as `alert-fired` / `alert-resolved` events, so they reach webhooks
+//! like any other event.
+//!
+//! Metrics: `gateway.active_connections` (gauge); `gateway.connections`, `gateway.syncs`,
+//! `gateway.transforms`, `gateway.bytes_relayed` (increase over the window); `usage.syncs`,
+//! `usage.bytes_relayed` (billed in the hourly usage buckets the window overlaps); and
+//! `telemetry.<metric>` (mean of the tenant's telemetry over the window).
+
+use crate::events::now_ms;
+use crate::rbac::{Configure, Read, Require};
+use crate::telemetry::Window;
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const EVAL_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_WINDOW_SECS: u64 = 3600;
+const COUNTERS: &[&str] = &["gateway.connections", "gateway.syncs", "gateway.transforms", "gateway.bytes_relayed"];
+const USAGE: &[&str] = &["usage.syncs", "usage.bytes_relayed"];
+
+/// A tenant's totals for each of `COUNTERS`.
+pub type Counters = [u64; 4];
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Comparator { Gt, Gte, Lt, Lte }
+
+impl Comparator {
+    fn breached(self, value: f64, threshold: f64) -> bool {
+        match self { Comparator::Gt => value > threshold, Comparator::Gte => value >= threshold, Comparator::Lt => value < threshold, Comparator::Lte => value <= threshold }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct AlertRule {
+    pub id: String, #[serde(skip)] pub tenant: String, pub name: String, pub metric: String, pub comparator: Comparator, pub threshold: f64, pub window_secs: u64, pub severity: String,
+    pub firing: bool, pub last_value: Option<f64>, pub last_evaluated_ms: Option<u64>, pub fired_at_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct AlertRuleRequest { name: String, metric: String, comparator: Comparator, threshold: f64, window_secs: Option<u64>, severity: Option<String> }
+
+/// Per-tenant counter snapshot taken every evaluation tick; windows are computed as deltas
+/// between snapshots.
+pub struct Sample { at: Instant, counters: HashMap<String, Counters> }
+
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Json(req): Json<AlertRuleRequest>) -> Result<(StatusCode, Json<AlertRule>), ApiError> {
+    let known = req.metric == "gateway.active_connections" || COUNTERS.contains(&req.metric.as_str()) || USAGE.contains(&req.metric.as_str()) || req.metric.strip_prefix("telemetry.").is_some_and(|m| !m.is_empty());
+    if !known { return Err(api_err(StatusCode::BAD_REQUEST, "Unknown metric", Some(req.metric))); }
+    let window_secs = req.window_secs.unwrap_or(300);
+    if !(EVAL_INTERVAL.as_secs()..=MAX_WINDOW_SECS).contains(&window_secs) {
+        return Err(api_err(StatusCode::BAD_REQUEST, "Invalid window", Some(format!("window_secs must be between {} and {MAX_WINDOW_SECS}", EVAL_INTERVAL.as_secs()))));
+    }
+    let severity = req.severity.unwrap_or_else(|| "warning".into());
+    if !["info", "warning", "critical"].contains(&severity.as_str()) { return Err(api_err(StatusCode::BAD_REQUEST, "Invalid severity", Some(severity))); }
+    let rule = AlertRule { id: uuid::Uuid::new_v4().to_string(), tenant, name: req.name, metric: req.metric, comparator: req.comparator, threshold: req.threshold, window_secs, severity, firing: false, last_value: None, last_evaluated_ms: None, fired_at_ms: None };
+    s.alert_rules.lock().unwrap().insert(rule.id.clone(), rule.clone());
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<AlertRule>> {
+    Json(s.alert_rules.lock().unwrap().values().filter(|r| r.tenant == tenant).cloned().collect())
+}
+
+pub async fn firing(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<AlertRule>> {
+    Json(s.alert_rules.lock().unwrap().values().filter(|r| r.tenant == tenant && r.firing).cloned().collect())
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let mut rules = s.alert_rules.lock().unwrap();
+    if rules.get(&id).is_none_or(|r| r.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown alert rule", None)); }
+    rules.remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn counter_increase(s: &AppState, tenant: &str, metric: &str, window: Duration) -> Option<f64> {
+    let idx = COUNTERS.iter().position(|c| *c == metric)?;
+    let samples = s.metric_samples.lock().unwrap();
+    let latest = samples.back()?;
+    let cutoff = latest.at.checked_sub(window)?;
+    let base = samples.iter().rev().find(|x| x.at <= cutoff).or(samples.front())?;
+    let count = |x: &Sample| x.counters.get(tenant).map_or(0, |c| c[idx]);
+    Some(count(latest).saturating_sub(count(base)) as f64)
+}
+
+async fn value_of(s: &AppState, rule: &AlertRule) -> Option<f64> {
+    if rule.metric == "gateway.active_connections" { return Some(s.connections.lock().unwrap().values().filter(|c| c.tenant == rule.tenant).count() as f64); }
+    if let Some(idx) = USAGE.iter().position(|m| *m == rule.metric) {
+        let to_ms = now_ms();
+        let (syncs, bytes) = crate::usage::totals(s, &rule.tenant, to_ms.saturating_sub(rule.window_secs * 1000), to_ms);
+        return Some([syncs, bytes][idx] as f64);
+    }
+    if let Some(metric) = rule.metric.strip_prefix("telemetry.") {
+        let to_ms = now_ms();
+        let bucket_ms = rule.window_secs * 1000;
+        let w = Window { tenant: &rule.tenant, metric, device_id: None, from_ms: to_ms.saturating_sub(bucket_ms), to_ms, bucket_ms };
+        let buckets = s.telemetry.rollup(&w).await.ok()?;
+        let count: u64 = buckets.iter().map(|b| b.count).sum();
+        return (count > 0).then(|| buckets.iter().map(|b| b.avg * b.count as f64).sum::<f64>() / count as f64);
+    }
+    counter_increase(s, &rule.tenant, &rule.metric, Duration::from_secs(rule.window_secs))
+}
+
+pub async fn evaluate_loop(s: Arc<AppState>) {
+    let mut tick = tokio::time::interval(EVAL_INTERVAL);
+    loop {
+        tick.tick().await;
+        let counters = s.stats.lock().unwrap().tenants.clone();
+        {
+            let mut samples = s.metric_samples.lock().unwrap();
+            samples.push_back(Sample { at: Instant::now(), counters });
+            while samples.len() as u64 > MAX_WINDOW_SECS / EVAL_INTERVAL.as_secs() + 1 { samples.pop_front(); }
+        }
+        let rules: Vec<AlertRule> = s.alert_rules.lock().unwrap().values().cloned().collect();
+        for rule in rules {
+            let value = value_of(&s, &rule).await;
+            let breached = value.is_some_and(|v| rule.comparator.breached(v, rule.threshold));
+            let transition = {
+                let mut all = s.alert_rules.lock().unwrap();
+                let Some(r) = all.get_mut(&rule.id) else { continue };
+                r.last_value = value;
+                r.last_evaluated_ms = Some(now_ms());
+                let changed = r.firing != breached;
+                r.firing = breached;
+                if changed && breached { r.fired_at_ms = r.last_evaluated_ms; }
+                changed.then(|| r.clone())
+            };
+            if let Some(r) = transition {
+                let kind = if r.firing { "alert-fired" } else { "alert-resolved" };
+                tracing::info!(rule = %r.id, metric = %r.metric, value = ?r.last_value, "{kind}");
+                s.emit(kind, &r.tenant, serde_json::json!({ "rule_id": r.id, "name": r.name, "metric": r.metric, "value": r.last_value, "threshold": r.threshold, "severity": r.severity }));
+            }
+        }
+    }
+}
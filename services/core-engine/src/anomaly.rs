@@ -0,0 +1,159 @@
+//! Per-device anomaly detection on sync patterns. Every connected device keeps an EWMA
+//! baseline (mean and variance, smoothing `ANOMALY_ALPHA`, default 0.1) of three metrics:
+//! `sync_rate` (syncs per window of `ANOMALY_WINDOW_SECS`, default 60), `payload_bytes` (per
+//! accepted sync) and `error_rate` (share of failed syncs per window; back-pressure sheds are
+//! the gateway's doing and do not count). Once a baseline has `ANOMALY_WARMUP` samples
+//! (default 10), a sample more than `ANOMALY_Z_THRESHOLD` (default 3) standard deviations from
+//! it flags the device, emitting `anomaly-detected`; the first sample back within the threshold
+//! emits `anomaly-cleared`. The deviation is floored at a tenth of the mean (and a small
+//! per-metric minimum), so perfectly regular devices are not flagged over noise.
+//! `GET /api/v1/gateway/anomalies` lists the tenant's current anomalies, largest first.
+
+use crate::events::now_ms;
+use crate::rbac::{Read, Require};
+use crate::{AppState, Tenant};
+use axum::{extract::State, response::Json};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric { SyncRate, PayloadBytes, ErrorRate }
+
+impl Metric {
+    fn min_deviation(self) -> f64 { match self { Metric::SyncRate => 1.0, Metric::PayloadBytes => 16.0, Metric::ErrorRate => 0.05 } }
+}
+
+#[derive(Default)]
+struct Baseline { mean: f64, var: f64, samples: u64 }
+
+#[derive(Default)]
+struct DeviceStats { rate: Baseline, bytes: Baseline, errors: Baseline, window_syncs: u64, window_errors: u64 }
+
+#[derive(Clone, Serialize)]
+pub struct Anomaly { device_id: String, metric: Metric, value: f64, baseline: f64, stddev: f64, z: f64, since_ms: u64, updated_ms: u64 }
+
+type DeviceKey = (String, String);
+
+struct Sample { tenant: String, device_id: String, metric: Metric, value: f64, baseline: f64, stddev: f64, z: Option<f64> }
+
+#[derive(Default)]
+struct Tracked { devices: HashMap<DeviceKey, DeviceStats>, active: HashMap<(String, String, Metric), Anomaly> }
+
+pub struct Detector { window: Duration, threshold: f64, alpha: f64, warmup: u64, state: Mutex<Tracked> }
+
+impl Baseline {
+    /// Scores `x` against the baseline as it was, then folds `x` in; `None` during warm-up.
+    fn observe(&mut self, x: f64, metric: Metric, alpha: f64, warmup: u64) -> (f64, f64, Option<f64>) {
+        let (mean, sd) = (self.mean, self.var.sqrt().max(self.mean.abs() * 0.1).max(metric.min_deviation()));
+        let z = (self.samples >= warmup).then(|| (x - mean) / sd);
+        if self.samples == 0 { self.mean = x; } else {
+            let d = x - self.mean;
+            self.mean += alpha * d;
+            self.var = (1.0 - alpha) * (self.var + alpha * d * d);
+        }
+        self.samples += 1;
+        (mean, sd, z)
+    }
+}
+
+impl Detector {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: f64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        Detector {
+            window: Duration::from_secs(env("ANOMALY_WINDOW_SECS", 60.0).max(1.0) as u64), threshold: env("ANOMALY_Z_THRESHOLD", 3.0),
+            alpha: env("ANOMALY_ALPHA", 0.1).clamp(0.001, 1.0), warmup: env("ANOMALY_WARMUP", 10.0) as u64, state: Mutex::new(Tracked::default()),
+        }
+    }
+
+    /// Records one sync attempt; accepted syncs also sample their payload size.
+    pub fn observe(&self, s: &AppState, tenant: &str, device_id: &str, bytes: u64, failed: bool) {
+        let sample = {
+            let mut st = self.state.lock().unwrap();
+            let d = st.devices.entry((tenant.into(), device_id.into())).or_default();
+            d.window_syncs += 1;
+            if failed { d.window_errors += 1; return; }
+            let (baseline, stddev, z) = d.bytes.observe(bytes as f64, Metric::PayloadBytes, self.alpha, self.warmup);
+            Sample { tenant: tenant.into(), device_id: device_id.into(), metric: Metric::PayloadBytes, value: bytes as f64, baseline, stddev, z }
+        };
+        self.settle(s, vec![sample]);
+    }
+
+    /// Closes the window: samples rate and error rate for every connected device and forgets
+    /// devices that are gone.
+    fn close_window(&self, s: &AppState) {
+        let connected: HashSet<DeviceKey> = s.connections.lock().unwrap().values().map(|c| (c.tenant.clone(), c.device_id.clone())).collect();
+        let mut samples = Vec::new();
+        {
+            let mut st = self.state.lock().unwrap();
+            st.devices.retain(|k, _| connected.contains(k));
+            st.active.retain(|(t, d, _), _| connected.contains(&(t.clone(), d.clone())));
+            for key in connected { st.devices.entry(key).or_default(); }
+            for ((tenant, device_id), d) in st.devices.iter_mut() {
+                let (syncs, errors) = (std::mem::take(&mut d.window_syncs), std::mem::take(&mut d.window_errors));
+                let rate = syncs as f64;
+                let (baseline, stddev, z) = d.rate.observe(rate, Metric::SyncRate, self.alpha, self.warmup);
+                samples.push(Sample { tenant: tenant.clone(), device_id: device_id.clone(), metric: Metric::SyncRate, value: rate, baseline, stddev, z });
+                if syncs > 0 {
+                    let ratio = errors as f64 / syncs as f64;
+                    let (baseline, stddev, z) = d.errors.observe(ratio, Metric::ErrorRate, self.alpha, self.warmup);
+                    samples.push(Sample { tenant: tenant.clone(), device_id: device_id.clone(), metric: Metric::ErrorRate, value: ratio, baseline, stddev, z });
+                }
+            }
+        }
+        self.settle(s, samples);
+    }
+
+    /// Flags and clears anomalies for the scored samples, emitting the transitions.
+    fn settle(&self, s: &AppState, samples: Vec<Sample>) {
+        let now = now_ms();
+        let mut events = Vec::new();
+        {
+            let mut st = self.state.lock().unwrap();
+            for x in samples {
+                let Some(z) = x.z else { continue };
+                let key = (x.tenant.clone(), x.device_id.clone(), x.metric);
+                if z.abs() > self.threshold {
+                    let since_ms = st.active.get(&key).map_or(now, |a| a.since_ms);
+                    let anomaly = Anomaly { device_id: x.device_id.clone(), metric: x.metric, value: x.value, baseline: x.baseline, stddev: x.stddev, z, since_ms, updated_ms: now };
+                    if st.active.insert(key, anomaly.clone()).is_none() {
+                        tracing::warn!(tenant = %x.tenant, device = %x.device_id, metric = ?x.metric, value = x.value, z, "sync anomaly detected");
+                        events.push(("anomaly-detected", x.tenant, serde_json::to_value(&anomaly).unwrap_or_default()));
+                    }
+                } else if st.active.remove(&key).is_some() {
+                    events.push(("anomaly-cleared", x.tenant, serde_json::json!({ "device_id": x.device_id, "metric": x.metric, "value": x.value, "baseline": x.baseline })));
+                }
+            }
+        }
+        for (kind, tenant, data) in events { s.emit(kind, &tenant, data); }
+    }
+
+    pub fn active_count(&self) -> usize { self.state.lock().unwrap().active.len() }
+
+    /// Returns how many device baselines were removed.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut st = self.state.lock().unwrap();
+        let n = st.devices.len();
+        st.devices.retain(|(t, _), _| t != tenant);
+        st.active.retain(|(t, _, _), _| t != tenant);
+        n - st.devices.len()
+    }
+}
+
+/// Background task closing a window every `ANOMALY_WINDOW_SECS`.
+pub async fn evaluate_loop(s: Arc<AppState>) {
+    let mut tick = tokio::time::interval(s.anomalies.window);
+    tick.tick().await;
+    loop {
+        tick.tick().await;
+        s.anomalies.close_window(&s);
+    }
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<Anomaly>> {
+    let mut out: Vec<Anomaly> = s.anomalies.state.lock().unwrap().active.iter().filter(|((t, _, _), _)| *t == tenant).map(|(_, a)| a.clone()).collect();
+    out.sort_by(|a, b| b.z.abs().total_cmp(&a.z.abs()));
+    Json(out)
+}
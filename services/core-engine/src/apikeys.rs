@@ -0,0 +1,189 @@
+//! Tenant API keys. Only the SHA-256 of a key is stored; the api-gateway resolves an
+//! incoming `X-API-Key` to its tenant, role and scopes through `POST /internal/keys/verify` on
+//! every request, so a revoked or expired key stops working at once.
+//!
+//! Tenants manage their own keys under `/tenants/:id/keys` (needs `configure`, and `:id` must be
+//! the caller's tenant): create with an optional `name`, `role` (no higher than the caller's;
+//! never `admin`), `scopes` (a subset of the role's permissions, narrowing what the key can do)
+//! and expiry, list them by prefix, rotate one (a new key with the same settings and lifetime;
+//! the old one is revoked) and revoke. The key itself is only ever returned when issued; its
+//! first 12 characters, the prefix, are unique among stored keys and name it from then on.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::rbac::{Configure, Permission, Require, Role};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Longest `expires_in_secs` a key may be issued with, ten years.
+const MAX_EXPIRES_IN_SECS: u64 = 10 * 366 * 86_400;
+
+#[derive(Clone)]
+pub struct ApiKey {
+    pub tenant: String, pub role: Role, pub prefix: String, pub created_at_ms: u64,
+    pub name: Option<String>, pub scopes: Option<Vec<Permission>>, pub expires_at_ms: Option<u64>, pub last_used_ms: Option<u64>,
+}
+
+/// A stored key as operators see it: never the key itself.
+#[derive(Serialize)]
+pub struct KeyInfo {
+    prefix: String, #[serde(skip_serializing_if = "Option::is_none")] name: Option<String>, role: Role,
+    #[serde(skip_serializing_if = "Option::is_none")] scopes: Option<Vec<Permission>>, created_at_ms: u64,
+    expires_at_ms: Option<u64>, last_used_ms: Option<u64>, expired: bool,
+}
+
+#[derive(Serialize)]
+pub struct IssuedKey {
+    pub tenant: String, pub role: Role, pub key: String, pub prefix: String, pub created_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")] pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub scopes: Option<Vec<Permission>>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub expires_at_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyRequest { key: String }
+
+#[derive(Serialize)]
+pub struct VerifyResponse { tenant: String, role: Role, #[serde(skip_serializing_if = "Option::is_none")] scopes: Option<Vec<Permission>> }
+
+#[derive(Deserialize)]
+pub struct CreateKey { name: Option<String>, role: Option<Role>, scopes: Option<Vec<Permission>>, expires_in_secs: Option<u64>, expires_at_ms: Option<u64> }
+
+impl Validate for CreateKey {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        let role = self.role.unwrap_or(Role::Operator);
+        v.check(role != Role::Admin, "role", "admin keys are issued by operators only");
+        if let Some(scopes) = &self.scopes {
+            v.check(!scopes.is_empty(), "scopes", "must not be empty; omit it for the role's full permissions");
+            for (i, p) in scopes.iter().enumerate() { v.check(role.allows(*p), format!("scopes[{i}]"), format!("not granted by role {}", role.as_str())); }
+        }
+        v.check(self.expires_in_secs.is_none() || self.expires_at_ms.is_none(), "expires_at_ms", "give expires_in_secs or expires_at_ms, not both");
+        v.check(self.expires_in_secs.is_none_or(|s| (1..=MAX_EXPIRES_IN_SECS).contains(&s)), "expires_in_secs", format!("must be between 1 and {MAX_EXPIRES_IN_SECS}"));
+        v.check(self.expires_at_ms.is_none_or(|t| t > now_ms()), "expires_at_ms", "must be in the future");
+        if let Some(name) = &self.name { v.check(!name.is_empty() && name.len() <= 100, "name", "must be 1-100 characters"); }
+    }
+}
+
+pub fn hash(key: &str) -> String { hex::encode(Sha256::digest(key.as_bytes())) }
+
+impl ApiKey {
+    fn expired(&self, now: u64) -> bool { self.expires_at_ms.is_some_and(|t| t <= now) }
+
+    fn info(&self) -> KeyInfo {
+        KeyInfo {
+            prefix: self.prefix.clone(), name: self.name.clone(), role: self.role, scopes: self.scopes.clone(), created_at_ms: self.created_at_ms,
+            expires_at_ms: self.expires_at_ms, last_used_ms: self.last_used_ms, expired: self.expired(now_ms()),
+        }
+    }
+}
+
+/// Stores a new key with `template`'s settings and returns it, the only time it is visible. A
+/// key whose prefix is already taken is drawn again, so a prefix names exactly one key.
+fn issue(s: &AppState, template: ApiKey) -> IssuedKey {
+    let mut keys = s.api_keys.lock().unwrap();
+    let key = loop {
+        let key = format!("agk_{}", uuid::Uuid::new_v4().simple());
+        if !keys.values().any(|k| k.prefix == key[..12]) { break key; }
+    };
+    let k = ApiKey { prefix: key[..12].to_string(), created_at_ms: now_ms(), last_used_ms: None, ..template };
+    let issued = IssuedKey { tenant: k.tenant.clone(), role: k.role, key: key.clone(), prefix: k.prefix.clone(), created_at_ms: k.created_at_ms, name: k.name.clone(), scopes: k.scopes.clone(), expires_at_ms: k.expires_at_ms };
+    keys.insert(hash(&key), k);
+    issued
+}
+
+/// Revokes every key the tenant holds and issues a single new one.
+pub fn rotate(s: &AppState, tenant: &str, role: Role) -> IssuedKey {
+    s.api_keys.lock().unwrap().retain(|_, k| k.tenant != tenant);
+    issue(s, ApiKey { tenant: tenant.into(), role, prefix: String::new(), created_at_ms: 0, name: None, scopes: None, expires_at_ms: None, last_used_ms: None })
+}
+
+pub fn list(s: &AppState, tenant: &str) -> Vec<KeyInfo> {
+    let mut keys: Vec<KeyInfo> = s.api_keys.lock().unwrap().values().filter(|k| k.tenant == tenant).map(ApiKey::info).collect();
+    keys.sort_by_key(|k| k.created_at_ms);
+    keys
+}
+
+/// Revokes the tenant's key with this prefix; false if there is none.
+pub fn revoke(s: &AppState, tenant: &str, prefix: &str) -> bool {
+    let mut keys = s.api_keys.lock().unwrap();
+    let n = keys.len();
+    keys.retain(|_, k| k.tenant != tenant || k.prefix != prefix);
+    keys.len() < n
+}
+
+/// Resolves a key to its tenant, role and scopes. Every lookup is audited under the key's
+/// prefix, including failures; an expired key is refused.
+pub fn authenticate(s: &AppState, key: &str) -> Option<(String, Role, Option<Vec<Permission>>)> {
+    let now = now_ms();
+    let found = s.api_keys.lock().unwrap().get_mut(&hash(key)).map(|k| {
+        if !k.expired(now) { k.last_used_ms = Some(now); }
+        (k.tenant.clone(), k.role, k.scopes.clone(), k.expired(now))
+    });
+    let actor = format!("key:{}", key.chars().take(12).collect::<String>());
+    match found {
+        Some((tenant, role, scopes, false)) => {
+            s.audit.record(&actor, "apikey.use", Some(&tenant), None, json!({ "role": role, "scopes": scopes }));
+            Some((tenant, role, scopes))
+        }
+        Some((tenant, _, _, true)) => { s.audit.record(&actor, "apikey.expired", Some(&tenant), None, Value::Null); None }
+        None => { s.audit.record(&actor, "apikey.rejected", None, None, Value::Null); None }
+    }
+}
+
+pub async fn verify(State(s): State<Arc<AppState>>, Json(req): Json<VerifyRequest>) -> Result<Json<VerifyResponse>, StatusCode> {
+    authenticate(&s, &req.key).map(|(tenant, role, scopes)| Json(VerifyResponse { tenant, role, scopes })).ok_or(StatusCode::UNAUTHORIZED)
+}
+
+/// Tenants manage only their own keys.
+fn own(tenant: &str, id: &str) -> Result<(), ApiError> {
+    if tenant == id { return Ok(()); }
+    Err(api_err(StatusCode::FORBIDDEN, "Permission denied", Some("keys can only be managed by their own tenant".into())).code("forbidden"))
+}
+
+fn unknown(prefix: String) -> ApiError { api_err(StatusCode::NOT_FOUND, "Unknown API key", Some(prefix)) }
+
+pub async fn create(State(s): State<Arc<AppState>>, caller: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(id): Path<String>, Valid(req): Valid<CreateKey>) -> Result<(StatusCode, Json<IssuedKey>), ApiError> {
+    own(&tenant, &id)?;
+    let (caller, role) = (caller.0, req.role.unwrap_or(Role::Operator));
+    if !role.permissions().iter().all(|p| caller.allows(*p)) {
+        return Err(api_err(StatusCode::FORBIDDEN, "Permission denied", Some(format!("role {} cannot issue {} keys", caller.as_str(), role.as_str()))).code("forbidden"));
+    }
+    let expires_at_ms = match req.expires_in_secs {
+        Some(secs) => Some(secs.checked_mul(1000).and_then(|ms| now_ms().checked_add(ms)).ok_or_else(|| api_err(StatusCode::BAD_REQUEST, "Invalid expiry", Some("expires_in_secs is out of range".into())))?),
+        None => req.expires_at_ms,
+    };
+    let issued = issue(&s, ApiKey { tenant: tenant.clone(), role, prefix: String::new(), created_at_ms: 0, name: req.name, scopes: req.scopes, expires_at_ms, last_used_ms: None });
+    s.audit.record(&actor, "apikey.create", Some(&tenant), Some(&issued.prefix), json!({ "role": role, "scopes": issued.scopes, "expires_at_ms": expires_at_ms }));
+    Ok((StatusCode::CREATED, Json(issued)))
+}
+
+pub async fn list_own(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Vec<KeyInfo>>, ApiError> {
+    own(&tenant, &id)?;
+    Ok(Json(list(&s, &tenant)))
+}
+
+pub async fn rotate_own(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path((id, prefix)): Path<(String, String)>) -> Result<Json<IssuedKey>, ApiError> {
+    own(&tenant, &id)?;
+    let old = {
+        let mut keys = s.api_keys.lock().unwrap();
+        let hash = keys.iter().find(|(_, k)| k.tenant == tenant && k.prefix == prefix).map(|(h, _)| h.clone()).ok_or_else(|| unknown(prefix.clone()))?;
+        keys.remove(&hash).expect("found above")
+    };
+    // The new key gets the old one's lifetime, counted from now.
+    let expires_at_ms = old.expires_at_ms.map(|t| now_ms() + t.saturating_sub(old.created_at_ms));
+    let issued = issue(&s, ApiKey { expires_at_ms, ..old });
+    s.audit.record(&actor, "apikey.rotate", Some(&tenant), Some(&prefix), json!({ "new_prefix": issued.prefix }));
+    Ok(Json(issued))
+}
+
+pub async fn revoke_own(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path((id, prefix)): Path<(String, String)>) -> Result<StatusCode, ApiError> {
+    own(&tenant, &id)?;
+    if !revoke(&s, &tenant, &prefix) { return Err(unknown(prefix)); }
+    s.audit.record(&actor, "apikey.revoke", Some(&tenant), Some(&prefix), Value::Null);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,243 @@
+//! Off-box archival of compacted snapshots to S3-compatible object storage (AWS S3, MinIO,
+//! Ceph RGW, ...), enabled by `ARCHIVE_S3_BUCKET`. Every compaction uploads the connection's
+//! snapshot to `<ARCHIVE_S3_PREFIX><tenant>/<connection_id>/<version>.json` (prefix default
+//! `snapshots/`) using path-style requests against `ARCHIVE_S3_ENDPOINT` (default
+//! `https://s3.<region>.amazonaws.com`), signed with SigV4 from `ARCHIVE_S3_ACCESS_KEY`,
+//! `ARCHIVE_S3_SECRET_KEY` and `ARCHIVE_S3_REGION` (default `us-east-1`).
+//!
+//! A connection resumed without local snapshot state (restored from a parked session, or
+//! resumed on another replica) is seeded from its latest archived version. Lifecycle is
+//! handled here rather than in bucket policy, so it works on any S3-compatible store: each
+//! upload prunes the connection's archive to the newest `ARCHIVE_KEEP_VERSIONS` (default 5),
+//! and an hourly sweep deletes archives older than `ARCHIVE_RETENTION_DAYS` (default 30,
+//! 0 keeps them forever).
+//!
+//! With encryption at rest configured (see `keystore`), the snapshot state is uploaded sealed
+//! with the tenant's key and opened again on restore; older plaintext archives still restore.
+//!
+//! Each version also carries the deltas folded into it, so delta replay (see `snapshots`) can
+//! reach back past what the gateway still holds in memory, as far as the kept versions go.
+
+use crate::events::now_ms;
+use crate::keystore::{Keystore, Sealed};
+use crate::sigv4::{self, encode, Credentials};
+use crate::snapshots::Entry;
+use crate::AppState;
+use reqwest::{Method, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+const DAY_MS: u64 = 86_400_000;
+
+/// One archived snapshot version.
+#[derive(Serialize, Deserialize)]
+pub struct Archived {
+    pub tenant: String, pub connection_id: String, pub device_id: String, pub version: u64, pub taken_at_ms: Option<u64>, pub last_sequence: Option<u64>, pub deltas_folded: u64,
+    /// Null while `sealed` holds the encrypted state.
+    #[serde(default)] pub state: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub sealed: Option<Sealed>,
+    /// The deltas folded into this version; empty while `sealed_deltas` holds them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")] pub deltas: Vec<Entry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub sealed_deltas: Option<Sealed>,
+}
+
+struct Listed { key: String, last_modified_ms: u64 }
+
+#[derive(Default, Serialize)]
+pub struct ArchiveCounts { pub uploads: u64, pub failures: u64, pub restores: u64, pub expired: u64 }
+
+pub struct Archive {
+    http: reqwest::Client, endpoint: Url, bucket: String, credentials: Credentials, prefix: String,
+    keep_versions: usize, retention_days: u64, keys: Arc<Keystore>,
+    uploads: AtomicU64, failures: AtomicU64, restores: AtomicU64, expired: AtomicU64,
+}
+
+/// Text of every `<tag>` element, entity-decoded; enough of XML for S3 listings.
+fn xml_all(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    let mut out = Vec::new();
+    let mut rest = xml;
+    while let Some(i) = rest.find(&open) {
+        let after = &rest[i + open.len()..];
+        let Some(j) = after.find(&close) else { break };
+        out.push(after[..j].replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&"));
+        rest = &after[j + close.len()..];
+    }
+    out
+}
+
+impl Archive {
+    /// `None` unless `ARCHIVE_S3_BUCKET` is set; a bad endpoint is logged and disables archival.
+    pub fn from_env(keys: Arc<Keystore>) -> Option<Self> {
+        let var = |k: &str| std::env::var(k).ok().filter(|v| !v.is_empty());
+        let bucket = var("ARCHIVE_S3_BUCKET")?;
+        let region = var("ARCHIVE_S3_REGION").unwrap_or_else(|| "us-east-1".into());
+        let raw = var("ARCHIVE_S3_ENDPOINT").unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"));
+        let endpoint = match Url::parse(&raw) {
+            Ok(u) if u.host_str().is_some() => u,
+            _ => { tracing::error!("Snapshot archival disabled, invalid ARCHIVE_S3_ENDPOINT {raw:?}"); return None; }
+        };
+        let num = |k: &str, d: u64| var(k).and_then(|v| v.parse().ok()).unwrap_or(d);
+        tracing::info!(%bucket, endpoint = %endpoint, "snapshot archival enabled");
+        Some(Archive {
+            http: reqwest::Client::new(), endpoint, bucket, credentials: Credentials { access_key: var("ARCHIVE_S3_ACCESS_KEY").unwrap_or_default(), secret_key: var("ARCHIVE_S3_SECRET_KEY").unwrap_or_default(), region },
+            prefix: var("ARCHIVE_S3_PREFIX").unwrap_or_else(|| "snapshots/".into()),
+            keep_versions: num("ARCHIVE_KEEP_VERSIONS", 5).max(1) as usize, retention_days: num("ARCHIVE_RETENTION_DAYS", 30), keys,
+            uploads: AtomicU64::new(0), failures: AtomicU64::new(0), restores: AtomicU64::new(0), expired: AtomicU64::new(0),
+        })
+    }
+
+    pub fn counts(&self) -> ArchiveCounts {
+        ArchiveCounts { uploads: self.uploads.load(Ordering::Relaxed), failures: self.failures.load(Ordering::Relaxed), restores: self.restores.load(Ordering::Relaxed), expired: self.expired.load(Ordering::Relaxed) }
+    }
+
+    fn folder(&self, tenant: &str, connection_id: &str) -> String { format!("{}{tenant}/{connection_id}/", self.prefix) }
+
+    /// Sends a SigV4-signed path-style request for `key` (the bucket itself when empty).
+    async fn send(&self, method: Method, key: &str, query: &[(&str, &str)], body: Vec<u8>) -> Result<reqwest::Response, String> {
+        let path = if key.is_empty() { format!("/{}", encode(&self.bucket, false)) } else { format!("/{}/{}", encode(&self.bucket, false), encode(key, true)) };
+        let mut pairs: Vec<(String, String)> = query.iter().map(|(k, v)| (encode(k, false), encode(v, false))).collect();
+        pairs.sort();
+        let canonical_query = pairs.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join("&");
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        url.set_query((!canonical_query.is_empty()).then_some(canonical_query.as_str()));
+        let mut req = self.http.request(method.clone(), url.clone());
+        for (k, v) in sigv4::sign(&self.credentials, "s3", &method, &url, &[], &body) { req = req.header(k, v); }
+        req.timeout(TIMEOUT).body(body).send().await.map_err(|e| e.to_string())
+    }
+
+    async fn ok(&self, method: Method, key: &str, query: &[(&str, &str)], body: Vec<u8>) -> Result<reqwest::Response, String> {
+        let r = self.send(method, key, query, body).await?;
+        if r.status().is_success() { Ok(r) } else { Err(format!("HTTP {}", r.status())) }
+    }
+
+    /// Every object under `prefix`, following pagination.
+    async fn list(&self, prefix: &str) -> Result<Vec<Listed>, String> {
+        let mut out = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(t) = &token { query.push(("continuation-token", t)); }
+            let xml = self.ok(Method::GET, "", &query, Vec::new()).await?.text().await.map_err(|e| e.to_string())?;
+            for item in xml_all(&xml, "Contents") {
+                let Some(key) = xml_all(&item, "Key").pop() else { continue };
+                let last_modified_ms = xml_all(&item, "LastModified").pop().and_then(|t| chrono::DateTime::parse_from_rfc3339(&t).ok()).map_or(0, |t| t.timestamp_millis() as u64);
+                out.push(Listed { key, last_modified_ms });
+            }
+            token = xml_all(&xml, "NextContinuationToken").pop();
+            if token.is_none() || xml_all(&xml, "IsTruncated").pop().as_deref() != Some("true") { return Ok(out); }
+        }
+    }
+
+    /// Uploads a snapshot version, then prunes the connection's older versions.
+    pub async fn put(&self, mut rec: Archived) {
+        let folder = self.folder(&rec.tenant, &rec.connection_id);
+        let key = format!("{folder}{:012}.json", rec.version);
+        if self.keys.enabled() {
+            let sealed = async {
+                let state = self.keys.seal(&rec.tenant, &serde_json::to_vec(&rec.state).unwrap_or_default()).await?;
+                let deltas = match rec.deltas.is_empty() {
+                    true => None,
+                    false => Some(self.keys.seal(&rec.tenant, &serde_json::to_vec(&rec.deltas).unwrap_or_default()).await?),
+                };
+                Ok::<_, String>((state, deltas))
+            };
+            match sealed.await {
+                Ok((state, deltas)) => { rec.sealed = Some(state); rec.state = Value::Null; rec.sealed_deltas = deltas; rec.deltas = Vec::new(); }
+                Err(e) => {
+                    self.failures.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(%key, "snapshot not archived, sealing failed: {e}");
+                    return;
+                }
+            }
+        }
+        if let Err(e) = self.ok(Method::PUT, &key, &[], serde_json::to_vec(&rec).unwrap_or_default()).await {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(%key, "snapshot archive upload failed: {e}");
+            return;
+        }
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+        match self.list(&folder).await {
+            Ok(mut versions) if versions.len() > self.keep_versions => {
+                versions.sort_by(|a, b| a.key.cmp(&b.key));
+                let stale = versions.len() - self.keep_versions;
+                for v in &versions[..stale] {
+                    if let Err(e) = self.ok(Method::DELETE, &v.key, &[], Vec::new()).await { tracing::warn!(key = %v.key, "pruning archived snapshot failed: {e}"); }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!(%folder, "listing archived snapshots failed: {e}"),
+        }
+    }
+
+    /// The newest archived version of the connection's snapshot, if any.
+    pub async fn latest(&self, tenant: &str, connection_id: &str) -> Option<Archived> {
+        let versions = self.list(&self.folder(tenant, connection_id)).await.map_err(|e| tracing::warn!(%connection_id, "listing archived snapshots failed: {e}")).ok()?;
+        let key = versions.into_iter().map(|v| v.key).max()?;
+        let rec = self.fetch(tenant, &key).await.map_err(|e| tracing::warn!(%key, "{e}")).ok()?;
+        self.restores.fetch_add(1, Ordering::Relaxed);
+        Some(rec)
+    }
+
+    /// Every kept version of the connection's snapshot, oldest first, for delta replay.
+    pub async fn history(&self, tenant: &str, connection_id: &str) -> Result<Vec<Archived>, String> {
+        let mut keys: Vec<String> = self.list(&self.folder(tenant, connection_id)).await.map_err(|e| format!("listing archived snapshots failed: {e}"))?.into_iter().map(|v| v.key).collect();
+        keys.sort();
+        let mut out = Vec::with_capacity(keys.len());
+        for key in &keys { out.push(self.fetch(tenant, key).await?); }
+        Ok(out)
+    }
+
+    /// Downloads one archived version, opening whatever was sealed.
+    async fn fetch(&self, tenant: &str, key: &str) -> Result<Archived, String> {
+        let body = self.ok(Method::GET, key, &[], Vec::new()).await.map_err(|e| format!("fetching archived snapshot failed: {e}"))?.bytes().await.map_err(|e| e.to_string())?;
+        let mut rec: Archived = serde_json::from_slice(&body).map_err(|e| format!("archived snapshot unreadable: {e}"))?;
+        if let Some(sealed) = rec.sealed.take() {
+            let plain = self.keys.open(tenant, &sealed).map_err(|e| format!("archived snapshot cannot be opened: {e}"))?;
+            rec.state = serde_json::from_slice(&plain).map_err(|e| format!("archived snapshot unreadable: {e}"))?;
+        }
+        if let Some(sealed) = rec.sealed_deltas.take() {
+            let plain = self.keys.open(tenant, &sealed).map_err(|e| format!("archived deltas cannot be opened: {e}"))?;
+            rec.deltas = serde_json::from_slice(&plain).map_err(|e| format!("archived deltas unreadable: {e}"))?;
+        }
+        Ok(rec)
+    }
+
+    /// Deletes archives older than the retention period.
+    async fn sweep(&self) {
+        if self.retention_days == 0 { return; }
+        let cutoff = now_ms().saturating_sub(self.retention_days * DAY_MS);
+        let objects = match self.list(&self.prefix).await {
+            Ok(o) => o,
+            Err(e) => { tracing::warn!("snapshot archive sweep failed to list: {e}"); return; }
+        };
+        let mut expired = 0;
+        for o in objects.iter().filter(|o| o.last_modified_ms < cutoff) {
+            match self.ok(Method::DELETE, &o.key, &[], Vec::new()).await {
+                Ok(_) => expired += 1,
+                Err(e) => tracing::warn!(key = %o.key, "expiring archived snapshot failed: {e}"),
+            }
+        }
+        self.expired.fetch_add(expired, Ordering::Relaxed);
+        if expired > 0 { tracing::info!(expired, "expired archived snapshots"); }
+    }
+
+    /// Checks the bucket is reachable with these credentials, for the readiness probe.
+    pub async fn ping(&self) -> Result<(), String> { self.ok(Method::HEAD, "", &[], Vec::new()).await.map(drop) }
+}
+
+/// Background task applying the retention period.
+pub async fn sweep_loop(s: Arc<AppState>) {
+    let Some(archive) = s.snapshots.archive.as_ref() else { return };
+    let mut tick = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        tick.tick().await;
+        archive.sweep().await;
+    }
+}
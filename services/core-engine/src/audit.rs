@@ -0,0 +1,309 @@
+//! Tamper-evident audit log of admin actions, device connects and API key use. Entries are
+//! appended as JSON lines to `AUDIT_LOG_PATH` (default `/tmp/alice-audit.jsonl`); each carries
+//! the SHA-256 of the previous entry, so editing or removing a line breaks the chain from
+//! that point on, which `GET /admin/audit/verify` reports; so does a line that cannot be read
+//! or decoded. On startup the chain continues from the last entry on disk. If that entry is
+//! unreadable the file is moved aside to `<path>.corrupt-<ms>` and a new chain starts, and the
+//! engine refuses to start when it cannot be moved. One writer thread owns the open file, so
+//! audited requests never wait on the disk.
+
+use crate::events::now_ms;
+use crate::rbac::{Admin, Require, Role};
+use crate::{api_err, ApiError, AppState};
+use axum::{async_trait, extract::{FromRequestParts, Query, State}, http::{request::Parts, StatusCode}, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    pub actor: String,
+    pub action: String,
+    pub tenant: Option<String>,
+    pub target: Option<String>,
+    pub details: Value,
+    pub prev_hash: String,
+    #[serde(default)] pub hash: String,
+}
+
+impl AuditEntry {
+    fn digest(&self) -> String {
+        let unsigned = AuditEntry { hash: String::new(), ..self.clone() };
+        hex::encode(Sha256::digest(serde_json::to_vec(&unsigned).unwrap_or_default()))
+    }
+}
+
+struct Tail { seq: u64, hash: String }
+
+/// A line of the log: an I/O error, a line that is not an entry (with why), or an entry.
+type Line = std::io::Result<Result<AuditEntry, String>>;
+
+fn lines(f: File) -> impl Iterator<Item = Line> {
+    BufReader::new(f).split(b'\n').map(|line| line.map(|raw| {
+        let text = std::str::from_utf8(&raw).map_err(|e| format!("not UTF-8: {e}"))?;
+        serde_json::from_str(text).map_err(|e| format!("not an audit entry: {e}"))
+    }))
+}
+
+/// The last entry on disk, or genesis for a missing or empty log.
+fn tail(path: &Path) -> Result<Tail, String> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Tail { seq: 0, hash: GENESIS.into() }),
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut last = None;
+    for line in lines(f) { last = Some(line.map_err(|e| e.to_string())?); }
+    match last {
+        None => Ok(Tail { seq: 0, hash: GENESIS.into() }),
+        Some(e) => e.map(|e| Tail { seq: e.seq, hash: e.hash }),
+    }
+}
+
+/// Owns the open log file and the chain's tail; only the writer thread touches it.
+struct Writer { path: PathBuf, file: Option<File>, tail: Tail }
+
+impl Writer {
+    fn open(path: PathBuf) -> Self {
+        let tail = tail(&path).unwrap_or_else(|reason| {
+            let aside = PathBuf::from(format!("{}.corrupt-{}", path.display(), now_ms()));
+            if let Err(e) = std::fs::rename(&path, &aside) {
+                panic!("audit log {} has an unreadable tail ({reason}) and cannot be moved aside: {e}; refusing to restart the chain over it", path.display());
+            }
+            tracing::error!("audit log {} has an unreadable tail ({reason}); moved to {} and starting a new chain", path.display(), aside.display());
+            Tail { seq: 0, hash: GENESIS.into() }
+        });
+        Writer { path, file: None, tail }
+    }
+
+    /// Chains and appends `e`; the tail only advances once the line is written.
+    fn append(&mut self, mut e: AuditEntry) -> std::io::Result<()> {
+        e.seq = self.tail.seq + 1;
+        e.prev_hash = self.tail.hash.clone();
+        e.hash = e.digest();
+        let line = serde_json::to_string(&e).unwrap_or_default();
+        let file = match &mut self.file {
+            Some(f) => f,
+            None => self.file.insert(std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?),
+        };
+        // Drop the handle on failure, so the next entry reopens the file.
+        if let Err(err) = writeln!(file, "{line}").and_then(|()| file.flush()) { self.file = None; return Err(err); }
+        self.tail = Tail { seq: e.seq, hash: e.hash };
+        Ok(())
+    }
+}
+
+pub struct AuditLog { path: PathBuf, tx: mpsc::Sender<AuditEntry> }
+
+impl AuditLog {
+    pub fn from_env() -> Self {
+        let path: PathBuf = std::env::var("AUDIT_LOG_PATH").unwrap_or_else(|_| "/tmp/alice-audit.jsonl".into()).into();
+        let mut writer = Writer::open(path.clone());
+        let (tx, rx) = mpsc::channel::<AuditEntry>();
+        std::thread::Builder::new().name("audit-writer".into()).spawn(move || {
+            for e in rx {
+                let action = e.action.clone();
+                if let Err(err) = writer.append(e) { tracing::error!(action, "audit log write to {} failed: {err}", writer.path.display()); }
+            }
+        }).expect("cannot start the audit writer");
+        AuditLog { path, tx }
+    }
+
+    /// Queues an entry for the writer. A failed write is logged but never fails the audited
+    /// request.
+    pub fn record(&self, actor: &str, action: &str, tenant: Option<&str>, target: Option<&str>, details: Value) {
+        let e = AuditEntry {
+            seq: 0, timestamp_ms: now_ms(), actor: actor.into(), action: action.into(), tenant: tenant.map(Into::into), target: target.map(Into::into),
+            details, prev_hash: String::new(), hash: String::new(),
+        };
+        let _ = self.tx.send(e);
+    }
+
+    fn entries(&self) -> std::io::Result<impl Iterator<Item = Line>> { File::open(&self.path).map(lines) }
+}
+
+/// Walks the chain, stopping at the first entry that is out of sequence, mislinked, altered or
+/// cannot be read at all.
+fn check(lines: impl Iterator<Item = Line>) -> ChainReport {
+    let (mut prev, mut expected_seq, mut entries) = (GENESIS.to_string(), 1, 0);
+    for line in lines {
+        entries += 1;
+        let invalid = |error: String| ChainReport { entries, valid: false, first_invalid_seq: Some(expected_seq), error: Some(error) };
+        let e = match line {
+            Ok(Ok(e)) => e,
+            Ok(Err(reason)) => return invalid(reason),
+            Err(err) => return invalid(format!("read failed: {err}")),
+        };
+        let error = if e.seq != expected_seq { Some(format!("expected seq {expected_seq}, found {}", e.seq)) }
+            else if e.prev_hash != prev { Some("prev_hash does not match the previous entry".to_string()) }
+            else if e.digest() != e.hash { Some("hash does not match the entry".to_string()) }
+            else { None };
+        if let Some(error) = error { return invalid(error); }
+        prev = e.hash;
+        expected_seq += 1;
+    }
+    ChainReport { entries, valid: true, first_invalid_seq: None, error: None }
+}
+
+/// Who is acting: the api-gateway forwards `X-Gateway-Actor` (user or key prefix); otherwise
+/// the admin token holder or the tenant itself.
+pub struct Actor(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = Infallible;
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        if let Some(a) = parts.headers.get("x-gateway-actor").and_then(|h| h.to_str().ok()).filter(|a| !a.is_empty()) { return Ok(Actor(a.into())); }
+        if parts.extensions.get::<Role>() == Some(&Role::Admin) { return Ok(Actor("admin-token".into())); }
+        let tenant = parts.headers.get("x-tenant-id").and_then(|h| h.to_str().ok()).unwrap_or("default");
+        Ok(Actor(format!("tenant:{tenant}")))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery { actor: Option<String>, action: Option<String>, tenant: Option<String>, from: Option<u64>, to: Option<u64>, limit: Option<usize> }
+
+#[derive(Serialize)]
+pub struct ChainReport {
+    entries: u64, valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")] first_invalid_seq: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")] error: Option<String>,
+}
+
+fn io_err(e: std::io::Error) -> ApiError { api_err(StatusCode::INTERNAL_SERVER_ERROR, "Audit log unreadable", Some(e.to_string())) }
+
+/// Newest matching entries first; `limit` defaults to 100 (max 1000). `from`/`to` are ms.
+pub async fn query(State(s): State<Arc<AppState>>, _: Require<Admin>, Query(q): Query<AuditQuery>) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    let limit = q.limit.unwrap_or(100).min(1000);
+    let s2 = s.clone();
+    let mut out: Vec<AuditEntry> = tokio::task::spawn_blocking(move || -> std::io::Result<Vec<AuditEntry>> {
+        let mut matched = std::collections::VecDeque::with_capacity(limit);
+        // Lines that do not decode are skipped here; `verify` is what reports them.
+        for e in s2.audit.entries()? {
+            let Ok(e) = e? else { continue };
+            let keep = q.actor.as_ref().is_none_or(|a| *a == e.actor) && q.action.as_ref().is_none_or(|a| *a == e.action)
+                && q.tenant.as_ref().is_none_or(|t| e.tenant.as_ref() == Some(t))
+                && q.from.is_none_or(|f| e.timestamp_ms >= f) && q.to.is_none_or(|t| e.timestamp_ms < t);
+            if keep {
+                if matched.len() == limit { matched.pop_front(); }
+                matched.push_back(e);
+            }
+        }
+        Ok(matched.into())
+    }).await.expect("audit query task panicked").or_else(|e| if e.kind() == std::io::ErrorKind::NotFound { Ok(vec![]) } else { Err(io_err(e)) })?;
+    out.reverse();
+    Ok(Json(out))
+}
+
+pub async fn verify(State(s): State<Arc<AppState>>, _: Require<Admin>) -> Result<Json<ChainReport>, ApiError> {
+    let report = tokio::task::spawn_blocking(move || s.audit.entries().map(check)).await.expect("audit verify task panicked");
+    match report {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Json(ChainReport { entries: 0, valid: true, first_invalid_seq: None, error: None })),
+        r => r.map(Json).map_err(io_err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_log() -> PathBuf { std::env::temp_dir().join(format!("audit-{}.jsonl", uuid::Uuid::new_v4().simple())) }
+
+    fn write_chain(path: &Path, n: usize) {
+        let mut w = Writer::open(path.to_path_buf());
+        for i in 0..n {
+            let e = AuditEntry { seq: 0, timestamp_ms: 1, actor: "admin-token".into(), action: format!("test.a{i}"), tenant: None, target: None, details: Value::Null, prev_hash: String::new(), hash: String::new() };
+            w.append(e).unwrap();
+        }
+    }
+
+    fn verify(path: &Path) -> ChainReport { check(lines(File::open(path).unwrap())) }
+
+    /// Rewrites the log with `f` applied to its raw lines.
+    fn rewrite(path: &Path, f: impl FnOnce(&mut Vec<Vec<u8>>)) {
+        let mut raw: Vec<Vec<u8>> = std::fs::read(path).unwrap().split(|b| *b == b'\n').filter(|l| !l.is_empty()).map(<[u8]>::to_vec).collect();
+        f(&mut raw);
+        std::fs::write(path, raw.join(&b'\n')).unwrap();
+    }
+
+    #[test]
+    fn intact_chain_verifies_and_continues_after_restart() {
+        let path = temp_log();
+        write_chain(&path, 3);
+        write_chain(&path, 2);
+        let report = verify(&path);
+        assert!(report.valid, "{:?}", report.error);
+        assert_eq!(report.entries, 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn edited_entry_breaks_the_chain() {
+        let path = temp_log();
+        write_chain(&path, 3);
+        rewrite(&path, |raw| raw[1] = String::from_utf8(raw[1].clone()).unwrap().replace("test.a1", "test.ax").into_bytes());
+        let report = verify(&path);
+        assert!(!report.valid);
+        assert_eq!(report.first_invalid_seq, Some(2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn removed_entry_breaks_the_chain() {
+        let path = temp_log();
+        write_chain(&path, 3);
+        rewrite(&path, |raw| { raw.remove(1); });
+        let report = verify(&path);
+        assert!(!report.valid);
+        assert_eq!(report.first_invalid_seq, Some(2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_line_is_invalid() {
+        let path = temp_log();
+        write_chain(&path, 3);
+        rewrite(&path, |raw| { let last = raw.last_mut().unwrap(); last.truncate(last.len() / 2); });
+        let report = verify(&path);
+        assert!(!report.valid);
+        assert_eq!(report.first_invalid_seq, Some(3));
+        assert!(report.error.unwrap().starts_with("not an audit entry"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn non_utf8_line_is_invalid_not_the_end_of_the_log() {
+        let path = temp_log();
+        write_chain(&path, 3);
+        rewrite(&path, |raw| raw[1] = vec![0xff, 0xfe, b'{']);
+        let report = verify(&path);
+        assert!(!report.valid);
+        assert_eq!(report.first_invalid_seq, Some(2));
+        assert!(report.error.unwrap().starts_with("not UTF-8"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_tail_is_moved_aside() {
+        let path = temp_log();
+        write_chain(&path, 2);
+        rewrite(&path, |raw| raw.push(b"{\"seq\": 3, \"act".to_vec()));
+        let w = Writer::open(path.clone());
+        assert_eq!((w.tail.seq, w.tail.hash.as_str()), (0, GENESIS));
+        assert!(!path.exists());
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let aside: Vec<_> = std::fs::read_dir(path.parent().unwrap()).unwrap().filter_map(Result::ok)
+            .filter(|e| e.file_name().to_string_lossy().starts_with(&format!("{name}.corrupt-"))).collect();
+        assert_eq!(aside.len(), 1);
+        std::fs::remove_file(aside[0].path()).unwrap();
+    }
+}
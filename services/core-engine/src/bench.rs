@@ -0,0 +1,103 @@
+//! Benchmarks of the sync and transform hot paths: SDF merge, transform pipelines and the body
+//! codecs. The same cases back the criterion suite (`cargo bench`) and the undocumented
+//! `POST /admin/debug/bench`, which runs each case for `?millis=` (default 200, at most 2000)
+//! on the deployed hardware and reports ops/sec. One run at a time; a second gets 409.
+
+use crate::codec::{self, Codec};
+use crate::rbac::{Admin, Require};
+use crate::{api_err, protocols, shadow, ApiError, AppState};
+use alice_gateway_types::SyncRequest;
+use axum::{extract::{Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::hint::black_box;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const TRANSFORM_PIPELINES: &[&[&str]] = &[
+    &["sdf-stream", "mqtt-bridge"],
+    &["mqtt-bridge", "sdf-stream", "grpc-relay"],
+    &["sdf-stream", "sdf-stream@1"],
+    &["sdf-stream@1", "sdf-stream"],
+];
+
+/// One benchmarked operation; `run` performs it once.
+pub struct Case { pub group: &'static str, pub name: String, op: Box<dyn FnMut() + Send> }
+
+impl Case {
+    pub fn run(&mut self) { (self.op)() }
+}
+
+/// An SDF delta touching `objects` objects.
+fn delta(objects: usize) -> Value {
+    let objects: Map<String, Value> = (0..objects).map(|i| {
+        (format!("obj-{i}"), json!({ "type": "cube", "position": [i as f64, 1.5, -2.0], "props": { "color": "red", "mass": i } }))
+    }).collect();
+    json!({ "objects": objects })
+}
+
+pub fn cases() -> Vec<Case> {
+    let mut cases = Vec::new();
+    for n in [10, 1000] {
+        let (mut target, patch) = (delta(n), delta(n));
+        cases.push(Case { group: "merge", name: format!("objects-{n}"), op: Box::new(move || shadow::merge_patch(black_box(&mut target), black_box(&patch))) });
+    }
+    let registry = protocols::Registry::from_env();
+    for chain in TRANSFORM_PIPELINES {
+        let Ok(plugins) = chain.iter().map(|p| registry.get(p)).collect::<Result<Vec<_>, _>>() else { continue };
+        let Ok(payload) = plugins[0].encode(&delta(100)) else { continue };
+        cases.push(Case {
+            group: "transform", name: chain.join(">"),
+            op: Box::new(move || {
+                let mut output = payload.clone();
+                for hop in plugins.windows(2) {
+                    output = hop[0].decode(&output).and_then(|sdf| hop[1].encode(&sdf)).expect("benchmark payloads transform cleanly");
+                }
+                black_box(output);
+            }),
+        });
+    }
+    let req = SyncRequest { connection_id: uuid::Uuid::new_v4().to_string(), sdf_delta: Some(delta(100)), sequence: Some(1), ..Default::default() };
+    for (name, c) in [("json", Codec::Json), ("cbor", Codec::Cbor), ("protobuf", Codec::Protobuf)] {
+        let body = codec::encode(c, &req);
+        let encoded = req.clone();
+        cases.push(Case { group: "codec", name: format!("encode-{name}"), op: Box::new(move || { black_box(codec::encode(c, black_box(&encoded))); }) });
+        cases.push(Case { group: "codec", name: format!("decode-{name}"), op: Box::new(move || { black_box(codec::decode::<SyncRequest>(c, black_box(&body)).expect("benchmark body decodes")); }) });
+    }
+    cases
+}
+
+#[derive(Deserialize)]
+pub struct BenchQuery { millis: Option<u64> }
+
+#[derive(Serialize)]
+pub struct BenchResult { group: &'static str, name: String, iterations: u64, mean_ns: f64, ops_per_sec: f64 }
+
+#[derive(Serialize)]
+pub struct BenchReport { cpus: usize, millis_per_case: u64, results: Vec<BenchResult> }
+
+/// Runs `case` for about `budget`, in batches that double until one takes a millisecond, so
+/// timer reads stay out of the figures for fast operations.
+fn measure(case: &mut Case, budget: Duration) -> BenchResult {
+    let warmup = Instant::now();
+    while warmup.elapsed() < budget / 10 { case.run(); }
+    let (mut iterations, mut batch, start) = (0u64, 1u64, Instant::now());
+    while start.elapsed() < budget {
+        let t = Instant::now();
+        for _ in 0..batch { case.run(); }
+        iterations += batch;
+        if t.elapsed() < Duration::from_millis(1) { batch *= 2; }
+    }
+    let secs = start.elapsed().as_secs_f64();
+    BenchResult { group: case.group, name: case.name.clone(), iterations, mean_ns: secs * 1e9 / iterations as f64, ops_per_sec: iterations as f64 / secs }
+}
+
+pub(crate) async fn run(State(s): State<Arc<AppState>>, _: Require<Admin>, Query(q): Query<BenchQuery>) -> Result<Json<BenchReport>, ApiError> {
+    let millis = q.millis.unwrap_or(200).clamp(1, 2000);
+    if s.benchmarking.swap(true, Ordering::AcqRel) { return Err(api_err(StatusCode::CONFLICT, "Benchmark already running", None)); }
+    let results = tokio::task::spawn_blocking(move || cases().iter_mut().map(|c| measure(c, Duration::from_millis(millis))).collect()).await;
+    s.benchmarking.store(false, Ordering::Release);
+    let results = results.map_err(|e| api_err(StatusCode::INTERNAL_SERVER_ERROR, "Benchmark failed", Some(e.to_string())))?;
+    Ok(Json(BenchReport { cpus: std::thread::available_parallelism().map_or(1, |n| n.get()), millis_per_case: millis, results }))
+}
@@ -0,0 +1,166 @@
+//! `gateway-ctl`: operator CLI over the engine's tenant and admin APIs.
+//!
+//! Talks to the engine directly (`--url` / `GATEWAY_URL`), scoping tenant calls with
+//! `X-Tenant-Id` (`--tenant` / `GATEWAY_TENANT`) and authenticating admin calls with
+//! `ADMIN_TOKEN`. Output is a table by default, or the raw JSON with `--json`.
+
+use clap::{Parser, Subcommand};
+use reqwest::{Client, Method, RequestBuilder};
+use serde_json::{json, Value};
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(name = "gateway-ctl", about = "Administer an ALICE Cloud Gateway engine")]
+struct Cli {
+    #[arg(long, env = "GATEWAY_URL", default_value = "http://localhost:8081", global = true)]
+    url: String,
+    #[arg(long, env = "GATEWAY_TENANT", default_value = "default", global = true)]
+    tenant: String,
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true, global = true)]
+    admin_token: Option<String>,
+    /// Print raw JSON instead of tables.
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// List the tenant's connections, or show one.
+    Connections { id: Option<String> },
+    /// Follow the tenant's event stream until interrupted.
+    Events {
+        /// Comma-separated event kinds, e.g. `connect,disconnect`.
+        #[arg(long)]
+        kinds: Option<String>,
+        /// Comma-separated device id globs.
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Push an SDF delta through a connection.
+    Sync {
+        connection_id: String,
+        /// The delta as JSON; omitted sends an empty sync.
+        #[arg(long)]
+        delta: Option<String>,
+    },
+    /// Gateway counters and sync pressure.
+    Stats,
+    /// Manage a tenant's API keys (admin).
+    Keys {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyAction {
+    List { tenant: String },
+    /// Revoke all of the tenant's keys and issue a new one.
+    Rotate {
+        tenant: String,
+        #[arg(long, default_value = "tenant-admin")]
+        role: String,
+    },
+    Revoke { tenant: String, prefix: String },
+}
+
+struct Ctl { http: Client, cli: Cli }
+
+impl Ctl {
+    fn tenant(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http.request(method, format!("{}{path}", self.cli.url.trim_end_matches('/'))).header("X-Tenant-Id", &self.cli.tenant).header("X-Gateway-Actor", actor())
+    }
+
+    fn admin(&self, method: Method, path: &str) -> Result<RequestBuilder, String> {
+        let token = self.cli.admin_token.as_deref().ok_or("admin commands need --admin-token or ADMIN_TOKEN")?;
+        Ok(self.http.request(method, format!("{}/admin{path}", self.cli.url.trim_end_matches('/'))).bearer_auth(token).header("X-Gateway-Actor", actor()))
+    }
+
+    async fn call(&self, req: RequestBuilder) -> Result<Value, String> {
+        let resp = req.send().await.map_err(|e| format!("request failed: {e}"))?;
+        let status = resp.status();
+        let body: Value = if status == reqwest::StatusCode::NO_CONTENT { Value::Null } else { resp.json().await.map_err(|e| format!("unreadable response ({status}): {e}"))? };
+        if !status.is_success() {
+            let detail = body["details"].as_str().map(|d| format!(": {d}")).unwrap_or_default();
+            return Err(format!("{status} {}{detail}", body["error"].as_str().unwrap_or("error")));
+        }
+        Ok(body)
+    }
+
+    fn print(&self, v: &Value, columns: &[&str]) {
+        if self.cli.json { println!("{}", serde_json::to_string_pretty(v).unwrap_or_default()); return; }
+        match v {
+            Value::Array(rows) => table(rows, columns),
+            Value::Object(map) => {
+                let width = map.keys().map(String::len).max().unwrap_or(0);
+                for (k, v) in map { println!("{k:<width$}  {}", cell(v)); }
+            }
+            Value::Null => {}
+            other => println!("{}", cell(other)),
+        }
+    }
+
+    async fn run(&self) -> Result<(), String> {
+        match &self.cli.cmd {
+            Cmd::Connections { id: None } => self.print(&self.call(self.tenant(Method::GET, "/api/v1/gateway/connections")).await?, &["connection_id", "device_id", "protocol", "region", "online", "queue.depth"]),
+            Cmd::Connections { id: Some(id) } => self.print(&self.call(self.tenant(Method::GET, &format!("/api/v1/gateway/connections/{id}"))).await?, &[]),
+            Cmd::Events { kinds, device } => return self.tail(kinds.as_deref(), device.as_deref()).await,
+            Cmd::Sync { connection_id, delta } => {
+                let sdf_delta = delta.as_deref().map(serde_json::from_str::<Value>).transpose().map_err(|e| format!("--delta is not JSON: {e}"))?;
+                let req = self.tenant(Method::POST, "/api/v1/gateway/sync").json(&json!({ "connection_id": connection_id, "sdf_delta": sdf_delta }));
+                self.print(&self.call(req).await?, &[]);
+            }
+            Cmd::Stats => self.print(&self.call(self.tenant(Method::GET, "/api/v1/gateway/stats")).await?, &[]),
+            Cmd::Keys { action: KeyAction::List { tenant } } => self.print(&self.call(self.admin(Method::GET, &format!("/tenants/{tenant}/keys"))?).await?, &["prefix", "role", "created_at_ms"]),
+            Cmd::Keys { action: KeyAction::Rotate { tenant, role } } => self.print(&self.call(self.admin(Method::POST, &format!("/tenants/{tenant}/keys/rotate"))?.query(&[("role", role)])).await?, &[]),
+            Cmd::Keys { action: KeyAction::Revoke { tenant, prefix } } => { self.call(self.admin(Method::DELETE, &format!("/tenants/{tenant}/keys/{prefix}"))?).await?; }
+        }
+        Ok(())
+    }
+
+    /// Reads the SSE stream and prints one line per event (the event JSON with `--json`).
+    async fn tail(&self, kinds: Option<&str>, device: Option<&str>) -> Result<(), String> {
+        let mut query = Vec::new();
+        if let Some(k) = kinds { query.push(("kinds", k)); }
+        if let Some(d) = device { query.push(("device", d)); }
+        let mut resp = self.tenant(Method::GET, "/api/v1/gateway/events").query(&query).send().await.map_err(|e| format!("request failed: {e}"))?;
+        if !resp.status().is_success() { return Err(format!("{} opening event stream", resp.status())); }
+        let mut buf = String::new();
+        while let Some(chunk) = resp.chunk().await.map_err(|e| format!("stream closed: {e}"))? {
+            buf.push_str(&String::from_utf8_lossy(&chunk));
+            while let Some(end) = buf.find("\n\n") {
+                let frame: String = buf.drain(..end + 2).collect();
+                let Some(data) = frame.lines().find_map(|l| l.strip_prefix("data:")) else { continue };
+                let Ok(ev) = serde_json::from_str::<Value>(data.trim()) else { continue };
+                if self.cli.json { println!("{ev}"); } else { println!("{}  {:<16} {}", cell(&ev["timestamp_ms"]), cell(&ev["kind"]), ev["data"]); }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn actor() -> String { format!("gateway-ctl:{}", std::env::var("USER").unwrap_or_else(|_| "operator".into())) }
+
+fn cell(v: &Value) -> String {
+    match v { Value::String(s) => s.clone(), Value::Null => "-".into(), other => other.to_string() }
+}
+
+/// Left-aligned columns; dotted names reach into nested objects.
+fn table(rows: &[Value], columns: &[&str]) {
+    let cells: Vec<Vec<String>> = rows.iter().map(|r| columns.iter().map(|c| cell(c.split('.').fold(r, |v, k| &v[k]))).collect()).collect();
+    let widths: Vec<usize> = columns.iter().enumerate().map(|(i, c)| cells.iter().map(|r| r[i].len()).chain([c.len()]).max().unwrap_or(0)).collect();
+    let line = |vals: Vec<&str>| vals.iter().zip(&widths).map(|(v, w)| format!("{v:<w$}")).collect::<Vec<_>>().join("  ").trim_end().to_string();
+    println!("{}", line(columns.iter().map(|c| c.to_uppercase()).collect::<Vec<_>>().iter().map(String::as_str).collect()));
+    for r in &cells { println!("{}", line(r.iter().map(String::as_str).collect())); }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let ctl = Ctl { http: Client::new(), cli: Cli::parse() };
+    match ctl.run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => { eprintln!("gateway-ctl: {e}"); ExitCode::FAILURE }
+    }
+}
@@ -0,0 +1,236 @@
+//! Fault injection for chaos experiments against staging. With `CHAOS_ENABLED=true` operators
+//! add faults under `/admin/chaos/faults`; each one hits `percent` of the requests (or events)
+//! matching its filters (`route`, a path prefix below `/api/v1` or `/api/v2`; `method`;
+//! `tenant`; `device`) and is one of:
+//!
+//! - `latency`: delays the request by `latency_ms` plus up to `jitter_ms`, then serves it;
+//! - `error`: answers `status` (default 503) with code `fault_injected` without serving it;
+//! - `drop-events`: silently drops matching events (`events` narrows the kinds) before they
+//!   reach SSE, WebSocket, webhook or Kafka subscribers. `route` and `method` do not apply.
+//!
+//! Every faulted response carries `X-Chaos-Fault: <id>`. Faults lapse after `duration_secs`
+//! (default 600) so a forgotten experiment cannot linger; `DELETE /admin/chaos/faults` ends
+//! them all. Admin, health, metrics and internal routes are never faulted. The device of a
+//! request is found the way the access log finds it, reading JSON bodies up to 64 KiB only
+//! while a fault filters on devices.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::rbac::{Admin, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::webhooks::EVENT_KINDS;
+use crate::{api_err, ApiError, AppState};
+use axum::{
+    body::{to_bytes, Body, HttpBody},
+    extract::{MatchedPath, Path, Request, State},
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MAX_INSPECTED_BODY: u64 = 64 * 1024;
+const MAX_LATENCY_MS: u64 = 60_000;
+const MAX_FAULTS: usize = 100;
+
+fn default_status() -> u16 { 503 }
+fn default_duration() -> u64 { 600 }
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Kind {
+    Latency { latency_ms: u64, #[serde(default)] jitter_ms: u64 },
+    Error { #[serde(default = "default_status")] status: u16, message: Option<String> },
+    DropEvents { #[serde(default)] events: Vec<String> },
+}
+
+#[derive(Clone, Serialize)]
+pub struct Fault {
+    id: String, #[serde(flatten)] kind: Kind, percent: f64,
+    route: Option<String>, method: Option<String>, tenant: Option<String>, device: Option<String>,
+    created_at_ms: u64, created_by: String, expires_at_ms: u64, injected: u64,
+}
+
+#[derive(Deserialize)]
+pub struct FaultRequest {
+    #[serde(flatten)] kind: Kind, percent: f64,
+    route: Option<String>, method: Option<String>, tenant: Option<String>, device: Option<String>,
+    #[serde(default = "default_duration")] duration_secs: u64,
+}
+
+impl Validate for FaultRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check(self.percent > 0.0 && self.percent <= 100.0, "percent", "must be above 0 and at most 100");
+        v.check(self.duration_secs > 0, "duration_secs", "must be positive");
+        if let Some(r) = &self.route { v.check(r.starts_with('/'), "route", "must be a path starting with /"); }
+        if let Some(t) = &self.tenant { v.id("tenant", t); }
+        if let Some(d) = &self.device { v.id("device", d); }
+        match &self.kind {
+            Kind::Latency { latency_ms, jitter_ms } => {
+                v.check(*latency_ms + *jitter_ms > 0, "latency_ms", "must be positive");
+                v.check(latency_ms + jitter_ms <= MAX_LATENCY_MS, "latency_ms", format!("latency_ms plus jitter_ms must be at most {MAX_LATENCY_MS}"));
+            }
+            Kind::Error { status, .. } => v.check((400..=599).contains(status), "status", "must be a 4xx or 5xx status"),
+            Kind::DropEvents { events } => {
+                v.check(self.route.is_none() && self.method.is_none(), "route", "does not apply to drop-events");
+                for (i, e) in events.iter().enumerate() { v.check(EVENT_KINDS.contains(&e.as_str()), format!("events[{i}]"), "unknown event kind"); }
+            }
+        }
+    }
+}
+
+impl Fault {
+    fn matches(&self, tenant: &str, device: Option<&str>) -> bool {
+        self.tenant.as_deref().is_none_or(|t| t == tenant) && self.device.as_deref().is_none_or(|d| device == Some(d))
+    }
+
+    fn matches_request(&self, method: &str, path: &str) -> bool {
+        !matches!(self.kind, Kind::DropEvents { .. })
+            && self.route.as_deref().is_none_or(|r| path.starts_with(r))
+            && self.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    fn hit(&self) -> bool { (uuid::Uuid::new_v4().as_u128() % 1_000_000) as f64 / 10_000.0 < self.percent }
+}
+
+pub struct Chaos { enabled: bool, faults: Mutex<Vec<Fault>>, latency: AtomicU64, errors: AtomicU64, dropped: AtomicU64 }
+
+pub struct ChaosCounts { pub active: usize, pub latency: u64, pub errors: u64, pub dropped: u64 }
+
+impl Chaos {
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("CHAOS_ENABLED").is_ok_and(|v| v == "true" || v == "1");
+        if enabled { tracing::warn!("fault injection is enabled; do not run this in production"); }
+        Chaos { enabled, faults: Mutex::default(), latency: AtomicU64::new(0), errors: AtomicU64::new(0), dropped: AtomicU64::new(0) }
+    }
+
+    /// The live faults, dropping lapsed ones.
+    fn live(&self) -> std::sync::MutexGuard<'_, Vec<Fault>> {
+        let now = now_ms();
+        let mut faults = self.faults.lock().unwrap();
+        faults.retain(|f| f.expires_at_ms > now);
+        faults
+    }
+
+    /// Whether an event about to be emitted is dropped by a fault.
+    pub fn drops(&self, kind: &str, tenant: &str, data: &Value) -> bool {
+        if !self.enabled { return false; }
+        let device = data["device_id"].as_str();
+        let mut faults = self.live();
+        let Some(f) = faults.iter_mut().find(|f| {
+            matches!(&f.kind, Kind::DropEvents { events } if events.is_empty() || events.iter().any(|e| e == kind)) && f.matches(tenant, device) && f.hit()
+        }) else { return false };
+        f.injected += 1;
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!(fault = %f.id, kind, %tenant, "event dropped by fault injection");
+        true
+    }
+
+    pub fn counts(&self) -> ChaosCounts {
+        ChaosCounts { active: self.live().len(), latency: self.latency.load(Ordering::Relaxed), errors: self.errors.load(Ordering::Relaxed), dropped: self.dropped.load(Ordering::Relaxed) }
+    }
+}
+
+/// Applied to every API route: delays or fails the requests that draw a matching fault.
+pub async fn chaos_mw(State(s): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let chaos = &s.chaos;
+    if !chaos.enabled { return next.run(req).await; }
+    let method = req.method().to_string();
+    let full = req.uri().path().to_string();
+    let path = ["/api/v1", "/api/v2"].iter().find_map(|p| full.strip_prefix(p)).unwrap_or(&full).to_string();
+    let tenant = crate::tenant::of(req.headers()).to_string();
+    let (candidates, by_device) = {
+        let faults = chaos.live();
+        let c: Vec<Fault> = faults.iter().filter(|f| f.matches_request(&method, &path) && f.tenant.as_deref().is_none_or(|t| t == tenant)).cloned().collect();
+        let d = c.iter().any(|f| f.device.is_some());
+        (c, d)
+    };
+    if candidates.is_empty() { return next.run(req).await; }
+    let (req, device) = if by_device { device_of(&s, req, &full).await } else { (req, None) };
+    let drawn: Vec<Fault> = candidates.into_iter().filter(|f| f.matches(&tenant, device.as_deref()) && f.hit()).collect();
+    if drawn.is_empty() { return next.run(req).await; }
+    {
+        let mut faults = chaos.faults.lock().unwrap();
+        for f in faults.iter_mut().filter(|f| drawn.iter().any(|d| d.id == f.id)) { f.injected += 1; }
+    }
+    let ids = drawn.iter().map(|f| f.id.as_str()).collect::<Vec<_>>().join(",");
+    let delay: u64 = drawn.iter().map(|f| match f.kind {
+        Kind::Latency { latency_ms, jitter_ms } => latency_ms + if jitter_ms > 0 { (uuid::Uuid::new_v4().as_u128() % (jitter_ms as u128 + 1)) as u64 } else { 0 },
+        _ => 0,
+    }).sum();
+    if delay > 0 {
+        chaos.latency.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+    let error = drawn.iter().find_map(|f| match &f.kind { Kind::Error { status, message } => Some((f.id.clone(), *status, message.clone())), _ => None });
+    let mut resp = match error {
+        Some((id, status, message)) => {
+            chaos.errors.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(fault = %id, %method, %full, %tenant, "request failed by fault injection");
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+            api_err(status, "Injected fault", Some(message.unwrap_or_else(|| format!("fault {id}")))).code("fault_injected").into_response()
+        }
+        None => next.run(req).await,
+    };
+    if let Ok(v) = HeaderValue::from_str(&ids) { resp.headers_mut().insert("x-chaos-fault", v); }
+    resp
+}
+
+/// The device a request concerns, buffering a small JSON body to find it.
+async fn device_of(s: &AppState, req: Request, path: &str) -> (Request, Option<String>) {
+    let route = req.extensions().get::<MatchedPath>().map(|m| m.as_str().to_string());
+    let small = req.body().size_hint().exact().is_some_and(|n| n > 0 && n <= MAX_INSPECTED_BODY);
+    let (req, body) = if small {
+        let (parts, b) = req.into_parts();
+        let bytes = to_bytes(b, MAX_INSPECTED_BODY as usize).await.unwrap_or_default();
+        let parsed = serde_json::from_slice::<Value>(&bytes).ok();
+        (Request::from_parts(parts, Body::from(bytes)), parsed)
+    } else { (req, None) };
+    let device = crate::accesslog::device_of(s, path, route.as_deref(), body.as_ref());
+    (req, device)
+}
+
+fn disabled() -> ApiError {
+    api_err(StatusCode::CONFLICT, "Fault injection disabled", Some("set CHAOS_ENABLED=true on this gateway".into())).code("chaos_disabled")
+}
+
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Valid(req): Valid<FaultRequest>) -> Result<(StatusCode, Json<Fault>), ApiError> {
+    if !s.chaos.enabled { return Err(disabled()); }
+    let now = now_ms();
+    let f = Fault {
+        id: uuid::Uuid::new_v4().to_string(), kind: req.kind, percent: req.percent, route: req.route, method: req.method, tenant: req.tenant, device: req.device,
+        created_at_ms: now, created_by: actor.clone(), expires_at_ms: now + req.duration_secs * 1000, injected: 0,
+    };
+    {
+        let mut faults = s.chaos.live();
+        if faults.len() >= MAX_FAULTS { return Err(api_err(StatusCode::CONFLICT, "Too many faults", Some(format!("at most {MAX_FAULTS} at a time"))).code("limit_exceeded")); }
+        faults.push(f.clone());
+    }
+    tracing::warn!(fault = %f.id, %actor, "fault injected");
+    s.audit.record(&actor, "admin.chaos.create", f.tenant.as_deref(), Some(&f.id), serde_json::to_value(&f).unwrap_or_default());
+    Ok((StatusCode::CREATED, Json(f)))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Admin>) -> Json<Value> {
+    Json(json!({ "enabled": s.chaos.enabled, "faults": *s.chaos.live() }))
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let removed = {
+        let mut faults = s.chaos.live();
+        faults.iter().position(|f| f.id == id).map(|i| faults.remove(i))
+    };
+    let Some(f) = removed else { return Err(api_err(StatusCode::NOT_FOUND, "Unknown fault", Some(id))) };
+    s.audit.record(&actor, "admin.chaos.remove", f.tenant.as_deref(), Some(&id), json!({ "injected": f.injected }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn clear(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor) -> Json<Value> {
+    let removed = std::mem::take(&mut *s.chaos.faults.lock().unwrap()).len();
+    s.audit.record(&actor, "admin.chaos.clear", None, None, json!({ "removed": removed }));
+    Json(json!({ "removed": removed }))
+}
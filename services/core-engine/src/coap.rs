@@ -0,0 +1,225 @@
+//! CoAP bridge for constrained devices, listening on UDP `COAP_ADDR` (disabled when unset).
+//! Devices first connect over HTTP and then speak CoAP, identified by their connection id:
+//!
+//! * `POST sync/<connection_id>` carries an SDF delta (JSON or CBOR content format) through the
+//!   same pipeline as `/api/v1/gateway/sync`; `?seq=`, `?ts=`, `?prio=` and `?schema=` map to
+//!   `sequence`/`timestamp`/`priority`/`schema_version`. With `?enc=1` the payload is an
+//!   encrypted envelope instead of a delta.
+//! * `GET deltas/<connection_id>` with `Observe: 0` subscribes to the device's SDF deltas: the
+//!   first reply is the current reported shadow, then every accepted delta is pushed as a
+//!   non-confirmable notification. `Observe: 1` or a Reset to a notification unsubscribes.
+//!
+//! `GET /api/v1/gateway/bridges/coap` reports listener counters and the tenant's observers.
+
+use crate::codec::{from_cbor, to_cbor};
+use crate::events::now_ms;
+use crate::rbac::{Operate, Read, Require};
+use crate::validate::ensure;
+use crate::{api_err, ApiError, AppState, SyncRequest, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use coap_lite::{create_notification, CoapOption, CoapRequest, ContentFormat, MessageType, ObserveOption, Packet, RequestType, ResponseType};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::net::UdpSocket;
+
+#[derive(Clone, Serialize)]
+pub struct Observer {
+    id: String,
+    #[serde(skip)] tenant: String,
+    device_id: String,
+    connection_id: String,
+    peer: SocketAddr,
+    #[serde(skip)] token: Vec<u8>,
+    #[serde(skip)] cbor: bool,
+    notifications: u32,
+    #[serde(skip)] last_message_id: u16,
+    registered_at_ms: u64,
+}
+
+pub struct Bridge {
+    listen_addr: Option<String>,
+    socket: OnceLock<Arc<UdpSocket>>,
+    observers: Mutex<HashMap<String, Observer>>,
+    message_id: AtomicU16,
+    packets: AtomicU64,
+    syncs: AtomicU64,
+    errors: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct BridgeStatus { enabled: bool, listen_addr: Option<String>, packets_received: u64, syncs: u64, errors: u64, observers: Vec<Observer> }
+
+impl Bridge {
+    pub fn from_env() -> Self {
+        Bridge {
+            listen_addr: std::env::var("COAP_ADDR").ok().filter(|a| !a.is_empty()),
+            socket: OnceLock::new(),
+            observers: Mutex::new(HashMap::new()),
+            message_id: AtomicU16::new(1),
+            packets: AtomicU64::new(0),
+            syncs: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Pushes an accepted SDF delta to every observer of the device; returns each observer's id
+    /// and whether its notification went out.
+    pub fn publish(&self, tenant: &str, device_id: &str, delta: &Value) -> Vec<(String, bool)> {
+        let Some(socket) = self.socket.get() else { return Vec::new() };
+        let mut sent = Vec::new();
+        for o in self.observers.lock().unwrap().values_mut().filter(|o| o.tenant == tenant && o.device_id == device_id) {
+            o.notifications = (o.notifications + 1) & 0x00ff_ffff;
+            o.last_message_id = self.message_id.fetch_add(1, Ordering::Relaxed);
+            let mut n = create_notification(o.last_message_id, o.token.clone(), o.notifications, payload(o.cbor, delta), false);
+            n.set_content_format(if o.cbor { ContentFormat::ApplicationCBOR } else { ContentFormat::ApplicationJSON });
+            let ok = n.to_bytes().is_ok_and(|bytes| socket.try_send_to(&bytes, o.peer).is_ok());
+            sent.push((o.id.clone(), ok));
+        }
+        sent
+    }
+
+    pub fn forget_connection(&self, connection_id: &str) {
+        self.observers.lock().unwrap().retain(|_, o| o.connection_id != connection_id);
+    }
+}
+
+fn payload<T: Serialize>(cbor: bool, v: &T) -> Vec<u8> {
+    if cbor { to_cbor(v) } else { serde_json::to_vec(v).unwrap_or_default() }
+}
+
+fn coap_status(s: StatusCode) -> ResponseType {
+    match s.as_u16() {
+        400 => ResponseType::BadRequest,
+        401 => ResponseType::Unauthorized,
+        403 => ResponseType::Forbidden,
+        404 => ResponseType::NotFound,
+        409 => ResponseType::Conflict,
+        413 => ResponseType::RequestEntityTooLarge,
+        415 => ResponseType::UnsupportedContentFormat,
+        422 => ResponseType::UnprocessableEntity,
+        429 => ResponseType::TooManyRequests,
+        502 => ResponseType::BadGateway,
+        503 => ResponseType::ServiceUnavailable,
+        504 => ResponseType::GatewayTimeout,
+        _ => ResponseType::InternalServerError,
+    }
+}
+
+fn query(p: &Packet, key: &str) -> Option<String> {
+    p.get_option(CoapOption::UriQuery)?.iter().filter_map(|q| std::str::from_utf8(q).ok()).find_map(|q| q.strip_prefix(key)?.strip_prefix('=').map(str::to_owned))
+}
+
+pub async fn serve(s: Arc<AppState>) {
+    let Some(addr) = s.coap.listen_addr.clone() else { return };
+    let socket = match UdpSocket::bind(&addr).await {
+        Ok(sock) => Arc::new(sock),
+        Err(e) => { tracing::error!("CoAP bridge failed to bind {addr}: {e}"); return; }
+    };
+    let _ = s.coap.socket.set(socket.clone());
+    tracing::info!("CoAP bridge on udp://{addr}");
+    let mut buf = vec![0u8; Packet::MAX_SIZE];
+    loop {
+        let Ok((n, src)) = socket.recv_from(&mut buf).await else { continue };
+        s.coap.packets.fetch_add(1, Ordering::Relaxed);
+        let Ok(packet) = Packet::from_bytes(&buf[..n]) else { s.coap.errors.fetch_add(1, Ordering::Relaxed); continue };
+        let (s, socket) = (s.clone(), socket.clone());
+        tokio::spawn(async move {
+            if let Some(reply) = handle(&s, packet, src).await.and_then(|r| r.to_bytes().ok()) {
+                let _ = socket.send_to(&reply, src).await;
+            }
+        });
+    }
+}
+
+async fn handle(s: &AppState, packet: Packet, src: SocketAddr) -> Option<Packet> {
+    if packet.header.get_type() == MessageType::Reset {
+        let mid = packet.header.message_id;
+        s.coap.observers.lock().unwrap().retain(|_, o| !(o.peer == src && o.last_message_id == mid));
+        return None;
+    }
+    let req = CoapRequest::from_packet(packet, src);
+    let cbor = req.message.get_content_format() == Some(ContentFormat::ApplicationCBOR);
+    let path = req.get_path();
+    let result = match (req.get_method(), path.split_once('/')) {
+        (RequestType::Post | RequestType::Put, Some(("sync", id))) => sync(s, &req.message, id, cbor).await,
+        (RequestType::Get, Some(("deltas", id))) => observe(s, &req, src, id),
+        _ => Err(api_err(StatusCode::NOT_FOUND, "Unknown CoAP resource", Some(path.clone()))),
+    };
+    let mut resp = req.response?;
+    match result {
+        Ok((status, body, observe_seq)) => {
+            resp.set_status(status);
+            resp.message.payload = payload(cbor, &body);
+            if let Some(seq) = observe_seq { resp.message.set_observe_value(seq); }
+        }
+        Err(e) => {
+            s.coap.errors.fetch_add(1, Ordering::Relaxed);
+            resp.set_status(coap_status(e.status));
+            resp.message.payload = payload(cbor, &e.body);
+        }
+    }
+    resp.message.set_content_format(if cbor { ContentFormat::ApplicationCBOR } else { ContentFormat::ApplicationJSON });
+    Some(resp.message)
+}
+
+type Reply = Result<(ResponseType, Value, Option<u32>), ApiError>;
+
+/// The connection id is the device's credential on the bridge; its tenant is the connection's.
+fn tenant_of(s: &AppState, connection_id: &str) -> Result<(String, String), ApiError> {
+    s.connections.lock().unwrap().get(connection_id).map(|c| (c.tenant.clone(), c.device_id.clone())).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(connection_id.into())))
+}
+
+#[tracing::instrument(name = "gateway.coap.sync", skip_all, fields(connection_id = %connection_id, device_id = tracing::field::Empty, bytes = tracing::field::Empty))]
+async fn sync(s: &AppState, p: &Packet, connection_id: &str, cbor: bool) -> Reply {
+    let (tenant, _) = tenant_of(s, connection_id)?;
+    let payload = match (p.payload.is_empty(), cbor) {
+        (true, _) => None,
+        (false, true) => Some(from_cbor(&p.payload).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid CBOR payload", Some(e)))?),
+        (false, false) => Some(serde_json::from_slice(&p.payload).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid JSON payload", Some(e.to_string())))?),
+    };
+    let sequence = query(p, "seq").map(|q| q.parse().map_err(|_| api_err(StatusCode::BAD_REQUEST, "Invalid seq", Some(q)))).transpose()?;
+    let schema_version = query(p, "schema").map(|q| q.parse().map_err(|_| api_err(StatusCode::BAD_REQUEST, "Invalid schema", Some(q)))).transpose()?;
+    let (sdf_delta, envelope) = match payload {
+        Some(v) if query(p, "enc").is_some() => (None, Some(serde_json::from_value(v).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid envelope", Some(e.to_string())))?)),
+        v => (v, None),
+    };
+    let req = SyncRequest { connection_id: connection_id.into(), sdf_delta, timestamp: query(p, "ts"), sequence, priority: query(p, "prio"), envelope, schema_version };
+    ensure(s, &req)?;
+    let out = s.process_sync(&tenant, None, req, p.payload.len()).await?;
+    s.coap.syncs.fetch_add(1, Ordering::Relaxed);
+    Ok((ResponseType::Changed, serde_json::to_value(out).unwrap_or_default(), None))
+}
+
+fn observe(s: &AppState, req: &CoapRequest<SocketAddr>, src: SocketAddr, connection_id: &str) -> Reply {
+    let (tenant, device_id) = tenant_of(s, connection_id)?;
+    let id = format!("{}@{src}", hex::encode(req.message.get_token()));
+    match req.get_observe_flag() {
+        Some(Ok(ObserveOption::Register)) => {
+            let cbor = req.message.get_first_option_as::<coap_lite::option_value::OptionValueU16>(CoapOption::Accept).and_then(Result::ok).is_some_and(|a| a.0 == usize::from(ContentFormat::ApplicationCBOR) as u16);
+            s.coap.observers.lock().unwrap().insert(id.clone(), Observer { id, tenant: tenant.clone(), device_id: device_id.clone(), connection_id: connection_id.into(), peer: src, token: req.message.get_token().to_vec(), cbor, notifications: 0, last_message_id: 0, registered_at_ms: now_ms() });
+        }
+        Some(Ok(ObserveOption::Deregister)) => { s.coap.observers.lock().unwrap().remove(&id); }
+        Some(Err(_)) => return Err(api_err(StatusCode::BAD_REQUEST, "Invalid Observe option", None)),
+        None => {}
+    }
+    let reported = s.shadows.lock().unwrap().get(&(tenant, device_id)).map(|sh| sh.reported.clone()).unwrap_or_else(|| Value::Object(Default::default()));
+    let registered = matches!(req.get_observe_flag(), Some(Ok(ObserveOption::Register)));
+    Ok((ResponseType::Content, reported, registered.then_some(0)))
+}
+
+pub async fn status(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<BridgeStatus> {
+    let b = &s.coap;
+    let observers = b.observers.lock().unwrap().values().filter(|o| o.tenant == tenant).cloned().collect();
+    Json(BridgeStatus { enabled: b.socket.get().is_some(), listen_addr: b.listen_addr.clone(), packets_received: b.packets.load(Ordering::Relaxed), syncs: b.syncs.load(Ordering::Relaxed), errors: b.errors.load(Ordering::Relaxed), observers })
+}
+
+pub async fn cancel_observer(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let mut observers = s.coap.observers.lock().unwrap();
+    if observers.get(&id).is_none_or(|o| o.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown observer", Some(id))); }
+    observers.remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,214 @@
+//! Body codecs for the hot sync/transform paths. Requests are decoded according to
+//! `Content-Type` (JSON, `application/cbor`, `application/x-protobuf`); responses use the
+//! `Accept` header when it names a supported codec and otherwise mirror the request.
+//! The protobuf schema is `proto/gateway.proto`; free-form SDF values travel as CBOR bytes.
+
+use crate::validate::{ensure, Validate};
+use crate::{api_err, ApiError, AppState, SyncRequest, SyncResponse, TransformRequest, TransformResponse, TransformStage};
+use alice_gateway_types::Envelope;
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec { Json, Cbor, Protobuf }
+
+impl Codec {
+    fn parse(mime: &str) -> Option<Codec> {
+        match mime.split(';').next().unwrap_or_default().trim() {
+            "application/json" => Some(Codec::Json),
+            "application/cbor" => Some(Codec::Cbor),
+            "application/x-protobuf" | "application/protobuf" => Some(Codec::Protobuf),
+            _ => None,
+        }
+    }
+    pub fn content_type(self) -> &'static str {
+        match self { Codec::Json => "application/json", Codec::Cbor => "application/cbor", Codec::Protobuf => "application/x-protobuf" }
+    }
+    /// Absent Content-Type is treated as JSON for backwards compatibility.
+    pub fn from_content_type(headers: &HeaderMap) -> Option<Codec> {
+        match headers.get(header::CONTENT_TYPE).and_then(|h| h.to_str().ok()) { None => Some(Codec::Json), Some(ct) => Codec::parse(ct) }
+    }
+    pub fn from_accept(headers: &HeaderMap) -> Option<Codec> {
+        headers.get(header::ACCEPT).and_then(|h| h.to_str().ok())?.split(',').find_map(Codec::parse)
+    }
+}
+
+pub fn to_cbor<T: Serialize>(v: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    ciborium::into_writer(v, &mut buf).expect("CBOR encoding into a Vec cannot fail");
+    buf
+}
+
+pub fn from_cbor<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> { ciborium::from_reader(bytes).map_err(|e| e.to_string()) }
+
+/// Bridges a serde API type to its protobuf message.
+pub trait Wire: Sized {
+    type Proto: Message + Default;
+    fn from_proto(p: Self::Proto) -> Result<Self, String>;
+    fn to_proto(&self) -> Self::Proto;
+}
+
+pub fn decode<T: DeserializeOwned + Wire>(codec: Codec, body: &[u8]) -> Result<T, String> {
+    match codec {
+        Codec::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+        Codec::Cbor => from_cbor(body),
+        Codec::Protobuf => T::from_proto(T::Proto::decode(body).map_err(|e| e.to_string())?),
+    }
+}
+
+pub fn encode<T: Serialize + Wire>(codec: Codec, v: &T) -> Vec<u8> {
+    match codec {
+        Codec::Json => serde_json::to_vec(v).unwrap_or_default(),
+        Codec::Cbor => to_cbor(v),
+        Codec::Protobuf => v.to_proto().encode_to_vec(),
+    }
+}
+
+/// Request body decoded with the negotiated codec; `respond_with` is the codec for the reply.
+pub struct Negotiated<T> { pub body: T, pub respond_with: Codec, pub wire_bytes: usize }
+
+#[async_trait]
+impl<T: DeserializeOwned + Wire + Validate> FromRequest<Arc<AppState>> for Negotiated<T> {
+    type Rejection = ApiError;
+    async fn from_request(req: Request, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let codec = Codec::from_content_type(req.headers()).ok_or_else(|| api_err(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported Content-Type", Some("use application/json, application/cbor or application/x-protobuf".into())))?;
+        let respond_with = Codec::from_accept(req.headers()).unwrap_or(codec);
+        let bytes = Bytes::from_request(req, state).await.map_err(|e| api_err(e.status(), "Body read fail", Some(e.body_text())))?;
+        let body = decode(codec, &bytes).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Malformed body", Some(e)))?;
+        ensure(state, &body)?;
+        Ok(Negotiated { body, respond_with, wire_bytes: bytes.len() })
+    }
+}
+
+pub struct Encoded<T>(pub Codec, pub T);
+
+impl<T: Serialize + Wire> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        ([(header::CONTENT_TYPE, HeaderValue::from_static(self.0.content_type()))], encode(self.0, &self.1)).into_response()
+    }
+}
+
+pub mod pb {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SyncRequest {
+        #[prost(string, tag = "1")] pub connection_id: String,
+        #[prost(bytes = "vec", optional, tag = "2")] pub sdf_delta: Option<Vec<u8>>,
+        #[prost(string, optional, tag = "3")] pub timestamp: Option<String>,
+        #[prost(uint64, optional, tag = "4")] pub sequence: Option<u64>,
+        #[prost(string, optional, tag = "5")] pub priority: Option<String>,
+        #[prost(message, optional, tag = "6")] pub envelope: Option<Envelope>,
+        #[prost(uint32, optional, tag = "7")] pub schema_version: Option<u32>,
+    }
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Envelope {
+        #[prost(string, tag = "1")] pub key_id: String,
+        #[prost(string, tag = "2")] pub alg: String,
+        #[prost(string, tag = "3")] pub ciphertext: String,
+        #[prost(string, optional, tag = "4")] pub nonce: Option<String>,
+        #[prost(string, tag = "5")] pub tag: String,
+    }
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SyncResponse {
+        #[prost(string, tag = "1")] pub sync_id: String,
+        #[prost(string, tag = "2")] pub status: String,
+        #[prost(uint32, tag = "3")] pub objects_synced: u32,
+        #[prost(uint64, tag = "4")] pub sdf_bytes_transferred: u64,
+        #[prost(double, tag = "5")] pub latency_ms: f64,
+        #[prost(string, optional, tag = "6")] pub delivery_id: Option<String>,
+    }
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TransformRequest {
+        #[prost(string, tag = "1")] pub source_protocol: String,
+        #[prost(string, tag = "2")] pub target_protocol: String,
+        #[prost(bytes = "vec", tag = "3")] pub payload: Vec<u8>,
+        #[prost(string, repeated, tag = "4")] pub pipeline: Vec<String>,
+        #[prost(uint32, optional, tag = "5")] pub schema_version: Option<u32>,
+    }
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TransformStage {
+        #[prost(string, tag = "1")] pub from: String,
+        #[prost(string, tag = "2")] pub to: String,
+        #[prost(uint64, tag = "3")] pub elapsed_us: u64,
+    }
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TransformResponse {
+        #[prost(string, tag = "1")] pub transform_id: String,
+        #[prost(string, tag = "2")] pub source: String,
+        #[prost(string, tag = "3")] pub target: String,
+        #[prost(bytes = "vec", tag = "4")] pub output: Vec<u8>,
+        #[prost(uint64, tag = "5")] pub elapsed_us: u64,
+        #[prost(message, repeated, tag = "6")] pub stages: Vec<TransformStage>,
+    }
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubscribeRequest {
+        #[prost(string, optional, tag = "1")] pub topics: Option<String>,
+        #[prost(string, optional, tag = "2")] pub kinds: Option<String>,
+        #[prost(string, optional, tag = "3")] pub device: Option<String>,
+        #[prost(string, optional, tag = "4")] pub r#type: Option<String>,
+        #[prost(string, optional, tag = "5")] pub bbox: Option<String>,
+        #[prost(double, optional, tag = "6")] pub min_change: Option<f64>,
+        #[prost(uint32, optional, tag = "7")] pub buffer: Option<u32>,
+        #[prost(string, optional, tag = "8")] pub overflow: Option<String>,
+    }
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Event {
+        #[prost(string, tag = "1")] pub kind: String,
+        #[prost(bytes = "vec", tag = "2")] pub event: Vec<u8>,
+    }
+}
+
+impl Wire for SyncRequest {
+    type Proto = pb::SyncRequest;
+    fn from_proto(p: pb::SyncRequest) -> Result<Self, String> {
+        Ok(SyncRequest { connection_id: p.connection_id, sdf_delta: p.sdf_delta.as_deref().map(from_cbor).transpose()?, timestamp: p.timestamp, sequence: p.sequence, priority: p.priority,
+            envelope: p.envelope.map(|e| Envelope { key_id: e.key_id, alg: e.alg, ciphertext: e.ciphertext, nonce: e.nonce, tag: e.tag }), schema_version: p.schema_version,
+        })
+    }
+    fn to_proto(&self) -> pb::SyncRequest {
+        pb::SyncRequest { connection_id: self.connection_id.clone(), sdf_delta: self.sdf_delta.as_ref().map(to_cbor), timestamp: self.timestamp.clone(), sequence: self.sequence, priority: self.priority.clone(),
+            envelope: self.envelope.clone().map(|e| pb::Envelope { key_id: e.key_id, alg: e.alg, ciphertext: e.ciphertext, nonce: e.nonce, tag: e.tag }), schema_version: self.schema_version,
+        }
+    }
+}
+
+impl Wire for SyncResponse {
+    type Proto = pb::SyncResponse;
+    fn from_proto(p: pb::SyncResponse) -> Result<Self, String> {
+        Ok(SyncResponse { sync_id: p.sync_id, status: p.status, objects_synced: p.objects_synced, sdf_bytes_transferred: p.sdf_bytes_transferred, latency_ms: p.latency_ms, delivery_id: p.delivery_id })
+    }
+    fn to_proto(&self) -> pb::SyncResponse {
+        pb::SyncResponse { sync_id: self.sync_id.clone(), status: self.status.clone(), objects_synced: self.objects_synced, sdf_bytes_transferred: self.sdf_bytes_transferred, latency_ms: self.latency_ms, delivery_id: self.delivery_id.clone() }
+    }
+}
+
+impl Wire for TransformRequest {
+    type Proto = pb::TransformRequest;
+    fn from_proto(p: pb::TransformRequest) -> Result<Self, String> {
+        let payload = if p.payload.is_empty() { serde_json::Value::Null } else { from_cbor(&p.payload)? };
+        Ok(TransformRequest { source_protocol: p.source_protocol, target_protocol: p.target_protocol, payload, pipeline: p.pipeline, schema_version: p.schema_version })
+    }
+    fn to_proto(&self) -> pb::TransformRequest {
+        pb::TransformRequest { source_protocol: self.source_protocol.clone(), target_protocol: self.target_protocol.clone(), payload: to_cbor(&self.payload), pipeline: self.pipeline.clone(), schema_version: self.schema_version }
+    }
+}
+
+impl Wire for TransformResponse {
+    type Proto = pb::TransformResponse;
+    fn from_proto(p: pb::TransformResponse) -> Result<Self, String> {
+        let output = if p.output.is_empty() { serde_json::Value::Null } else { from_cbor(&p.output)? };
+        let stages = p.stages.into_iter().map(|st| TransformStage { from: st.from, to: st.to, elapsed_us: st.elapsed_us }).collect();
+        Ok(TransformResponse { transform_id: p.transform_id, source: p.source, target: p.target, output, elapsed_us: p.elapsed_us as u128, stages })
+    }
+    fn to_proto(&self) -> pb::TransformResponse {
+        pb::TransformResponse { transform_id: self.transform_id.clone(), source: self.source.clone(), target: self.target.clone(), output: to_cbor(&self.output), elapsed_us: self.elapsed_us as u64,
+            stages: self.stages.iter().map(|st| pb::TransformStage { from: st.from.clone(), to: st.to.clone(), elapsed_us: st.elapsed_us }).collect() }
+    }
+}
@@ -0,0 +1,73 @@
+//! Per-route-group concurrency limits. Each group shares one limit across its routes, enforced
+//! by a tower concurrency-limit/load-shed stack: a request arriving while the group is full is
+//! not queued but answered at once with 503 (`overloaded`) and `Retry-After`
+//! (`ROUTE_RETRY_AFTER_SECS`, default 1), so a flood on one group cannot tie up the runtime
+//! for the others. Limits are set with `ROUTE_LIMIT_SYNC` (default 1024; the sync pipeline's
+//! own back-pressure sheds first, with pacing), `ROUTE_LIMIT_TRANSFORM` (default 128) and
+//! `ROUTE_LIMIT_QUERY` (default 256, stats and listings); 0 disables a group's limit.
+
+use crate::{api_err, AppState};
+use axum::{error_handling::HandleErrorLayer, http::StatusCode, response::IntoResponse, routing::MethodRouter, BoxError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::LoadShedLayer;
+use tower::ServiceBuilder;
+
+#[derive(Clone, Copy)]
+pub enum Group { Sync, Transform, Query }
+
+impl Group {
+    pub const ALL: [Group; 3] = [Group::Sync, Group::Transform, Group::Query];
+
+    pub fn as_str(self) -> &'static str { match self { Group::Sync => "sync", Group::Transform => "transform", Group::Query => "query" } }
+
+    fn limit_from_env(self) -> usize {
+        let (var, default) = match self { Group::Sync => ("ROUTE_LIMIT_SYNC", 1024), Group::Transform => ("ROUTE_LIMIT_TRANSFORM", 128), Group::Query => ("ROUTE_LIMIT_QUERY", 256) };
+        std::env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+}
+
+struct Limit { max: usize, semaphore: Arc<Semaphore>, shed: Arc<AtomicU64> }
+
+pub struct GroupSnapshot { pub group: Group, pub limit: usize, pub in_flight: usize, pub shed_total: u64 }
+
+pub struct Limits { retry_after_secs: u64, groups: [Limit; 3] }
+
+impl Limits {
+    pub fn from_env() -> Self {
+        let retry_after_secs = std::env::var("ROUTE_RETRY_AFTER_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(1u64).max(1);
+        let groups = Group::ALL.map(|g| {
+            let max = g.limit_from_env();
+            Limit { max, semaphore: Arc::new(Semaphore::new(if max == 0 { Semaphore::MAX_PERMITS } else { max })), shed: Arc::default() }
+        });
+        Limits { retry_after_secs, groups }
+    }
+
+    /// Puts `route` under `group`'s shared limit.
+    pub fn apply(&self, group: Group, route: MethodRouter<Arc<AppState>>) -> MethodRouter<Arc<AppState>> {
+        let l = &self.groups[group as usize];
+        if l.max == 0 { return route; }
+        let (shed, retry_after) = (l.shed.clone(), self.retry_after_secs);
+        route.layer(ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(move |e: BoxError| {
+                let shed = shed.clone();
+                async move {
+                    if !e.is::<tower::load_shed::error::Overloaded>() {
+                        return api_err(StatusCode::INTERNAL_SERVER_ERROR, "Request failed", Some(e.to_string())).into_response();
+                    }
+                    shed.fetch_add(1, Ordering::Relaxed);
+                    api_err(StatusCode::SERVICE_UNAVAILABLE, "Server busy", Some(format!("too many concurrent {} requests", group.as_str()))).code("overloaded").retry_after(retry_after).into_response()
+                }
+            }))
+            .layer(LoadShedLayer::new())
+            .layer(GlobalConcurrencyLimitLayer::with_semaphore(l.semaphore.clone())))
+    }
+
+    pub fn snapshot(&self) -> Vec<GroupSnapshot> {
+        Group::ALL.iter().zip(&self.groups).filter(|(_, l)| l.max > 0).map(|(g, l)| GroupSnapshot {
+            group: *g, limit: l.max, in_flight: l.max.saturating_sub(l.semaphore.available_permits()), shed_total: l.shed.load(Ordering::Relaxed),
+        }).collect()
+    }
+}
@@ -0,0 +1,77 @@
+//! Conditional GETs for polling clients. Successful JSON responses to `GET` carry a strong
+//! `ETag` (a hash of the body, so replicas agree) and a `Last-Modified` of when this node first
+//! served that exact body, i.e. when the data last changed. A request whose `If-None-Match`
+//! matches, or (without `If-None-Match`) whose `If-Modified-Since` is not older than
+//! `Last-Modified`, gets an empty `304 Not Modified` instead. Streams and non-JSON responses
+//! pass through untouched.
+
+use crate::events::now_ms;
+use crate::AppState;
+use axum::{
+    body::{to_bytes, Body, HttpBody},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+const MAX_TAGGED_BODY: u64 = 4 * 1024 * 1024;
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// When each recently served ETag was first seen, in whole seconds (HTTP date precision).
+pub struct Validators { first_seen: Mutex<LruCache<String, u64>> }
+
+impl Default for Validators {
+    fn default() -> Self { Validators { first_seen: Mutex::new(LruCache::new(NonZeroUsize::new(4096).unwrap())) } }
+}
+
+impl Validators {
+    fn last_modified(&self, etag: &str) -> u64 {
+        *self.first_seen.lock().unwrap().get_or_insert(etag.to_string(), || now_ms() / 1000 * 1000)
+    }
+}
+
+pub(crate) fn http_date(ms: u64) -> String {
+    chrono::DateTime::from_timestamp_millis(ms as i64).unwrap_or_default().format(HTTP_DATE).to_string()
+}
+
+/// `If-None-Match` compares weakly: `W/"x"` matches `"x"`.
+fn none_match(headers: &HeaderMap, etag: &str) -> Option<bool> {
+    let v = headers.get(header::IF_NONE_MATCH)?.to_str().ok()?;
+    Some(v.split(',').map(str::trim).any(|t| t == "*" || t.trim_start_matches("W/") == etag))
+}
+
+fn not_modified_since(headers: &HeaderMap, last_modified_ms: u64) -> bool {
+    headers.get(header::IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok())
+        .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+        .is_some_and(|since| last_modified_ms as i64 <= since.timestamp_millis())
+}
+
+pub async fn conditional_mw(State(s): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    if req.method() != Method::GET { return next.run(req).await; }
+    let headers = req.headers().clone();
+    let resp = next.run(req).await;
+    let is_json = resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(|ct| ct.starts_with("application/json"));
+    // Only complete bodies of known size are hashed; streamed responses go out as they are.
+    if resp.status() != StatusCode::OK || !is_json || resp.headers().contains_key(header::ETAG) || resp.body().size_hint().exact().is_none_or(|n| n > MAX_TAGGED_BODY) { return resp; }
+    let (mut parts, body) = resp.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_TAGGED_BODY as usize).await else { return StatusCode::INTERNAL_SERVER_ERROR.into_response() };
+    let etag = format!("\"{}\"", hex::encode(&Sha256::digest(&bytes)[..16]));
+    let last_modified = s.validators.last_modified(&etag);
+    let fresh = none_match(&headers, &etag).unwrap_or_else(|| not_modified_since(&headers, last_modified));
+    let h = &mut parts.headers;
+    h.insert(header::ETAG, HeaderValue::from_str(&etag).expect("hex etag is a valid header"));
+    h.insert(header::LAST_MODIFIED, HeaderValue::from_str(&http_date(last_modified)).expect("http date is a valid header"));
+    h.entry(header::CACHE_CONTROL).or_insert(HeaderValue::from_static("private, no-cache"));
+    if fresh {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.remove(header::CONTENT_TYPE);
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
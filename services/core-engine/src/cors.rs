@@ -0,0 +1,158 @@
+//! CORS policy, loaded from the JSON file named by `CORS_CONFIG_PATH`:
+//!
+//! ```json
+//! { "default": { "allowed_origins": ["https://console.example.com"], "allow_credentials": true },
+//!   "routes": [{ "path": "/api/v1/gateway/events*", "allowed_origins": ["https://*.example.com"] }] }
+//! ```
+//!
+//! Origins are exact or `*` globs; `routes` override individual fields of `default` for paths
+//! matching `path` (first match wins). Without a file, or when the file is invalid, the strict
+//! default applies: no origin is allowed, so browsers only reach the engine same-origin.
+//! `GET /admin/cors` shows the effective policy.
+
+use crate::rbac::{Admin, Require};
+use crate::subscriptions::glob;
+use crate::AppState;
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy { allowed_origins: Vec<String>, allowed_methods: Vec<String>, allowed_headers: Vec<String>, expose_headers: Vec<String>, allow_credentials: bool, max_age_secs: u64 }
+
+#[derive(Deserialize)]
+struct RouteOverride {
+    path: String, allowed_origins: Option<Vec<String>>, allowed_methods: Option<Vec<String>>, allowed_headers: Option<Vec<String>>,
+    expose_headers: Option<Vec<String>>, allow_credentials: Option<bool>, max_age_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct CorsFile { default: Policy, routes: Vec<RouteOverride> }
+
+#[derive(Clone, Serialize)]
+pub struct RoutePolicy { path: String, #[serde(flatten)] policy: Policy }
+
+pub struct Cors { source: Option<String>, default: Policy, routes: Vec<RoutePolicy> }
+
+#[derive(Deserialize)]
+pub struct CorsQuery { path: Option<String> }
+
+#[derive(Serialize)]
+pub struct CorsView {
+    /// The config file in effect; absent when running on the strict default.
+    source: Option<String>, default: Policy, routes: Vec<RoutePolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")] effective: Option<RoutePolicy>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        let list = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        Policy {
+            allowed_origins: Vec::new(), allowed_methods: list(&["GET", "POST", "PUT", "DELETE"]),
+            allowed_headers: list(&["authorization", "content-type", "idempotency-key", "if-modified-since", "if-none-match", "x-grpc-web", "x-tenant-id", "x-user-agent"]),
+            expose_headers: list(&["etag", "grpc-message", "grpc-status", "retry-after", "x-suggested-interval-ms"]),
+            allow_credentials: false, max_age_secs: 600,
+        }
+    }
+}
+
+impl Policy {
+    fn check(&self) -> Result<(), String> {
+        if self.allow_credentials && self.allowed_origins.iter().any(|o| o == "*") { return Err("allow_credentials cannot be combined with the \"*\" origin".into()); }
+        Ok(())
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool { self.allowed_origins.iter().any(|o| o == "*" || glob(&o.to_ascii_lowercase(), &origin.to_ascii_lowercase())) }
+
+    fn allows_method(&self, method: &str) -> bool { self.allowed_methods.iter().any(|m| m == "*" || m.eq_ignore_ascii_case(method)) }
+
+    /// `requested` is the comma-separated `Access-Control-Request-Headers` value.
+    fn allows_headers(&self, requested: &str) -> bool {
+        requested.split(',').map(str::trim).filter(|h| !h.is_empty()).all(|h| self.allowed_headers.iter().any(|a| a == "*" || a.eq_ignore_ascii_case(h)))
+    }
+}
+
+impl RouteOverride {
+    fn over(self, base: &Policy) -> RoutePolicy {
+        let b = base.clone();
+        let policy = Policy {
+            allowed_origins: self.allowed_origins.unwrap_or(b.allowed_origins), allowed_methods: self.allowed_methods.unwrap_or(b.allowed_methods),
+            allowed_headers: self.allowed_headers.unwrap_or(b.allowed_headers), expose_headers: self.expose_headers.unwrap_or(b.expose_headers),
+            allow_credentials: self.allow_credentials.unwrap_or(b.allow_credentials), max_age_secs: self.max_age_secs.unwrap_or(b.max_age_secs),
+        };
+        RoutePolicy { path: self.path, policy }
+    }
+}
+
+impl Cors {
+    /// Reads `CORS_CONFIG_PATH`; an unreadable or invalid file is logged and the strict default used.
+    pub fn from_env() -> Self {
+        let strict = Cors { source: None, default: Policy::default(), routes: Vec::new() };
+        let Ok(path) = std::env::var("CORS_CONFIG_PATH") else { return strict };
+        let loaded = std::fs::read_to_string(&path).map_err(|e| e.to_string())
+            .and_then(|t| serde_json::from_str::<CorsFile>(&t).map_err(|e| e.to_string()))
+            .and_then(|f| {
+                let routes: Vec<RoutePolicy> = f.routes.into_iter().map(|r| r.over(&f.default)).collect();
+                f.default.check()?;
+                for r in &routes { r.policy.check().map_err(|e| format!("route {}: {e}", r.path))?; }
+                Ok(Cors { source: Some(path.clone()), default: f.default, routes })
+            });
+        match loaded {
+            Ok(c) => { tracing::info!(path = %path, routes = c.routes.len(), "CORS policy loaded"); c }
+            Err(e) => { tracing::warn!("Ignoring CORS_CONFIG_PATH {path}, no cross-origin access allowed: {e}"); strict }
+        }
+    }
+
+    fn policy_for(&self, path: &str) -> &Policy { self.routes.iter().find(|r| glob(&r.path, path)).map_or(&self.default, |r| &r.policy) }
+}
+
+fn join(v: &[String]) -> Option<HeaderValue> { (!v.is_empty()).then(|| HeaderValue::from_str(&v.join(", ")).ok()).flatten() }
+
+fn allow_origin(h: &mut HeaderMap, p: &Policy, origin: &HeaderValue) {
+    h.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    if p.allow_credentials { h.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true")); }
+}
+
+/// Answers preflights and decorates responses to allowed origins; requests without `Origin`
+/// pass through untouched.
+pub async fn cors_mw(State(s): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let Some(origin) = req.headers().get(header::ORIGIN).cloned() else { return next.run(req).await };
+    let policy = s.cors.policy_for(req.uri().path());
+    let allowed = origin.to_str().is_ok_and(|o| policy.allows_origin(o));
+    let requested_method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD).and_then(|v| v.to_str().ok());
+    if let (&Method::OPTIONS, Some(method)) = (req.method(), requested_method) {
+        let requested_headers = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS).and_then(|v| v.to_str().ok()).unwrap_or("");
+        let mut resp = StatusCode::NO_CONTENT.into_response();
+        let h = resp.headers_mut();
+        h.insert(header::VARY, HeaderValue::from_static("origin, access-control-request-method, access-control-request-headers"));
+        if allowed && policy.allows_method(method) && policy.allows_headers(requested_headers) {
+            allow_origin(h, policy, &origin);
+            if let Some(v) = join(&policy.allowed_methods) { h.insert(header::ACCESS_CONTROL_ALLOW_METHODS, v); }
+            if let Some(v) = join(&policy.allowed_headers) { h.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, v); }
+            h.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(policy.max_age_secs));
+        }
+        return resp;
+    }
+    let mut resp = next.run(req).await;
+    let h = resp.headers_mut();
+    h.append(header::VARY, HeaderValue::from_static("origin"));
+    if allowed {
+        allow_origin(h, policy, &origin);
+        if let Some(v) = join(&policy.expose_headers) { h.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, v); }
+    }
+    resp
+}
+
+/// `GET /admin/cors[?path=/api/v1/...]`: the loaded policy, plus the one applying to `path`.
+pub async fn view(State(s): State<Arc<AppState>>, _: Require<Admin>, Query(q): Query<CorsQuery>) -> Json<CorsView> {
+    let c = &s.cors;
+    let effective = q.path.map(|path| RoutePolicy { policy: c.policy_for(&path).clone(), path });
+    Json(CorsView { source: c.source.clone(), default: c.default.clone(), routes: c.routes.clone(), effective })
+}
@@ -0,0 +1,126 @@
+//! Live registry and mesh views for browser dashboards: `GET /dashboard/ws` (WebSocket,
+//! read-only). The first message is a `snapshot` of the caller's connections and meshes (shaped
+//! as in `GET /connections` and `GET /mesh`); after that the server sends incremental updates
+//! as they happen: `connection-upsert` / `connection-removed` on connect and disconnect,
+//! `mesh-upsert` / `mesh-removed` on mesh changes, degradation and healing. A fresh snapshot
+//! follows every `DASHBOARD_REFRESH_SECS` (default 30; 0 disables), catching changes no event
+//! reports (online state, queue depth), and whenever the socket falls behind.
+//!
+//! Views are scoped to the caller's tenant. A caller with the `admin` role may pass
+//! `?scope=all` to watch every tenant; items then carry their `tenant`. Client messages other
+//! than close are ignored.
+
+use crate::hub::{Overflow, Pattern, Subscription};
+use crate::outbox::ConnectionInfo;
+use crate::rbac::{Read, Require, Role};
+use crate::trash::Deletion;
+use crate::{api_err, mesh, ApiError, AppState, Tenant};
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Query, State},
+    http::StatusCode,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+const KINDS: &[&str] = &["connect", "disconnect", "mesh-change", "mesh-degraded", "mesh-healed"];
+
+#[derive(Deserialize)]
+pub struct DashboardQuery { scope: Option<String> }
+
+/// An item of the view, tagged with its tenant when the view spans tenants.
+#[derive(Serialize)]
+struct Scoped<T> { #[serde(skip_serializing_if = "Option::is_none")] tenant: Option<String>, #[serde(flatten)] item: T }
+
+/// `None` watches every tenant.
+struct View { tenant: Option<String> }
+
+impl View {
+    fn sees(&self, tenant: &str) -> bool { self.tenant.as_deref().is_none_or(|t| t == tenant) }
+
+    fn scoped<T>(&self, tenant: &str, item: T) -> Scoped<T> { Scoped { tenant: self.tenant.is_none().then(|| tenant.to_string()), item } }
+
+    fn connection(&self, s: &AppState, id: String, c: crate::Connection) -> Scoped<ConnectionInfo> {
+        let (tenant, queue) = (c.tenant.clone(), s.outbox.info(&c.tenant, &c.device_id));
+        self.scoped(&tenant, ConnectionInfo::new(s, id, c, queue, Deletion::default()))
+    }
+
+    fn mesh(&self, s: &AppState, id: &str) -> Option<Scoped<mesh::Mesh>> {
+        let m = s.meshes.lock().unwrap().get(id).filter(|m| self.sees(&m.tenant)).cloned()?;
+        Some(self.scoped(&m.tenant.clone(), m))
+    }
+
+    fn snapshot(&self, s: &AppState) -> Value {
+        let mut conns: Vec<(String, crate::Connection)> = s.connections.lock().unwrap().iter().filter(|(_, c)| self.sees(&c.tenant)).map(|(id, c)| (id.clone(), c.clone())).collect();
+        conns.sort_by(|(_, a), (_, b)| (&a.tenant, &a.device_id).cmp(&(&b.tenant, &b.device_id)));
+        let connections: Vec<Scoped<ConnectionInfo>> = conns.into_iter().map(|(id, c)| self.connection(s, id, c)).collect();
+        let mut meshes: Vec<Scoped<mesh::Mesh>> = s.meshes.lock().unwrap().values().filter(|m| self.sees(&m.tenant)).map(|m| self.scoped(&m.tenant, m.clone())).collect();
+        meshes.sort_by(|a, b| a.item.mesh_id.cmp(&b.item.mesh_id));
+        json!({ "kind": "snapshot", "connections": connections, "meshes": meshes })
+    }
+
+    /// The update a registry or mesh event amounts to, read from current state so a stale
+    /// event cannot resurrect something already gone.
+    fn update(&self, s: &AppState, kind: &str, data: &Value) -> Option<Value> {
+        if kind == "connect" || kind == "disconnect" {
+            let id = data.get("connection_id")?.as_str()?;
+            let found = s.connections.lock().unwrap().get(id).filter(|c| self.sees(&c.tenant)).cloned();
+            return Some(match found {
+                Some(c) => json!({ "kind": "connection-upsert", "connection": self.connection(s, id.into(), c) }),
+                None => json!({ "kind": "connection-removed", "connection_id": id }),
+            });
+        }
+        let id = data.get("mesh_id")?.as_str()?;
+        Some(match self.mesh(s, id) {
+            Some(m) => json!({ "kind": "mesh-upsert", "mesh": m }),
+            None => json!({ "kind": "mesh-removed", "mesh_id": id }),
+        })
+    }
+
+    fn subscribe(&self, s: &AppState) -> Subscription {
+        let patterns: Vec<Pattern> = KINDS.iter().map(|k| {
+            let relative = format!("+/${k}");
+            Pattern::parse(&if self.tenant.is_some() { relative } else { format!("+/{relative}") }).expect("static patterns are valid")
+        }).collect();
+        s.hub.subscribe(self.tenant.as_deref(), "dashboard", &patterns, Overflow::DropOldest, None)
+    }
+}
+
+pub async fn ws(State(s): State<Arc<AppState>>, read: Require<Read>, Tenant(tenant): Tenant, Query(q): Query<DashboardQuery>, upgrade: WebSocketUpgrade) -> Result<Response, ApiError> {
+    let view = match q.scope.as_deref() {
+        None | Some("tenant") => View { tenant: Some(tenant) },
+        Some("all") if read.0 == Role::Admin => View { tenant: None },
+        Some("all") => return Err(api_err(StatusCode::FORBIDDEN, "Permission denied", Some("scope=all needs the admin role".into())).code("forbidden")),
+        Some(other) => return Err(api_err(StatusCode::BAD_REQUEST, "Unknown scope", Some(format!("{other}: expected tenant or all")))),
+    };
+    Ok(upgrade.on_upgrade(move |socket| pump(s, socket, view)))
+}
+
+async fn pump(s: Arc<AppState>, mut socket: WebSocket, view: View) {
+    // Subscribe before the snapshot so nothing between the two is missed.
+    let mut rx = view.subscribe(&s);
+    if socket.send(Message::Text(view.snapshot(&s).to_string())).await.is_err() { return }
+    let refresh_secs = std::env::var("DASHBOARD_REFRESH_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30u64);
+    let period = Duration::from_secs(refresh_secs.max(1));
+    let mut refresh = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        let msg = tokio::select! {
+            _ = refresh.tick(), if refresh_secs > 0 => Some(view.snapshot(&s)),
+            ev = rx.recv() => match ev {
+                Ok(ev) => view.update(&s, &ev.kind, &ev.data),
+                Err(RecvError::Lagged(_)) => Some(view.snapshot(&s)),
+                Err(RecvError::Closed) => break,
+            },
+            inc = socket.recv() => match inc {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => None,
+            },
+        };
+        if let Some(msg) = msg {
+            if socket.send(Message::Text(msg.to_string())).await.is_err() { break }
+        }
+    }
+}
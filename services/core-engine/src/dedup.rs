@@ -0,0 +1,141 @@
+//! Content-hash deduplication of syncs, for firmware that resends the same delta over and over.
+//! A sync whose `sdf_delta` (or envelope) hashes the same as one accepted on the same connection
+//! within `SYNC_DEDUP_WINDOW_MS` (default 5000; 0 turns it off) is not merged or delivered
+//! again: it is answered with the earlier sync's `sync_id` and `delivery_id`, status
+//! `duplicate`, and counted in `duplicates_suppressed`. This runs before replay protection and
+//! regardless of `Idempotency-Key`, so a resend with a reused sequence number is absorbed
+//! rather than refused.
+//!
+//! A device with `SYNC_DEDUP_OFFENDER_THRESHOLD` (default 20) suppressed duplicates within
+//! `SYNC_DEDUP_OFFENDER_WINDOW_SECS` (default 600) is flagged as an offender, emitting
+//! `duplicate-offender` once per flagging. `GET /api/v1/gateway/dedup/offenders` lists the
+//! tenant's devices with recent duplicates, worst first (`?flagged=true` for offenders only).
+
+use crate::events::now_ms;
+use crate::rbac::{Read, Require};
+use crate::{AppState, SyncRequest, SyncResponse, Tenant};
+use axum::{extract::{Query, State}, response::Json};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Suppressed-duplicate timestamps kept per device; far above any sensible threshold.
+const MAX_RECENT: usize = 10_000;
+
+struct Seen { fingerprint: u64, at_ms: u64, sync_id: String, delivery_id: Option<String> }
+
+#[derive(Clone, Serialize)]
+pub struct Offender {
+    device_id: String, duplicates: u64, recent_duplicates: usize, first_duplicate_ms: u64, last_duplicate_ms: u64, flagged: bool,
+    #[serde(skip)] recent: VecDeque<u64>,
+}
+
+pub struct Dedup {
+    window_ms: u64,
+    threshold: usize,
+    offender_window_ms: u64,
+    seen: Mutex<HashMap<String, VecDeque<Seen>>>,
+    offenders: Mutex<HashMap<(String, String), Offender>>,
+    suppressed: AtomicU64,
+}
+
+pub struct DedupCounts { pub suppressed: u64, pub offenders: usize }
+
+impl Offender {
+    /// Drops duplicates older than the window; returns whether the device is (still) an offender.
+    fn trim(&mut self, now: u64, window_ms: u64, threshold: usize) -> bool {
+        while self.recent.front().is_some_and(|&t| now.saturating_sub(t) > window_ms) { self.recent.pop_front(); }
+        self.recent_duplicates = self.recent.len();
+        self.recent.len() >= threshold
+    }
+}
+
+impl Dedup {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        Dedup {
+            window_ms: env("SYNC_DEDUP_WINDOW_MS", 5000), threshold: env("SYNC_DEDUP_OFFENDER_THRESHOLD", 20).max(1) as usize,
+            offender_window_ms: env("SYNC_DEDUP_OFFENDER_WINDOW_SECS", 600).max(1) * 1000,
+            seen: Mutex::default(), offenders: Mutex::default(), suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// The content hash of a sync that carries something to deduplicate, while dedup is on.
+    pub fn fingerprint(&self, req: &SyncRequest) -> Option<u64> {
+        if self.window_ms == 0 || (req.sdf_delta.is_none() && req.envelope.is_none()) { return None; }
+        let mut h = DefaultHasher::new();
+        req.sdf_delta.as_ref().map(|d| d.to_string()).hash(&mut h);
+        req.envelope.as_ref().map(|e| serde_json::to_string(e).unwrap_or_default()).hash(&mut h);
+        Some(h.finish())
+    }
+
+    /// Answers a duplicate of a recent sync on the connection with that sync's ids.
+    pub fn suppress(&self, s: &AppState, tenant: &str, device_id: &str, connection_id: &str, fingerprint: u64, started: Instant) -> Option<SyncResponse> {
+        let now = now_ms();
+        let (sync_id, delivery_id) = {
+            let mut seen = self.seen.lock().unwrap();
+            let recent = seen.get_mut(connection_id)?;
+            while recent.front().is_some_and(|r| now.saturating_sub(r.at_ms) > self.window_ms) { recent.pop_front(); }
+            let r = recent.iter().rev().find(|r| r.fingerprint == fingerprint)?;
+            (r.sync_id.clone(), r.delivery_id.clone())
+        };
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+        let flagged = {
+            let mut offenders = self.offenders.lock().unwrap();
+            let o = offenders.entry((tenant.to_string(), device_id.to_string())).or_insert_with(|| Offender {
+                device_id: device_id.to_string(), duplicates: 0, recent_duplicates: 0, first_duplicate_ms: now, last_duplicate_ms: now, flagged: false, recent: VecDeque::new(),
+            });
+            o.duplicates += 1;
+            o.last_duplicate_ms = now;
+            o.recent.push_back(now);
+            if o.recent.len() > MAX_RECENT { o.recent.pop_front(); }
+            let offending = o.trim(now, self.offender_window_ms, self.threshold);
+            let newly = offending && !o.flagged;
+            o.flagged = offending;
+            newly.then(|| o.clone())
+        };
+        if let Some(o) = flagged {
+            tracing::warn!(%tenant, device_id, duplicates = o.recent_duplicates, "device flagged for resending duplicate syncs");
+            s.emit("duplicate-offender", tenant, serde_json::json!({ "device_id": device_id, "connection_id": connection_id, "recent_duplicates": o.recent_duplicates, "window_secs": self.offender_window_ms / 1000 }));
+        }
+        tracing::debug!(%tenant, device_id, %sync_id, "duplicate sync suppressed");
+        Some(SyncResponse { sync_id, status: "duplicate".into(), objects_synced: 0, sdf_bytes_transferred: 0, latency_ms: started.elapsed().as_secs_f64() * 1000.0, delivery_id })
+    }
+
+    /// Remembers an accepted sync so resends of it within the window are suppressed.
+    pub fn record(&self, connection_id: &str, fingerprint: u64, resp: &SyncResponse) {
+        let now = now_ms();
+        let mut seen = self.seen.lock().unwrap();
+        let recent = seen.entry(connection_id.to_string()).or_default();
+        while recent.front().is_some_and(|r| now.saturating_sub(r.at_ms) > self.window_ms) { recent.pop_front(); }
+        recent.push_back(Seen { fingerprint, at_ms: now, sync_id: resp.sync_id.clone(), delivery_id: resp.delivery_id.clone() });
+    }
+
+    pub fn forget(&self, connection_id: &str) { self.seen.lock().unwrap().remove(connection_id); }
+
+    /// Brings every device's flag up to date, dropping devices without recent duplicates.
+    fn sweep(&self, offenders: &mut HashMap<(String, String), Offender>) {
+        let now = now_ms();
+        offenders.retain(|_, o| { o.flagged = o.trim(now, self.offender_window_ms, self.threshold); !o.recent.is_empty() });
+    }
+
+    pub fn counts(&self) -> DedupCounts {
+        let mut offenders = self.offenders.lock().unwrap();
+        self.sweep(&mut offenders);
+        DedupCounts { suppressed: self.suppressed.load(Ordering::Relaxed), offenders: offenders.values().filter(|o| o.flagged).count() }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OffenderQuery { #[serde(default)] flagged: bool }
+
+pub async fn offenders(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Query(q): Query<OffenderQuery>) -> Json<Vec<Offender>> {
+    let mut offenders = s.dedup.offenders.lock().unwrap();
+    s.dedup.sweep(&mut offenders);
+    let mut list: Vec<Offender> = offenders.iter().filter(|((t, _), o)| *t == tenant && (o.flagged || !q.flagged)).map(|(_, o)| o.clone()).collect();
+    list.sort_by(|a, b| b.recent_duplicates.cmp(&a.recent_duplicates).then_with(|| a.device_id.cmp(&b.device_id)));
+    Json(list)
+}
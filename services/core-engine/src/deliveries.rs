@@ -0,0 +1,125 @@
+//! Delivery receipts for accepted syncs. Every successful sync returns a `delivery_id` naming
+//! a record of each destination its delta was handed to:
+//!
+//! * `merge`: the device shadow, snapshot log and envelope store;
+//! * `upstream:<region>`: the home region, for relayed connections (its own `delivery_id` is the
+//!   destination's `detail`);
+//! * `coap:<observer>`: CoAP observers, delivered once the non-confirmable notification is sent;
+//! * `sse:<n>` / `ws:<n>`: stream subscribers whose topics matched, delivered when the stream
+//!   takes the event from its hub buffer;
+//! * `kafka`: the Kafka sink, once the broker acknowledges the record.
+//!
+//! A destination stays `pending` until it is `delivered`, `failed`, or `dropped` (overflowed out
+//! of a subscriber's buffer, or the subscriber left before reading it). The record is `pending`
+//! while any destination is, then `delivered`, `partial` or `failed`.
+//! `GET /api/v1/gateway/deliveries/:id` reports it. Records are kept `DELIVERY_TTL_SECS`
+//! (default 3600), at most `DELIVERY_RETENTION` of them (default 100000, oldest evicted first).
+
+use crate::events::now_ms;
+use crate::rbac::{Read, Require};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status { Pending, Delivered, Failed, Dropped }
+
+#[derive(Clone, Serialize)]
+pub struct Destination { name: String, status: Status, updated_at_ms: u64, #[serde(skip_serializing_if = "Option::is_none")] detail: Option<String> }
+
+struct Record { tenant: String, sync_id: String, connection_id: String, device_id: String, created_at_ms: u64, destinations: BTreeMap<String, Destination> }
+
+#[derive(Serialize)]
+pub struct Receipt {
+    delivery_id: String, sync_id: String, connection_id: String, device_id: String, status: &'static str, created_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")] completed_at_ms: Option<u64>,
+    destinations: Vec<Destination>,
+}
+
+#[derive(Default)]
+struct Records { by_id: HashMap<String, Record>, order: VecDeque<String> }
+
+pub struct DeliveryCounts { pub tracked: usize, pub pending: usize, pub delivered: u64, pub failed: u64, pub dropped: u64 }
+
+pub struct Deliveries { retention: usize, ttl_ms: u64, records: Mutex<Records>, delivered: AtomicU64, failed: AtomicU64, dropped: AtomicU64 }
+
+impl Deliveries {
+    pub fn from_env() -> Self {
+        let retention = std::env::var("DELIVERY_RETENTION").ok().and_then(|v| v.parse().ok()).unwrap_or(100_000usize).max(1);
+        let ttl_secs = std::env::var("DELIVERY_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(3600u64).max(1);
+        Deliveries { retention, ttl_ms: ttl_secs * 1000, records: Mutex::default(), delivered: AtomicU64::new(0), failed: AtomicU64::new(0), dropped: AtomicU64::new(0) }
+    }
+
+    /// Opens a record for an accepted sync; returns its delivery id.
+    pub fn begin(&self, tenant: &str, sync_id: &str, connection_id: &str, device_id: &str) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = now_ms();
+        let mut r = self.records.lock().unwrap();
+        while let Some(oldest) = r.order.front() {
+            let expired = r.by_id.get(oldest).is_none_or(|rec| now.saturating_sub(rec.created_at_ms) > self.ttl_ms);
+            if !expired && r.order.len() < self.retention { break; }
+            if let Some(old) = r.order.pop_front() { r.by_id.remove(&old); }
+        }
+        r.by_id.insert(id.clone(), Record {
+            tenant: tenant.into(), sync_id: sync_id.into(), connection_id: connection_id.into(), device_id: device_id.into(), created_at_ms: now, destinations: BTreeMap::new(),
+        });
+        r.order.push_back(id.clone());
+        id
+    }
+
+    /// Sets destination `name` of delivery `id`, adding it if new.
+    pub fn set(&self, id: &str, name: &str, status: Status, detail: Option<String>) {
+        let mut r = self.records.lock().unwrap();
+        let Some(rec) = r.by_id.get_mut(id) else { return };
+        match status {
+            Status::Pending => {}
+            Status::Delivered => { self.delivered.fetch_add(1, Ordering::Relaxed); }
+            Status::Failed => { self.failed.fetch_add(1, Ordering::Relaxed); }
+            Status::Dropped => { self.dropped.fetch_add(1, Ordering::Relaxed); }
+        }
+        rec.destinations.insert(name.into(), Destination { name: name.into(), status, updated_at_ms: now_ms(), detail });
+    }
+
+    /// Drops destination `name`, for a sink that turned out not to want the event.
+    pub fn forget(&self, id: &str, name: &str) {
+        if let Some(rec) = self.records.lock().unwrap().by_id.get_mut(id) { rec.destinations.remove(name); }
+    }
+
+    /// Records the CoAP observers a delta was pushed to, as returned by `coap::Bridge::publish`.
+    pub fn notified(&self, id: &str, observers: Vec<(String, bool)>) {
+        for (observer, sent) in observers {
+            let (status, detail) = if sent { (Status::Delivered, None) } else { (Status::Failed, Some("send failed".into())) };
+            self.set(id, &format!("coap:{observer}"), status, detail);
+        }
+    }
+
+    pub fn get(&self, tenant: &str, id: &str) -> Option<Receipt> {
+        let r = self.records.lock().unwrap();
+        let rec = r.by_id.get(id).filter(|rec| rec.tenant == tenant && now_ms().saturating_sub(rec.created_at_ms) <= self.ttl_ms)?;
+        let destinations: Vec<Destination> = rec.destinations.values().cloned().collect();
+        let pending = destinations.iter().any(|d| d.status == Status::Pending);
+        let delivered = destinations.iter().filter(|d| d.status == Status::Delivered).count();
+        let status = if pending { "pending" } else if delivered == destinations.len() { "delivered" } else if delivered > 0 { "partial" } else { "failed" };
+        Some(Receipt {
+            delivery_id: id.into(), sync_id: rec.sync_id.clone(), connection_id: rec.connection_id.clone(), device_id: rec.device_id.clone(), status, created_at_ms: rec.created_at_ms,
+            completed_at_ms: (!pending).then(|| destinations.iter().map(|d| d.updated_at_ms).max().unwrap_or(rec.created_at_ms)),
+            destinations,
+        })
+    }
+
+    pub fn counts(&self) -> DeliveryCounts {
+        let r = self.records.lock().unwrap();
+        DeliveryCounts {
+            tracked: r.by_id.len(), pending: r.by_id.values().filter(|rec| rec.destinations.values().any(|d| d.status == Status::Pending)).count(),
+            delivered: self.delivered.load(Ordering::Relaxed), failed: self.failed.load(Ordering::Relaxed), dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub async fn get(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Receipt>, ApiError> {
+    s.deliveries.get(&tenant, &id).map(Json).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown delivery", Some(id)))
+}
@@ -0,0 +1,215 @@
+//! Dead-letter queue: syncs whose delta could not be merged or delivered, and failed transforms,
+//! kept per tenant with the original request, the error and where it happened, instead of
+//! vanishing with the error response.
+//!
+//! A sync is dead-lettered when it carried a delta (or envelope) and failed for a reason other
+//! than throttling, authorization or a conflict the device resolves itself (401, 403, 409, 429,
+//! 503); a transform on any failure except in a dry run. The same payload failing again bumps
+//! `occurrences` on its entry rather than adding another. Each tenant keeps at most
+//! `DLQ_CAPACITY` entries (default 1000, oldest evicted first) for `DLQ_TTL_SECS` (default 7 days).
+//!
+//! `GET /dlq?kind=&limit=` lists entries without payloads, newest first (all of them without
+//! `limit`); `GET /dlq/:id` includes the payload. `POST /dlq/:id/retry` runs the request again
+//! (a sync without the device's client certificate) and removes the entry on success, answering with the sync or transform
+//! response; on failure the entry records the new error and the error is returned.
+//! `DELETE /dlq/:id` and `DELETE /dlq?kind=` purge.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::rbac::{Operate, Read, Require};
+use crate::{api_err, ApiError, AppState, Tenant};
+use alice_gateway_types::{SyncRequest, TransformRequest};
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind { Sync, Transform }
+
+#[derive(Clone, Serialize)]
+pub struct Failure { status: u16, error: String, #[serde(skip_serializing_if = "Option::is_none")] code: Option<&'static str>, #[serde(skip_serializing_if = "Option::is_none")] details: Option<String> }
+
+impl Failure {
+    fn of(e: &ApiError) -> Self { Failure { status: e.status.as_u16(), error: e.body.error.clone(), code: e.body.code, details: e.body.details.clone() } }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Summary { id: String, kind: Kind, first_failed_at_ms: u64, last_failed_at_ms: u64, occurrences: u32, retries: u32, error: Failure, context: Value }
+
+#[derive(Clone, Serialize)]
+pub struct Entry { #[serde(flatten)] summary: Summary, payload: Value, #[serde(skip)] fingerprint: u64 }
+
+pub struct DlqCounts { pub entries: usize, pub captured: u64, pub recovered: u64 }
+
+pub struct Dlq {
+    capacity: usize,
+    ttl_ms: u64,
+    entries: Mutex<HashMap<String, VecDeque<Entry>>>,
+    /// Entries being retried; a second retry of one is refused.
+    retrying: Mutex<HashSet<String>>,
+    captured: AtomicU64,
+    recovered: AtomicU64,
+}
+
+/// Failures the caller is expected to resolve by waiting or fixing credentials; not dead-lettered.
+fn transient(e: &ApiError) -> bool {
+    matches!(e.status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::CONFLICT | StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE)
+}
+
+fn unknown(id: String) -> ApiError { api_err(StatusCode::NOT_FOUND, "Unknown dead letter", Some(id)) }
+
+impl Dlq {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        Dlq {
+            capacity: env("DLQ_CAPACITY", 1000).max(1) as usize, ttl_ms: env("DLQ_TTL_SECS", 7 * 24 * 3600).max(1) * 1000,
+            entries: Mutex::default(), retrying: Mutex::default(), captured: AtomicU64::new(0), recovered: AtomicU64::new(0),
+        }
+    }
+
+    fn expire(&self, queue: &mut VecDeque<Entry>, now: u64) {
+        queue.retain(|e| now.saturating_sub(e.summary.last_failed_at_ms) <= self.ttl_ms);
+    }
+
+    fn push(&self, tenant: &str, kind: Kind, payload: Value, context: Value, e: &ApiError) {
+        let mut h = DefaultHasher::new();
+        (kind, payload.to_string()).hash(&mut h);
+        let (fingerprint, now) = (h.finish(), now_ms());
+        let mut entries = self.entries.lock().unwrap();
+        let queue = entries.entry(tenant.to_string()).or_default();
+        self.expire(queue, now);
+        let entry = match queue.iter().position(|d| d.fingerprint == fingerprint) {
+            Some(i) => {
+                let mut d = queue.remove(i).expect("position is in range");
+                d.summary.occurrences += 1;
+                (d.summary.last_failed_at_ms, d.summary.error, d.summary.context) = (now, Failure::of(e), context);
+                d
+            }
+            None => Entry {
+                summary: Summary { id: uuid::Uuid::new_v4().to_string(), kind, first_failed_at_ms: now, last_failed_at_ms: now, occurrences: 1, retries: 0, error: Failure::of(e), context },
+                payload, fingerprint,
+            },
+        };
+        tracing::warn!(%tenant, id = %entry.summary.id, ?kind, status = e.status.as_u16(), "request dead-lettered");
+        queue.push_back(entry);
+        while queue.len() > self.capacity { queue.pop_front(); }
+        self.captured.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sync_failed(&self, s: &AppState, tenant: &str, req: &SyncRequest, e: &ApiError) {
+        if (req.sdf_delta.is_none() && req.envelope.is_none()) || transient(e) { return; }
+        let conn = s.connections.lock().unwrap().get(&req.connection_id).filter(|c| c.tenant == tenant).cloned();
+        let context = json!({
+            "connection_id": req.connection_id, "device_id": conn.as_ref().map(|c| &c.device_id), "protocol": conn.as_ref().map(|c| &c.protocol), "sequence": req.sequence,
+        });
+        self.push(tenant, Kind::Sync, serde_json::to_value(req).unwrap_or_default(), context, e);
+    }
+
+    pub fn transform_failed(&self, tenant: &str, req: &TransformRequest, e: &ApiError) {
+        if transient(e) { return; }
+        self.push(tenant, Kind::Transform, serde_json::to_value(req).unwrap_or_default(), json!({ "pipeline": req.chain() }), e);
+    }
+
+    fn get(&self, tenant: &str, id: &str) -> Option<Entry> {
+        let mut entries = self.entries.lock().unwrap();
+        let queue = entries.get_mut(tenant)?;
+        self.expire(queue, now_ms());
+        queue.iter().find(|e| e.summary.id == id).cloned()
+    }
+
+    /// Drops all of the tenant's entries; returns how many went.
+    pub fn remove_tenant(&self, tenant: &str) -> usize { self.entries.lock().unwrap().remove(tenant).map_or(0, |q| q.len()) }
+
+    /// Removes the tenant's entries matching `remove`; returns how many went.
+    fn purge(&self, tenant: &str, remove: impl Fn(&Entry) -> bool) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(queue) = entries.get_mut(tenant) else { return 0 };
+        let before = queue.len();
+        queue.retain(|e| !remove(e));
+        before - queue.len()
+    }
+
+    fn retry_failed(&self, tenant: &str, id: &str, e: &ApiError) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(d) = entries.get_mut(tenant).and_then(|q| q.iter_mut().find(|d| d.summary.id == id)) {
+            d.summary.retries += 1;
+            (d.summary.last_failed_at_ms, d.summary.error) = (now_ms(), Failure::of(e));
+        }
+    }
+
+    pub fn counts(&self) -> DlqCounts {
+        let entries = self.entries.lock().unwrap().values().map(VecDeque::len).sum();
+        DlqCounts { entries, captured: self.captured.load(Ordering::Relaxed), recovered: self.recovered.load(Ordering::Relaxed) }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery { kind: Option<Kind>, limit: Option<usize> }
+
+#[derive(Deserialize)]
+pub struct PurgeQuery { kind: Option<Kind> }
+
+#[derive(Serialize)]
+pub struct Purged { purged: usize }
+
+#[derive(Serialize)]
+pub struct Retried { id: String, kind: Kind, result: Value }
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Query(q): Query<ListQuery>) -> Json<Vec<Summary>> {
+    let mut entries = s.dlq.entries.lock().unwrap();
+    let Some(queue) = entries.get_mut(&tenant) else { return Json(Vec::new()) };
+    s.dlq.expire(queue, now_ms());
+    let limit = q.limit.map_or(usize::MAX, |l| l.max(1));
+    Json(queue.iter().rev().filter(|e| q.kind.is_none_or(|k| k == e.summary.kind)).take(limit).map(|e| e.summary.clone()).collect())
+}
+
+pub async fn get(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Entry>, ApiError> {
+    s.dlq.get(&tenant, &id).map(Json).ok_or_else(|| unknown(id))
+}
+
+pub async fn retry(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Retried>, ApiError> {
+    let entry = s.dlq.get(&tenant, &id).ok_or_else(|| unknown(id.clone()))?;
+    if !s.dlq.retrying.lock().unwrap().insert(id.clone()) { return Err(api_err(StatusCode::CONFLICT, "Retry already in progress", Some(id)).code("retrying")); }
+    let (kind, wire_bytes) = (entry.summary.kind, entry.payload.to_string().len());
+    let invalid = |e: serde_json::Error| api_err(StatusCode::UNPROCESSABLE_ENTITY, "Stored payload is unreadable", Some(e.to_string()));
+    let result = match kind {
+        Kind::Sync => match serde_json::from_value::<SyncRequest>(entry.payload).map_err(invalid) {
+            Ok(req) => s.attempt_sync(&tenant, None, &req, wire_bytes).await.map(|r| serde_json::to_value(r).unwrap_or_default()),
+            Err(e) => Err(e),
+        },
+        Kind::Transform => match serde_json::from_value::<TransformRequest>(entry.payload).map_err(invalid) {
+            Ok(req) => crate::run_transform(&s, &tenant, &req, false, wire_bytes).await.map(|r| serde_json::to_value(r).unwrap_or_default()),
+            Err(e) => Err(e),
+        },
+    };
+    s.dlq.retrying.lock().unwrap().remove(&id);
+    s.audit.record(&actor, "dlq.retry", Some(&tenant), None, json!({ "id": id, "kind": kind, "succeeded": result.is_ok() }));
+    match result {
+        Ok(result) => {
+            s.dlq.purge(&tenant, |e| e.summary.id == id);
+            s.dlq.recovered.fetch_add(1, Ordering::Relaxed);
+            Ok(Json(Retried { id, kind, result }))
+        }
+        Err(e) => {
+            s.dlq.retry_failed(&tenant, &id, &e);
+            Err(e)
+        }
+    }
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    if s.dlq.purge(&tenant, |e| e.summary.id == id) == 0 { return Err(unknown(id)); }
+    s.audit.record(&actor, "dlq.purge", Some(&tenant), None, json!({ "id": id }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn purge(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Query(q): Query<PurgeQuery>) -> Json<Purged> {
+    let purged = s.dlq.purge(&tenant, |e| q.kind.is_none_or(|k| k == e.summary.kind));
+    s.audit.record(&actor, "dlq.purge", Some(&tenant), None, json!({ "kind": q.kind, "purged": purged }));
+    Json(Purged { purged })
+}
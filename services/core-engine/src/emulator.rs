@@ -0,0 +1,236 @@
+//! Virtual devices for integration suites (feature `emulator`), so tests need no hardware.
+//! `POST /api/v1/emulator/devices` connects a device through the real connect pipeline and
+//! attaches it to its outbox like a device socket: queued and live deltas land in its inbox
+//! (`GET .../:device_id/inbox`, `?drain=true` empties it). `POST .../:device_id/heartbeat` is
+//! answered by the device, and `POST .../:device_id/sync` has it sync a delta through the real
+//! sync pipeline.
+//!
+//! `PUT .../:device_id/failures` injects faults into heartbeats and syncs: each one draws from
+//! a PRNG seeded with `seed`, so a given configuration fails the same calls on every run. A
+//! `timeout` stalls for `timeout_ms` and answers 504 (`device_timeout`); the sync never reaches
+//! the gateway. A `malformed` heartbeat answers with a truncated JSON body, and a malformed sync
+//! sends its delta garbled into a string and returns the gateway's answer as is: a rejection
+//! wherever a registered schema or the protocol's required fields expect an object. `offline`
+//! detaches the device so deltas queue for it until it comes back.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::outbox::Outbound;
+use crate::rbac::{Operate, Read, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use alice_gateway_types::{ConnectRequest, SyncRequest, SyncResponse};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const INBOX_DEPTH: usize = 1024;
+const MAX_TIMEOUT_MS: u64 = 60_000;
+
+fn default_timeout() -> u64 { 5000 }
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Failures {
+    /// Share of heartbeats and syncs, 0 to 1, that time out.
+    #[serde(default)] timeout_rate: f64,
+    /// Share of heartbeats and syncs, 0 to 1, that come out malformed.
+    #[serde(default)] malformed_rate: f64,
+    #[serde(default = "default_timeout")] timeout_ms: u64,
+    #[serde(default)] seed: u64,
+    #[serde(default)] offline: bool,
+}
+
+impl Default for Failures {
+    fn default() -> Self { Failures { timeout_rate: 0.0, malformed_rate: 0.0, timeout_ms: default_timeout(), seed: 0, offline: false } }
+}
+
+impl Validate for Failures {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check((0.0..=1.0).contains(&self.timeout_rate), "timeout_rate", "must be between 0 and 1");
+        v.check((0.0..=1.0).contains(&self.malformed_rate), "malformed_rate", "must be between 0 and 1");
+        v.check(self.timeout_rate + self.malformed_rate <= 1.0, "malformed_rate", "timeout_rate and malformed_rate must add up to at most 1");
+        v.check(self.timeout_ms <= MAX_TIMEOUT_MS, "timeout_ms", format!("must be at most {MAX_TIMEOUT_MS}"));
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateDevice { device_id: String, protocol: Option<String>, region: Option<String>, #[serde(default)] failures: Failures }
+
+impl Validate for CreateDevice {
+    fn validate(&self, s: &AppState, v: &mut Violations) {
+        v.id("device_id", &self.device_id);
+        if let Some(p) = &self.protocol { v.protocol(s, "protocol", p); }
+        self.failures.validate(s, v);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeviceSync { delta: Value }
+
+#[derive(Deserialize)]
+pub struct InboxQuery { #[serde(default)] drain: bool }
+
+#[derive(Clone, Copy, PartialEq)]
+enum Fault { Timeout, Malformed }
+
+#[derive(Clone, Default, Serialize)]
+pub struct Counters { heartbeats: u64, syncs: u64, timeouts: u64, malformed: u64, received: u64, inbox_dropped: u64 }
+
+struct Device { connection_id: String, created_ms: u64, failures: Failures, rng: u64, counters: Counters, inbox: VecDeque<Outbound>, pump: Option<JoinHandle<()>> }
+
+#[derive(Serialize)]
+pub struct DeviceInfo { device_id: String, connection_id: String, online: bool, created_ms: u64, failures: Failures, inbox_depth: usize, #[serde(flatten)] counters: Counters }
+
+type DeviceKey = (String, String);
+
+#[derive(Default)]
+pub struct Emulator { devices: Mutex<HashMap<DeviceKey, Device>> }
+
+impl Device {
+    /// Decides the fate of the next heartbeat or sync.
+    fn draw(&mut self) -> Option<Fault> {
+        // splitmix64: tiny, and the same sequence for the same seed everywhere.
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        let r = (z ^ (z >> 31)) as f64 / u64::MAX as f64;
+        let f = &self.failures;
+        let fault = if r < f.timeout_rate { Some(Fault::Timeout) } else if r < f.timeout_rate + f.malformed_rate { Some(Fault::Malformed) } else { None };
+        match fault { Some(Fault::Timeout) => self.counters.timeouts += 1, Some(Fault::Malformed) => self.counters.malformed += 1, None => {} }
+        fault
+    }
+
+    fn info(&self, device_id: &str) -> DeviceInfo {
+        DeviceInfo {
+            device_id: device_id.into(), connection_id: self.connection_id.clone(), online: self.pump.is_some(), created_ms: self.created_ms,
+            failures: self.failures.clone(), inbox_depth: self.inbox.len(), counters: self.counters.clone(),
+        }
+    }
+}
+
+impl Emulator {
+    /// Hands deltas received by the device to its inbox, oldest dropped when full.
+    fn deliver(&self, key: &DeviceKey, m: Outbound) {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(d) = devices.get_mut(key) else { return };
+        if d.inbox.len() >= INBOX_DEPTH { d.inbox.pop_front(); d.counters.inbox_dropped += 1; }
+        d.inbox.push_back(m);
+        d.counters.received += 1;
+    }
+
+    fn with<T>(&self, tenant: &str, device_id: &str, f: impl FnOnce(&mut Device) -> T) -> Result<T, ApiError> {
+        let mut devices = self.devices.lock().unwrap();
+        let d = devices.get_mut(&(tenant.to_string(), device_id.to_string())).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown emulated device", Some(device_id.into())))?;
+        Ok(f(d))
+    }
+}
+
+/// Attaches the device to its outbox; everything queued while it was away arrives first.
+fn attach(s: &Arc<AppState>, key: DeviceKey, connection_id: &str) -> JoinHandle<()> {
+    let (tx, mut rx) = mpsc::channel(INBOX_DEPTH);
+    let backlog = s.outbox.attach(connection_id, key.clone(), tx);
+    let s = s.clone();
+    tokio::spawn(async move {
+        for m in backlog { s.emulator.deliver(&key, m); }
+        while let Some(m) = rx.recv().await { s.emulator.deliver(&key, m); }
+    })
+}
+
+fn detach(s: &AppState, d: &mut Device) {
+    if let Some(pump) = d.pump.take() { pump.abort(); }
+    s.outbox.detach(&d.connection_id);
+}
+
+async fn stall(timeout_ms: u64, what: &str) -> ApiError {
+    tokio::time::sleep(Duration::from_millis(timeout_ms)).await;
+    api_err(StatusCode::GATEWAY_TIMEOUT, "Device did not respond", Some(format!("injected {what} timeout after {timeout_ms}ms"))).code("device_timeout")
+}
+
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Valid(req): Valid<CreateDevice>) -> Result<(StatusCode, Json<DeviceInfo>), ApiError> {
+    let key = (tenant.clone(), req.device_id.clone());
+    if s.emulator.devices.lock().unwrap().contains_key(&key) {
+        return Err(api_err(StatusCode::CONFLICT, "Emulated device exists", Some(req.device_id)).code("exists"));
+    }
+    let connect = ConnectRequest { device_id: req.device_id.clone(), protocol: req.protocol, region: req.region, ..Default::default() };
+    let conn = s.open_connection(&tenant, &actor, None, None, connect).await?;
+    let pump = (!req.failures.offline).then(|| attach(&s, key.clone(), &conn.connection_id));
+    let device = Device { connection_id: conn.connection_id, created_ms: now_ms(), rng: req.failures.seed, failures: req.failures, counters: Counters::default(), inbox: VecDeque::new(), pump };
+    let info = device.info(&req.device_id);
+    s.emulator.devices.lock().unwrap().insert(key, device);
+    s.audit.record(&actor, "emulator.create", Some(&tenant), Some(&req.device_id), json!({ "connection_id": info.connection_id }));
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<DeviceInfo>> {
+    let mut out: Vec<DeviceInfo> = s.emulator.devices.lock().unwrap().iter().filter(|((t, _), _)| *t == tenant).map(|((_, id), d)| d.info(id)).collect();
+    out.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    Json(out)
+}
+
+pub async fn get_device(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(device_id): Path<String>) -> Result<Json<DeviceInfo>, ApiError> {
+    s.emulator.with(&tenant, &device_id, |d| Json(d.info(&device_id)))
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(device_id): Path<String>) -> Result<StatusCode, ApiError> {
+    let mut d = s.emulator.devices.lock().unwrap().remove(&(tenant.clone(), device_id.clone())).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown emulated device", Some(device_id.clone())))?;
+    detach(&s, &mut d);
+    s.drop_connection(&d.connection_id);
+    s.audit.record(&actor, "emulator.delete", Some(&tenant), Some(&device_id), json!({ "connection_id": d.connection_id }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replaces the device's failure settings and restarts its fault sequence from `seed`.
+pub async fn set_failures(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(device_id): Path<String>, Valid(failures): Valid<Failures>) -> Result<Json<DeviceInfo>, ApiError> {
+    let key = (tenant.clone(), device_id.clone());
+    let info = s.emulator.with(&tenant, &device_id, |d| {
+        if failures.offline { detach(&s, d); } else if d.pump.is_none() { d.pump = Some(attach(&s, key, &d.connection_id)); }
+        d.rng = failures.seed;
+        d.failures = failures;
+        d.info(&device_id)
+    })?;
+    s.audit.record(&actor, "emulator.failures", Some(&tenant), Some(&device_id), serde_json::to_value(&info.failures).unwrap_or_default());
+    Ok(Json(info))
+}
+
+pub async fn heartbeat(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(device_id): Path<String>) -> Result<Response, ApiError> {
+    let (fault, timeout_ms, reply) = s.emulator.with(&tenant, &device_id, |d| {
+        d.counters.heartbeats += 1;
+        let reply = json!({ "device_id": device_id, "connection_id": d.connection_id, "seq": d.counters.heartbeats, "uptime_ms": now_ms().saturating_sub(d.created_ms), "inbox_depth": d.inbox.len() });
+        (d.draw(), d.failures.timeout_ms, reply)
+    })?;
+    match fault {
+        Some(Fault::Timeout) => Err(stall(timeout_ms, "heartbeat").await),
+        Some(Fault::Malformed) => {
+            let body = reply.to_string();
+            Ok(([(header::CONTENT_TYPE, "application/json")], body[..body.len() / 2].to_string()).into_response())
+        }
+        None => Ok(Json(reply).into_response()),
+    }
+}
+
+pub async fn sync(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(device_id): Path<String>, Json(req): Json<DeviceSync>) -> Result<Json<SyncResponse>, ApiError> {
+    let (fault, timeout_ms, connection_id) = s.emulator.with(&tenant, &device_id, |d| { d.counters.syncs += 1; (d.draw(), d.failures.timeout_ms, d.connection_id.clone()) })?;
+    let delta = match fault {
+        Some(Fault::Timeout) => return Err(stall(timeout_ms, "sync").await),
+        Some(Fault::Malformed) => Value::String(req.delta.to_string().chars().rev().collect()),
+        None => req.delta,
+    };
+    let wire_bytes = delta.to_string().len();
+    let req = SyncRequest { connection_id, sdf_delta: Some(delta), ..Default::default() };
+    s.process_sync(&tenant, None, req, wire_bytes).await.map(Json)
+}
+
+/// Deltas the device has received, oldest first.
+pub async fn inbox(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(device_id): Path<String>, Query(q): Query<InboxQuery>) -> Result<Json<Vec<Outbound>>, ApiError> {
+    s.emulator.with(&tenant, &device_id, |d| Json(if q.drain { d.inbox.drain(..).collect() } else { d.inbox.iter().cloned().collect() }))
+}
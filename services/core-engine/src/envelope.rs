@@ -0,0 +1,161 @@
+//! End-to-end encrypted payloads. A sync may carry an `envelope` (ciphertext, nonce and
+//! integrity tag in base64, plus the `key_id` and `alg` of a key the gateway never holds)
+//! instead of a plaintext `sdf_delta`. The gateway relays it to the device's home region, runs
+//! size-based routing rules on it, publishes it to observers and event subscribers, and keeps
+//! the last `ENVELOPE_RETENTION` (default 1000) per connection for
+//! `GET /connections/:id/envelopes`, all without decrypting. It still tracks what it can see:
+//! ciphertext size, sequence numbers (replay protection applies as usual), the tag and a
+//! SHA-256 digest of the ciphertext so consumers can check it arrived unaltered. Shadows and
+//! snapshots only hold plaintext and are left untouched.
+//!
+//! Tenants listed in `ENCRYPTION_REQUIRED_TENANTS`, or switched with
+//! `PUT /admin/tenants/:tenant/encryption`, have plaintext deltas rejected with 422
+//! (`encryption_required`). The setting is configuration, so clearing a tenant's state keeps it.
+
+use crate::audit::Actor;
+use crate::deliveries::Status;
+use crate::events::now_ms;
+use crate::rbac::{Admin, Read, Require};
+use crate::validate::Violations;
+use crate::{api_err, routing, ApiError, AppState, SyncRequest, Tenant};
+use alice_gateway_types::Envelope;
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const MAX_ALG_LEN: usize = 32;
+
+#[derive(Clone, Serialize)]
+pub struct StoredEnvelope {
+    #[serde(skip_serializing_if = "Option::is_none")] sequence: Option<u64>,
+    received_at_ms: u64,
+    /// Decoded ciphertext length.
+    bytes: usize,
+    /// Hex SHA-256 of the base64 `ciphertext` as received.
+    digest: String,
+    #[serde(flatten)] envelope: Envelope,
+}
+
+#[derive(Deserialize)]
+pub struct EnvelopeQuery { after: Option<u64>, limit: Option<usize> }
+
+#[derive(Deserialize, Serialize)]
+pub struct EncryptionSetting { required: bool }
+
+#[derive(Default, Serialize)]
+pub struct EncryptionCounts { pub envelopes: u64, pub plaintext_rejected: u64 }
+
+/// A connection's retained envelopes.
+struct EnvelopeLog { tenant: String, envelopes: VecDeque<StoredEnvelope> }
+
+pub struct Encryption {
+    retention: usize,
+    required: Mutex<HashSet<String>>,
+    logs: Mutex<HashMap<String, EnvelopeLog>>,
+    envelopes: AtomicU64, rejected: AtomicU64,
+}
+
+impl Encryption {
+    pub fn from_env() -> Self {
+        let required = std::env::var("ENCRYPTION_REQUIRED_TENANTS").unwrap_or_default().split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect();
+        let retention = std::env::var("ENVELOPE_RETENTION").ok().and_then(|v| v.parse().ok()).unwrap_or(1000usize);
+        Encryption { retention, required: Mutex::new(required), logs: Mutex::new(HashMap::new()), envelopes: AtomicU64::new(0), rejected: AtomicU64::new(0) }
+    }
+
+    pub fn required(&self, tenant: &str) -> bool { self.required.lock().unwrap().contains(tenant) }
+
+    pub fn counts(&self) -> EncryptionCounts {
+        EncryptionCounts { envelopes: self.envelopes.load(Ordering::Relaxed), plaintext_rejected: self.rejected.load(Ordering::Relaxed) }
+    }
+
+    fn store(&self, tenant: &str, connection_id: &str, sequence: Option<u64>, envelope: &Envelope) {
+        if self.retention == 0 { return; }
+        let stored = StoredEnvelope { sequence, received_at_ms: now_ms(), bytes: decoded_len(&envelope.ciphertext), digest: hex::encode(Sha256::digest(envelope.ciphertext.as_bytes())), envelope: envelope.clone() };
+        let mut logs = self.logs.lock().unwrap();
+        let log = &mut logs.entry(connection_id.into()).or_insert_with(|| EnvelopeLog { tenant: tenant.into(), envelopes: VecDeque::new() }).envelopes;
+        if log.len() == self.retention { log.pop_front(); }
+        log.push_back(stored);
+    }
+
+    pub fn forget(&self, connection_id: &str) { self.logs.lock().unwrap().remove(connection_id); }
+
+    /// Drops every envelope retained for the tenant's connections; returns how many went.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut logs = self.logs.lock().unwrap();
+        let n: usize = logs.values().filter(|l| l.tenant == tenant).map(|l| l.envelopes.len()).sum();
+        logs.retain(|_, l| l.tenant != tenant);
+        n
+    }
+}
+
+fn is_base64(v: &str) -> bool {
+    let body = v.trim_end_matches('=');
+    v.len() - body.len() <= 2 && body.chars().all(|c| c.is_ascii_alphanumeric() || "+/-_".contains(c))
+}
+
+fn decoded_len(v: &str) -> usize { v.trim_end_matches('=').len() * 3 / 4 }
+
+/// Field checks for an envelope at `prefix` (e.g. `envelope`).
+pub fn validate(e: &Envelope, prefix: &str, v: &mut Violations) {
+    v.id(format!("{prefix}.key_id"), &e.key_id);
+    v.check(!e.alg.is_empty() && e.alg.len() <= MAX_ALG_LEN, format!("{prefix}.alg"), format!("must be 1-{MAX_ALG_LEN} characters"));
+    v.check(!e.ciphertext.is_empty() && is_base64(&e.ciphertext), format!("{prefix}.ciphertext"), "must be non-empty base64");
+    v.check(!e.tag.is_empty() && is_base64(&e.tag), format!("{prefix}.tag"), "must be non-empty base64");
+    if let Some(n) = &e.nonce { v.check(is_base64(n), format!("{prefix}.nonce"), "must be base64"); }
+}
+
+/// Rejects plaintext deltas for tenants that require encryption.
+pub fn check(s: &AppState, tenant: &str, req: &SyncRequest) -> Result<(), ApiError> {
+    if req.sdf_delta.is_none() || !s.encryption.required(tenant) { return Ok(()); }
+    s.encryption.rejected.fetch_add(1, Ordering::Relaxed);
+    Err(api_err(StatusCode::UNPROCESSABLE_ENTITY, "Tenant requires encrypted payloads", Some("send the delta as an envelope".into())).code("encryption_required"))
+}
+
+/// Where an envelope came from: a plain sync, tracked under its delivery id, or a transaction.
+#[derive(Clone, Copy)]
+pub enum Origin<'a> { Sync { delivery: &'a str }, Transaction(&'a str) }
+
+/// Routes, stores and publishes an accepted envelope; returns false when a routing rule dropped it.
+pub fn commit(s: &AppState, tenant: &str, connection_id: &str, device_id: &str, sequence: Option<u64>, envelope: &Envelope, origin: Origin) -> bool {
+    if !routing::apply_envelope(s, tenant, connection_id, device_id, envelope) { return false; }
+    s.encryption.envelopes.fetch_add(1, Ordering::Relaxed);
+    s.encryption.store(tenant, connection_id, sequence, envelope);
+    let wrapped = json!({ "envelope": envelope });
+    let observers = s.coap.publish(tenant, device_id, &wrapped);
+    let delivery = match origin {
+        Origin::Sync { delivery } => Some(delivery),
+        Origin::Transaction(_) => None,
+    };
+    if let Some(d) = delivery {
+        s.deliveries.set(d, "merge", Status::Delivered, None);
+        s.deliveries.notified(d, observers);
+    }
+    let mut event = json!({ "connection_id": connection_id, "device_id": device_id, "sequence": sequence, "envelope": envelope });
+    if let Origin::Transaction(id) = origin { event["transaction_id"] = json!(id); }
+    s.emit_tracked("delta", tenant, event, delivery);
+    true
+}
+
+/// `GET /connections/:id/envelopes[?after=<sequence>&limit=]`: retained envelopes, oldest first.
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>, Query(q): Query<EnvelopeQuery>) -> Result<Json<Vec<StoredEnvelope>>, ApiError> {
+    if s.connections.lock().unwrap().get(&id).is_none_or(|c| c.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(id))); }
+    let logs = s.encryption.logs.lock().unwrap();
+    let Some(log) = logs.get(&id) else { return Ok(Json(Vec::new())) };
+    let after = |e: &&StoredEnvelope| q.after.is_none_or(|a| e.sequence.is_some_and(|seq| seq > a));
+    Ok(Json(log.envelopes.iter().filter(after).take(q.limit.unwrap_or(usize::MAX)).cloned().collect()))
+}
+
+/// `PUT /admin/tenants/:tenant/encryption` with `{"required": true}`.
+pub async fn set_required(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(tenant): Path<String>, Json(req): Json<EncryptionSetting>) -> Json<EncryptionSetting> {
+    {
+        let mut required = s.encryption.required.lock().unwrap();
+        if req.required { required.insert(tenant.clone()); } else { required.remove(&tenant); }
+    }
+    tracing::info!(%tenant, required = req.required, "admin set tenant encryption requirement");
+    s.audit.record(&actor, "admin.tenant.encryption", Some(&tenant), None, json!({ "required": req.required }));
+    Json(req)
+}
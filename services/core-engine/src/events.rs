@@ -0,0 +1,27 @@
+//! Gateway lifecycle events, published through the pub/sub hub to any subscriber
+//! (webhook dispatcher, streaming endpoints, sinks).
+
+use crate::AppState;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Serialize)]
+pub struct Event {
+    pub id: String, pub kind: String, pub tenant: String, pub timestamp_ms: u64, pub topics: Vec<String>, pub data: serde_json::Value,
+    /// The sync delivery this event carries, whose destinations the hub and sinks settle.
+    #[serde(skip)] pub delivery: Option<String>,
+}
+
+pub fn now_ms() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0) }
+
+impl AppState {
+    pub fn emit(&self, kind: &str, tenant: &str, data: serde_json::Value) { self.emit_tracked(kind, tenant, data, None) }
+
+    /// `emit`, tracking each subscriber it reaches as a destination of `delivery`.
+    pub fn emit_tracked(&self, kind: &str, tenant: &str, data: serde_json::Value, delivery: Option<&str>) {
+        if self.chaos.drops(kind, tenant, &data) { return; }
+        // No subscribers is not an error; the event is simply dropped.
+        let topics = crate::hub::topics(self, kind, tenant, &data);
+        self.hub.publish(Event { id: uuid::Uuid::new_v4().to_string(), kind: kind.into(), tenant: tenant.into(), timestamp_ms: now_ms(), topics, data, delivery: delivery.map(Into::into) });
+    }
+}
@@ -0,0 +1,129 @@
+//! Geo-fencing policies on device connections. A tenant policy names the regions
+//! (`eu-*` style globs) and client IP ranges (CIDR) its devices may connect from, either
+//! for every device or for one device group. A connect must satisfy every policy that
+//! applies to the device; otherwise it is refused with 403 (`geofence`) and audited as
+//! `device.connect.denied`. The client IP comes from `X-Gateway-Client-IP`, set by the
+//! api-gateway (or the QUIC peer address); when a policy lists IP ranges and no IP is
+//! known, the connect is refused.
+
+use crate::events::now_ms;
+use crate::rbac::{Configure, Read, Require};
+use crate::subscriptions::glob;
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, State},
+    http::{request::Parts, StatusCode},
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Serialize)]
+pub struct Policy {
+    id: String, #[serde(skip)] tenant: String, name: String,
+    /// Applies to this group's devices only; every device of the tenant when absent.
+    #[serde(skip_serializing_if = "Option::is_none")] group_id: Option<String>,
+    allowed_regions: Vec<String>, allowed_cidrs: Vec<String>, #[serde(skip)] cidrs: Vec<Cidr>, created_at_ms: u64,
+}
+
+#[derive(Deserialize)]
+pub struct PolicyRequest { name: String, group_id: Option<String>, #[serde(default)] allowed_regions: Vec<String>, #[serde(default)] allowed_cidrs: Vec<String> }
+
+#[derive(Clone, Copy)]
+struct Cidr { net: IpAddr, len: u8 }
+
+#[derive(Default)]
+pub struct Policies { policies: Mutex<HashMap<String, Policy>> }
+
+/// The device's address as forwarded by the api-gateway, when known.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Infallible> {
+        Ok(ClientIp(parts.headers.get("x-gateway-client-ip").and_then(|h| h.to_str().ok()).and_then(|ip| ip.trim().parse().ok())))
+    }
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Option<Cidr> {
+        let (addr, len) = match s.split_once('/') { Some((a, l)) => (a, Some(l.parse::<u8>().ok()?)), None => (s, None) };
+        let net: IpAddr = addr.parse().ok()?;
+        let max = if net.is_ipv4() { 32 } else { 128 };
+        let len = len.unwrap_or(max);
+        (len <= max).then_some(Cidr { net, len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let bits = |ip: IpAddr| match ip { IpAddr::V4(v) => (u32::from(v) as u128) << 96, IpAddr::V6(v) => u128::from(v) };
+        // An IPv4-mapped IPv6 address is checked as the IPv4 address it carries.
+        let ip = match ip { IpAddr::V6(v) => v.to_ipv4_mapped().map_or(ip, IpAddr::V4), v4 => v4 };
+        if ip.is_ipv4() != self.net.is_ipv4() { return false; }
+        let mask = if self.len == 0 { 0 } else { u128::MAX << (128 - self.len as u32) };
+        bits(ip) & mask == bits(self.net) & mask
+    }
+}
+
+impl Validate for PolicyRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check(!self.name.trim().is_empty(), "name", "must not be empty");
+        v.check(!self.allowed_regions.is_empty() || !self.allowed_cidrs.is_empty(), "allowed_regions", "a policy needs allowed regions, IP ranges or both");
+        for (i, c) in self.allowed_cidrs.iter().enumerate() { v.check(Cidr::parse(c).is_some(), format!("allowed_cidrs[{i}]"), "must be an IP address or CIDR range"); }
+    }
+}
+
+impl Policies {
+    /// Checks a connect against the tenant's policies; the error names the first policy violated.
+    pub fn check(&self, s: &AppState, tenant: &str, device_id: &str, region: &str, ip: Option<IpAddr>) -> Result<(), (String, String)> {
+        let policies: Vec<Policy> = self.policies.lock().unwrap().values().filter(|p| p.tenant == tenant).cloned().collect();
+        for p in policies {
+            if p.group_id.as_ref().is_some_and(|g| !s.groups.contains(tenant, g, device_id)) { continue; }
+            if !p.allowed_regions.is_empty() && !p.allowed_regions.iter().any(|r| glob(r, region)) {
+                return Err((p.id, format!("region {region} is not allowed by policy {}", p.name)));
+            }
+            if !p.cidrs.is_empty() {
+                match ip {
+                    None => return Err((p.id, format!("policy {} restricts client IPs and the client IP is unknown", p.name))),
+                    Some(ip) if !p.cidrs.iter().any(|c| c.contains(ip)) => return Err((p.id, format!("client IP {ip} is not allowed by policy {}", p.name))),
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn references_group(&self, group_id: &str) -> bool { self.policies.lock().unwrap().values().any(|p| p.group_id.as_deref() == Some(group_id)) }
+
+    /// Returns how many policies were removed.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut policies = self.policies.lock().unwrap();
+        let n = policies.len();
+        policies.retain(|_, p| p.tenant != tenant);
+        n - policies.len()
+    }
+}
+
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Valid(req): Valid<PolicyRequest>) -> Result<(StatusCode, Json<Policy>), ApiError> {
+    if let Some(g) = req.group_id.as_ref().filter(|g| !s.groups.exists(&tenant, g)) { return Err(api_err(StatusCode::BAD_REQUEST, "Unknown group", Some(g.clone()))); }
+    let cidrs = req.allowed_cidrs.iter().filter_map(|c| Cidr::parse(c)).collect();
+    let policy = Policy { id: uuid::Uuid::new_v4().to_string(), tenant, name: req.name, group_id: req.group_id, allowed_regions: req.allowed_regions, allowed_cidrs: req.allowed_cidrs, cidrs, created_at_ms: now_ms() };
+    s.geofence.policies.lock().unwrap().insert(policy.id.clone(), policy.clone());
+    Ok((StatusCode::CREATED, Json(policy)))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<Policy>> {
+    Json(s.geofence.policies.lock().unwrap().values().filter(|p| p.tenant == tenant).cloned().collect())
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let mut policies = s.geofence.policies.lock().unwrap();
+    if policies.get(&id).is_none_or(|p| p.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown policy", Some(id))); }
+    policies.remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
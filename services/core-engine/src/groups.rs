@@ -0,0 +1,191 @@
+//! Device groups for fleet-wide operations. A group's devices are its explicit `members` plus,
+//! when `match_tags` is set, every device carrying all of those tags (`PUT /devices/:id/tags`),
+//! so tagging a new device is enough to enrol it.
+//!
+//! `POST /groups/:id/actions` runs `connect-check`, `disconnect` or `sync` (a pull request
+//! through the outbox, like scheduled syncs) against every device as a background job (see
+//! `jobs`); the job result lists per-device outcomes, including after a cancellation.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::jobs::{Job, JobCtx, Jobs};
+use crate::pressure::Priority;
+use crate::rbac::{Configure, Operate, Read, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+const ACTIONS: &[&str] = &["connect-check", "disconnect", "sync"];
+
+type DeviceKey = (String, String);
+
+#[derive(Clone, Serialize)]
+pub struct Group { id: String, #[serde(skip)] tenant: String, name: String, members: BTreeSet<String>, match_tags: BTreeSet<String>, created_at_ms: u64 }
+
+#[derive(Serialize)]
+pub struct GroupView { #[serde(flatten)] group: Group, devices: Vec<String> }
+
+#[derive(Clone, Serialize)]
+pub struct DeviceResult { device_id: String, ok: bool, detail: Value }
+
+#[derive(Default)]
+pub struct Groups { groups: Mutex<HashMap<String, Group>>, tags: Mutex<HashMap<DeviceKey, BTreeSet<String>>> }
+
+#[derive(Deserialize)]
+pub struct GroupRequest { name: String, #[serde(default)] members: BTreeSet<String>, #[serde(default)] match_tags: BTreeSet<String> }
+
+#[derive(Deserialize)]
+pub struct MemberUpdate { #[serde(default)] add: Vec<String>, #[serde(default)] remove: Vec<String> }
+
+#[derive(Deserialize)]
+pub struct TagsRequest { tags: BTreeSet<String> }
+
+#[derive(Deserialize)]
+pub struct ActionRequest { action: String }
+
+impl Validate for GroupRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check(!self.name.trim().is_empty(), "name", "must not be empty");
+        for m in &self.members { v.id("members", m); }
+        for t in &self.match_tags { v.id("match_tags", t); }
+    }
+}
+
+impl Validate for MemberUpdate {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        for m in &self.add { v.id("add", m); }
+    }
+}
+
+impl Validate for TagsRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        for t in &self.tags { v.id("tags", t); }
+    }
+}
+
+impl Validate for ActionRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) { v.one_of("action", &self.action, ACTIONS); }
+}
+
+impl Groups {
+    fn resolve(&self, g: &Group) -> Vec<String> {
+        let mut devices = g.members.clone();
+        if !g.match_tags.is_empty() {
+            devices.extend(self.tags.lock().unwrap().iter().filter(|((t, _), tags)| *t == g.tenant && g.match_tags.is_subset(tags)).map(|((_, d), _)| d.clone()));
+        }
+        devices.into_iter().collect()
+    }
+
+    fn lookup(&self, tenant: &str, id: &str) -> Result<Group, ApiError> {
+        self.groups.lock().unwrap().get(id).filter(|g| g.tenant == tenant).cloned().ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown group", Some(id.into())))
+    }
+
+    pub fn exists(&self, tenant: &str, id: &str) -> bool { self.groups.lock().unwrap().get(id).is_some_and(|g| g.tenant == tenant) }
+
+    /// Whether the device is in the group, as a member or through its tags.
+    pub fn contains(&self, tenant: &str, id: &str, device_id: &str) -> bool {
+        let Some(g) = self.groups.lock().unwrap().get(id).filter(|g| g.tenant == tenant).cloned() else { return false };
+        g.members.contains(device_id) || (!g.match_tags.is_empty() && self.tags.lock().unwrap().get(&(tenant.to_string(), device_id.to_string())).is_some_and(|t| g.match_tags.is_subset(t)))
+    }
+
+    /// Returns how many groups were removed.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        self.tags.lock().unwrap().retain(|(t, _), _| t != tenant);
+        let mut groups = self.groups.lock().unwrap();
+        let n = groups.len();
+        groups.retain(|_, g| g.tenant != tenant);
+        n - groups.len()
+    }
+}
+
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Valid(req): Valid<GroupRequest>) -> (StatusCode, Json<GroupView>) {
+    let group = Group { id: uuid::Uuid::new_v4().to_string(), tenant, name: req.name, members: req.members, match_tags: req.match_tags, created_at_ms: now_ms() };
+    s.groups.groups.lock().unwrap().insert(group.id.clone(), group.clone());
+    let devices = s.groups.resolve(&group);
+    (StatusCode::CREATED, Json(GroupView { group, devices }))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<Group>> {
+    Json(s.groups.groups.lock().unwrap().values().filter(|g| g.tenant == tenant).cloned().collect())
+}
+
+pub async fn get_group(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<GroupView>, ApiError> {
+    let group = s.groups.lookup(&tenant, &id)?;
+    let devices = s.groups.resolve(&group);
+    Ok(Json(GroupView { group, devices }))
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    s.groups.lookup(&tenant, &id)?;
+    if s.geofence.references_group(&id) { return Err(api_err(StatusCode::CONFLICT, "Group is used by a geo-fencing policy", Some(id))); }
+    s.groups.groups.lock().unwrap().remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn update_members(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(id): Path<String>, Valid(req): Valid<MemberUpdate>) -> Result<Json<GroupView>, ApiError> {
+    let group = {
+        let mut groups = s.groups.groups.lock().unwrap();
+        let g = groups.get_mut(&id).filter(|g| g.tenant == tenant).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown group", Some(id.clone())))?;
+        g.members.extend(req.add);
+        for d in &req.remove { g.members.remove(d); }
+        g.clone()
+    };
+    let devices = s.groups.resolve(&group);
+    Ok(Json(GroupView { group, devices }))
+}
+
+pub async fn get_tags(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(device_id): Path<String>) -> Json<BTreeSet<String>> {
+    Json(s.groups.tags.lock().unwrap().get(&(tenant, device_id)).cloned().unwrap_or_default())
+}
+
+/// Replaces the device's tags; an empty list removes them.
+pub async fn put_tags(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(device_id): Path<String>, Valid(req): Valid<TagsRequest>) -> Json<BTreeSet<String>> {
+    let mut all = s.groups.tags.lock().unwrap();
+    if req.tags.is_empty() { all.remove(&(tenant, device_id)); } else { all.insert((tenant, device_id), req.tags.clone()); }
+    Json(req.tags)
+}
+
+pub async fn start_action(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(id): Path<String>, Valid(req): Valid<ActionRequest>) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let group = s.groups.lookup(&tenant, &id)?;
+    let devices = s.groups.resolve(&group);
+    let (s2, t2, action) = (s.clone(), tenant.clone(), req.action.clone());
+    let job = Jobs::submit(&s, &tenant, &format!("group.{action}"), devices.len() as u64, move |ctx| run(s2, ctx, t2, action, devices));
+    s.audit.record(&actor, &job.kind, Some(&tenant), Some(&id), json!({ "job_id": job.id, "devices": job.total }));
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+fn act(s: &AppState, tenant: &str, job_id: &str, action: &str, device_id: &str) -> DeviceResult {
+    let conns: Vec<String> = s.connections.lock().unwrap().iter().filter(|(_, c)| c.tenant == tenant && c.device_id == device_id).map(|(id, _)| id.clone()).collect();
+    let (ok, detail) = match action {
+        "connect-check" => {
+            let online = conns.iter().any(|id| s.outbox.is_online(id));
+            (!conns.is_empty(), json!({ "connections": conns, "online": online }))
+        }
+        "disconnect" => {
+            let dropped = conns.iter().filter_map(|id| s.drop_connection(id)).count();
+            (true, json!({ "disconnected": dropped }))
+        }
+        _ => {
+            let (message_id, delivered, queue_depth) = s.outbox.send(tenant, device_id, json!({ "pull": { "job_id": job_id } }), Priority::Normal);
+            (true, json!({ "message_id": message_id, "delivered": delivered, "queue_depth": queue_depth }))
+        }
+    };
+    DeviceResult { device_id: device_id.into(), ok, detail }
+}
+
+async fn run(s: Arc<AppState>, ctx: JobCtx, tenant: String, action: String, devices: Vec<String>) -> Result<Value, String> {
+    let total = devices.len() as u64;
+    let mut results = Vec::with_capacity(devices.len());
+    for device_id in devices {
+        if ctx.cancelled() { break; }
+        results.push(act(&s, &tenant, ctx.id(), &action, &device_id));
+        ctx.progress(results.len() as u64, total);
+        tokio::task::yield_now().await;
+    }
+    let succeeded = results.iter().filter(|r| r.ok).count();
+    Ok(json!({ "succeeded": succeeded, "failed": results.len() - succeeded, "results": results }))
+}
@@ -0,0 +1,112 @@
+//! gRPC-Web for browser consoles, which cannot speak raw gRPC. `alice.gateway.v1.Events/Subscribe`
+//! (see `proto/gateway.proto`) is a server-streaming call delivering what `GET /events` does,
+//! with the same filters in `SubscribeRequest`, so a grpc-web client needs no proxy translating
+//! for it. It is served under `/api/v1` and `/api/v2` on the public listener, over HTTP/1.1, h2c
+//! or TLS with ALPN h2.
+//!
+//! Both the binary (`application/grpc-web`, `+proto`) and base64 (`application/grpc-web-text`)
+//! modes are spoken, and the reply uses the request's. Each event is a length-prefixed
+//! `Event` message whose `event` is the JSON event as CBOR; the stream ends with a trailer frame
+//! when the hub drops the subscription. A request the call rejects gets a trailers-only reply
+//! (`grpc-status` in the headers); missing tenant or role is answered with the plain HTTP
+//! status, which grpc-web clients map to UNAUTHENTICATED or PERMISSION_DENIED.
+
+use crate::codec::{pb, to_cbor};
+use crate::rbac::{Read, Require};
+use crate::subscriptions::{self, FilterSpec};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::StreamExt;
+use prost::Message;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+const TRAILER: u8 = 0x80;
+const INVALID_ARGUMENT: u8 = 3;
+const UNIMPLEMENTED: u8 = 12;
+
+#[derive(Clone, Copy)]
+enum Mode { Binary, Text }
+
+impl Mode {
+    fn of(headers: &HeaderMap) -> Option<Mode> {
+        match headers.get(header::CONTENT_TYPE)?.to_str().ok()?.split(';').next()?.trim() {
+            "application/grpc-web" | "application/grpc-web+proto" => Some(Mode::Binary),
+            "application/grpc-web-text" | "application/grpc-web-text+proto" => Some(Mode::Text),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self { Mode::Binary => "application/grpc-web+proto", Mode::Text => "application/grpc-web-text+proto" }
+    }
+
+    /// A frame as sent: flag, big-endian length, payload; base64 of all that in text mode.
+    fn frame(self, flag: u8, payload: &[u8]) -> Bytes {
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        buf.push(flag);
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        match self { Mode::Binary => buf.into(), Mode::Text => STANDARD.encode(buf).into() }
+    }
+
+    /// The request message out of its single frame; an empty body is the default request.
+    fn message(self, body: &[u8]) -> Result<Vec<u8>, (u8, String)> {
+        let raw = match self {
+            Mode::Binary => body.to_vec(),
+            Mode::Text => STANDARD.decode(body.trim_ascii()).map_err(|e| (INVALID_ARGUMENT, format!("body is not base64: {e}")))?,
+        };
+        if raw.is_empty() { return Ok(raw); }
+        let Some((&[flag, a, b, c, d], rest)) = raw.split_first_chunk::<5>() else { return Err((INVALID_ARGUMENT, "truncated frame header".into())) };
+        if flag != 0 { return Err((UNIMPLEMENTED, "compressed messages are not supported".into())); }
+        let len = u32::from_be_bytes([a, b, c, d]) as usize;
+        if rest.len() != len { return Err((INVALID_ARGUMENT, format!("frame declares {len} bytes, body has {}", rest.len()))); }
+        Ok(rest.to_vec())
+    }
+}
+
+/// `grpc-message` is percent-encoded.
+fn percent_encode(msg: &str) -> String {
+    msg.bytes().map(|b| if (0x20..0x7f).contains(&b) && b != b'%' { (b as char).to_string() } else { format!("%{b:02X}") }).collect()
+}
+
+fn trailers_only(mode: Mode, status: u8, message: &str) -> Response {
+    let mut r = StatusCode::OK.into_response();
+    let h = r.headers_mut();
+    h.insert(header::CONTENT_TYPE, HeaderValue::from_static(mode.content_type()));
+    h.insert("grpc-status", HeaderValue::from(u16::from(status)));
+    if let Ok(v) = HeaderValue::from_str(&percent_encode(message)) { h.insert("grpc-message", v); }
+    r
+}
+
+fn filter(req: pb::SubscribeRequest) -> FilterSpec {
+    FilterSpec {
+        topics: req.topics, buffer: req.buffer.map(|b| b as usize), overflow: req.overflow, kinds: req.kinds,
+        device: req.device, r#type: req.r#type, bbox: req.bbox, min_change: req.min_change,
+    }
+}
+
+pub async fn subscribe(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, headers: HeaderMap, body: Bytes) -> Result<Response, ApiError> {
+    let mode = Mode::of(&headers).ok_or_else(|| api_err(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported Content-Type", Some("use application/grpc-web+proto or application/grpc-web-text+proto".into())))?;
+    let req = match mode.message(&body).and_then(|m| pb::SubscribeRequest::decode(m.as_slice()).map_err(|e| (INVALID_ARGUMENT, e.to_string()))) {
+        Ok(req) => req,
+        Err((status, message)) => return Ok(trailers_only(mode, status, &message)),
+    };
+    let events = match subscriptions::events(s, tenant, filter(req), "grpc-web") {
+        Ok(events) => events,
+        Err(e) => return Ok(trailers_only(mode, INVALID_ARGUMENT, &format!("{}: {}", e.body.error, e.body.details.unwrap_or_default()))),
+    };
+    let frames = events
+        .map(move |ev| {
+            let msg = pb::Event { kind: ev["kind"].as_str().unwrap_or_default().to_string(), event: to_cbor(&ev) };
+            Ok::<_, Infallible>(mode.frame(0, &msg.encode_to_vec()))
+        })
+        .chain(futures_util::stream::once(async move { Ok(mode.frame(TRAILER, b"grpc-status:0\r\n")) }));
+    Ok(([(header::CONTENT_TYPE, mode.content_type())], Body::from_stream(frames)).into_response())
+}
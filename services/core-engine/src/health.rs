@@ -0,0 +1,73 @@
+//! Kubernetes probes. `/health/live` only says the process is serving; `/health/ready` checks
+//! downstream dependencies concurrently (each bounded by `READY_CHECK_TIMEOUT_MS`, default
+//! 2000) and answers 503 when a critical one is down. The telemetry store, the shared state
+//! backend and the MQTT broker (`MQTT_BROKER_ADDR`, when set) are critical; upstream regions
+//! only mark the pod `degraded`, since a remote outage should not drain local traffic, and so
+//! do the Kafka sink and the snapshot archive when configured.
+
+use crate::AppState;
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+#[derive(Serialize)]
+pub struct Liveness { status: &'static str, uptime_secs: u64 }
+
+#[derive(Serialize)]
+pub struct Check { name: String, critical: bool, status: &'static str, latency_ms: f64, #[serde(skip_serializing_if = "Option::is_none")] error: Option<String> }
+
+#[derive(Serialize)]
+pub struct Readiness { status: &'static str, checks: Vec<Check> }
+
+pub async fn live(State(s): State<Arc<AppState>>) -> Json<Liveness> {
+    Json(Liveness { status: "alive", uptime_secs: s.start_time.elapsed().as_secs() })
+}
+
+pub fn timeout() -> Duration {
+    Duration::from_millis(std::env::var("READY_CHECK_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(2000))
+}
+
+async fn run(name: String, critical: bool, probe: impl Future<Output = Result<(), String>>) -> Check {
+    let t = Instant::now();
+    let result = tokio::time::timeout(timeout(), probe).await.unwrap_or_else(|_| Err("timed out".into()));
+    let latency_ms = t.elapsed().as_secs_f64() * 1000.0;
+    match result {
+        Ok(()) => Check { name, critical, status: "up", latency_ms, error: None },
+        Err(e) => Check { name, critical, status: "down", latency_ms, error: Some(e) },
+    }
+}
+
+pub async fn ready(State(s): State<Arc<AppState>>) -> (StatusCode, Json<Readiness>) {
+    let mut set = JoinSet::new();
+    let st = s.clone();
+    set.spawn(async move { run(format!("storage:{}", st.telemetry.name()), true, st.telemetry.ping()).await });
+    let st = s.clone();
+    set.spawn(async move { run(format!("state:{}", st.shared.name()), true, st.shared.ping()).await });
+    if let Ok(addr) = std::env::var("MQTT_BROKER_ADDR") {
+        set.spawn(run("mqtt".into(), true, async move { tokio::net::TcpStream::connect(&addr).await.map(drop).map_err(|e| e.to_string()) }));
+    }
+    if s.snapshots.archive.is_some() {
+        let st = s.clone();
+        set.spawn(async move { run("archive:s3".into(), false, async { st.snapshots.archive.as_ref().expect("checked above").ping().await }).await });
+    }
+    #[cfg(feature = "kafka")]
+    if s.kafka.is_some() {
+        let st = s.clone();
+        set.spawn(async move { run("kafka".into(), false, async { st.kafka.as_ref().expect("checked above").ping().await }).await });
+    }
+    for (region, base) in s.relay.upstreams() {
+        let (http, url) = (s.http.clone(), format!("{base}/health/live"));
+        set.spawn(run(format!("upstream:{region}"), false, async move {
+            let r = http.get(&url).send().await.map_err(|e| e.to_string())?;
+            if r.status().is_success() { Ok(()) } else { Err(format!("HTTP {}", r.status())) }
+        }));
+    }
+    let mut checks = set.join_all().await;
+    checks.sort_by(|a, b| a.name.cmp(&b.name));
+    let down = |critical: bool| checks.iter().any(|c| c.critical == critical && c.status == "down");
+    let (code, status) = if down(true) { (StatusCode::SERVICE_UNAVAILABLE, "unready") } else if down(false) { (StatusCode::OK, "degraded") } else { (StatusCode::OK, "ready") };
+    (code, Json(Readiness { status, checks }))
+}
@@ -0,0 +1,241 @@
+//! In-memory pub/sub hub behind every event consumer. Each event is published under one or more
+//! hierarchical topics, `tenant/device/object-type`: a sync delta under one topic per object
+//! type it touches (resolved against the device's merged shadow, so partial updates keep their
+//! type; untyped, non-object and encrypted deltas use `_`), every other event under
+//! `tenant/device/$kind` (device `_` when the event has none).
+//!
+//! Subscribers match topics with MQTT-style patterns: `+` is exactly one level, a trailing `#`
+//! any number. Tenant-facing subscribers (SSE, WebSocket, webhooks) write patterns relative to
+//! their tenant, e.g. `+/sensor` or `dev-42/#`; internal sinks subscribe to every tenant.
+//!
+//! Every subscriber gets its own buffer (`HUB_SUBSCRIBER_BUFFER` events, default 1024; streams
+//! may ask for up to 65536 with `buffer`). When it fills, the subscriber's `overflow` policy
+//! decides: `drop-oldest` (the default) or `drop-newest`, either way reporting the gap to the
+//! consumer as `lagged`, or `disconnect`, which closes the subscription. A slow consumer only
+//! ever loses its own events. `GET /events/subscribers` lists the tenant's subscribers.
+//!
+//! An event carrying a sync delivery marks every subscriber it is queued for as a pending
+//! destination: streams settle it when they take the event, or on drop; internal sinks start
+//! as one pending destination each and report their own outcome.
+
+use crate::deliveries::{Deliveries, Status};
+use crate::events::{now_ms, Event};
+use crate::rbac::{Read, Require};
+use crate::{AppState, Tenant};
+use axum::{extract::State, response::Json};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+
+pub const MAX_BUFFER: usize = 65536;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow { #[default] DropOldest, DropNewest, Disconnect }
+
+impl Overflow {
+    pub fn parse(s: &str) -> Option<Overflow> {
+        match s { "drop-oldest" => Some(Overflow::DropOldest), "drop-newest" => Some(Overflow::DropNewest), "disconnect" => Some(Overflow::Disconnect), _ => None }
+    }
+}
+
+/// A validated topic pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern(Vec<String>);
+
+impl Pattern {
+    /// Parses a pattern; `#` may only be the last level, and `+`/`#` must fill their level.
+    pub fn parse(s: &str) -> Result<Pattern, String> {
+        let levels: Vec<String> = s.split('/').map(str::to_string).collect();
+        for (i, l) in levels.iter().enumerate() {
+            if l.is_empty() { return Err(format!("{s}: empty level")); }
+            if l == "#" && i + 1 != levels.len() { return Err(format!("{s}: # must be the last level")); }
+            if l.len() > 1 && (l.contains('+') || l.contains('#')) { return Err(format!("{s}: + and # must fill a whole level")); }
+        }
+        Ok(Pattern(levels))
+    }
+
+    /// Every topic of every tenant.
+    pub fn all() -> Pattern { Pattern(vec!["#".into()]) }
+
+    /// Pins a tenant-relative pattern under `tenant`.
+    pub fn within(&self, tenant: &str) -> Pattern {
+        Pattern(std::iter::once(level(tenant)).chain(self.0.iter().cloned()).collect())
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut levels = topic.split('/');
+        for p in &self.0 {
+            if p == "#" { return true; }
+            match levels.next() {
+                Some(l) if p == "+" || p == l => {}
+                _ => return false,
+            }
+        }
+        levels.next().is_none()
+    }
+
+    pub fn relative(&self) -> String { self.0.iter().skip(1).cloned().collect::<Vec<_>>().join("/") }
+}
+
+/// A topic level built from a user-supplied name, which must not add levels or wildcards.
+fn level(s: &str) -> String { s.replace(['/', '+', '#'], "_") }
+
+/// The topics an event is published under.
+pub fn topics(s: &AppState, kind: &str, tenant: &str, data: &serde_json::Value) -> Vec<String> {
+    let device = data.get("device_id").and_then(|d| d.as_str());
+    let base = format!("{}/{}", level(tenant), device.map_or_else(|| "_".into(), level));
+    if kind != "delta" { return vec![format!("{base}/${kind}")]; }
+    let Some(objects) = data.get("delta").and_then(|d| d.get("objects")).and_then(|o| o.as_object()) else { return vec![format!("{base}/_")] };
+    let shadows = s.shadows.lock().unwrap();
+    let merged = device.and_then(|d| shadows.get(&(tenant.to_string(), d.to_string()))).and_then(|sh| sh.reported.get("objects"));
+    let types: BTreeSet<String> = objects.iter().map(|(id, v)| {
+        let t = v.get("type").or_else(|| merged?.get(id)?.get("type")).and_then(|t| t.as_str());
+        t.map_or_else(|| "_".into(), level)
+    }).collect();
+    if types.is_empty() { return vec![format!("{base}/_")]; }
+    types.into_iter().map(|t| format!("{base}/{t}")).collect()
+}
+
+#[derive(Default)]
+struct Queue { events: VecDeque<Event>, lagged: u64, closed: bool, delivered: u64, dropped: u64 }
+
+/// `destination` names the subscriber in delivery receipts.
+struct Slot { id: u64, tenant: Option<String>, consumer: &'static str, destination: String, patterns: Mutex<Vec<Pattern>>, overflow: Overflow, capacity: usize, since_ms: u64, queue: Mutex<Queue>, notify: Notify }
+
+type Slots = Arc<Mutex<HashMap<u64, Arc<Slot>>>>;
+
+#[derive(Serialize)]
+pub struct SubscriberInfo { id: String, consumer: &'static str, topics: Vec<String>, overflow: Overflow, buffer: usize, buffered: usize, delivered: u64, dropped: u64, since_ms: u64 }
+
+#[derive(Default)]
+pub struct HubCounts { pub subscribers: usize, pub published: u64, pub dropped: u64, pub disconnected: u64 }
+
+pub struct Hub { buffer: usize, next_id: AtomicU64, slots: Slots, deliveries: Arc<Deliveries>, published: AtomicU64, dropped: AtomicU64, disconnected: AtomicU64 }
+
+/// One subscriber's end; dropping it unsubscribes.
+pub struct Subscription { slot: Arc<Slot>, slots: Slots, deliveries: Arc<Deliveries> }
+
+impl Hub {
+    pub fn from_env(deliveries: Arc<Deliveries>) -> Self {
+        let buffer = std::env::var("HUB_SUBSCRIBER_BUFFER").ok().and_then(|v| v.parse().ok()).unwrap_or(1024usize).clamp(1, MAX_BUFFER);
+        Hub { buffer, next_id: AtomicU64::new(1), slots: Arc::default(), deliveries, published: AtomicU64::new(0), dropped: AtomicU64::new(0), disconnected: AtomicU64::new(0) }
+    }
+
+    /// Subscribes `consumer` to `patterns`, which are pinned under `tenant` when one is given.
+    pub fn subscribe(&self, tenant: Option<&str>, consumer: &'static str, patterns: &[Pattern], overflow: Overflow, buffer: Option<usize>) -> Subscription {
+        let patterns = patterns.iter().map(|p| tenant.map_or_else(|| p.clone(), |t| p.within(t))).collect();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let destination = if tenant.is_some() { format!("{consumer}:{id}") } else { consumer.to_string() };
+        let slot = Arc::new(Slot {
+            id, tenant: tenant.map(Into::into), consumer, destination, patterns: Mutex::new(patterns), overflow,
+            capacity: buffer.unwrap_or(self.buffer).clamp(1, MAX_BUFFER), since_ms: now_ms(), queue: Mutex::default(), notify: Notify::new(),
+        });
+        self.slots.lock().unwrap().insert(slot.id, slot.clone());
+        Subscription { slot, slots: self.slots.clone(), deliveries: self.deliveries.clone() }
+    }
+
+    pub fn publish(&self, ev: Event) {
+        self.published.fetch_add(1, Ordering::Relaxed);
+        let slots: Vec<Arc<Slot>> = self.slots.lock().unwrap().values().cloned().collect();
+        for slot in slots {
+            if !slot.patterns.lock().unwrap().iter().any(|p| ev.topics.iter().any(|t| p.matches(t))) { continue; }
+            let mut q = slot.queue.lock().unwrap();
+            if q.closed { continue; }
+            if q.events.len() >= slot.capacity {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                q.dropped += 1;
+                match slot.overflow {
+                    Overflow::DropOldest => {
+                        if let Some(old) = q.events.pop_front() { settle(&self.deliveries, &slot, &old, Status::Dropped, Some("buffer full")); }
+                        q.lagged += 1;
+                    }
+                    Overflow::DropNewest => {
+                        settle(&self.deliveries, &slot, &ev, Status::Dropped, Some("buffer full"));
+                        q.lagged += 1;
+                        drop(q);
+                        slot.notify.notify_one();
+                        continue;
+                    }
+                    Overflow::Disconnect => {
+                        q.closed = true;
+                        for old in q.events.drain(..).chain(std::iter::once(ev.clone())) { settle(&self.deliveries, &slot, &old, Status::Dropped, Some("subscriber disconnected")); }
+                        self.disconnected.fetch_add(1, Ordering::Relaxed);
+                        tracing::warn!(subscriber = slot.id, consumer = slot.consumer, tenant = ?slot.tenant, "slow event subscriber disconnected");
+                        drop(q);
+                        slot.notify.notify_one();
+                        continue;
+                    }
+                }
+            }
+            settle(&self.deliveries, &slot, &ev, Status::Pending, None);
+            q.events.push_back(ev.clone());
+            drop(q);
+            slot.notify.notify_one();
+        }
+    }
+
+    pub fn subscribers(&self, tenant: &str) -> Vec<SubscriberInfo> {
+        let mut out: Vec<SubscriberInfo> = self.slots.lock().unwrap().values().filter(|s| s.tenant.as_deref() == Some(tenant)).map(|s| {
+            let q = s.queue.lock().unwrap();
+            SubscriberInfo {
+                id: s.id.to_string(), consumer: s.consumer, topics: s.patterns.lock().unwrap().iter().map(Pattern::relative).collect(), overflow: s.overflow,
+                buffer: s.capacity, buffered: q.events.len(), delivered: q.delivered, dropped: q.dropped, since_ms: s.since_ms,
+            }
+        }).collect();
+        out.sort_by_key(|s| s.since_ms);
+        out
+    }
+
+    pub fn counts(&self) -> HubCounts {
+        HubCounts {
+            subscribers: self.slots.lock().unwrap().len(), published: self.published.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed), disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Subscription {
+    /// Next event; a gap left by overflow is reported once as `Lagged`, a disconnect as `Closed`.
+    pub async fn recv(&mut self) -> Result<Event, RecvError> {
+        loop {
+            let notified = self.slot.notify.notified();
+            {
+                let mut q = self.slot.queue.lock().unwrap();
+                if q.closed { return Err(RecvError::Closed); }
+                if q.lagged > 0 { return Err(RecvError::Lagged(std::mem::take(&mut q.lagged))); }
+                if let Some(ev) = q.events.pop_front() {
+                    q.delivered += 1;
+                    if self.slot.tenant.is_some() { settle(&self.deliveries, &self.slot, &ev, Status::Delivered, None); }
+                    return Ok(ev);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Replaces the patterns; tenant-scoped subscriptions stay pinned to their tenant.
+    pub fn set_patterns(&self, patterns: &[Pattern]) {
+        *self.slot.patterns.lock().unwrap() = patterns.iter().map(|p| self.slot.tenant.as_deref().map_or_else(|| p.clone(), |t| p.within(t))).collect();
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.slots.lock().unwrap().remove(&self.slot.id);
+        let mut q = self.slot.queue.lock().unwrap();
+        for ev in q.events.drain(..) { settle(&self.deliveries, &self.slot, &ev, Status::Dropped, Some("unsubscribed")); }
+    }
+}
+
+/// Settles `slot` as a destination of the delivery `ev` carries, if any.
+fn settle(deliveries: &Deliveries, slot: &Slot, ev: &Event, status: Status, detail: Option<&str>) {
+    if let Some(id) = &ev.delivery { deliveries.set(id, &slot.destination, status, detail.map(Into::into)); }
+}
+
+pub async fn subscribers(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<SubscriberInfo>> {
+    Json(s.hub.subscribers(&tenant))
+}
@@ -0,0 +1,83 @@
+//! `Idempotency-Key` support for mutating device calls. The first successful response for a
+//! (tenant, route, key) triple is cached in the state backend for `IDEMPOTENCY_TTL_SECS`
+//! (default 24h) and replayed verbatim for retries.
+//!
+//! The key is reserved in the backend before the handler runs, so a second request with it
+//! while the first is still running gets 409 `idempotency_in_flight` (with `Retry-After`)
+//! instead of running twice; a reservation lapses after a minute if its replica dies, and is
+//! released when the handler fails so the client may retry. A key is bound to the SHA-256 of
+//! the request body: reusing it with a different body is 422 `idempotency_key_reused`.
+
+use crate::codec::{from_cbor, to_cbor};
+use crate::{api_err, AppState, Tenant};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_CACHED_BODY: usize = 1024 * 1024;
+const IN_FLIGHT_TTL: Duration = Duration::from_secs(60);
+
+/// A stored key: a reservation while `in_flight`, the response to replay after. Entries written
+/// before keys were bound to bodies have no `body_hash` and replay for any body.
+#[derive(Serialize, Deserialize)]
+struct Cached {
+    status: u16, content_type: Option<String>, body: Vec<u8>,
+    #[serde(default)] body_hash: Option<String>, #[serde(default)] in_flight: bool,
+}
+
+fn ttl() -> Duration {
+    Duration::from_secs(std::env::var("IDEMPOTENCY_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(86_400))
+}
+
+/// What to do with a request whose key is already stored.
+fn existing(c: Cached, body_hash: &str) -> Response {
+    if c.body_hash.as_deref().is_some_and(|h| h != body_hash) {
+        return api_err(StatusCode::UNPROCESSABLE_ENTITY, "Idempotency key reused", Some("this key was first used with a different request body".into())).code("idempotency_key_reused").into_response();
+    }
+    if c.in_flight {
+        return api_err(StatusCode::CONFLICT, "Request in progress", Some("a request with this idempotency key is still running".into())).code("idempotency_in_flight").retry_after(1).into_response();
+    }
+    let mut r = (StatusCode::from_u16(c.status).unwrap_or(StatusCode::OK), c.body).into_response();
+    if let Some(ct) = c.content_type.and_then(|ct| HeaderValue::from_str(&ct).ok()) { r.headers_mut().insert(header::CONTENT_TYPE, ct); }
+    r.headers_mut().insert("idempotent-replayed", HeaderValue::from_static("true"));
+    r
+}
+
+pub async fn idempotency_mw(State(s): State<Arc<AppState>>, Tenant(tenant): Tenant, req: Request, next: Next) -> Response {
+    let Some(key) = req.headers().get("idempotency-key").and_then(|h| h.to_str().ok()).map(str::to_owned) else { return next.run(req).await };
+    let scoped = format!("{tenant}:{}:{key}", req.uri().path());
+    let (parts, body) = req.into_parts();
+    // The route's body limit sits outside this layer, so this only fails on an oversized body.
+    let Ok(body) = to_bytes(body, usize::MAX).await else { return api_err(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large", None).into_response() };
+    let body_hash = hex::encode(Sha256::digest(&body));
+    let reservation = Cached { status: 0, content_type: None, body: Vec::new(), body_hash: Some(body_hash.clone()), in_flight: true };
+    if !s.shared.idempotency_reserve(&scoped, &to_cbor(&reservation), IN_FLIGHT_TTL).await {
+        return match s.shared.idempotency_get(&scoped).await.and_then(|b| from_cbor::<Cached>(&b).ok()) {
+            Some(c) => existing(c, &body_hash),
+            // Taken and gone again (expired or released): let the client retry.
+            None => existing(reservation, &body_hash),
+        };
+    }
+    let resp = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if !resp.status().is_success() {
+        s.shared.idempotency_release(&scoped).await;
+        return resp;
+    }
+    let (parts, body) = resp.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_CACHED_BODY).await else {
+        s.shared.idempotency_release(&scoped).await;
+        return (StatusCode::INTERNAL_SERVER_ERROR, "response too large to cache").into_response();
+    };
+    let content_type = parts.headers.get(header::CONTENT_TYPE).and_then(|h| h.to_str().ok()).map(str::to_owned);
+    let cached = Cached { status: parts.status.as_u16(), content_type, body: bytes.to_vec(), body_hash: Some(body_hash), in_flight: false };
+    s.shared.idempotency_put(&scoped, &to_cbor(&cached), ttl()).await;
+    Response::from_parts(parts, Body::from(bytes))
+}
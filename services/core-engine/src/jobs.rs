@@ -0,0 +1,124 @@
+//! Long-running work off the request path. `submit` registers a job and runs it on a bounded
+//! pool (`JOB_WORKERS` concurrent jobs, default 8); callers answer 202 with the job and clients
+//! poll `GET /api/v1/jobs/:id` for status, progress and result. `POST /api/v1/jobs/:id/cancel`
+//! drops a queued job outright and asks a running one to stop at its next checkpoint. Finished
+//! jobs are kept for `JOB_RETENTION_SECS` (default 3600).
+
+use crate::events::now_ms;
+use crate::rbac::{Operate, Read, Require};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status { Queued, Running, Succeeded, Failed, Cancelled }
+
+#[derive(Clone, Serialize)]
+pub struct Job {
+    pub id: String, #[serde(skip)] tenant: String, pub kind: String, pub status: Status, pub done: u64, pub total: u64,
+    pub created_at_ms: u64, pub started_at_ms: Option<u64>, pub finished_at_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Value::is_null")] pub result: Value, #[serde(skip_serializing_if = "Option::is_none")] pub error: Option<String>,
+    #[serde(skip)] cancel: Arc<AtomicBool>, #[serde(skip)] abort: Option<AbortHandle>,
+}
+
+pub struct Jobs { pool: Arc<Semaphore>, retention_ms: u64, jobs: Mutex<HashMap<String, Job>> }
+
+/// Handed to the job body for progress reports and cancellation checkpoints.
+pub struct JobCtx { s: Arc<AppState>, id: String, cancel: Arc<AtomicBool> }
+
+impl JobCtx {
+    pub fn id(&self) -> &str { &self.id }
+    pub fn cancelled(&self) -> bool { self.cancel.load(Ordering::Acquire) }
+    pub fn progress(&self, done: u64, total: u64) { self.s.jobs.update(&self.id, |j| { j.done = done; j.total = total; }); }
+}
+
+impl Jobs {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        Jobs { pool: Arc::new(Semaphore::new(env("JOB_WORKERS", 8).max(1) as usize)), retention_ms: env("JOB_RETENTION_SECS", 3600) * 1000, jobs: Mutex::new(HashMap::new()) }
+    }
+
+    /// Queues `body` for `tenant`; `total` is the initial progress denominator (0 if unknown).
+    /// A body that returns `Ok` after being cancelled keeps its partial result.
+    pub fn submit<F, Fut>(s: &Arc<AppState>, tenant: &str, kind: &str, total: u64, body: F) -> Job
+    where F: FnOnce(JobCtx) -> Fut + Send + 'static, Fut: Future<Output = Result<Value, String>> + Send + 'static {
+        let now = now_ms();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let job = Job { id: uuid::Uuid::new_v4().to_string(), tenant: tenant.into(), kind: kind.into(), status: Status::Queued, done: 0, total, created_at_ms: now, started_at_ms: None, finished_at_ms: None, result: Value::Null, error: None, cancel: cancel.clone(), abort: None };
+        let ctx = JobCtx { s: s.clone(), id: job.id.clone(), cancel };
+        {
+            let mut jobs = s.jobs.jobs.lock().unwrap();
+            jobs.retain(|_, j| j.finished_at_ms.is_none_or(|f| now.saturating_sub(f) < s.jobs.retention_ms));
+            jobs.insert(job.id.clone(), job.clone());
+        }
+        let (s2, id) = (s.clone(), job.id.clone());
+        let handle = tokio::spawn(async move {
+            let _permit = s2.jobs.pool.clone().acquire_owned().await.expect("job pool is never closed");
+            s2.jobs.update(&id, |j| { j.status = Status::Running; j.started_at_ms = Some(now_ms()); });
+            let outcome = body(ctx).await;
+            s2.jobs.update(&id, |j| {
+                j.finished_at_ms = Some(now_ms());
+                let cancelled = j.cancel.load(Ordering::Acquire);
+                match outcome {
+                    Ok(v) => { j.result = v; j.status = if cancelled { Status::Cancelled } else { Status::Succeeded }; }
+                    Err(e) => { j.error = Some(e); j.status = if cancelled { Status::Cancelled } else { Status::Failed }; }
+                }
+            });
+            tracing::info!(job = %id, "job finished");
+        });
+        s.jobs.update(&job.id, |j| j.abort = Some(handle.abort_handle()));
+        job
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(j) = self.jobs.lock().unwrap().get_mut(id) { f(j); }
+    }
+
+    /// Cancels the tenant's unfinished jobs and forgets all of them; returns how many were removed.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        let n = jobs.len();
+        jobs.retain(|_, j| {
+            if j.tenant != tenant { return true; }
+            j.cancel.store(true, Ordering::Release);
+            if j.status == Status::Queued { if let Some(a) = &j.abort { a.abort(); } }
+            false
+        });
+        n - jobs.len()
+    }
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<Job>> {
+    let mut jobs: Vec<Job> = s.jobs.jobs.lock().unwrap().values().filter(|j| j.tenant == tenant).cloned().collect();
+    jobs.sort_by_key(|j| std::cmp::Reverse(j.created_at_ms));
+    Json(jobs)
+}
+
+pub async fn get_job(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Job>, ApiError> {
+    s.jobs.jobs.lock().unwrap().get(&id).filter(|j| j.tenant == tenant).cloned().map(Json).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown job", Some(id)))
+}
+
+/// 409 once the job has finished.
+pub async fn cancel(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let mut jobs = s.jobs.jobs.lock().unwrap();
+    let j = jobs.get_mut(&id).filter(|j| j.tenant == tenant).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown job", Some(id.clone())))?;
+    j.cancel.store(true, Ordering::Release);
+    match j.status {
+        Status::Queued => {
+            if let Some(a) = &j.abort { a.abort(); }
+            j.status = Status::Cancelled;
+            j.finished_at_ms = Some(now_ms());
+            Ok((StatusCode::OK, Json(j.clone())))
+        }
+        Status::Running => Ok((StatusCode::ACCEPTED, Json(j.clone()))),
+        _ => Err(api_err(StatusCode::CONFLICT, "Job already finished", Some(id))),
+    }
+}
@@ -0,0 +1,140 @@
+//! Kafka sink for the data platform (built with the `kafka` feature). When `KAFKA_BROKERS` is
+//! set, gateway events are published as JSON: sync events (`delta`, `sync-failure`) to
+//! `KAFKA_TOPIC_SYNC` (default `alice.gateway.sync`), `connect` and `disconnect` to
+//! `KAFKA_TOPIC_CONNECT` (`alice.gateway.connect`) and `transform` to `KAFKA_TOPIC_TRANSFORM`
+//! (`alice.gateway.transform`); an empty topic turns that stream off. Records are keyed by
+//! tenant, or by `<tenant>/<device_id>` with `KAFKA_PARTITION_BY=device`, so a key's events
+//! share a partition. Further librdkafka settings (e.g. SASL) come from
+//! `KAFKA_PRODUCER_CONFIG` as `key=value` pairs separated by commas.
+//!
+//! Delivery is at least once: the producer is idempotent with `acks=all`, and a record the
+//! broker did not acknowledge is retried with backoff until it is, with up to
+//! `KAFKA_MAX_IN_FLIGHT` (default 1000) records outstanding. Events the sink falls too far
+//! behind on to read at all are counted in `gateway_kafka_lagged_total`. An unreachable
+//! cluster marks `/health/ready` degraded rather than unready.
+
+use crate::deliveries::Status;
+use crate::events::Event;
+use crate::hub::{Overflow, Pattern};
+use crate::AppState;
+use futures_util::stream::{FuturesOrdered, StreamExt};
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use rdkafka::util::Timeout;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct Sink {
+    producer: FutureProducer,
+    sync_topic: Option<String>, connect_topic: Option<String>, transform_topic: Option<String>,
+    by_device: bool, max_in_flight: usize,
+    published: AtomicU64, retried: AtomicU64, lagged: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct SinkCounts { pub published: u64, pub retried: u64, pub lagged: u64 }
+
+impl Sink {
+    /// `None` when `KAFKA_BROKERS` is unset or the producer cannot be configured (logged).
+    pub fn from_env() -> Option<Self> {
+        let brokers = std::env::var("KAFKA_BROKERS").ok().filter(|b| !b.is_empty())?;
+        let topic = |k: &str, d: &str| Some(std::env::var(k).unwrap_or_else(|_| d.into())).filter(|t| !t.is_empty());
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", &brokers).set("client.id", "alice-gateway-engine").set("enable.idempotence", "true").set("acks", "all").set("compression.type", "lz4");
+        for pair in std::env::var("KAFKA_PRODUCER_CONFIG").unwrap_or_default().split(',').filter(|p| !p.trim().is_empty()) {
+            match pair.split_once('=') {
+                Some((k, v)) => { config.set(k.trim(), v.trim()); }
+                None => tracing::warn!("Ignoring KAFKA_PRODUCER_CONFIG entry {pair:?}: expected key=value"),
+            }
+        }
+        let producer = match config.create::<FutureProducer>() {
+            Ok(p) => p,
+            Err(e) => { tracing::error!("Kafka sink disabled, producer config rejected: {e}"); return None; }
+        };
+        tracing::info!(%brokers, "Kafka sink enabled");
+        Some(Sink {
+            producer,
+            sync_topic: topic("KAFKA_TOPIC_SYNC", "alice.gateway.sync"), connect_topic: topic("KAFKA_TOPIC_CONNECT", "alice.gateway.connect"), transform_topic: topic("KAFKA_TOPIC_TRANSFORM", "alice.gateway.transform"),
+            by_device: std::env::var("KAFKA_PARTITION_BY").is_ok_and(|v| v == "device"),
+            max_in_flight: std::env::var("KAFKA_MAX_IN_FLIGHT").ok().and_then(|v| v.parse().ok()).unwrap_or(1000usize).max(1),
+            published: AtomicU64::new(0), retried: AtomicU64::new(0), lagged: AtomicU64::new(0),
+        })
+    }
+
+    fn topic(&self, kind: &str) -> Option<&str> {
+        match kind {
+            "delta" | "sync-failure" => self.sync_topic.as_deref(),
+            "connect" | "disconnect" => self.connect_topic.as_deref(),
+            "transform" => self.transform_topic.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn key(&self, e: &Event) -> String {
+        match e.data.get("device_id").and_then(|d| d.as_str()) {
+            Some(device) if self.by_device => format!("{}/{device}", e.tenant),
+            _ => e.tenant.clone(),
+        }
+    }
+
+    pub fn counts(&self) -> SinkCounts {
+        SinkCounts { published: self.published.load(Ordering::Relaxed), retried: self.retried.load(Ordering::Relaxed), lagged: self.lagged.load(Ordering::Relaxed) }
+    }
+
+    /// Fetches cluster metadata, for the readiness probe.
+    pub async fn ping(&self) -> Result<(), String> {
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || producer.client().fetch_metadata(None, Timeout::After(Duration::from_secs(2))).map(drop).map_err(|e| e.to_string()))
+            .await.map_err(|e| e.to_string())?
+    }
+
+    /// Sends one record, retrying until the broker acknowledges it.
+    async fn deliver(&self, topic: &str, key: String, payload: Vec<u8>) {
+        let mut backoff = Duration::from_millis(100);
+        loop {
+            match self.producer.send(FutureRecord::to(topic).key(&key).payload(&payload), Timeout::After(SEND_TIMEOUT)).await {
+                Ok(_) => { self.published.fetch_add(1, Ordering::Relaxed); return; }
+                Err((e, _)) => {
+                    self.retried.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(%topic, "Kafka publish failed, retrying in {backoff:?}: {e}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// Background task publishing events while the sink is configured.
+pub async fn run(s: Arc<AppState>) {
+    let Some(sink) = s.kafka.as_ref() else { return };
+    let mut rx = s.hub.subscribe(None, "kafka", &[Pattern::all()], Overflow::DropOldest, None);
+    let mut in_flight = FuturesOrdered::new();
+    loop {
+        tokio::select! {
+            Some(()) = in_flight.next(), if !in_flight.is_empty() => {}
+            event = rx.recv(), if in_flight.len() < sink.max_in_flight => match event {
+                Ok(e) => {
+                    let Some(topic) = sink.topic(&e.kind) else {
+                        if let Some(d) = &e.delivery { s.deliveries.forget(d, "kafka"); }
+                        continue;
+                    };
+                    let (deliveries, delivery) = (&s.deliveries, e.delivery.clone());
+                    let record = sink.deliver(topic, sink.key(&e), serde_json::to_vec(&e).unwrap_or_default());
+                    in_flight.push_back(async move {
+                        record.await;
+                        if let Some(d) = delivery { deliveries.set(&d, "kafka", Status::Delivered, Some(topic.into())); }
+                    });
+                }
+                Err(RecvError::Lagged(n)) => { sink.lagged.fetch_add(n, Ordering::Relaxed); tracing::warn!("Kafka sink fell behind, {n} events not published"); }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+    while in_flight.next().await.is_some() {}
+}
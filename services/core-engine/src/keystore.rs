@@ -0,0 +1,294 @@
+//! Per-tenant encryption of data at rest. Every tenant gets its own AES-256-GCM data keys,
+//! created on first use; blobs written to storage are sealed with the tenant's active key
+//! (the tenant id is bound in as associated data) and opened with whichever version sealed
+//! them, so rotating never strands older blobs. Today that is the snapshot archive; shadows are
+//! held in memory only, and any store added for them must go through `seal` and `open` too.
+//!
+//! Data keys are only ever kept wrapped: by a local master key (`TENANT_KEYSTORE_MASTER_KEY`,
+//! 64 hex characters) or, when `KMS_KEY_ID` is set, by AWS KMS (`KMS_REGION`, default
+//! `us-east-1`; `KMS_ENDPOINT`, default `https://kms.<region>.amazonaws.com`; credentials from
+//! `KMS_ACCESS_KEY` and `KMS_SECRET_KEY`), with the tenant as encryption context. Wrapped keys
+//! are saved to `TENANT_KEYSTORE_PATH` when set, and must be there on restart to read blobs
+//! written before it. With neither master configured, blobs are stored in plaintext as before.
+//!
+//! A tenant's key is rotated once it is `TENANT_KEY_ROTATION_DAYS` old (default 90, 0 rotates
+//! only on demand), or at once with `POST /admin/tenants/:tenant/encryption-keys/rotate`.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::rbac::{Admin, Require};
+use crate::sigv4::{self, Credentials};
+use crate::{api_err, ApiError, AppState};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use reqwest::{Method, Url};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const ALG: &str = "AES-256-GCM";
+const KEY_LEN: usize = 32;
+const ROTATION_CHECK: Duration = Duration::from_secs(3600);
+const KMS_TIMEOUT: Duration = Duration::from_secs(10);
+const DAY_MS: u64 = 86_400_000;
+
+/// A blob sealed with one of its tenant's keys.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sealed { pub key_version: u32, pub alg: String, pub nonce: String, pub ciphertext: String }
+
+struct KeyVersion { version: u32, created_ms: u64, wrapped: String, key: LessSafeKey }
+
+/// A key version as saved to `TENANT_KEYSTORE_PATH`; the key itself only wrapped.
+#[derive(Serialize, Deserialize)]
+struct StoredVersion { version: u32, created_ms: u64, wrapped: String }
+
+#[derive(Serialize)]
+pub struct KeyInfo { version: u32, created_ms: u64, active: bool }
+
+#[derive(Serialize)]
+pub struct TenantKeys { tenant: String, backend: &'static str, #[serde(skip_serializing_if = "Option::is_none")] rotation_days: Option<u64>, keys: Vec<KeyInfo> }
+
+#[derive(Default)]
+pub struct KeyCounts { pub tenants: usize, pub sealed: u64, pub opened: u64, pub failures: u64, pub rotations: u64 }
+
+struct Kms { http: reqwest::Client, endpoint: Url, key_id: String, credentials: Credentials }
+
+/// The local variant is boxed: an expanded AES key schedule is large.
+enum Master { Local(Box<LessSafeKey>), Kms(Kms) }
+
+pub struct Keystore {
+    master: Option<Master>, path: Option<PathBuf>, rotation_ms: u64, rng: SystemRandom,
+    tenants: Mutex<HashMap<String, Vec<KeyVersion>>>,
+    sealed: AtomicU64, opened: AtomicU64, failures: AtomicU64, rotations: AtomicU64,
+}
+
+fn aes_key(bytes: &[u8]) -> Result<LessSafeKey, String> {
+    UnboundKey::new(&AES_256_GCM, bytes).map(LessSafeKey::new).map_err(|_| format!("keys must be {KEY_LEN} bytes"))
+}
+
+/// Encrypts with a fresh random nonce; returns (nonce, ciphertext with tag).
+fn encrypt(rng: &SystemRandom, key: &LessSafeKey, aad: &[u8], plaintext: &[u8]) -> Result<([u8; NONCE_LEN], Vec<u8>), String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce).map_err(|_| "no randomness available")?;
+    let mut buf = plaintext.to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut buf).map_err(|_| "encryption failed")?;
+    Ok((nonce, buf))
+}
+
+fn decrypt(key: &LessSafeKey, aad: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "bad nonce")?;
+    let mut buf = ciphertext.to_vec();
+    let len = key.open_in_place(nonce, Aad::from(aad), &mut buf).map_err(|_| "decryption failed: wrong key or tampered data")?.len();
+    buf.truncate(len);
+    Ok(buf)
+}
+
+impl Kms {
+    async fn call(&self, target: &str, body: Value) -> Result<Value, String> {
+        let body = serde_json::to_vec(&body).map_err(|e| e.to_string())?;
+        let headers = sigv4::sign(&self.credentials, "kms", &Method::POST, &self.endpoint, &[("content-type", "application/x-amz-json-1.1"), ("x-amz-target", target)], &body);
+        let mut req = self.http.post(self.endpoint.clone());
+        for (k, v) in headers { req = req.header(k, v); }
+        let r = req.timeout(KMS_TIMEOUT).body(body).send().await.map_err(|e| format!("KMS unreachable: {e}"))?;
+        let status = r.status();
+        let reply: Value = r.json().await.map_err(|e| format!("KMS reply unreadable: {e}"))?;
+        if !status.is_success() { return Err(format!("KMS {target} failed: HTTP {status} {}", reply.get("message").or_else(|| reply.get("Message")).and_then(Value::as_str).unwrap_or_default())); }
+        Ok(reply)
+    }
+}
+
+impl Master {
+    async fn wrap(&self, rng: &SystemRandom, tenant: &str, key: &[u8]) -> Result<String, String> {
+        match self {
+            Master::Local(master) => {
+                let (nonce, ct) = encrypt(rng, master, tenant.as_bytes(), key)?;
+                Ok(B64.encode([nonce.as_slice(), &ct].concat()))
+            }
+            Master::Kms(kms) => {
+                let reply = kms.call("TrentService.Encrypt", json!({ "KeyId": kms.key_id, "Plaintext": B64.encode(key), "EncryptionContext": { "tenant": tenant } })).await?;
+                reply.get("CiphertextBlob").and_then(Value::as_str).map(String::from).ok_or_else(|| "KMS returned no CiphertextBlob".into())
+            }
+        }
+    }
+
+    async fn unwrap(&self, tenant: &str, wrapped: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Master::Local(master) => {
+                let raw = B64.decode(wrapped).map_err(|e| e.to_string())?;
+                if raw.len() < NONCE_LEN { return Err("wrapped key too short".into()); }
+                decrypt(master, tenant.as_bytes(), &raw[..NONCE_LEN], &raw[NONCE_LEN..])
+            }
+            Master::Kms(kms) => {
+                let reply = kms.call("TrentService.Decrypt", json!({ "KeyId": kms.key_id, "CiphertextBlob": wrapped, "EncryptionContext": { "tenant": tenant } })).await?;
+                let plain = reply.get("Plaintext").and_then(Value::as_str).ok_or("KMS returned no Plaintext")?;
+                B64.decode(plain).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str { match self { Master::Local(_) => "local", Master::Kms(_) => "kms" } }
+}
+
+impl Keystore {
+    /// Loads the saved keys, unwrapping each; a master that cannot unwrap them stops startup,
+    /// since blobs sealed under them would be unreadable.
+    pub async fn from_env() -> Self {
+        let var = |k: &str| std::env::var(k).ok().filter(|v| !v.is_empty());
+        let master = if let Some(key_id) = var("KMS_KEY_ID") {
+            let region = var("KMS_REGION").unwrap_or_else(|| "us-east-1".into());
+            let raw = var("KMS_ENDPOINT").unwrap_or_else(|| format!("https://kms.{region}.amazonaws.com"));
+            let endpoint = Url::parse(&raw).ok().filter(|u| u.host_str().is_some()).unwrap_or_else(|| panic!("invalid KMS_ENDPOINT {raw:?}"));
+            let credentials = Credentials { access_key: var("KMS_ACCESS_KEY").unwrap_or_default(), secret_key: var("KMS_SECRET_KEY").unwrap_or_default(), region };
+            Some(Master::Kms(Kms { http: reqwest::Client::new(), endpoint, key_id, credentials }))
+        } else {
+            var("TENANT_KEYSTORE_MASTER_KEY").map(|k| {
+                let key = hex::decode(&k).ok().filter(|b| b.len() == KEY_LEN).unwrap_or_else(|| panic!("TENANT_KEYSTORE_MASTER_KEY must be {} hex characters", KEY_LEN * 2));
+                Master::Local(Box::new(aes_key(&key).expect("length checked above")))
+            })
+        };
+        let ks = Keystore {
+            path: var("TENANT_KEYSTORE_PATH").map(PathBuf::from), rotation_ms: var("TENANT_KEY_ROTATION_DAYS").and_then(|v| v.parse().ok()).unwrap_or(90u64) * DAY_MS,
+            master, rng: SystemRandom::new(), tenants: Mutex::new(HashMap::new()),
+            sealed: AtomicU64::new(0), opened: AtomicU64::new(0), failures: AtomicU64::new(0), rotations: AtomicU64::new(0),
+        };
+        let Some(master) = &ks.master else {
+            if ks.path.is_some() { tracing::warn!("TENANT_KEYSTORE_PATH is set without TENANT_KEYSTORE_MASTER_KEY or KMS_KEY_ID; data at rest is not encrypted"); }
+            return ks;
+        };
+        let saved: BTreeMap<String, Vec<StoredVersion>> = match ks.path.as_ref().map(std::fs::read_to_string) {
+            Some(Ok(text)) => serde_json::from_str(&text).unwrap_or_else(|e| panic!("TENANT_KEYSTORE_PATH is unreadable: {e}")),
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => panic!("TENANT_KEYSTORE_PATH is unreadable: {e}"),
+            _ => BTreeMap::new(),
+        };
+        let mut loaded = 0;
+        for (tenant, versions) in saved {
+            let mut ring = Vec::with_capacity(versions.len());
+            for v in versions {
+                let key = master.unwrap(&tenant, &v.wrapped).await.and_then(|k| aes_key(&k)).unwrap_or_else(|e| panic!("cannot unwrap key {} of tenant {tenant}: {e}", v.version));
+                ring.push(KeyVersion { version: v.version, created_ms: v.created_ms, wrapped: v.wrapped, key });
+                loaded += 1;
+            }
+            ks.tenants.lock().unwrap().insert(tenant, ring);
+        }
+        tracing::info!(backend = master.name(), keys = loaded, "encryption at rest enabled");
+        ks
+    }
+
+    pub fn enabled(&self) -> bool { self.master.is_some() }
+
+    /// Creates the tenant's next key version and makes it the active one.
+    async fn add_version(&self, tenant: &str) -> Result<(), String> {
+        let master = self.master.as_ref().ok_or("encryption at rest is not configured")?;
+        let mut raw = [0u8; KEY_LEN];
+        self.rng.fill(&mut raw).map_err(|_| "no randomness available")?;
+        let wrapped = master.wrap(&self.rng, tenant, &raw).await?;
+        let key = aes_key(&raw)?;
+        {
+            let mut tenants = self.tenants.lock().unwrap();
+            let ring = tenants.entry(tenant.to_string()).or_default();
+            let version = ring.last().map_or(1, |v| v.version + 1);
+            ring.push(KeyVersion { version, created_ms: now_ms(), wrapped, key });
+        }
+        self.save()
+    }
+
+    /// Writes every wrapped key to `TENANT_KEYSTORE_PATH`, replacing the file atomically. The
+    /// lock is held throughout so concurrent saves cannot land out of order.
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else { return Ok(()) };
+        let tenants = self.tenants.lock().unwrap();
+        let saved: BTreeMap<&String, Vec<StoredVersion>> = tenants.iter()
+            .map(|(t, ring)| (t, ring.iter().map(|v| StoredVersion { version: v.version, created_ms: v.created_ms, wrapped: v.wrapped.clone() }).collect())).collect();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&saved).map_err(|e| e.to_string())?).and_then(|_| std::fs::rename(&tmp, path)).map_err(|e| format!("saving keystore: {e}"))
+    }
+
+    /// Seals `plaintext` with the tenant's active key, creating the tenant's first key if needed.
+    pub async fn seal(&self, tenant: &str, plaintext: &[u8]) -> Result<Sealed, String> {
+        let has_key = self.tenants.lock().unwrap().get(tenant).is_some_and(|r| !r.is_empty());
+        if !has_key { self.add_version(tenant).await.inspect_err(|_| { self.failures.fetch_add(1, Ordering::Relaxed); })?; }
+        let tenants = self.tenants.lock().unwrap();
+        let active = tenants.get(tenant).and_then(|r| r.last()).expect("created above");
+        let (nonce, ct) = encrypt(&self.rng, &active.key, tenant.as_bytes(), plaintext).inspect_err(|_| { self.failures.fetch_add(1, Ordering::Relaxed); })?;
+        self.sealed.fetch_add(1, Ordering::Relaxed);
+        Ok(Sealed { key_version: active.version, alg: ALG.into(), nonce: B64.encode(nonce), ciphertext: B64.encode(ct) })
+    }
+
+    pub fn open(&self, tenant: &str, sealed: &Sealed) -> Result<Vec<u8>, String> {
+        let result = (|| {
+            if sealed.alg != ALG { return Err(format!("unsupported algorithm {}", sealed.alg)); }
+            let tenants = self.tenants.lock().unwrap();
+            let v = tenants.get(tenant).and_then(|r| r.iter().find(|v| v.version == sealed.key_version)).ok_or_else(|| format!("tenant {tenant} has no key version {}", sealed.key_version))?;
+            let nonce = B64.decode(&sealed.nonce).map_err(|e| e.to_string())?;
+            decrypt(&v.key, tenant.as_bytes(), &nonce, &B64.decode(&sealed.ciphertext).map_err(|e| e.to_string())?)
+        })();
+        match &result { Ok(_) => self.opened.fetch_add(1, Ordering::Relaxed), Err(_) => self.failures.fetch_add(1, Ordering::Relaxed) };
+        result
+    }
+
+    pub async fn rotate(&self, tenant: &str) -> Result<(), String> {
+        self.add_version(tenant).await?;
+        self.rotations.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn listing(&self, tenant: &str) -> TenantKeys {
+        let tenants = self.tenants.lock().unwrap();
+        let ring = tenants.get(tenant).map(Vec::as_slice).unwrap_or_default();
+        TenantKeys {
+            tenant: tenant.into(), backend: self.master.as_ref().map_or("none", Master::name), rotation_days: (self.rotation_ms > 0).then_some(self.rotation_ms / DAY_MS),
+            keys: ring.iter().map(|v| KeyInfo { version: v.version, created_ms: v.created_ms, active: v.version == ring.last().map_or(0, |l| l.version) }).collect(),
+        }
+    }
+
+    pub fn counts(&self) -> KeyCounts {
+        KeyCounts {
+            tenants: self.tenants.lock().unwrap().len(), sealed: self.sealed.load(Ordering::Relaxed), opened: self.opened.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed), rotations: self.rotations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Rotates tenants whose active key has reached the rotation age.
+pub async fn rotation_loop(s: Arc<AppState>) {
+    if !s.keys.enabled() || s.keys.rotation_ms == 0 { return; }
+    let mut tick = tokio::time::interval(ROTATION_CHECK);
+    loop {
+        tick.tick().await;
+        let cutoff = now_ms().saturating_sub(s.keys.rotation_ms);
+        let due: Vec<String> = s.keys.tenants.lock().unwrap().iter().filter(|(_, r)| r.last().is_some_and(|v| v.created_ms <= cutoff)).map(|(t, _)| t.clone()).collect();
+        for tenant in due {
+            match s.keys.rotate(&tenant).await {
+                Ok(()) => {
+                    tracing::info!(%tenant, "rotated tenant data key");
+                    s.audit.record("system", "tenant.key.rotate", Some(&tenant), None, json!({ "scheduled": true }));
+                }
+                Err(e) => tracing::warn!(%tenant, "scheduled key rotation failed: {e}"),
+            }
+        }
+    }
+}
+
+fn not_configured() -> ApiError {
+    api_err(StatusCode::CONFLICT, "Encryption at rest is not configured", Some("set TENANT_KEYSTORE_MASTER_KEY or KMS_KEY_ID".into())).code("not_configured")
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Admin>, Path(tenant): Path<String>) -> Json<TenantKeys> {
+    Json(s.keys.listing(&tenant))
+}
+
+pub async fn rotate(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(tenant): Path<String>) -> Result<Json<TenantKeys>, ApiError> {
+    if !s.keys.enabled() { return Err(not_configured()); }
+    s.keys.rotate(&tenant).await.map_err(|e| api_err(StatusCode::BAD_GATEWAY, "Key rotation failed", Some(e)).code("key_rotation_failed"))?;
+    let listing = s.keys.listing(&tenant);
+    let version = listing.keys.last().map(|k| k.version);
+    tracing::info!(%tenant, ?version, "admin rotated tenant data key");
+    s.audit.record(&actor, "admin.tenant.key.rotate", Some(&tenant), None, json!({ "key_version": version }));
+    Ok(Json(listing))
+}
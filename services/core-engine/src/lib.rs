@@ -0,0 +1,864 @@
+mod accesslog;
+mod admin;
+mod alerts;
+mod anomaly;
+mod apikeys;
+mod archive;
+mod audit;
+pub mod bench;
+mod chaos;
+mod codec;
+mod concurrency;
+mod coap;
+mod conditional;
+mod cors;
+mod dashboard;
+mod dedup;
+mod deliveries;
+mod dlq;
+#[cfg(feature = "emulator")]
+mod emulator;
+mod envelope;
+mod events;
+mod geofence;
+mod groups;
+mod grpc_web;
+mod health;
+mod hub;
+mod idempotency;
+mod jobs;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod keystore;
+mod listeners;
+mod maintenance;
+mod mesh;
+mod metrics;
+mod migrate;
+mod objects;
+mod otel;
+mod pressure;
+mod outbox;
+mod protocols;
+mod provisioning;
+mod quic;
+mod regions;
+mod rbac;
+mod relay;
+mod replay;
+mod resilience;
+mod resume;
+mod routing;
+mod schedules;
+mod schemas;
+mod shadow;
+mod shaping;
+mod shared;
+mod sigv4;
+#[cfg(feature = "simulate")]
+mod simulate;
+mod snapshots;
+mod spatial;
+mod standby;
+mod subscriptions;
+mod synclog;
+mod telemetry;
+mod tenant;
+mod tls;
+mod transaction;
+mod transform_cache;
+mod trash;
+mod units;
+mod uploads;
+mod usage;
+mod validate;
+mod versioning;
+mod wal;
+mod webhooks;
+
+use alice_gateway_types::{ConnectRequest, ConnectResponse, MeshConnection, MeshRequest, MeshResponse, SyncRequest, SyncResponse, TransformRequest, TransformResponse, TransformStage, MAX_PIPELINE};
+use axum::{extract::{Extension, Path, Query, State}, http::{header, StatusCode}, response::{IntoResponse, Json, Response}, routing::{delete, get, post, put}, Router};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tower_http::trace::TraceLayer;
+use codec::{Encoded, Negotiated};
+use concurrency::Group;
+use listeners::Surface;
+use rbac::{Operate, Read, Require};
+use tenant::Tenant;
+use validate::{Valid, Validate, Violations};
+use versioning::Version;
+
+struct AppState {
+    start_time: Instant,
+    stats: Mutex<Stats>,
+    connections: Mutex<HashMap<String, Connection>>,
+    /// Connections in `connections` loaded from the shared backend, and when.
+    foreign_connections: Mutex<HashMap<String, Instant>>,
+    standbys: Mutex<HashMap<String, standby::Standby>>,
+    hub: hub::Hub,
+    deliveries: Arc<deliveries::Deliveries>,
+    dlq: dlq::Dlq,
+    dedup: dedup::Dedup,
+    webhooks: Mutex<HashMap<String, webhooks::Webhook>>,
+    http: reqwest::Client,
+    webhook_egress: webhooks::Egress,
+    telemetry: Arc<dyn telemetry::TelemetryStore>,
+    alert_rules: Mutex<HashMap<String, alerts::AlertRule>>,
+    metric_samples: Mutex<std::collections::VecDeque<alerts::Sample>>,
+    api_keys: Mutex<HashMap<String, apikeys::ApiKey>>,
+    maintenance: AtomicBool,
+    windows: maintenance::Windows,
+    unit_rules: units::Rules,
+    chaos: chaos::Chaos,
+    benchmarking: AtomicBool,
+    relay: relay::Relay,
+    breakers: Arc<resilience::Breakers>,
+    shadows: Mutex<HashMap<(String, String), shadow::Shadow>>,
+    usage: Mutex<HashMap<String, usage::TenantUsage>>,
+    default_quota: usage::Quota,
+    shaping: shaping::Shaper,
+    versions: versioning::Versions,
+    meshes: Mutex<HashMap<String, mesh::Mesh>>,
+    shared: Arc<dyn shared::StateBackend>,
+    /// Last accepted sync sequence number per connection.
+    sequences: Mutex<HashMap<String, u64>>,
+    uploads: Mutex<HashMap<String, uploads::Upload>>,
+    protocols: protocols::Registry,
+    coap: coap::Bridge,
+    outbox: outbox::Outbox,
+    audit: audit::AuditLog,
+    schedules: Mutex<HashMap<String, schedules::Schedule>>,
+    /// Connections whose state is being handed to another region; their syncs are refused.
+    migrating: Mutex<std::collections::HashSet<String>>,
+    pressure: pressure::Pressure,
+    sync_log: synclog::SyncLog,
+    groups: groups::Groups,
+    jobs: jobs::Jobs,
+    transform_cache: transform_cache::TransformCache,
+    access_log: accesslog::AccessLog,
+    snapshots: snapshots::Snapshots,
+    wal: Arc<wal::Wal>,
+    geofence: geofence::Policies,
+    routing: routing::Routing,
+    cors: cors::Cors,
+    resume: resume::Sessions,
+    encryption: envelope::Encryption,
+    anomalies: anomaly::Detector,
+    validators: conditional::Validators,
+    provisioning: provisioning::Registry,
+    schemas: schemas::Schemas,
+    regions: regions::Tracker,
+    trash: trash::Trash,
+    limits: concurrency::Limits,
+    keys: Arc<keystore::Keystore>,
+    #[cfg(feature = "kafka")]
+    kafka: Option<kafka::Sink>,
+    #[cfg(feature = "emulator")]
+    emulator: emulator::Emulator,
+}
+struct Stats { total_connections: u64, total_syncs: u64, total_transforms: u64, bytes_relayed: u64, duplicates_suppressed: u64, tenants: HashMap<String, alerts::Counters> }
+impl Stats {
+    /// Counts connections, syncs, transforms and bytes relayed, in that order, towards the
+    /// process totals and `tenant`'s own, which back its `gateway.*` alert metrics.
+    fn add(&mut self, tenant: &str, n: alerts::Counters) {
+        self.total_connections += n[0];
+        self.total_syncs += n[1];
+        self.total_transforms += n[2];
+        self.bytes_relayed += n[3];
+        let t = self.tenants.entry(tenant.to_string()).or_default();
+        for (c, n) in t.iter_mut().zip(n) { *c += n; }
+    }
+}
+#[derive(Clone, Serialize, Deserialize)]
+struct Connection { tenant: String, device_id: String, protocol: String, region: String, upstream: Option<Upstream> }
+/// Mirror of a roaming device's connection on its home-region gateway.
+#[derive(Clone, Serialize, Deserialize)]
+struct Upstream { home_region: String, connection_id: String }
+
+#[derive(Serialize)]
+pub struct Err { error: String, #[serde(skip_serializing_if = "Option::is_none")] code: Option<&'static str>, #[serde(skip_serializing_if = "Option::is_none")] details: Option<String>, #[serde(skip_serializing_if = "Vec::is_empty")] violations: Vec<validate::Violation>, #[serde(flatten)] hints: Option<Box<Hints>> }
+/// Where and when to retry; boxed since most errors carry none of it.
+#[derive(Serialize, Default)]
+struct Hints { #[serde(skip_serializing_if = "Option::is_none")] suggested_interval_ms: Option<u32>, #[serde(skip_serializing_if = "Option::is_none")] suggested_region: Option<String>, #[serde(skip_serializing_if = "Option::is_none")] maintenance: Option<maintenance::Notice> }
+pub struct ApiError { status: StatusCode, body: Err, retry_after_secs: Option<u64> }
+fn api_err(code: StatusCode, error: &str, details: Option<String>) -> ApiError { ApiError { status: code, body: Err { error: error.into(), code: None, details, violations: Vec::new(), hints: None }, retry_after_secs: None } }
+impl ApiError {
+    fn retry_after(mut self, secs: u64) -> Self { self.retry_after_secs = Some(secs); self }
+    /// Machine-readable reason for clients that need to tell rejections with the same status apart.
+    fn code(mut self, code: &'static str) -> Self { self.body.code = Some(code); self }
+    fn violations(mut self, v: Vec<validate::Violation>) -> Self { self.body.violations = v; self }
+    /// How long the client should wait between requests; also sent as `X-Suggested-Interval-Ms`.
+    fn pacing(mut self, ms: u32) -> Self { self.body.hints.get_or_insert_default().suggested_interval_ms = Some(ms); self }
+    /// Where the client should connect instead.
+    fn suggest_region(mut self, region: String) -> Self { self.body.hints.get_or_insert_default().suggested_region = Some(region); self }
+    /// The maintenance window the request ran into.
+    fn maintenance(mut self, notice: maintenance::Notice) -> Self { self.body.hints.get_or_insert_default().maintenance = Some(notice); self }
+}
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let pacing = self.body.hints.as_ref().and_then(|h| h.suggested_interval_ms);
+        let mut r = (self.status, Json(self.body)).into_response();
+        if let Some(secs) = self.retry_after_secs { r.headers_mut().insert(header::RETRY_AFTER, secs.into()); }
+        if let Some(ms) = pacing { r.headers_mut().insert("x-suggested-interval-ms", ms.into()); }
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct Health { status: String, version: String, uptime_secs: u64, total_ops: u64 }
+
+impl Validate for ConnectRequest {
+    fn validate(&self, s: &AppState, v: &mut Violations) {
+        v.id("device_id", &self.device_id);
+        if let Some(p) = &self.protocol { v.protocol(s, "protocol", p); }
+        if let Some(offered) = &self.accept_protocols { v.check(!offered.is_empty(), "accept_protocols", "must not be empty"); }
+        for (field, region) in [("region", &self.region), ("standby_region", &self.standby_region), ("home_region", &self.home_region)] {
+            if let Some(r) = region { v.id(field, r); }
+        }
+        if let Some(p) = &self.priority { v.one_of("priority", p, validate::PRIORITIES); }
+    }
+}
+
+impl Validate for SyncRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.id("connection_id", &self.connection_id);
+        if let Some(p) = &self.priority { v.one_of("priority", p, validate::PRIORITIES); }
+        if let Some(e) = &self.envelope {
+            v.check(self.sdf_delta.is_none(), "sdf_delta", "must not be set together with envelope");
+            envelope::validate(e, "envelope", v);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TransformQuery { #[serde(default)] dry_run: bool }
+/// Outcome of `/transform/validate`; `errors` names the stage (schema, validate, decode, units, encode) that failed.
+#[derive(Serialize)]
+struct TransformReport { valid: bool, source: String, target: String, #[serde(skip_serializing_if = "Option::is_none")] sdf: Option<serde_json::Value>, #[serde(skip_serializing_if = "Option::is_none")] output: Option<serde_json::Value>, errors: Vec<StageError> }
+#[derive(Serialize)]
+struct StageError { stage: &'static str, protocol: String, message: String }
+
+impl Validate for TransformRequest {
+    fn validate(&self, s: &AppState, v: &mut Violations) {
+        if self.pipeline.is_empty() {
+            v.protocol(s, "source_protocol", &self.source_protocol);
+            v.protocol(s, "target_protocol", &self.target_protocol);
+            return;
+        }
+        v.check((2..=MAX_PIPELINE).contains(&self.pipeline.len()), "pipeline", format!("must list 2 to {MAX_PIPELINE} protocols"));
+        for (i, p) in self.pipeline.iter().enumerate() { v.protocol(s, format!("pipeline[{i}]"), p); }
+        v.check(self.source_protocol.is_empty() || self.pipeline.first() == Some(&self.source_protocol), "source_protocol", "must match the first pipeline entry");
+        v.check(self.target_protocol.is_empty() || self.pipeline.last() == Some(&self.target_protocol), "target_protocol", "must match the last pipeline entry");
+    }
+}
+
+impl Validate for MeshRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check((1..=1000).contains(&self.devices.len()), "devices", "must list 1-1000 devices");
+        for (i, d) in self.devices.iter().enumerate() { v.id(format!("devices[{i}]"), d); }
+        v.check(self.devices.iter().collect::<std::collections::HashSet<_>>().len() == self.devices.len(), "devices", "must not contain duplicates");
+        if let Some(t) = &self.topology { v.one_of("topology", t, validate::TOPOLOGIES); }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProtocolInfo { name: String, description: String, latency_ms: f64, throughput_mbps: f64, #[serde(skip_serializing_if = "Option::is_none")] version: Option<String>, #[serde(skip_serializing_if = "Option::is_none")] observed: Option<protocols::Observed> }
+#[derive(Serialize)]
+struct StatsResponse { total_connections: u64, total_syncs: u64, total_transforms: u64, bytes_relayed: u64, duplicates_suppressed: u64, active_meshes: u32, pressure: pressure::PressureSnapshot }
+
+/// Runs the gateway until it is shut down; the `gateway-engine` binary is just this.
+pub async fn run() {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "gateway_engine=info".into()))
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel::layer())
+        .init();
+    let telemetry = telemetry::from_env().await;
+    tracing::info!("Telemetry backend: {}", telemetry.name());
+    let shared = shared::from_env().await;
+    tracing::info!("State backend: {}", shared.name());
+    let keys = Arc::new(keystore::Keystore::from_env().await);
+    let wal = Arc::new(wal::Wal::from_env());
+    let deliveries = Arc::new(deliveries::Deliveries::from_env());
+    let breakers = Arc::new(resilience::Breakers::new(resilience::BreakerConfig::from_env()));
+    let state = Arc::new(AppState {
+        start_time: Instant::now(),
+        stats: Mutex::new(Stats { total_connections: 0, total_syncs: 0, total_transforms: 0, bytes_relayed: 0, duplicates_suppressed: 0, tenants: HashMap::new() }),
+        connections: Mutex::new(HashMap::new()),
+        foreign_connections: Mutex::new(HashMap::new()),
+        standbys: Mutex::new(HashMap::new()),
+        hub: hub::Hub::from_env(deliveries.clone()),
+        deliveries,
+        dlq: dlq::Dlq::from_env(),
+        dedup: dedup::Dedup::from_env(),
+        webhooks: Mutex::new(HashMap::new()),
+        http: reqwest::Client::new(),
+        webhook_egress: webhooks::Egress::from_env(),
+        telemetry,
+        alert_rules: Mutex::new(HashMap::new()),
+        metric_samples: Mutex::new(std::collections::VecDeque::new()),
+        api_keys: Mutex::new(HashMap::new()),
+        maintenance: AtomicBool::new(false),
+        windows: maintenance::Windows::default(),
+        unit_rules: units::Rules::default(),
+        chaos: chaos::Chaos::from_env(),
+        benchmarking: AtomicBool::new(false),
+        relay: relay::Relay::from_env(breakers.clone()),
+        breakers,
+        shadows: Mutex::new(HashMap::new()),
+        usage: Mutex::new(HashMap::new()),
+        default_quota: usage::Quota::from_env(),
+        shaping: shaping::Shaper::from_env(),
+        versions: versioning::Versions::from_env(),
+        meshes: Mutex::new(HashMap::new()),
+        shared,
+        sequences: Mutex::new(HashMap::new()),
+        uploads: Mutex::new(HashMap::new()),
+        protocols: protocols::Registry::from_env(),
+        coap: coap::Bridge::from_env(),
+        outbox: outbox::Outbox::from_env(),
+        audit: audit::AuditLog::from_env(),
+        schedules: Mutex::new(HashMap::new()),
+        migrating: Mutex::new(Default::default()),
+        pressure: pressure::Pressure::from_env(),
+        sync_log: synclog::SyncLog::from_env(),
+        groups: groups::Groups::default(),
+        jobs: jobs::Jobs::from_env(),
+        transform_cache: transform_cache::TransformCache::from_env(),
+        access_log: accesslog::AccessLog::from_env(),
+        snapshots: snapshots::Snapshots::from_env(keys.clone(), wal.clone()),
+        wal,
+        keys,
+        geofence: geofence::Policies::default(),
+        routing: routing::Routing::from_env(),
+        cors: cors::Cors::from_env(),
+        resume: resume::Sessions::from_env(),
+        encryption: envelope::Encryption::from_env(),
+        anomalies: anomaly::Detector::from_env(),
+        validators: conditional::Validators::default(),
+        provisioning: provisioning::Registry::from_env(),
+        schemas: schemas::Schemas::default(),
+        regions: regions::Tracker::from_env(),
+        trash: trash::Trash::from_env(),
+        limits: concurrency::Limits::from_env(),
+        #[cfg(feature = "kafka")]
+        kafka: kafka::Sink::from_env(),
+        #[cfg(feature = "emulator")]
+        emulator: emulator::Emulator::default(),
+    });
+    state.wal.recover(&state);
+    tokio::spawn(wal::fsync_loop(state.clone()));
+    tokio::spawn(webhooks::dispatch(state.clone()));
+    tokio::spawn(alerts::evaluate_loop(state.clone()));
+    tokio::spawn(anomaly::evaluate_loop(state.clone()));
+    #[cfg(feature = "kafka")]
+    tokio::spawn(kafka::run(state.clone()));
+    #[cfg(not(feature = "kafka"))]
+    if std::env::var("KAFKA_BROKERS").is_ok_and(|b| !b.is_empty()) { tracing::warn!("KAFKA_BROKERS is set but this build lacks the `kafka` feature; events are not published to Kafka"); }
+    tokio::spawn(coap::serve(state.clone()));
+    tokio::spawn(quic::serve(state.clone()));
+    tokio::spawn(schedules::run_loop(state.clone()));
+    tokio::spawn(maintenance::run_loop(state.clone()));
+    tokio::spawn(mesh::heal_loop(state.clone()));
+    tokio::spawn(snapshots::compact_loop(state.clone()));
+    tokio::spawn(archive::sweep_loop(state.clone()));
+    tokio::spawn(uploads::sweep_loop(state.clone()));
+    tokio::spawn(keystore::rotation_loop(state.clone()));
+    tokio::spawn(regions::probe_loop(state.clone()));
+    let mut servers = tokio::task::JoinSet::new();
+    for l in listeners::from_env() {
+        let (listener, app) = (l.bind().await, app(&state, &l.surfaces));
+        servers.spawn(l.serve(listener, app));
+    }
+    while let Some(done) = servers.join_next().await { done.unwrap(); }
+}
+
+/// The router of a listener serving `surfaces` (see [`listeners`]).
+fn app(state: &Arc<AppState>, surfaces: &[Surface]) -> Router {
+    let mut app = Router::new()
+        .route("/health", get(health))
+        .route("/health/live", get(health::live))
+        .route("/health/ready", get(health::ready));
+    if surfaces.contains(&Surface::Admin) { app = app.route("/metrics", get(metrics::render)); }
+    if surfaces.iter().any(|s| *s != Surface::Admin) {
+        for (prefix, v) in [("/api/v1", Version::V1), ("/api/v2", Version::V2)] {
+            let mut api = Router::new();
+            if surfaces.contains(&Surface::Api) { api = api.merge(api_routes(state, v)); }
+            if surfaces.contains(&Surface::Device) { api = api.merge(device_routes(state, v)); }
+            app = app.nest(prefix, api);
+        }
+    }
+    let mut app = app
+        .layer(axum::middleware::from_fn_with_state(state.clone(), conditional::conditional_mw))
+        .layer(validate::body_limit("BODY_LIMIT_BYTES", 1024 * 1024))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), admin::maintenance_mw))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), chaos::chaos_mw));
+    if surfaces.contains(&Surface::Admin) { app = app.route("/internal/keys/verify", post(apikeys::verify)).nest("/admin", admin::router()); }
+    app.layer(axum::middleware::from_fn_with_state(state.clone(), accesslog::access_log_mw))
+        .layer(axum::middleware::from_fn(rbac::gateway_hop_mw))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), cors::cors_mw)).layer(TraceLayer::new_for_http()).with_state(state.clone())
+}
+
+async fn health(State(s): State<Arc<AppState>>) -> Json<Health> {
+    let st = s.stats.lock().unwrap();
+    Json(Health { status: "ok".into(), version: env!("CARGO_PKG_VERSION").into(), uptime_secs: s.start_time.elapsed().as_secs(), total_ops: st.total_connections + st.total_syncs })
+}
+
+fn endpoint_for(region: &str, protocol: &str) -> String {
+    match protocol {
+        quic::PROTOCOL => format!("quic://gateway.alicelaw.net:{}/{}", quic::public_port(), region),
+        _ => format!("wss://gateway.alicelaw.net/{}", region),
+    }
+}
+
+#[tracing::instrument(name = "gateway.connect", skip_all, fields(tenant = %tenant, device_id = %req.device_id, connection_id = tracing::field::Empty))]
+async fn connect(State(s): State<Arc<AppState>>, _: Require<Operate>, audit::Actor(actor): audit::Actor, Tenant(tenant): Tenant, peer: Option<Extension<tls::PeerIdentity>>, geofence::ClientIp(ip): geofence::ClientIp, Valid(req): Valid<ConnectRequest>) -> Result<Json<ConnectResponse>, ApiError> {
+    Ok(Json(s.open_connection(&tenant, &actor, peer.as_ref().map(|Extension(p)| p), ip, req).await?))
+}
+
+impl AppState {
+    /// The connect pipeline shared by HTTP `/connect` and the QUIC listener; `req` is validated.
+    async fn open_connection(&self, tenant: &str, actor: &str, peer: Option<&tls::PeerIdentity>, client_ip: Option<std::net::IpAddr>, req: ConnectRequest) -> Result<ConnectResponse, ApiError> {
+        let (req, admission) = self.provisioning.admit(self, tenant, actor, req)?;
+        let mut resp = self.establish(tenant, actor, peer, client_ip, req).await?;
+        match self.provisioning.complete(self, tenant, actor, &resp.device_id, admission) {
+            Ok(credential) => resp.device_credential = credential,
+            Err(e) => { self.drop_connection(&resp.connection_id); return Err(e); }
+        }
+        Ok(resp)
+    }
+
+    /// The connect pipeline past provisioning; migrations enter here, admitted by their source region.
+    async fn establish(&self, tenant: &str, actor: &str, peer: Option<&tls::PeerIdentity>, client_ip: Option<std::net::IpAddr>, req: ConnectRequest) -> Result<ConnectResponse, ApiError> {
+        if let Some(peer) = peer { peer.authorize(&req.device_id)?; }
+        let protocol = match (req.protocol, req.accept_protocols) {
+            (Some(p), _) => self.protocols.canonical(&p),
+            (None, Some(offered)) => self.protocols.negotiate(&offered)?,
+            (None, None) => "sdf-stream".into(),
+        };
+        let region = req.region.unwrap_or_else(|| "us-east-1".into());
+        if let Err((policy, reason)) = self.geofence.check(self, tenant, &req.device_id, &region, client_ip) {
+            self.audit.record(actor, "device.connect.denied", Some(tenant), Some(&req.device_id), serde_json::json!({ "policy_id": policy, "region": region, "client_ip": client_ip, "reason": reason }));
+            return Err(api_err(StatusCode::FORBIDDEN, "Connection denied by geo-fencing policy", Some(reason)).code("geofence"));
+        }
+        self.regions.admit(self, &region)?;
+        if let Some(token) = &req.resume_token {
+            if let Some(resumed) = resume::resume(self, tenant, actor, &req.device_id, token).await? { return Ok(resumed); }
+        }
+        let upstream = match req.home_region {
+            Some(home) if self.relay.relays_to(&home) => Some(Upstream { connection_id: self.relay.register(&home, tenant, &req.device_id, &protocol).await?, home_region: home }),
+            _ => None,
+        };
+        let relayed_to = upstream.as_ref().map(|u| u.home_region.clone());
+        let connection_id = uuid::Uuid::new_v4().to_string();
+        tracing::Span::current().record("connection_id", connection_id.as_str());
+        self.stats.lock().unwrap().add(tenant, [1, 0, 0, 0]);
+        let conn = Connection { tenant: tenant.into(), device_id: req.device_id.clone(), protocol: protocol.clone(), region: region.clone(), upstream };
+        self.shared.save_connection(&connection_id, &conn).await;
+        self.shared.incr(&[("total_connections", 1)]).await;
+        self.connections.lock().unwrap().insert(connection_id.clone(), conn);
+        self.emit("connect", tenant, serde_json::json!({ "connection_id": connection_id, "device_id": req.device_id, "protocol": protocol, "region": region }));
+        self.audit.record(actor, "device.connect", Some(tenant), Some(&req.device_id), serde_json::json!({ "connection_id": connection_id, "protocol": protocol, "region": region }));
+        let standby = match (req.priority.as_deref(), req.standby_region) {
+            (Some("high"), Some(sr)) if sr != region => Some(standby::register(self, tenant, &connection_id, &protocol, sr)),
+            _ => None,
+        };
+        let resume_token = Some(self.resume.issue(tenant, &req.device_id, &connection_id));
+        Ok(ConnectResponse { connection_id, device_id: req.device_id, endpoint: endpoint_for(&region, &protocol), protocol, region, status: "connected".into(), standby, relayed_to, resume_token, device_credential: None })
+    }
+}
+
+async fn disconnect(State(s): State<Arc<AppState>>, _: Require<Operate>, audit::Actor(actor): audit::Actor, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    if s.lookup_connection(&id).await.is_none_or(|c| c.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown connection", None)); }
+    // A device-side close stays resumable for the grace period.
+    let sequence = replay::last(&s, &id);
+    if let Some(conn) = s.drop_connection(&id) {
+        s.trash.put_connection(&id, conn.clone(), sequence, &actor);
+        s.resume.park(&id, conn, sequence);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl AppState {
+    /// Local registry first, then the shared backend (connections opened on another replica). The
+    /// backend stays the source of truth for those: a cached copy is looked up again once it is
+    /// older than [`shared::foreign_ttl`], and forgotten when its owner has dropped it.
+    async fn lookup_connection(&self, id: &str) -> Option<Connection> {
+        let stale = self.foreign_connections.lock().unwrap().get(id).is_some_and(|at| at.elapsed() >= shared::foreign_ttl());
+        if !stale {
+            if let Some(c) = self.connections.lock().unwrap().get(id) { return Some(c.clone()); }
+        }
+        let Some(c) = self.shared.load_connection(id).await else {
+            if stale { self.forget_connection(id); }
+            return None;
+        };
+        self.connections.lock().unwrap().insert(id.to_string(), c.clone());
+        self.foreign_connections.lock().unwrap().insert(id.to_string(), Instant::now());
+        Some(c)
+    }
+
+    /// Removes a connection and its standbys, emitting `disconnect` to the owning tenant.
+    fn drop_connection(&self, id: &str) -> Option<Connection> {
+        let conn = self.forget_connection(id)?;
+        let (shared, key) = (self.shared.clone(), id.to_string());
+        tokio::spawn(async move { shared.delete_connection(&key).await });
+        self.emit("disconnect", &conn.tenant, serde_json::json!({ "connection_id": id, "device_id": conn.device_id, "region": conn.region }));
+        Some(conn)
+    }
+
+    /// Clears a connection's state on this replica only.
+    fn forget_connection(&self, id: &str) -> Option<Connection> {
+        let conn = self.connections.lock().unwrap().remove(id)?;
+        self.foreign_connections.lock().unwrap().remove(id);
+        self.standbys.lock().unwrap().retain(|_, sb| sb.connection_id != id);
+        self.sequences.lock().unwrap().remove(id);
+        self.coap.forget_connection(id);
+        self.outbox.detach(id);
+        self.shaping.forget(id);
+        self.wal.remove(id);
+        self.dedup.forget(id);
+        self.snapshots.forget(id);
+        self.encryption.forget(id);
+        Some(conn)
+    }
+}
+
+/// The device surface under `/api/v1` and `/api/v2`: what devices call, which [`listeners`] can
+/// serve apart from the rest of the API.
+fn device_routes(state: &Arc<AppState>, v: Version) -> Router<Arc<AppState>> {
+    let limits = &state.limits;
+    let api = Router::new()
+        .route("/gateway/connect", post(connect).layer(axum::middleware::from_fn_with_state(state.clone(), idempotency::idempotency_mw)))
+        .route("/gateway/sync", limits.apply(Group::Sync, post(sync_data).layer(tower::ServiceBuilder::new().layer(validate::body_limit("SYNC_BODY_LIMIT_BYTES", 8 * 1024 * 1024)).layer(axum::middleware::from_fn_with_state(state.clone(), idempotency::idempotency_mw)))))
+        .route("/gateway/sync/transaction", limits.apply(Group::Sync, post(transaction::sync_transaction).layer(tower::ServiceBuilder::new().layer(validate::body_limit("SYNC_BODY_LIMIT_BYTES", 8 * 1024 * 1024)).layer(axum::middleware::from_fn_with_state(state.clone(), idempotency::idempotency_mw)))))
+        .route("/gateway/sync/uploads", post(uploads::start))
+        .route("/gateway/sync/uploads/:id", get(uploads::status).delete(uploads::abort))
+        .route("/gateway/sync/uploads/:id/chunks/:index", limits.apply(Group::Sync, put(uploads::put_chunk).layer(axum::extract::DefaultBodyLimit::max(uploads::MAX_CHUNK))))
+        .route("/gateway/sync/uploads/:id/complete", post(uploads::complete))
+        .route("/gateway/connections/:id/ws", get(outbox::ws))
+        .route("/telemetry", limits.apply(Group::Sync, post(telemetry::ingest).layer(validate::body_limit("TELEMETRY_BODY_LIMIT_BYTES", 4 * 1024 * 1024))));
+    v.apply(state, api)
+}
+
+/// The rest of the routes under `/api/v1` and `/api/v2`; `v` adds the version's response shape
+/// (see [`versioning`]).
+fn api_routes(state: &Arc<AppState>, v: Version) -> Router<Arc<AppState>> {
+    let limits = &state.limits;
+    let api = Router::new()
+        .route("/gateway/syncs/export", limits.apply(Group::Query, get(synclog::export).layer(tower_http::compression::CompressionLayer::new())))
+        .route("/gateway/transform", limits.apply(Group::Transform, post(transform).layer(validate::body_limit("SYNC_BODY_LIMIT_BYTES", 8 * 1024 * 1024))))
+        .route("/gateway/transform/rules", v.list(post(units::create).get(units::list), &["id"]))
+        .route("/gateway/transform/rules/:id", delete(units::remove))
+        .route("/gateway/transform/validate", limits.apply(Group::Transform, post(validate_transform).layer(validate::body_limit("SYNC_BODY_LIMIT_BYTES", 8 * 1024 * 1024))))
+        .route("/schemas", v.list(get(schemas::list), &["protocol"]))
+        .route("/schemas/:protocol", delete(schemas::remove))
+        .route("/schemas/:protocol/config", get(schemas::get_config).put(schemas::set_config))
+        .route("/schemas/:protocol/versions", v.list(post(schemas::register).get(schemas::versions), &["version"]))
+        .route("/schemas/:protocol/versions/:version", get(schemas::get_version).delete(schemas::remove_version))
+        .route("/gateway/schedules", v.list(post(schedules::create).get(schedules::list), &["id"]))
+        .route("/gateway/schedules/:id", get(schedules::get_schedule).delete(schedules::remove))
+        .route("/gateway/schedules/:id/runs", v.list(get(schedules::runs).post(schedules::trigger), &["started_at_ms", "id"]))
+        .route("/gateway/events", get(subscriptions::sse))
+        .route("/gateway/events/ws", get(subscriptions::ws))
+        .route("/alice.gateway.v1.Events/Subscribe", post(grpc_web::subscribe))
+        .route("/gateway/events/subscribers", v.list(get(hub::subscribers), &["id"]))
+        .route("/gateway/dashboard/ws", get(dashboard::ws))
+        .route("/gateway/deliveries/:id", get(deliveries::get))
+        .route("/gateway/maintenance", get(maintenance::for_tenant))
+        .route("/gateway/dedup/offenders", v.list(get(dedup::offenders), &["device_id"]))
+        .route("/gateway/dlq", v.list(get(dlq::list).delete(dlq::purge), &["first_failed_at_ms", "id"]))
+        .route("/gateway/dlq/:id", get(dlq::get).delete(dlq::remove))
+        .route("/gateway/dlq/:id/retry", post(dlq::retry))
+        .route("/gateway/mesh", v.list(post(create_mesh).get(trash::list_meshes), &["mesh_id"]))
+        .route("/gateway/mesh/:id", get(mesh::get_mesh).delete(trash::delete_mesh))
+        .route("/gateway/mesh/:id/restore", post(trash::restore_mesh))
+        .route("/gateway/mesh/:id/links", put(mesh::update_links))
+        .route("/gateway/mesh/:id/route", get(mesh::route))
+        .route("/gateway/mesh/:id/analysis", get(mesh::analysis))
+        .route("/gateway/protocols", v.list(get(protocols), &["name"]))
+        .route("/gateway/stats", limits.apply(Group::Query, get(stats)))
+        .route("/gateway/anomalies", limits.apply(Group::Query, v.list(get(anomaly::list), &["device_id", "metric"])))
+        .route("/gateway/failover", post(standby::failover))
+        .route("/gateway/regions", limits.apply(Group::Query, v.list(get(regions::capacity), &["region"])))
+        .route("/gateway/regions/health", v.list(get(regions::health), &["region"]))
+        .route("/gateway/connections", limits.apply(Group::Query, v.list(get(outbox::list_connections), &["connection_id"])))
+        .route("/gateway/connections/:id", get(outbox::get_connection).delete(disconnect))
+        .route("/gateway/connections/:id/restore", post(trash::restore_connection))
+        .route("/gateway/connections/:id/objects", v.cursor(get(objects::list), "objects"))
+        .route("/gateway/connections/:id/objects/index", get(objects::index_stats))
+        .route("/gateway/connections/:id/snapshot", get(snapshots::get_snapshot))
+        .route("/gateway/connections/:id/compact", post(snapshots::compact))
+        .route("/gateway/connections/:id/replay", limits.apply(Group::Query, post(snapshots::replay)))
+        .route("/gateway/connections/:id/envelopes", v.list(get(envelope::list), &["received_at_ms", "sequence"]))
+        .route("/gateway/connections/:id/migrate", post(migrate::migrate))
+        .route("/gateway/connections/import", post(migrate::import))
+        .route("/gateway/connections/:id/messages", post(outbox::post_message))
+        .route("/gateway/bridges/coap", get(coap::status))
+        .route("/gateway/bridges/coap/observers/:id", delete(coap::cancel_observer))
+        .route("/devices", v.list(post(provisioning::create).get(provisioning::list), &["device_id"]))
+        .route("/devices/:device_id", get(provisioning::get_device).delete(provisioning::remove))
+        .route("/devices/:device_id/token", post(provisioning::reissue))
+        .route("/gateway/devices/:device_id/tags", get(groups::get_tags).put(groups::put_tags))
+        .route("/gateway/groups", v.list(post(groups::create).get(groups::list), &["id"]))
+        .route("/gateway/groups/:id", get(groups::get_group).delete(groups::remove))
+        .route("/gateway/groups/:id/members", post(groups::update_members))
+        .route("/gateway/groups/:id/actions", post(groups::start_action))
+        .route("/gateway/policies/geofence", v.list(post(geofence::create).get(geofence::list), &["id"]))
+        .route("/gateway/policies/geofence/:id", delete(geofence::remove))
+        .route("/jobs", v.list(get(jobs::list), &["created_at_ms", "id"]))
+        .route("/jobs/:id", get(jobs::get_job))
+        .route("/jobs/:id/cancel", post(jobs::cancel))
+        .route("/routing/priority/drain", post(routing::drain))
+        .route("/gateway/devices/:device_id/shadow", get(shadow::get_shadow).put(shadow::put_shadow))
+        .route("/tenants/:id/usage", get(usage::export))
+        .route("/tenants/:id/keys", v.list(post(apikeys::create).get(apikeys::list_own), &["created_at_ms", "prefix"]))
+        .route("/tenants/:id/keys/:prefix", delete(apikeys::revoke_own))
+        .route("/tenants/:id/keys/:prefix/rotate", post(apikeys::rotate_own))
+        .route("/analytics/rollup", limits.apply(Group::Query, v.list(get(telemetry::rollup), &["bucket_start_ms"])))
+        .route("/webhooks", v.list(post(webhooks::create).get(webhooks::list), &["id"]))
+        .route("/webhooks/:id", delete(webhooks::remove))
+        .route("/webhooks/:id/deliveries", v.list(get(webhooks::deliveries), &["timestamp_ms", "event_id", "attempt"]))
+        .route("/auth/permissions", get(rbac::permissions))
+        .route("/alerts", v.list(get(alerts::firing), &["id"]))
+        .route("/alerts/rules", v.list(post(alerts::create).get(alerts::list), &["id"]))
+        .route("/alerts/rules/:id", delete(alerts::remove));
+    #[cfg(feature = "simulate")]
+    let api = api.route("/simulate", post(simulate::start));
+    #[cfg(feature = "emulator")]
+    let api = api
+        .route("/emulator/devices", v.list(post(emulator::create).get(emulator::list), &["device_id"]))
+        .route("/emulator/devices/:device_id", get(emulator::get_device).delete(emulator::remove))
+        .route("/emulator/devices/:device_id/failures", put(emulator::set_failures))
+        .route("/emulator/devices/:device_id/heartbeat", post(emulator::heartbeat))
+        .route("/emulator/devices/:device_id/sync", post(emulator::sync))
+        .route("/emulator/devices/:device_id/inbox", v.list(get(emulator::inbox), &["queued_at_ms", "id"]));
+    v.apply(state, api)
+}
+
+#[tracing::instrument(name = "gateway.sync", skip_all, fields(tenant = %tenant, connection_id = %req.connection_id, device_id = tracing::field::Empty, bytes = tracing::field::Empty))]
+async fn sync_data(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, peer: Option<Extension<tls::PeerIdentity>>, Negotiated { body: req, respond_with, wire_bytes }: Negotiated<SyncRequest>) -> Result<Encoded<SyncResponse>, ApiError> {
+    Ok(Encoded(respond_with, s.process_sync(&tenant, peer.as_ref().map(|Extension(p)| p), req, wire_bytes).await?))
+}
+
+impl AppState {
+    /// The sync pipeline shared by HTTP `/sync`, the CoAP bridge and QUIC; every attempt lands in
+    /// the sync history, and a delta that cannot be delivered lands in the dead-letter queue.
+    async fn process_sync(&self, tenant: &str, peer: Option<&tls::PeerIdentity>, req: SyncRequest, wire_bytes: usize) -> Result<SyncResponse, ApiError> {
+        let result = self.attempt_sync(tenant, peer, &req, wire_bytes).await;
+        if let Err(e) = &result { self.dlq.sync_failed(self, tenant, &req, e); }
+        result
+    }
+
+    async fn attempt_sync(&self, tenant: &str, peer: Option<&tls::PeerIdentity>, req: &SyncRequest, wire_bytes: usize) -> Result<SyncResponse, ApiError> {
+        let t = Instant::now();
+        let connection_id = req.connection_id.clone();
+        let priority = pressure::Priority::parse(req.priority.as_deref());
+        let result = self.run_sync(tenant, peer, req, wire_bytes, t).await;
+        if result.is_ok() { self.pressure.observe(priority, t.elapsed()); }
+        let device_id = self.connections.lock().unwrap().get(&connection_id).map(|c| c.device_id.clone());
+        if let Some(device_id) = &device_id {
+            let failed = result.as_ref().is_err_and(|e| e.status != StatusCode::TOO_MANY_REQUESTS);
+            if result.is_ok() || failed { self.anomalies.observe(self, tenant, device_id, wire_bytes as u64, failed); }
+        }
+        self.sync_log.record(tenant, &connection_id, device_id, wire_bytes as u64, t.elapsed(), &result);
+        result
+    }
+
+    async fn run_sync(&self, tenant: &str, peer: Option<&tls::PeerIdentity>, req: &SyncRequest, wire_bytes: usize, started: Instant) -> Result<SyncResponse, ApiError> {
+        let _permit = self.pressure.admit(&req.connection_id, pressure::Priority::parse(req.priority.as_deref())).await?;
+        let found = self.lookup_connection(&req.connection_id).await.filter(|c| c.tenant == tenant).map(|c| (c.device_id, c.protocol, c.upstream.map(|u| (u.home_region, u.connection_id))));
+        let Some((device_id, protocol, upstream)) = found else {
+            self.emit("sync-failure", tenant, serde_json::json!({ "connection_id": req.connection_id, "reason": "unknown connection" }));
+            return Err(api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(req.connection_id.clone())));
+        };
+        if let Some(peer) = peer { peer.authorize(&device_id)?; }
+        maintenance::check(self, tenant)?;
+        if self.migrating.lock().unwrap().contains(&req.connection_id) {
+            return Err(api_err(StatusCode::CONFLICT, "Connection is migrating", Some("reconnect to the endpoint returned by the migration".into())).code("migrating").retry_after(1));
+        }
+        let fingerprint = self.dedup.fingerprint(req);
+        if let Some(dup) = fingerprint.and_then(|fp| self.dedup.suppress(self, tenant, &device_id, &req.connection_id, fp, started)) {
+            self.stats.lock().unwrap().duplicates_suppressed += 1;
+            self.shared.incr(&[("duplicates_suppressed", 1)]).await;
+            return Ok(dup);
+        }
+        if let Err(e) = envelope::check(self, tenant, req) {
+            self.emit("sync-failure", tenant, serde_json::json!({ "connection_id": req.connection_id, "reason": e.body.code }));
+            return Err(e);
+        }
+        if let Some(Err(e)) = req.sdf_delta.as_ref().map(|d| self.schemas.enforce(tenant, &protocol, req.schema_version, d, "sdf_delta")) {
+            self.emit("sync-failure", tenant, serde_json::json!({ "connection_id": req.connection_id, "reason": e.body.code, "schema_version": req.schema_version }));
+            return Err(e);
+        }
+        let claim = match replay::check(self, req) {
+            Ok(claim) => claim,
+            Err(e) => {
+                self.emit("sync-failure", tenant, serde_json::json!({ "connection_id": req.connection_id, "reason": e.body.code, "sequence": req.sequence }));
+                return Err(e);
+            }
+        };
+        let sdf_delta = match &req.sdf_delta {
+            Some(d) => Some(routing::apply(self, tenant, &req.connection_id, &device_id, self.protocols.get(&protocol)?.decode(d).map_err(|e| protocols::invalid(&protocol, e))?)),
+            None => None,
+        };
+        let bytes = wire_bytes as u64;
+        let billed = usage::reserve(self, tenant, 1, bytes)?;
+        shaping::admit(self, tenant, &req.connection_id, &device_id, bytes).await?;
+        tracing::Span::current().record("device_id", device_id.as_str()).record("bytes", bytes);
+        let mut status = "synced";
+        let relayed = match upstream {
+            Some((home, upstream_id)) => match self.relay.forward_sync(&home, tenant, &upstream_id, req).await {
+                Ok(resp) => Some((home, resp.get("delivery_id").and_then(|d| d.as_str()).map(str::to_owned))),
+                Err(e) => {
+                    self.emit("sync-failure", tenant, serde_json::json!({ "connection_id": req.connection_id, "reason": "upstream relay failed", "home_region": home }));
+                    return Err(e.into());
+                }
+            },
+            None => None,
+        };
+        if let Some(Err(e)) = sdf_delta.as_ref().map(|d| self.wal.append(self, &req.connection_id, req.sequence, d)) {
+            self.emit("sync-failure", tenant, serde_json::json!({ "connection_id": req.connection_id, "reason": e.body.code, "sequence": req.sequence }));
+            return Err(e);
+        }
+        let sync_id = uuid::Uuid::new_v4().to_string();
+        let delivery_id = self.deliveries.begin(tenant, &sync_id, &req.connection_id, &device_id);
+        if let Some((home, remote)) = relayed {
+            self.deliveries.set(&delivery_id, &format!("upstream:{home}"), deliveries::Status::Delivered, remote);
+            status = "relayed";
+        }
+        if let Some(delta) = &sdf_delta {
+            shadow::apply_reported(self, tenant, &device_id, delta);
+            self.snapshots.append(tenant, &req.connection_id, &device_id, req.sequence, delta);
+            self.deliveries.set(&delivery_id, "merge", deliveries::Status::Delivered, None);
+            self.deliveries.notified(&delivery_id, self.coap.publish(tenant, &device_id, delta));
+            self.emit_tracked("delta", tenant, serde_json::json!({ "connection_id": req.connection_id, "device_id": device_id, "sequence": req.sequence, "delta": delta }), Some(&delivery_id));
+        }
+        let sealed = req.envelope.as_ref().is_some_and(|e| envelope::commit(self, tenant, &req.connection_id, &device_id, req.sequence, e, envelope::Origin::Sync { delivery: &delivery_id }));
+        self.stats.lock().unwrap().add(tenant, [0, 1, 0, bytes]);
+        self.shared.incr(&[("total_syncs", 1), ("bytes_relayed", bytes)]).await;
+        billed.commit();
+        claim.commit();
+        self.protocols.observe(&protocol, started.elapsed(), bytes);
+        let objects_synced = sdf_delta.as_ref().map_or(u32::from(sealed), |d| d.get("objects").and_then(|o| o.as_object()).or(d.as_object()).map_or(1, |o| o.len()) as u32);
+        let resp = SyncResponse { sync_id, status: status.into(), objects_synced, sdf_bytes_transferred: bytes, latency_ms: started.elapsed().as_secs_f64() * 1000.0, delivery_id: Some(delivery_id) };
+        if let Some(fp) = fingerprint { self.dedup.record(&req.connection_id, fp, &resp); }
+        Ok(resp)
+    }
+}
+
+#[tracing::instrument(name = "gateway.transform", skip_all, fields(pipeline = %req.chain().join(">"), bytes = tracing::field::Empty))]
+/// `?dry_run=true` returns the same response without counting towards stats. Repeated payloads
+/// are answered from the transform cache; their stages report no elapsed time.
+/// A failed transform is kept in the dead-letter queue, unless it was a dry run.
+async fn transform(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Query(q): Query<TransformQuery>, Negotiated { body: req, respond_with, wire_bytes }: Negotiated<TransformRequest>) -> Result<Encoded<TransformResponse>, ApiError> {
+    let result = run_transform(&s, &tenant, &req, q.dry_run, wire_bytes).await;
+    match &result {
+        Ok(_) => { tracing::Span::current().record("bytes", wire_bytes); }
+        Err(e) if !q.dry_run => s.dlq.transform_failed(&tenant, &req, e),
+        Err(_) => {}
+    }
+    Ok(Encoded(respond_with, result?))
+}
+
+async fn run_transform(s: &AppState, tenant: &str, req: &TransformRequest, dry_run: bool, wire_bytes: usize) -> Result<TransformResponse, ApiError> {
+    let t = Instant::now();
+    let chain = req.chain();
+    // Resolve every stage before running any, so an unknown protocol fails without partial work.
+    let routed = chain.iter().map(|p| s.protocols.route(p)).collect::<Result<Vec<_>, _>>()?;
+    s.schemas.enforce(tenant, chain[0], req.schema_version, &req.payload, "payload")?;
+    let rule = s.unit_rules.find(tenant, chain[0], chain[chain.len() - 1]);
+    let mut route = chain.iter().zip(&routed).map(|(p, (_, v))| if *v == protocols::Variant::Canary { format!("{p}@canary") } else { p.to_string() }).collect::<Vec<_>>().join(">");
+    if let Some(r) = &rule { route = format!("{route}|{}", r.fingerprint()); }
+    let key = s.transform_cache.key(s.protocols.generation(), route, &req.payload);
+    let (output, stages) = match key.as_ref().and_then(|k| s.transform_cache.get(k)) {
+        Some(output) => (output, chain.windows(2).map(|w| TransformStage { from: w[0].into(), to: w[1].into(), elapsed_us: 0 }).collect()),
+        None => {
+            let mut output = req.payload.clone();
+            let mut stages = Vec::with_capacity(chain.len() - 1);
+            let (mut failed, mut rejected) = (None, None);
+            for (i, hop) in routed.windows(2).enumerate() {
+                let st = Instant::now();
+                let mut sdf = match hop[0].0.decode(&output) { Ok(v) => v, Err(e) => { failed = Some((i, e)); break } };
+                if let Some(r) = rule.as_ref().filter(|_| i == 0) {
+                    if let Err(e) = r.apply(&mut sdf) { rejected = Some(e); break; }
+                }
+                match hop[1].0.encode(&sdf) { Ok(v) => output = v, Err(e) => { failed = Some((i + 1, e)); break } }
+                stages.push(TransformStage { from: chain[i].into(), to: chain[i + 1].into(), elapsed_us: st.elapsed().as_micros() as u64 });
+            }
+            if !dry_run {
+                // Canary error rates: the failing protocol counts an error, the ones it got past succeed.
+                let reached = if rejected.is_some() { 1 } else { failed.as_ref().map_or(chain.len(), |(i, _)| i + 1) };
+                for (i, (_, variant)) in routed.iter().enumerate().take(reached) { s.protocols.record(chain[i], *variant, failed.as_ref().is_none_or(|(f, _)| *f != i)); }
+            }
+            if let Some(e) = rejected { return Err(e); }
+            if let Some((i, e)) = failed { return Err(protocols::invalid(chain[i], e)); }
+            if let Some(k) = key { s.transform_cache.put(k, output.clone()); }
+            (output, stages)
+        }
+    };
+    if !dry_run {
+        s.protocols.observe(chain[0], t.elapsed(), wire_bytes as u64);
+        s.stats.lock().unwrap().add(tenant, [0, 0, 1, 0]);
+        s.shared.incr(&[("total_transforms", 1)]).await;
+    }
+    let (source, target) = (chain[0].to_string(), chain[chain.len() - 1].to_string());
+    let (transform_id, elapsed_us) = (uuid::Uuid::new_v4().to_string(), t.elapsed().as_micros());
+    if !dry_run { s.emit("transform", tenant, serde_json::json!({ "transform_id": transform_id, "source": source, "target": target, "pipeline": chain, "bytes": wire_bytes, "elapsed_us": elapsed_us })); }
+    Ok(TransformResponse { transform_id, source, target, output, elapsed_us, stages })
+}
+
+/// Checks a payload against the source protocol and previews the target output, reporting the
+/// failing stage with 200 instead of rejecting; nothing is counted. `sdf` is the first decode.
+async fn validate_transform(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Negotiated { body: req, .. }: Negotiated<TransformRequest>) -> Result<Json<TransformReport>, ApiError> {
+    let chain = req.chain();
+    let plugins = chain.iter().map(|p| s.protocols.get(p)).collect::<Result<Vec<_>, _>>()?;
+    let mut errors = Vec::new();
+    let mut fail = |stage, protocol: &str, message| errors.push(StageError { stage, protocol: protocol.into(), message });
+    for problem in s.schemas.problems(&tenant, chain[0], req.schema_version, &req.payload, "payload") { fail("schema", chain[0], problem); }
+    if let Err(e) = plugins[0].validate(&req.payload) { fail("validate", chain[0], e); }
+    let rule = s.unit_rules.find(&tenant, chain[0], chain[chain.len() - 1]);
+    let (mut sdf, mut output) = (None, Some(req.payload.clone()));
+    for (i, hop) in plugins.windows(2).enumerate() {
+        let Some(input) = output.take() else { break };
+        let Some(mut decoded) = hop[0].decode(&input).map_err(|e| fail("decode", chain[i], e)).ok() else { break };
+        if let Some(r) = rule.as_ref().filter(|_| i == 0) {
+            if let Err(e) = r.apply(&mut decoded) { fail("units", chain[0], e.body.details.unwrap_or(e.body.error)); break; }
+        }
+        output = hop[1].encode(&decoded).map_err(|e| fail("encode", chain[i + 1], e)).ok();
+        sdf.get_or_insert(decoded);
+    }
+    // decode re-runs validate on the builtins; report that failure once.
+    errors.dedup_by(|b, a| a.stage == "validate" && b.stage == "decode" && a.message == b.message);
+    let (source, target) = (chain[0].to_string(), chain[chain.len() - 1].to_string());
+    Ok(Json(TransformReport { valid: errors.is_empty(), source, target, sdf, output, errors }))
+}
+
+async fn create_mesh(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Valid(req): Valid<MeshRequest>) -> Json<MeshResponse> {
+    let topology = req.topology.unwrap_or_else(|| "full-mesh".into());
+    let count = req.devices.len();
+    let connections: Vec<MeshConnection> = if count >= 2 { (0..count-1).map(|i| MeshConnection { from: req.devices[i].clone(), to: req.devices[i+1].clone(), latency_ms: 15.0 + i as f64 * 5.0 }).collect() } else { vec![] };
+    let mesh_id = uuid::Uuid::new_v4().to_string();
+    let mesh = mesh::Mesh::new(mesh_id.clone(), tenant.clone(), req.devices.clone(), topology.clone(), connections.clone()).await;
+    s.meshes.lock().unwrap().insert(mesh_id.clone(), mesh);
+    s.emit("mesh-change", &tenant, serde_json::json!({ "mesh_id": mesh_id, "devices": req.devices, "topology": topology }));
+    Json(MeshResponse { mesh_id, devices: count, topology, connections, status: "established".into() })
+}
+
+async fn protocols(State(s): State<Arc<AppState>>, _: Require<Read>) -> Json<Vec<ProtocolInfo>> {
+    Json(s.protocols.list())
+}
+
+async fn stats(State(s): State<Arc<AppState>>, _: Require<Read>) -> Json<StatsResponse> {
+    let active_meshes = s.meshes.lock().unwrap().len() as u32;
+    if let Some(c) = s.shared.counters().await {
+        let get = |k: &str| c.get(k).copied().unwrap_or(0);
+        return Json(StatsResponse { total_connections: get("total_connections"), total_syncs: get("total_syncs"), total_transforms: get("total_transforms"), bytes_relayed: get("bytes_relayed"), duplicates_suppressed: get("duplicates_suppressed"), active_meshes, pressure: s.pressure.snapshot() });
+    }
+    let st = s.stats.lock().unwrap();
+    Json(StatsResponse { total_connections: st.total_connections, total_syncs: st.total_syncs, total_transforms: st.total_transforms, bytes_relayed: st.bytes_relayed, duplicates_suppressed: st.duplicates_suppressed, active_meshes, pressure: s.pressure.snapshot() })
+}
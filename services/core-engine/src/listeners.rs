@@ -0,0 +1,65 @@
+//! HTTP listeners. The engine serves up to three TCP listeners, each with its own bind address,
+//! TLS settings (see [`tls`]) and router:
+//!
+//! - public, on `GATEWAY_ADDR` (default `0.0.0.0:8081`) with `TLS_*`: the tenant API;
+//! - internal, on `INTERNAL_ADDR` with `INTERNAL_TLS_*`: `/admin`, `/metrics` and
+//!   `/internal/keys/verify`, so they can be bound to an interface only the cluster reaches;
+//! - device, on `DEVICE_ADDR` with `DEVICE_TLS_*`: connect, sync, uploads, telemetry and the
+//!   connection WebSocket. QUIC (`QUIC_ADDR`) is a device transport too and takes the same
+//!   certificate.
+//!
+//! A surface with its own listener is no longer served by the public one; with neither set,
+//! everything is on `GATEWAY_ADDR` as before. Every listener answers `/health`, so probes may
+//! target any of them.
+
+use crate::tls::{self, TlsConfig};
+use axum::Router;
+use tokio::net::TcpListener;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Surface { Api, Admin, Device }
+
+impl Surface {
+    fn as_str(self) -> &'static str {
+        match self { Surface::Api => "api", Surface::Admin => "admin", Surface::Device => "device" }
+    }
+}
+
+pub struct Listener { name: &'static str, addr: String, tls: Option<TlsConfig>, pub surfaces: Vec<Surface> }
+
+fn var(name: &str) -> Option<String> { std::env::var(name).ok().filter(|v| !v.is_empty()) }
+
+pub fn from_env() -> Vec<Listener> {
+    let (internal, device) = (var("INTERNAL_ADDR"), var("DEVICE_ADDR"));
+    let mut public = vec![Surface::Api];
+    if internal.is_none() { public.push(Surface::Admin); }
+    if device.is_none() { public.push(Surface::Device); }
+    let mut listeners = vec![Listener { name: "public", addr: var("GATEWAY_ADDR").unwrap_or_else(|| "0.0.0.0:8081".into()), tls: tls::config_from_env(""), surfaces: public }];
+    if let Some(addr) = internal { listeners.push(Listener { name: "internal", addr, tls: tls::config_from_env("INTERNAL_"), surfaces: vec![Surface::Admin] }); }
+    if let Some(addr) = device { listeners.push(Listener { name: "device", addr, tls: tls::config_from_env("DEVICE_"), surfaces: vec![Surface::Device] }); }
+    listeners
+}
+
+impl Listener {
+    /// Binds before anything is served, so a bad address stops startup.
+    pub async fn bind(&self) -> TcpListener {
+        TcpListener::bind(&self.addr).await.unwrap_or_else(|e| panic!("cannot bind the {} listener to {}: {e}", self.name, self.addr))
+    }
+
+    pub async fn serve(self, listener: TcpListener, app: Router) {
+        let (name, addr) = (self.name, &self.addr);
+        let surfaces = self.surfaces.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", ");
+        match &self.tls {
+            Some(cfg) => {
+                tracing::info!("Cloud Gateway Engine {name} listener on {addr} serving {surfaces} (TLS{})", if cfg.client_ca_path.is_some() { ", mTLS" } else { "" });
+                tls::serve(listener, app, cfg).await;
+            }
+            // Plaintext speaks HTTP/1.1 and prior-knowledge HTTP/2 (h2c) for proxies in front of browser
+            // consoles; over TLS, h2 is negotiated with ALPN.
+            None => {
+                tracing::info!("Cloud Gateway Engine {name} listener on {addr} serving {surfaces}");
+                axum::serve(listener, app).await.unwrap();
+            }
+        }
+    }
+}
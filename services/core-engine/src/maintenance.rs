@@ -0,0 +1,175 @@
+//! Scheduled maintenance windows. An operator schedules a window (`POST /admin/maintenance/windows`)
+//! with a start, an end and optionally the tenants and regions it covers (empty means all); while
+//! it is active on this gateway's region, writes from the covered tenants answer 503 with code
+//! `maintenance`, a `Retry-After` until the window ends and the notice under `maintenance`, and
+//! reads keep working. The two read-only POSTs, `/gateway/transform` and
+//! `/gateway/transform/validate`, stay open; CoAP and QUIC syncs are refused the same way.
+//!
+//! Covered tenants get `maintenance-scheduled`, `maintenance-started` and `maintenance-ended`
+//! events, and their connected devices the same notice as `{"maintenance": {...}}` through the
+//! outbox. A window that covers all tenants is announced to the tenants connected at the time.
+//! Ended windows are dropped; cancelling one (`DELETE`) ends it early. `GET /gateway/maintenance`
+//! shows a tenant the windows that cover it.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::pressure::Priority;
+use crate::rbac::{Admin, Read, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::{Method, StatusCode}, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const TICK: Duration = Duration::from_secs(1);
+/// POSTs that only compute and change nothing; open during maintenance.
+const READ_ONLY: &[&str] = &["/gateway/transform", "/gateway/transform/validate"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase { Scheduled, Active, Ended }
+
+#[derive(Clone, Serialize)]
+pub struct Window {
+    id: String, message: String, starts_at_ms: u64, ends_at_ms: u64, tenants: Vec<String>, regions: Vec<String>,
+    created_at_ms: u64, created_by: String, state: Phase,
+}
+
+/// What clients are told: in error bodies, events and device messages.
+#[derive(Clone, Serialize)]
+pub struct Notice { id: String, message: String, state: Phase, starts_at_ms: u64, ends_at_ms: u64 }
+
+#[derive(Deserialize)]
+pub struct WindowRequest { message: Option<String>, starts_at_ms: Option<u64>, ends_at_ms: u64, #[serde(default)] tenants: Vec<String>, #[serde(default)] regions: Vec<String> }
+
+impl Validate for WindowRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        let start = self.starts_at_ms.unwrap_or_else(now_ms);
+        v.check(self.ends_at_ms > start, "ends_at_ms", "must be after starts_at_ms");
+        v.check(self.ends_at_ms > now_ms(), "ends_at_ms", "must be in the future");
+        for (i, t) in self.tenants.iter().enumerate() { v.id(format!("tenants[{i}]"), t); }
+        for (i, r) in self.regions.iter().enumerate() { v.id(format!("regions[{i}]"), r); }
+    }
+}
+
+impl Window {
+    fn phase_at(&self, now: u64) -> Phase {
+        if now >= self.ends_at_ms { Phase::Ended } else if now >= self.starts_at_ms { Phase::Active } else { Phase::Scheduled }
+    }
+
+    fn covers(&self, tenant: &str, region: &str) -> bool {
+        (self.tenants.is_empty() || self.tenants.iter().any(|t| t == tenant)) && (self.regions.is_empty() || self.regions.iter().any(|r| r == region))
+    }
+
+    fn notice(&self) -> Notice {
+        Notice { id: self.id.clone(), message: self.message.clone(), state: self.state, starts_at_ms: self.starts_at_ms, ends_at_ms: self.ends_at_ms }
+    }
+}
+
+#[derive(Default)]
+pub struct Windows { windows: Mutex<Vec<Window>>, rejected: AtomicU64 }
+
+pub struct MaintenanceCounts { pub scheduled: usize, pub active: usize, pub rejected: u64 }
+
+impl Windows {
+    /// The active window covering `tenant` here that ends last, if any.
+    fn active(&self, tenant: &str, region: &str) -> Option<Window> {
+        let now = now_ms();
+        self.windows.lock().unwrap().iter().filter(|w| w.phase_at(now) == Phase::Active && w.covers(tenant, region)).max_by_key(|w| w.ends_at_ms).cloned()
+    }
+
+    pub fn counts(&self) -> MaintenanceCounts {
+        let now = now_ms();
+        let windows = self.windows.lock().unwrap();
+        let count = |p| windows.iter().filter(|w| w.phase_at(now) == p).count();
+        MaintenanceCounts { scheduled: count(Phase::Scheduled), active: count(Phase::Active), rejected: self.rejected.load(Ordering::Relaxed) }
+    }
+}
+
+/// Refuses a write from `tenant` while a window covers it on this gateway.
+pub fn check(s: &AppState, tenant: &str) -> Result<(), ApiError> {
+    let Some(mut w) = s.windows.active(tenant, &s.relay.local_region) else { return Ok(()) };
+    s.windows.rejected.fetch_add(1, Ordering::Relaxed);
+    w.state = Phase::Active;
+    let secs = w.ends_at_ms.saturating_sub(now_ms()).div_ceil(1000).max(1);
+    Err(api_err(StatusCode::SERVICE_UNAVAILABLE, "Maintenance in progress", Some(w.message.clone())).code("maintenance").retry_after(secs).maintenance(w.notice()))
+}
+
+/// Whether a request changes state; everything but GET/HEAD/OPTIONS and the read-only POSTs.
+pub fn writes(method: &Method, path: &str) -> bool {
+    let path = ["/api/v1", "/api/v2"].iter().find_map(|p| path.strip_prefix(p)).unwrap_or(path);
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) && !READ_ONLY.contains(&path)
+}
+
+fn here(s: &AppState, w: &Window) -> bool { w.regions.is_empty() || w.regions.contains(&s.relay.local_region) }
+
+/// Announces `w` as `kind` to the tenants it covers and their connected devices.
+fn announce(s: &AppState, w: &Window, kind: &str) {
+    let notice = w.notice();
+    let data = serde_json::to_value(&notice).unwrap_or_default();
+    let devices: HashSet<(String, String)> = s.connections.lock().unwrap().values()
+        .filter(|c| w.covers(&c.tenant, &s.relay.local_region)).map(|c| (c.tenant.clone(), c.device_id.clone())).collect();
+    let tenants: HashSet<&str> = if w.tenants.is_empty() { devices.iter().map(|(t, _)| t.as_str()).collect() } else { w.tenants.iter().map(String::as_str).collect() };
+    for tenant in tenants { s.emit(kind, tenant, data.clone()); }
+    for (tenant, device_id) in &devices { s.outbox.send(tenant, device_id, json!({ "maintenance": notice }), Priority::High); }
+    tracing::warn!(id = %w.id, kind, devices = devices.len(), "maintenance window announced");
+}
+
+/// Moves windows through their phases, announcing each transition.
+pub async fn run_loop(s: Arc<AppState>) {
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tick.tick().await;
+        let now = now_ms();
+        let changed: Vec<Window> = s.windows.windows.lock().unwrap().iter_mut().filter_map(|w| {
+            let phase = w.phase_at(now);
+            (phase != w.state).then(|| { w.state = phase; w.clone() })
+        }).collect();
+        s.windows.windows.lock().unwrap().retain(|w| w.state != Phase::Ended);
+        for w in changed.iter().filter(|w| here(&s, w)) {
+            announce(&s, w, if w.state == Phase::Active { "maintenance-started" } else { "maintenance-ended" });
+        }
+    }
+}
+
+pub async fn schedule(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Valid(req): Valid<WindowRequest>) -> (StatusCode, Json<Window>) {
+    let now = now_ms();
+    let w = Window {
+        id: uuid::Uuid::new_v4().to_string(), message: req.message.unwrap_or_else(|| "Scheduled maintenance".into()),
+        starts_at_ms: req.starts_at_ms.unwrap_or(now), ends_at_ms: req.ends_at_ms, tenants: req.tenants, regions: req.regions,
+        created_at_ms: now, created_by: actor.clone(), state: Phase::Scheduled,
+    };
+    s.audit.record(&actor, "admin.maintenance.schedule", None, Some(&w.id), json!({ "starts_at_ms": w.starts_at_ms, "ends_at_ms": w.ends_at_ms, "tenants": w.tenants, "regions": w.regions }));
+    if here(&s, &w) { announce(&s, &w, "maintenance-scheduled"); }
+    // A window starting now is announced as started on the next tick.
+    s.windows.windows.lock().unwrap().push(w.clone());
+    (StatusCode::CREATED, Json(Window { state: w.phase_at(now), ..w }))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Admin>) -> Json<Vec<Window>> {
+    let now = now_ms();
+    Json(s.windows.windows.lock().unwrap().iter().map(|w| Window { state: w.phase_at(now), ..w.clone() }).collect())
+}
+
+pub async fn cancel(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let removed = {
+        let mut windows = s.windows.windows.lock().unwrap();
+        windows.iter().position(|w| w.id == id).map(|i| windows.remove(i))
+    };
+    let Some(mut w) = removed else { return Err(api_err(StatusCode::NOT_FOUND, "Unknown maintenance window", Some(id))) };
+    s.audit.record(&actor, "admin.maintenance.cancel", None, Some(&id), json!({ "state": w.phase_at(now_ms()) }));
+    w.state = Phase::Ended;
+    if here(&s, &w) { announce(&s, &w, "maintenance-ended"); }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The scheduled and active windows covering the caller's tenant on this gateway.
+pub async fn for_tenant(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<Notice>> {
+    let now = now_ms();
+    Json(s.windows.windows.lock().unwrap().iter().filter(|w| w.covers(&tenant, &s.relay.local_region) && w.phase_at(now) != Phase::Ended)
+        .map(|w| Notice { state: w.phase_at(now), ..w.notice() }).collect())
+}
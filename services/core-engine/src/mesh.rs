@@ -0,0 +1,428 @@
+//! Stored meshes and their measured link graph. Devices report link latencies with
+//! `PUT /mesh/:id/links`; `GET /mesh/:id/route` runs Dijkstra over the current graph, so
+//! routes always reflect the latest measurements.
+//!
+//! A reconciliation loop (every `MESH_HEAL_INTERVAL_SECS`, default 15) treats mesh devices
+//! without an active connection as down: their links are parked, their live neighbours are
+//! bridged with the latency of the path they used to take through it, and the mesh turns
+//! `degraded` (`mesh-degraded`). When the device reconnects the bridges are dropped and its links
+//! restored (`mesh-healed`). The last transitions are kept in `healing` on the mesh.
+//!
+//! `GET /mesh/:id/analysis` reports the health of the live graph (down devices excluded):
+//! diameter in hops and latency, articulation points and critical links (single points of
+//! failure), a redundancy factor (links per spanning-forest link; 1.0 is a tree) and the
+//! partitions. It is recomputed whenever the links change, and a change that splits the mesh
+//! emits `mesh-partitioned`; one that joins it back up, `mesh-rejoined`. The graph is copied
+//! out of the registry and analysed without holding its lock (on the blocking pool for meshes
+//! over `INLINE_ANALYSIS_DEVICES` devices); the result is only stored if the links have not
+//! changed again meanwhile, since that change brings its own analysis.
+
+use crate::rbac::{Operate, Read, Require};
+use crate::events::now_ms;
+use crate::{api_err, ApiError, AppState, MeshConnection, Tenant};
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+const HEALING_HISTORY: usize = 50;
+const INLINE_ANALYSIS_DEVICES: usize = 64;
+
+#[derive(Clone, Serialize)]
+pub struct Mesh {
+    pub mesh_id: String, #[serde(skip)] pub tenant: String, pub devices: Vec<String>, pub topology: String, pub links: Vec<MeshConnection>, pub links_version: u64, pub status: String,
+    pub down: Vec<String>, pub healing: VecDeque<HealRecord>,
+    /// Links of down devices, restored when they come back; bridges are keyed by the device they route around.
+    #[serde(skip)] parked: Vec<MeshConnection>, #[serde(skip)] bridges: Vec<(String, MeshConnection)>,
+    #[serde(skip)] analysis: Analysis,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Analysis {
+    links_version: u64, computed_at_ms: u64, live_devices: usize, links: usize,
+    partitioned: bool, partitions: Vec<Vec<String>>,
+    /// Longest shortest path; null while the mesh is partitioned.
+    diameter_hops: Option<usize>, diameter_latency_ms: Option<f64>,
+    articulation_points: Vec<String>, critical_links: Vec<[String; 2]>,
+    redundancy_factor: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct AnalysisReport { mesh_id: String, status: String, down: Vec<String>, #[serde(flatten)] analysis: Analysis }
+
+#[derive(Clone, Serialize)]
+pub struct HealRecord { at_ms: u64, kind: &'static str, devices: Vec<String>, links_bridged: usize, links_version: u64 }
+
+#[derive(Deserialize)]
+pub struct LinkUpdate { from: String, to: String, latency_ms: Option<f64> }
+
+#[derive(Deserialize)]
+pub struct RouteQuery { from: String, to: String }
+
+#[derive(Serialize)]
+pub struct Hop { device_id: String, link_latency_ms: f64, cumulative_latency_ms: f64 }
+
+#[derive(Serialize)]
+pub struct Route { mesh_id: String, from: String, to: String, total_latency_ms: f64, hops: Vec<Hop>, links_version: u64 }
+
+impl Mesh {
+    pub async fn new(mesh_id: String, tenant: String, devices: Vec<String>, topology: String, links: Vec<MeshConnection>) -> Self {
+        let analysis = compute(devices.clone(), links.clone(), 0).await;
+        Mesh { mesh_id, tenant, devices, topology, links, links_version: 0, status: "established".into(), down: Vec::new(), healing: VecDeque::new(), parked: Vec::new(), bridges: Vec::new(), analysis }
+    }
+
+    /// The live devices, their links and the version they are at.
+    fn live_graph(&self) -> (Vec<String>, Vec<MeshConnection>, u64) {
+        (self.devices.iter().filter(|d| !self.down.contains(d)).cloned().collect(), self.links.clone(), self.links_version)
+    }
+
+    fn partition_event(&self, (before, after): (usize, usize)) -> Option<(&'static str, serde_json::Value)> {
+        let kind = if after > before && after > 1 { "mesh-partitioned" } else if before > 1 && after <= 1 { "mesh-rejoined" } else { return None };
+        Some((kind, serde_json::json!({ "mesh_id": self.mesh_id, "partitions": self.analysis.partitions, "articulation_points": self.analysis.articulation_points, "links_version": self.links_version })))
+    }
+
+    /// Applies the current liveness; returns the devices that went down and came back, plus new bridges.
+    fn reconcile(&mut self, live: &HashSet<String>) -> (Vec<String>, Vec<String>, usize) {
+        let recovered: Vec<String> = self.down.iter().filter(|d| live.contains(*d)).cloned().collect();
+        self.down.retain(|d| !recovered.contains(d));
+        for r in &recovered {
+            for (_, b) in self.bridges.iter().filter(|(via, _)| via == r) { self.links.retain(|l| !same_link(l, b)); }
+            self.bridges.retain(|(via, _)| via != r);
+        }
+        let down = &self.down;
+        let (restore, keep): (Vec<_>, Vec<_>) = self.parked.drain(..).partition(|l| !down.contains(&l.from) && !down.contains(&l.to));
+        self.parked = keep;
+        self.links.extend(restore);
+
+        let went_down: Vec<String> = self.devices.iter().filter(|d| !live.contains(*d) && !self.down.contains(d)).cloned().collect();
+        self.down.extend(went_down.iter().cloned());
+        let down = &self.down;
+        let (dead, alive): (Vec<_>, Vec<_>) = self.links.drain(..).partition(|l| down.contains(&l.from) || down.contains(&l.to));
+        self.links = alive;
+        // A bridge through a device that is now down is dropped rather than parked: it was never measured.
+        let dead: Vec<MeshConnection> = dead.into_iter().filter(|l| !self.bridges.iter().any(|(_, b)| same_link(l, b))).collect();
+        self.bridges.retain(|(_, b)| self.links.iter().any(|l| same_link(l, b)));
+        let mut bridged = 0;
+        for d in &went_down {
+            let mut around: Vec<(&str, f64)> = dead.iter().filter_map(|l| if &l.from == d { Some((l.to.as_str(), l.latency_ms)) } else if &l.to == d { Some((l.from.as_str(), l.latency_ms)) } else { None })
+                .filter(|(n, _)| !self.down.iter().any(|x| x == n)).collect();
+            around.sort_by(|a, b| a.1.total_cmp(&b.1));
+            for w in around.windows(2) {
+                let link = MeshConnection { from: w[0].0.into(), to: w[1].0.into(), latency_ms: w[0].1 + w[1].1 };
+                if self.links.iter().any(|l| same_link(l, &link)) { continue; }
+                self.links.push(link.clone());
+                self.bridges.push((d.clone(), link));
+                bridged += 1;
+            }
+        }
+        self.parked.extend(dead);
+
+        if !went_down.is_empty() || !recovered.is_empty() {
+            self.links_version += 1;
+            self.status = if self.down.is_empty() { "established" } else { "degraded" }.into();
+            let at_ms = now_ms();
+            if !went_down.is_empty() { self.healing.push_back(HealRecord { at_ms, kind: "degraded", devices: went_down.clone(), links_bridged: bridged, links_version: self.links_version }); }
+            if !recovered.is_empty() { self.healing.push_back(HealRecord { at_ms, kind: "healed", devices: recovered.clone(), links_bridged: 0, links_version: self.links_version }); }
+            while self.healing.len() > HEALING_HISTORY { self.healing.pop_front(); }
+        }
+        (went_down, recovered, bridged)
+    }
+}
+
+/// Per node, its neighbours and the latency of the link to each.
+type Adjacency = Vec<Vec<(usize, f64)>>;
+
+/// Adjacency lists over `devices` (duplicate and unknown-endpoint links dropped).
+fn adjacency<'a>(devices: &'a [String], links: &[MeshConnection]) -> (HashMap<&'a str, usize>, Adjacency) {
+    let idx: HashMap<&str, usize> = devices.iter().enumerate().map(|(i, d)| (d.as_str(), i)).collect();
+    let mut adj: Adjacency = vec![Vec::new(); devices.len()];
+    for l in links {
+        let (Some(&a), Some(&b)) = (idx.get(l.from.as_str()), idx.get(l.to.as_str())) else { continue };
+        if a == b || adj[a].iter().any(|&(n, _)| n == b) { continue; }
+        adj[a].push((b, l.latency_ms));
+        adj[b].push((a, l.latency_ms));
+    }
+    (idx, adj)
+}
+
+/// Each node's predecessor on its shortest path, with the latency of the link from it.
+type Predecessors = Vec<Option<(usize, f64)>>;
+
+/// Latency from `src` to every node, and each node's predecessor; stops early at `dst`.
+fn dijkstra(adj: &[Vec<(usize, f64)>], src: usize, dst: Option<usize>) -> (Vec<f64>, Predecessors) {
+    let mut dist = vec![f64::INFINITY; adj.len()];
+    let mut prev: Predecessors = vec![None; adj.len()];
+    let mut heap = BinaryHeap::new();
+    dist[src] = 0.0;
+    heap.push(Frontier { cost: 0.0, node: src });
+    while let Some(Frontier { cost, node }) = heap.pop() {
+        if Some(node) == dst { break; }
+        if cost > dist[node] { continue; }
+        for &(next, w) in &adj[node] {
+            let c = cost + w;
+            if c < dist[next] { dist[next] = c; prev[next] = Some((node, w)); heap.push(Frontier { cost: c, node: next }); }
+        }
+    }
+    (dist, prev)
+}
+
+/// Hop counts from `src`; `usize::MAX` where unreachable.
+fn hops_from(adj: &[Vec<(usize, f64)>], src: usize) -> Vec<usize> {
+    let mut hops = vec![usize::MAX; adj.len()];
+    let mut queue = VecDeque::from([src]);
+    hops[src] = 0;
+    while let Some(n) = queue.pop_front() {
+        for &(next, _) in &adj[n] {
+            if hops[next] == usize::MAX { hops[next] = hops[n] + 1; queue.push_back(next); }
+        }
+    }
+    hops
+}
+
+/// Articulation points and bridges (Tarjan), iteratively so large meshes cannot overflow the stack.
+fn cut_points(adj: &[Vec<(usize, f64)>]) -> (Vec<usize>, Vec<(usize, usize)>) {
+    const UNSEEN: usize = usize::MAX;
+    let n = adj.len();
+    let (mut disc, mut low, mut cut, mut bridges, mut time) = (vec![UNSEEN; n], vec![0; n], vec![false; n], Vec::new(), 0);
+    for root in 0..n {
+        if disc[root] != UNSEEN { continue; }
+        (disc[root], low[root], time) = (time, time, time + 1);
+        let mut root_children = 0;
+        // (node, parent, index of the next neighbour to visit)
+        let mut stack = vec![(root, UNSEEN, 0)];
+        while let Some(top) = stack.last_mut() {
+            let (u, parent) = (top.0, top.1);
+            if let Some(&(v, _)) = adj[u].get(top.2) {
+                top.2 += 1;
+                if disc[v] == UNSEEN {
+                    (disc[v], low[v], time) = (time, time, time + 1);
+                    stack.push((v, u, 0));
+                } else if v != parent {
+                    low[u] = low[u].min(disc[v]);
+                }
+                continue;
+            }
+            stack.pop();
+            if parent == UNSEEN { continue; }
+            low[parent] = low[parent].min(low[u]);
+            if parent == root { root_children += 1; } else if low[u] >= disc[parent] { cut[parent] = true; }
+            if low[u] > disc[parent] { bridges.push((parent, u)); }
+        }
+        if root_children > 1 { cut[root] = true; }
+    }
+    ((0..n).filter(|&i| cut[i]).collect(), bridges)
+}
+
+/// Analyses a graph copied out of the registry, off the async workers when it is large.
+async fn compute(devices: Vec<String>, links: Vec<MeshConnection>, links_version: u64) -> Analysis {
+    if devices.len() <= INLINE_ANALYSIS_DEVICES { return analyze(&devices, &links, links_version); }
+    tokio::task::spawn_blocking(move || analyze(&devices, &links, links_version)).await.expect("mesh analysis panicked")
+}
+
+/// Recomputes a mesh's analysis after a link change and returns the partition event it causes,
+/// if any. Nothing is stored when the mesh is gone or its links changed during the analysis.
+async fn reanalyze(s: &AppState, id: &str) -> Option<(String, (&'static str, serde_json::Value))> {
+    let (devices, links, version) = s.meshes.lock().unwrap().get(id)?.live_graph();
+    let analysis = compute(devices, links, version).await;
+    let mut meshes = s.meshes.lock().unwrap();
+    let m = meshes.get_mut(id).filter(|m| m.links_version == version)?;
+    let before = m.analysis.partitions.len();
+    m.analysis = analysis;
+    let after = m.analysis.partitions.len();
+    Some((m.tenant.clone(), m.partition_event((before, after))?))
+}
+
+fn analyze(devices: &[String], links: &[MeshConnection], links_version: u64) -> Analysis {
+    let (_, adj) = adjacency(devices, links);
+    let hops: Vec<Vec<usize>> = (0..devices.len()).map(|i| hops_from(&adj, i)).collect();
+    let mut seen = vec![false; devices.len()];
+    let mut partitions: Vec<Vec<String>> = Vec::new();
+    for i in 0..devices.len() {
+        if seen[i] { continue; }
+        let mut part: Vec<String> = (0..devices.len()).filter(|&j| hops[i][j] != usize::MAX).map(|j| { seen[j] = true; devices[j].clone() }).collect();
+        part.sort();
+        partitions.push(part);
+    }
+    partitions.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    let partitioned = partitions.len() > 1;
+    let (diameter_hops, diameter_latency_ms) = if partitioned || devices.is_empty() { (None, None) } else {
+        let latency = (0..devices.len()).flat_map(|i| dijkstra(&adj, i, None).0).fold(0.0, f64::max);
+        (hops.iter().flatten().copied().max(), Some(latency))
+    };
+    let (cuts, bridges) = cut_points(&adj);
+    let mut articulation_points: Vec<String> = cuts.into_iter().map(|i| devices[i].clone()).collect();
+    articulation_points.sort();
+    let mut critical_links: Vec<[String; 2]> = bridges.into_iter().map(|(a, b)| { let mut l = [devices[a].clone(), devices[b].clone()]; l.sort(); l }).collect();
+    critical_links.sort();
+    let links = adj.iter().map(Vec::len).sum::<usize>() / 2;
+    let spanning = devices.len() - partitions.len();
+    Analysis {
+        links_version, computed_at_ms: now_ms(), live_devices: devices.len(), links, partitioned, partitions, diameter_hops, diameter_latency_ms,
+        articulation_points, critical_links, redundancy_factor: (spanning > 0).then(|| links as f64 / spanning as f64),
+    }
+}
+
+fn same_link(a: &MeshConnection, b: &MeshConnection) -> bool { (a.from == b.from && a.to == b.to) || (a.from == b.to && a.to == b.from) }
+
+fn lookup(s: &AppState, tenant: &str, id: &str) -> Result<Mesh, ApiError> {
+    s.meshes.lock().unwrap().get(id).filter(|m| m.tenant == tenant).cloned().ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown mesh", Some(id.into())))
+}
+
+pub async fn get_mesh(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Mesh>, ApiError> {
+    lookup(&s, &tenant, &id).map(Json)
+}
+
+/// Upserts measured links (undirected); an absent or negative `latency_ms` removes the link.
+pub async fn update_links(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(id): Path<String>, Json(updates): Json<Vec<LinkUpdate>>) -> Result<Json<Mesh>, ApiError> {
+    let mesh = {
+        let mut meshes = s.meshes.lock().unwrap();
+        let m = meshes.get_mut(&id).filter(|m| m.tenant == tenant).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown mesh", Some(id.clone())))?;
+        if let Some(u) = updates.iter().find(|u| !m.devices.contains(&u.from) || !m.devices.contains(&u.to)) {
+            return Err(api_err(StatusCode::BAD_REQUEST, "Link endpoint not in mesh", Some(format!("{} -> {}", u.from, u.to))));
+        }
+        for u in updates {
+            m.links.retain(|l| !((l.from == u.from && l.to == u.to) || (l.from == u.to && l.to == u.from)));
+            if let Some(latency_ms) = u.latency_ms.filter(|l| *l >= 0.0) { m.links.push(MeshConnection { from: u.from, to: u.to, latency_ms }); }
+        }
+        m.links_version += 1;
+        m.clone()
+    };
+    s.emit("mesh-change", &tenant, serde_json::json!({ "mesh_id": mesh.mesh_id, "links": mesh.links.len(), "links_version": mesh.links_version }));
+    if let Some((tenant, (kind, data))) = reanalyze(&s, &id).await { s.emit(kind, &tenant, data); }
+    Ok(Json(mesh))
+}
+
+pub async fn analysis(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<AnalysisReport>, ApiError> {
+    let m = lookup(&s, &tenant, &id)?;
+    Ok(Json(AnalysisReport { mesh_id: m.mesh_id, status: m.status, down: m.down, analysis: m.analysis }))
+}
+
+struct Frontier { cost: f64, node: usize }
+impl PartialEq for Frontier { fn eq(&self, o: &Self) -> bool { self.cost.total_cmp(&o.cost) == Ordering::Equal } }
+impl Eq for Frontier {}
+impl PartialOrd for Frontier { fn partial_cmp(&self, o: &Self) -> Option<Ordering> { Some(self.cmp(o)) } }
+// Reversed so BinaryHeap pops the cheapest node first.
+impl Ord for Frontier { fn cmp(&self, o: &Self) -> Ordering { o.cost.total_cmp(&self.cost) } }
+
+/// Lowest-latency path as (device, link latency, cumulative latency) triples, or None if unreachable.
+pub fn shortest_path(devices: &[String], links: &[MeshConnection], from: &str, to: &str) -> Option<Vec<(String, f64, f64)>> {
+    let (idx, adj) = adjacency(devices, links);
+    let (src, dst) = (*idx.get(from)?, *idx.get(to)?);
+    let (dist, prev) = dijkstra(&adj, src, Some(dst));
+    if dist[dst].is_infinite() { return None; }
+    let mut path = vec![(devices[dst].clone(), prev[dst].map_or(0.0, |p| p.1), dist[dst])];
+    let mut cur = dst;
+    while let Some((p, _)) = prev[cur] {
+        path.push((devices[p].clone(), prev[p].map_or(0.0, |x| x.1), dist[p]));
+        cur = p;
+    }
+    path.reverse();
+    Some(path)
+}
+
+pub async fn route(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>, Query(q): Query<RouteQuery>) -> Result<Json<Route>, ApiError> {
+    let m = lookup(&s, &tenant, &id)?;
+    for d in [&q.from, &q.to] {
+        if !m.devices.contains(d) { return Err(api_err(StatusCode::BAD_REQUEST, "Device not in mesh", Some(d.clone()))); }
+        if m.down.contains(d) { return Err(api_err(StatusCode::CONFLICT, "Device is down", Some(d.clone()))); }
+    }
+    let path = shortest_path(&m.devices, &m.links, &q.from, &q.to).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "No route", Some(format!("{} is unreachable from {}", q.to, q.from))))?;
+    let total_latency_ms = path.last().map_or(0.0, |h| h.2);
+    let hops = path.into_iter().map(|(device_id, link_latency_ms, cumulative_latency_ms)| Hop { device_id, link_latency_ms, cumulative_latency_ms }).collect();
+    Ok(Json(Route { mesh_id: m.mesh_id, from: q.from, to: q.to, total_latency_ms, hops, links_version: m.links_version }))
+}
+
+pub async fn heal_loop(s: Arc<AppState>) {
+    let secs = std::env::var("MESH_HEAL_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(15u64).max(1);
+    let mut tick = tokio::time::interval(Duration::from_secs(secs));
+    loop {
+        tick.tick().await;
+        let mut live: HashMap<String, HashSet<String>> = HashMap::new();
+        for c in s.connections.lock().unwrap().values() { live.entry(c.tenant.clone()).or_default().insert(c.device_id.clone()); }
+        let none = HashSet::new();
+        let mut changes = Vec::new();
+        for m in s.meshes.lock().unwrap().values_mut() {
+            let (down, up, bridged) = m.reconcile(live.get(&m.tenant).unwrap_or(&none));
+            if down.is_empty() && up.is_empty() { continue; }
+            changes.push((m.tenant.clone(), m.mesh_id.clone(), down, up, bridged, m.down.len(), m.links_version));
+        }
+        for (_, mesh_id, ..) in &changes {
+            if let Some((tenant, (kind, data))) = reanalyze(&s, mesh_id).await {
+                tracing::warn!(%tenant, kind, "mesh partition change");
+                s.emit(kind, &tenant, data);
+            }
+        }
+        for (tenant, mesh_id, down, up, bridged, still_down, links_version) in changes {
+            if !down.is_empty() {
+                tracing::warn!(mesh = %mesh_id, devices = ?down, "mesh degraded");
+                s.emit("mesh-degraded", &tenant, serde_json::json!({ "mesh_id": mesh_id, "devices": down, "links_bridged": bridged, "links_version": links_version }));
+            }
+            if !up.is_empty() {
+                tracing::info!(mesh = %mesh_id, devices = ?up, "mesh healed");
+                s.emit("mesh-healed", &tenant, serde_json::json!({ "mesh_id": mesh_id, "devices": up, "still_down": still_down, "links_version": links_version }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(devices: &[&str], links: &[(&str, &str, f64)]) -> (Vec<String>, Vec<MeshConnection>) {
+        (devices.iter().map(|d| d.to_string()).collect(), links.iter().map(|&(from, to, latency_ms)| MeshConnection { from: from.into(), to: to.into(), latency_ms }).collect())
+    }
+
+    fn names(v: &[&str]) -> Vec<String> { v.iter().map(|d| d.to_string()).collect() }
+
+    #[test]
+    fn chain_has_every_inner_node_and_link_critical() {
+        let (devices, links) = graph(&["a", "b", "c", "d"], &[("a", "b", 1.0), ("b", "c", 2.0), ("c", "d", 3.0)]);
+        let a = analyze(&devices, &links, 7);
+        assert_eq!((a.diameter_hops, a.diameter_latency_ms), (Some(3), Some(6.0)));
+        assert_eq!(a.articulation_points, names(&["b", "c"]));
+        assert_eq!(a.critical_links.len(), 3);
+        assert_eq!(a.redundancy_factor, Some(1.0));
+        assert!(!a.partitioned);
+        assert_eq!(a.links_version, 7);
+    }
+
+    #[test]
+    fn ring_with_a_pendant_has_one_single_point_of_failure() {
+        let (devices, links) = graph(&["a", "b", "c", "d", "e"], &[("a", "b", 1.0), ("b", "c", 1.0), ("c", "d", 1.0), ("d", "a", 1.0), ("d", "e", 10.0)]);
+        let a = analyze(&devices, &links, 0);
+        assert_eq!(a.articulation_points, names(&["d"]));
+        assert_eq!(a.critical_links, vec![["d".to_string(), "e".to_string()]]);
+        // e -> d -> a -> b (or via c): three hops, 12 ms.
+        assert_eq!((a.diameter_hops, a.diameter_latency_ms), (Some(3), Some(12.0)));
+        assert_eq!(a.redundancy_factor, Some(5.0 / 4.0));
+    }
+
+    #[test]
+    fn duplicate_and_reversed_links_count_once() {
+        let (devices, links) = graph(&["a", "b"], &[("a", "b", 1.0), ("b", "a", 1.0), ("a", "b", 2.0)]);
+        let a = analyze(&devices, &links, 0);
+        assert_eq!(a.links, 1);
+        assert_eq!(a.redundancy_factor, Some(1.0));
+    }
+
+    #[test]
+    fn partitions_are_reported_largest_first_without_a_diameter() {
+        let (devices, links) = graph(&["e", "c", "d", "a", "b", "f"], &[("a", "b", 1.0), ("b", "f", 1.0), ("c", "d", 1.0)]);
+        let a = analyze(&devices, &links, 0);
+        assert!(a.partitioned);
+        assert_eq!(a.partitions, vec![names(&["a", "b", "f"]), names(&["c", "d"]), names(&["e"])]);
+        assert_eq!((a.diameter_hops, a.diameter_latency_ms), (None, None));
+        assert_eq!(a.articulation_points, names(&["b"]));
+    }
+
+    #[test]
+    fn star_centre_is_the_articulation_point() {
+        let (devices, links) = graph(&["hub", "x", "y", "z"], &[("hub", "x", 1.0), ("hub", "y", 1.0), ("hub", "z", 1.0)]);
+        let a = analyze(&devices, &links, 0);
+        assert_eq!(a.articulation_points, names(&["hub"]));
+        assert_eq!(a.diameter_hops, Some(2));
+    }
+}
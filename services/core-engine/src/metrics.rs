@@ -0,0 +1,150 @@
+//! Prometheus text exposition at `/metrics`.
+
+use crate::{pressure, AppState};
+use axum::{extract::State, http::header};
+use std::fmt::Write;
+use std::sync::Arc;
+
+pub async fn render(State(s): State<Arc<AppState>>) -> ([(header::HeaderName, &'static str); 1], String) {
+    let mut out = String::new();
+    let (c, sy, t, b) = { let st = s.stats.lock().unwrap(); (st.total_connections, st.total_syncs, st.total_transforms, st.bytes_relayed) };
+    for (name, help, v) in [
+        ("gateway_connections_total", "Device connections accepted.", c),
+        ("gateway_syncs_total", "Sync operations completed.", sy),
+        ("gateway_transforms_total", "Protocol transforms performed.", t),
+        ("gateway_bytes_relayed_total", "SDF bytes relayed.", b),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {v}");
+    }
+    let _ = writeln!(out, "# HELP gateway_active_connections Currently registered connections.\n# TYPE gateway_active_connections gauge\ngateway_active_connections {}", s.connections.lock().unwrap().len());
+    let p = s.pressure.snapshot();
+    let _ = writeln!(out, "# HELP gateway_sync_in_flight Sync requests currently being processed.\n# TYPE gateway_sync_in_flight gauge\ngateway_sync_in_flight {}", p.in_flight);
+    let _ = writeln!(out, "# HELP gateway_sync_shed_total Sync requests rejected with 429 under back-pressure.\n# TYPE gateway_sync_shed_total counter\ngateway_sync_shed_total {}", p.shed_total);
+    let _ = writeln!(out, "# HELP gateway_sync_lane_waiting Syncs waiting for a slot, by priority lane.\n# TYPE gateway_sync_lane_waiting gauge");
+    for l in &p.lanes { let _ = writeln!(out, "gateway_sync_lane_waiting{{priority=\"{}\"}} {}", l.priority.as_str(), l.waiting); }
+    let _ = writeln!(out, "# HELP gateway_sync_lane_shed_total Syncs shed under back-pressure, by priority lane.\n# TYPE gateway_sync_lane_shed_total counter");
+    for l in &p.lanes { let _ = writeln!(out, "gateway_sync_lane_shed_total{{priority=\"{}\"}} {}", l.priority.as_str(), l.shed_total); }
+    let _ = writeln!(out, "# HELP gateway_sync_latency_seconds Completed sync latency including lane wait, by priority.\n# TYPE gateway_sync_latency_seconds histogram");
+    for l in s.pressure.latencies() {
+        let prio = l.priority.as_str();
+        for (le, n) in pressure::LATENCY_BUCKETS.iter().zip(l.buckets) { let _ = writeln!(out, "gateway_sync_latency_seconds_bucket{{priority=\"{prio}\",le=\"{le}\"}} {n}"); }
+        let _ = writeln!(out, "gateway_sync_latency_seconds_bucket{{priority=\"{prio}\",le=\"+Inf\"}} {}\ngateway_sync_latency_seconds_sum{{priority=\"{prio}\"}} {}\ngateway_sync_latency_seconds_count{{priority=\"{prio}\"}} {}", l.count, l.sum_secs, l.count);
+    }
+    let tc = s.transform_cache.snapshot();
+    let _ = writeln!(out, "# HELP gateway_transform_cache_hits_total Transforms answered from the cache.\n# TYPE gateway_transform_cache_hits_total counter\ngateway_transform_cache_hits_total {}", tc.hits);
+    let _ = writeln!(out, "# HELP gateway_transform_cache_misses_total Transforms looked up in the cache and computed.\n# TYPE gateway_transform_cache_misses_total counter\ngateway_transform_cache_misses_total {}", tc.misses);
+    let _ = writeln!(out, "# HELP gateway_transform_cache_hit_ratio Share of cache lookups that hit since start.\n# TYPE gateway_transform_cache_hit_ratio gauge\ngateway_transform_cache_hit_ratio {}", if tc.hits + tc.misses == 0 { 0.0 } else { tc.hits as f64 / (tc.hits + tc.misses) as f64 });
+    let _ = writeln!(out, "# HELP gateway_transform_cache_entries Transform results currently cached.\n# TYPE gateway_transform_cache_entries gauge\ngateway_transform_cache_entries {}", tc.entries);
+    let rc = s.routing.counts();
+    let _ = writeln!(out, "# HELP gateway_routed_objects_total Delta objects handled by content routing rules.\n# TYPE gateway_routed_objects_total counter");
+    for (action, v) in [("forward", rc.forwarded), ("priority-queue", rc.queued), ("drop", rc.dropped)] { let _ = writeln!(out, "gateway_routed_objects_total{{action=\"{action}\"}} {v}"); }
+    let _ = writeln!(out, "# HELP gateway_routing_queue_overflow_total Priority-queued objects evicted unread.\n# TYPE gateway_routing_queue_overflow_total counter\ngateway_routing_queue_overflow_total {}", rc.queue_overflow);
+    let _ = writeln!(out, "# HELP gateway_anomalies_active Devices currently flagged for anomalous sync patterns (per metric).\n# TYPE gateway_anomalies_active gauge\ngateway_anomalies_active {}", s.anomalies.active_count());
+    if let Some(a) = &s.snapshots.archive {
+        let ac = a.counts();
+        for (name, help, v) in [
+            ("gateway_archive_uploads_total", "Compacted snapshots uploaded to the archive.", ac.uploads),
+            ("gateway_archive_failures_total", "Snapshot archive uploads that failed.", ac.failures),
+            ("gateway_archive_restores_total", "Resumed connections seeded from an archived snapshot.", ac.restores),
+            ("gateway_archive_expired_total", "Archived snapshots deleted by the retention sweep.", ac.expired),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {v}");
+        }
+    }
+    if s.keys.enabled() {
+        let kc = s.keys.counts();
+        let _ = writeln!(out, "# HELP gateway_keystore_tenants Tenants holding data keys.\n# TYPE gateway_keystore_tenants gauge\ngateway_keystore_tenants {}", kc.tenants);
+        for (name, help, v) in [
+            ("gateway_keystore_sealed_total", "Blobs sealed with a tenant data key.", kc.sealed),
+            ("gateway_keystore_opened_total", "Sealed blobs opened.", kc.opened),
+            ("gateway_keystore_failures_total", "Seal, open or key creation failures.", kc.failures),
+            ("gateway_keystore_rotations_total", "Tenant data key rotations.", kc.rotations),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {v}");
+        }
+    }
+    let groups = s.limits.snapshot();
+    let _ = writeln!(out, "# HELP gateway_route_in_flight Requests in flight per route group.\n# TYPE gateway_route_in_flight gauge");
+    for g in &groups { let _ = writeln!(out, "gateway_route_in_flight{{group=\"{}\"}} {}", g.group.as_str(), g.in_flight); }
+    let _ = writeln!(out, "# HELP gateway_route_concurrency_limit Concurrency limit per route group.\n# TYPE gateway_route_concurrency_limit gauge");
+    for g in &groups { let _ = writeln!(out, "gateway_route_concurrency_limit{{group=\"{}\"}} {}", g.group.as_str(), g.limit); }
+    let _ = writeln!(out, "# HELP gateway_route_shed_total Requests shed with 503 because their route group was at its limit.\n# TYPE gateway_route_shed_total counter");
+    for g in &groups { let _ = writeln!(out, "gateway_route_shed_total{{group=\"{}\"}} {}", g.group.as_str(), g.shed_total); }
+    let ec = s.encryption.counts();
+    let _ = writeln!(out, "# HELP gateway_envelopes_total Encrypted envelopes accepted and stored without decrypting.\n# TYPE gateway_envelopes_total counter\ngateway_envelopes_total {}", ec.envelopes);
+    let _ = writeln!(out, "# HELP gateway_plaintext_rejected_total Plaintext syncs rejected for tenants requiring encryption.\n# TYPE gateway_plaintext_rejected_total counter\ngateway_plaintext_rejected_total {}", ec.plaintext_rejected);
+    let _ = writeln!(out, "# HELP gateway_schema_versions Registered payload schema versions, all tenants.\n# TYPE gateway_schema_versions gauge\ngateway_schema_versions {}", s.schemas.version_count());
+    let _ = writeln!(out, "# HELP gateway_schema_violations_total Syncs and transforms refused for not matching their payload schema.\n# TYPE gateway_schema_violations_total counter\ngateway_schema_violations_total {}", s.schemas.violations());
+    let _ = writeln!(out, "# HELP gateway_soft_deleted_resources Deleted connections and meshes still restorable.\n# TYPE gateway_soft_deleted_resources gauge\ngateway_soft_deleted_resources {}", s.trash.count());
+    let (indexed, cells, wide) = s.shadows.lock().unwrap().values().map(|sh| sh.index.stats(0)).fold((0, 0, 0), |(o, c, w), st| (o + st.objects, c + st.cells, w + st.wide));
+    for (name, help, v) in [
+        ("gateway_spatial_indexed_objects", "SDF objects with bounds in the spatial indexes.", indexed),
+        ("gateway_spatial_cells", "Occupied spatial index cells, all devices.", cells),
+        ("gateway_spatial_wide_objects", "Indexed objects too large for the grid, checked by every bbox query.", wide),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {v}");
+    }
+    let hc = s.hub.counts();
+    let _ = writeln!(out, "# HELP gateway_hub_subscribers Event hub subscribers (streams, webhooks, sinks).\n# TYPE gateway_hub_subscribers gauge\ngateway_hub_subscribers {}", hc.subscribers);
+    for (name, help, v) in [
+        ("gateway_hub_published_total", "Events published to the hub.", hc.published),
+        ("gateway_hub_dropped_total", "Events dropped from full subscriber buffers.", hc.dropped),
+        ("gateway_hub_disconnected_total", "Subscribers disconnected for falling behind.", hc.disconnected),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {v}");
+    }
+    let dc = s.deliveries.counts();
+    let _ = writeln!(out, "# HELP gateway_deliveries_tracked Sync delivery receipts retained.\n# TYPE gateway_deliveries_tracked gauge\ngateway_deliveries_tracked {}", dc.tracked);
+    let _ = writeln!(out, "# HELP gateway_deliveries_pending Delivery receipts with a destination still pending.\n# TYPE gateway_deliveries_pending gauge\ngateway_deliveries_pending {}", dc.pending);
+    let _ = writeln!(out, "# HELP gateway_delivery_destinations_total Delivery destinations settled, by outcome.\n# TYPE gateway_delivery_destinations_total counter");
+    for (status, v) in [("delivered", dc.delivered), ("failed", dc.failed), ("dropped", dc.dropped)] {
+        let _ = writeln!(out, "gateway_delivery_destinations_total{{status=\"{status}\"}} {v}");
+    }
+    let _ = writeln!(out, "# HELP gateway_api_requests_total API requests by version.\n# TYPE gateway_api_requests_total counter");
+    for (version, v) in s.versions.requests() { let _ = writeln!(out, "gateway_api_requests_total{{version=\"{version}\"}} {v}"); }
+    let qc = s.dlq.counts();
+    let _ = writeln!(out, "# HELP gateway_dlq_entries Dead-lettered syncs and transforms retained, all tenants.\n# TYPE gateway_dlq_entries gauge\ngateway_dlq_entries {}", qc.entries);
+    let _ = writeln!(out, "# HELP gateway_dlq_captured_total Failed syncs and transforms dead-lettered.\n# TYPE gateway_dlq_captured_total counter\ngateway_dlq_captured_total {}", qc.captured);
+    let _ = writeln!(out, "# HELP gateway_dlq_recovered_total Dead letters retried successfully.\n# TYPE gateway_dlq_recovered_total counter\ngateway_dlq_recovered_total {}", qc.recovered);
+    let bc = s.shaping.counts();
+    for (name, help, v) in [
+        ("gateway_bandwidth_throttled_total", "Bandwidth buckets that started throttling.", bc.throttled),
+        ("gateway_bandwidth_rejected_total", "Syncs refused for exceeding a bandwidth limit.", bc.rejected),
+        ("gateway_bandwidth_delay_ms_total", "Time transfers were held for bandwidth, in milliseconds.", bc.delay_ms),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {v}");
+    }
+    let dc = s.dedup.counts();
+    let _ = writeln!(out, "# HELP gateway_sync_duplicates_suppressed_total Resent syncs answered from the dedup window instead of applied.\n# TYPE gateway_sync_duplicates_suppressed_total counter\ngateway_sync_duplicates_suppressed_total {}", dc.suppressed);
+    let _ = writeln!(out, "# HELP gateway_sync_duplicate_offenders Devices currently flagged for resending duplicate syncs.\n# TYPE gateway_sync_duplicate_offenders gauge\ngateway_sync_duplicate_offenders {}", dc.offenders);
+    let mc = s.windows.counts();
+    let _ = writeln!(out, "# HELP gateway_maintenance_windows Maintenance windows by state.\n# TYPE gateway_maintenance_windows gauge\ngateway_maintenance_windows{{state=\"scheduled\"}} {}\ngateway_maintenance_windows{{state=\"active\"}} {}", mc.scheduled, mc.active);
+    let _ = writeln!(out, "# HELP gateway_maintenance_rejected_total Writes refused during a maintenance window.\n# TYPE gateway_maintenance_rejected_total counter\ngateway_maintenance_rejected_total {}", mc.rejected);
+    let cc = s.chaos.counts();
+    let _ = writeln!(out, "# HELP gateway_chaos_faults Fault injection rules in effect.\n# TYPE gateway_chaos_faults gauge\ngateway_chaos_faults {}", cc.active);
+    let _ = writeln!(out, "# HELP gateway_chaos_injected_total Faults injected, by kind.\n# TYPE gateway_chaos_injected_total counter\ngateway_chaos_injected_total{{kind=\"latency\"}} {}\ngateway_chaos_injected_total{{kind=\"error\"}} {}\ngateway_chaos_injected_total{{kind=\"drop-events\"}} {}", cc.latency, cc.errors, cc.dropped);
+    let regions = s.regions.report(&s);
+    let _ = writeln!(out, "# HELP gateway_region_up Whether a region is healthy (1) or degraded (0).\n# TYPE gateway_region_up gauge");
+    for r in &regions { let _ = writeln!(out, "gateway_region_up{{region=\"{}\"}} {}", r.region(), u8::from(r.healthy())); }
+    let _ = writeln!(out, "# HELP gateway_region_latency_ms Smoothed probe latency to upstream regions.\n# TYPE gateway_region_latency_ms gauge");
+    for r in &regions { if let Some(ms) = r.latency_ms() { let _ = writeln!(out, "gateway_region_latency_ms{{region=\"{}\"}} {ms}", r.region()); } }
+    let _ = writeln!(out, "# HELP gateway_region_redirects_total Connections redirected away from a degraded region.\n# TYPE gateway_region_redirects_total counter\ngateway_region_redirects_total {}", s.regions.redirects());
+    let pc = s.provisioning.counts();
+    let _ = writeln!(out, "# HELP gateway_provisioned_devices Pre-registered devices by enrollment status.\n# TYPE gateway_provisioned_devices gauge\ngateway_provisioned_devices{{status=\"pending\"}} {}\ngateway_provisioned_devices{{status=\"enrolled\"}} {}", pc.pending, pc.enrolled);
+    let _ = writeln!(out, "# HELP gateway_provisioning_rejected_total Connects refused for a missing or failed device registration.\n# TYPE gateway_provisioning_rejected_total counter\ngateway_provisioning_rejected_total {}", pc.rejected);
+    #[cfg(feature = "kafka")]
+    if let Some(k) = &s.kafka {
+        let kc = k.counts();
+        let _ = writeln!(out, "# HELP gateway_kafka_published_total Events acknowledged by Kafka.\n# TYPE gateway_kafka_published_total counter\ngateway_kafka_published_total {}", kc.published);
+        let _ = writeln!(out, "# HELP gateway_kafka_retries_total Kafka publish attempts that failed and were retried.\n# TYPE gateway_kafka_retries_total counter\ngateway_kafka_retries_total {}", kc.retried);
+        let _ = writeln!(out, "# HELP gateway_kafka_lagged_total Events skipped because the Kafka sink fell behind.\n# TYPE gateway_kafka_lagged_total counter\ngateway_kafka_lagged_total {}", kc.lagged);
+    }
+    let breakers = s.breakers.snapshots();
+    let _ = writeln!(out, "# HELP gateway_breaker_state Circuit breaker state (0=closed, 1=half-open, 2=open).\n# TYPE gateway_breaker_state gauge");
+    for b in &breakers { let _ = writeln!(out, "gateway_breaker_state{{breaker=\"{}\"}} {}", b.name, b.state.as_gauge()); }
+    let _ = writeln!(out, "# HELP gateway_breaker_failures_total Failed calls through a breaker.\n# TYPE gateway_breaker_failures_total counter");
+    for b in &breakers { let _ = writeln!(out, "gateway_breaker_failures_total{{breaker=\"{}\"}} {}", b.name, b.failures); }
+    let _ = writeln!(out, "# HELP gateway_breaker_rejected_total Calls short-circuited by an open breaker.\n# TYPE gateway_breaker_rejected_total counter");
+    for b in &breakers { let _ = writeln!(out, "gateway_breaker_rejected_total{{breaker=\"{}\"}} {}", b.name, b.rejected); }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
@@ -0,0 +1,91 @@
+//! Moving a live connection to another region's gateway when its device roams. The source
+//! gateway freezes the connection (syncs get 409 `migrating`), hands the device's shadow,
+//! queued outbound deltas and last sync sequence to the target's `/connections/import` over
+//! the upstream relay, and only drops its own copy once the target has accepted. If the
+//! target fails, the queue is put back and the connection carries on where it was.
+//! With `?async=true` the migration runs as a job and the call returns 202 with it.
+
+use crate::audit::Actor;
+use crate::jobs::Jobs;
+use crate::outbox::Outbound;
+use crate::rbac::{Operate, Require};
+use crate::shadow::{self, ShadowState};
+use crate::validate::{ensure, Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, ConnectRequest, ConnectResponse, Tenant};
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::{IntoResponse, Json, Response}};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct MigrateRequest { target_region: String }
+
+#[derive(Deserialize)]
+pub struct MigrateQuery { #[serde(default, rename = "async")] run_async: bool }
+
+#[derive(Serialize, Deserialize)]
+pub struct MigrationBundle { device_id: String, protocol: String, home_region: Option<String>, sequence: Option<u64>, shadow: Option<ShadowState>, queue: Vec<Outbound> }
+
+#[derive(Serialize)]
+pub struct MigrateResponse { previous_connection_id: String, source_region: String, #[serde(flatten)] connection: Value }
+
+impl Validate for MigrateRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) { v.id("target_region", &self.target_region); }
+}
+
+impl Validate for MigrationBundle {
+    fn validate(&self, s: &AppState, v: &mut Violations) {
+        v.id("device_id", &self.device_id);
+        v.protocol(s, "protocol", &self.protocol);
+    }
+}
+
+pub async fn migrate(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(id): Path<String>, Query(q): Query<MigrateQuery>, Valid(req): Valid<MigrateRequest>) -> Result<Response, ApiError> {
+    if !q.run_async { return Ok(Json(run(&s, &actor, &tenant, id, req.target_region).await?).into_response()); }
+    let s2 = s.clone();
+    let job = Jobs::submit(&s, &tenant.clone(), "connection.migrate", 1, move |_| async move {
+        let done = run(&s2, &actor, &tenant, id, req.target_region).await.map_err(|e| e.body.details.map_or(e.body.error.clone(), |d| format!("{}: {d}", e.body.error)))?;
+        Ok(serde_json::to_value(done).unwrap_or_default())
+    });
+    Ok((StatusCode::ACCEPTED, Json(job)).into_response())
+}
+
+async fn run(s: &AppState, actor: &str, tenant: &str, id: String, target: String) -> Result<MigrateResponse, ApiError> {
+    if !s.relay.relays_to(&target) {
+        let known: Vec<&str> = s.relay.upstreams().map(|(r, _)| r.as_str()).collect();
+        return Err(api_err(StatusCode::BAD_REQUEST, "Unknown target region", Some(format!("{target}; reachable: {}", known.join(", ")))));
+    }
+    let conn = s.connections.lock().unwrap().get(&id).filter(|c| c.tenant == tenant).cloned().ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(id.clone())))?;
+    if !s.migrating.lock().unwrap().insert(id.clone()) { return Err(api_err(StatusCode::CONFLICT, "Migration already in progress", Some(id)).code("migrating")); }
+    let queue = s.outbox.take(tenant, &conn.device_id);
+    let bundle = MigrationBundle {
+        device_id: conn.device_id.clone(), protocol: conn.protocol.clone(), home_region: conn.upstream.as_ref().map(|u| u.home_region.clone()),
+        sequence: s.sequences.lock().unwrap().get(&id).copied(), shadow: shadow::export(s, tenant, &conn.device_id), queue,
+    };
+    let result = s.relay.migrate(&target, tenant, &serde_json::to_value(&bundle).unwrap_or_default()).await;
+    s.migrating.lock().unwrap().remove(&id);
+    let connection = match result {
+        Ok(v) => v,
+        Err(e) => {
+            s.outbox.restore(tenant, &conn.device_id, bundle.queue);
+            tracing::warn!(connection_id = %id, %target, error = %e.message(), "migration failed; connection kept");
+            return Err(e.into());
+        }
+    };
+    s.shadows.lock().unwrap().remove(&(tenant.to_string(), conn.device_id.clone()));
+    s.drop_connection(&id);
+    tracing::info!(connection_id = %id, device_id = %conn.device_id, from = %conn.region, %target, queued = bundle.queue.len(), "connection migrated");
+    s.audit.record(actor, "device.migrate", Some(tenant), Some(&conn.device_id), serde_json::json!({ "connection_id": id, "from": conn.region, "to": target, "new_connection_id": connection.get("connection_id") }));
+    Ok(MigrateResponse { previous_connection_id: id, source_region: conn.region, connection })
+}
+
+/// Target side of a migration: opens the connection here and restores the transferred state.
+pub async fn import(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Valid(bundle): Valid<MigrationBundle>) -> Result<(StatusCode, Json<ConnectResponse>), ApiError> {
+    let req = ConnectRequest { device_id: bundle.device_id.clone(), protocol: Some(bundle.protocol), accept_protocols: None, region: Some(s.relay.local_region.clone()), priority: None, standby_region: None, home_region: bundle.home_region, ..Default::default() };
+    ensure(&s, &req)?;
+    let resp = s.establish(&tenant, &actor, None, None, req).await?;
+    if let Some(state) = bundle.shadow { shadow::import(&s, &tenant, &bundle.device_id, state); }
+    s.outbox.restore(&tenant, &bundle.device_id, bundle.queue);
+    if let Some(seq) = bundle.sequence { s.sequences.lock().unwrap().insert(resp.connection_id.clone(), seq); }
+    Ok((StatusCode::CREATED, Json(resp)))
+}
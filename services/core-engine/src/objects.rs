@@ -0,0 +1,93 @@
+//! Queries over a connection's merged SDF state, so visualization services can fetch just the
+//! objects they draw instead of a full snapshot. Objects live under `objects` in the device's
+//! reported shadow, keyed by id, e.g.
+//! `{"objects": {"pipe-7": {"type": "cylinder", "bounds": {"min": [0,0,0], "max": [1,2,1]}}}}`;
+//! an object without `bounds` but with a `position` is treated as a point.
+//!
+//! Filters: `type`, `bbox=minx,miny,minz,maxx,maxy,maxz` (intersection; 2-D boxes use four
+//! values) and `modified_since` (Unix ms). Results are ordered by id and paged with `limit`
+//! (default 100, max 1000) and the opaque `cursor` from the previous page. `bbox` is answered
+//! from the device's spatial index (see [`crate::spatial`]), whose occupancy
+//! `GET /connections/:id/objects/index` reports.
+
+use crate::rbac::{Read, Require};
+use crate::spatial::IndexStats;
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ObjectQuery { r#type: Option<String>, bbox: Option<String>, modified_since: Option<u64>, cursor: Option<String>, limit: Option<usize> }
+
+#[derive(Serialize)]
+pub struct SdfObject { id: String, modified_at_ms: Option<u64>, #[serde(flatten)] body: Map<String, Value> }
+
+#[derive(Serialize)]
+pub struct ObjectPage { connection_id: String, device_id: String, shadow_version: u64, objects: Vec<SdfObject>, next_cursor: Option<String> }
+
+/// Axis-aligned box; points are degenerate boxes.
+#[derive(Clone)]
+pub(crate) struct Aabb { pub(crate) min: Vec<f64>, pub(crate) max: Vec<f64> }
+
+impl Aabb {
+    pub(crate) fn parse_query(bbox: &str) -> Result<Aabb, String> {
+        let v = bbox.split(',').map(|x| x.trim().parse::<f64>().map_err(|_| format!("not a number: {x}"))).collect::<Result<Vec<_>, _>>()?;
+        if v.len() != 4 && v.len() != 6 { return Err("expected 4 (2-D) or 6 (3-D) comma-separated numbers".into()); }
+        let (min, max) = v.split_at(v.len() / 2);
+        if min.iter().zip(max).any(|(a, b)| a > b) { return Err("min must not exceed max".into()); }
+        Ok(Aabb { min: min.to_vec(), max: max.to_vec() })
+    }
+    pub(crate) fn of(object: &Map<String, Value>) -> Option<Aabb> {
+        let coords = |v: &Value| v.as_array()?.iter().map(Value::as_f64).collect::<Option<Vec<f64>>>();
+        match (object.get("bounds"), object.get("position")) {
+            (Some(b), _) => Some(Aabb { min: coords(b.get("min")?)?, max: coords(b.get("max")?)? }),
+            (None, Some(p)) => coords(p).map(|c| Aabb { min: c.clone(), max: c }),
+            _ => None,
+        }
+    }
+    /// Compares the dimensions both boxes have, so a 2-D query matches 3-D objects by footprint.
+    pub(crate) fn intersects(&self, other: &Aabb) -> bool {
+        self.min.iter().zip(&self.max).zip(other.min.iter().zip(&other.max)).all(|((amin, amax), (bmin, bmax))| amin <= bmax && bmin <= amax)
+    }
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>, Query(q): Query<ObjectQuery>) -> Result<Json<ObjectPage>, ApiError> {
+    let conn = s.lookup_connection(&id).await.filter(|c| c.tenant == tenant).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(id.clone())))?;
+    let bbox = q.bbox.as_deref().map(Aabb::parse_query).transpose().map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid bbox", Some(e)))?;
+    let limit = q.limit.unwrap_or(100).clamp(1, MAX_LIMIT);
+    let shadows = s.shadows.lock().unwrap();
+    let Some(sh) = shadows.get(&(tenant, conn.device_id.clone())) else {
+        return Ok(Json(ObjectPage { connection_id: id, device_id: conn.device_id, shadow_version: 0, objects: Vec::new(), next_cursor: None }));
+    };
+    let all = sh.reported.get("objects").and_then(Value::as_object);
+    let mut ids: Vec<&str> = match &bbox {
+        Some(b) => sh.index.query(b).into_iter().collect(),
+        None => all.map(|o| o.keys().map(String::as_str).collect()).unwrap_or_default(),
+    };
+    ids.retain(|k| q.cursor.as_deref().is_none_or(|c| *k > c));
+    ids.sort_unstable();
+    let mut objects = Vec::new();
+    let mut next_cursor = None;
+    for oid in ids {
+        let Some(Value::Object(body)) = all.and_then(|o| o.get(oid)) else { continue };
+        let modified_at_ms = sh.objects_modified_ms.get(oid).copied();
+        if q.r#type.as_ref().is_some_and(|t| body.get("type").and_then(Value::as_str) != Some(t)) { continue; }
+        if q.modified_since.is_some_and(|since| modified_at_ms.is_none_or(|m| m < since)) { continue; }
+        if objects.len() == limit { next_cursor = objects.last().map(|o: &SdfObject| o.id.clone()); break; }
+        objects.push(SdfObject { id: oid.into(), modified_at_ms, body: body.clone() });
+    }
+    Ok(Json(ObjectPage { connection_id: id, device_id: conn.device_id, shadow_version: sh.version, objects, next_cursor }))
+}
+
+pub async fn index_stats(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<IndexStats>, ApiError> {
+    let conn = s.lookup_connection(&id).await.filter(|c| c.tenant == tenant).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(id.clone())))?;
+    let shadows = s.shadows.lock().unwrap();
+    Ok(Json(match shadows.get(&(tenant, conn.device_id)) {
+        Some(sh) => sh.index.stats(sh.reported.get("objects").and_then(Value::as_object).map_or(0, Map::len)),
+        None => crate::spatial::Index::from_env().stats(0),
+    }))
+}
@@ -0,0 +1,24 @@
+//! OTLP trace export. Enabled when `OTEL_EXPORTER_OTLP_ENDPOINT` (or the traces-specific
+//! variant) is set; endpoint, headers, service name and resource attributes all come from
+//! the standard `OTEL_*` environment variables.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() && std::env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_none() { return None; }
+    let exporter = match opentelemetry_otlp::SpanExporter::builder().with_http().build() {
+        Ok(e) => e,
+        Err(e) => { eprintln!("OTLP exporter disabled: {e}"); return None; }
+    };
+    let provider = TracerProvider::builder().with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio).build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider);
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
@@ -0,0 +1,212 @@
+//! Outbound deltas for devices. Desired-shadow changes and messages posted to
+//! `/connections/:id/messages` go straight to the device while it holds a WebSocket on
+//! `/connections/:id/ws`; otherwise they wait in a bounded per-device queue
+//! (`OUTBOX_DEPTH`, default 256, oldest dropped first) for up to `OUTBOX_TTL_SECS`
+//! (default 3600). The queue is keyed by device so it survives reconnecting under a new
+//! connection id, and is drained over the socket as soon as the device attaches.
+//!
+//! Every frame carries a `priority` (`high`, `normal`, `low`; posted messages choose theirs).
+//! A backlog is sent highest priority first, and a full queue evicts its oldest
+//! lowest-priority delta.
+
+use crate::events::now_ms;
+use crate::pressure::Priority;
+use crate::rbac::{Operate, Read, Require};
+use crate::shaping::{self, Bandwidth};
+use crate::trash::{Deletion, ListQuery};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Path, Query, State},
+    http::StatusCode,
+    response::{Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Outbound { id: String, delta: Value, queued_at_ms: u64, #[serde(default)] priority: Priority }
+
+#[derive(Default)]
+struct Queue { items: VecDeque<Outbound>, dropped: u64 }
+
+#[derive(Serialize)]
+pub struct QueueInfo { depth: usize, dropped: u64, oldest_age_ms: Option<u64> }
+
+type DeviceKey = (String, String);
+
+pub struct Outbox {
+    depth: usize,
+    ttl_ms: u64,
+    queues: Mutex<HashMap<DeviceKey, Queue>>,
+    /// Attached WebSockets by connection id.
+    live: Mutex<HashMap<String, (DeviceKey, mpsc::Sender<Outbound>)>>,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionInfo { connection_id: String, device_id: String, protocol: String, region: String, online: bool, queue: QueueInfo, #[serde(skip_serializing_if = "Option::is_none")] bandwidth: Option<Bandwidth>, #[serde(flatten)] deletion: Deletion }
+
+impl ConnectionInfo {
+    pub fn new(s: &AppState, id: String, c: crate::Connection, queue: QueueInfo, deletion: Deletion) -> Self {
+        ConnectionInfo { online: s.outbox.is_online(&id), bandwidth: s.shaping.bandwidth(&c.tenant, &id), connection_id: id, device_id: c.device_id, protocol: c.protocol, region: c.region, queue, deletion }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PostMessage { delta: Value, #[serde(default)] priority: Priority }
+
+#[derive(Serialize)]
+pub struct PostMessageResult { id: String, delivered: bool, queue_depth: usize }
+
+impl Outbox {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        Outbox { depth: env("OUTBOX_DEPTH", 256).max(1) as usize, ttl_ms: env("OUTBOX_TTL_SECS", 3600) * 1000, queues: Mutex::new(HashMap::new()), live: Mutex::new(HashMap::new()) }
+    }
+
+    /// Hands the delta to an attached socket for the device, or queues it. Returns whether it
+    /// was delivered live and the resulting queue depth.
+    pub fn send(&self, tenant: &str, device_id: &str, delta: Value, priority: Priority) -> (String, bool, usize) {
+        let key = (tenant.to_string(), device_id.to_string());
+        let msg = Outbound { id: uuid::Uuid::new_v4().to_string(), delta, queued_at_ms: now_ms(), priority };
+        let id = msg.id.clone();
+        let mut msg = Some(msg);
+        self.live.lock().unwrap().retain(|_, (k, tx)| {
+            if *k != key || msg.is_none() { return !tx.is_closed(); }
+            match tx.try_send(msg.take().expect("checked above")) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(m)) => { msg = Some(m); true }
+                Err(mpsc::error::TrySendError::Closed(m)) => { msg = Some(m); false }
+            }
+        });
+        let Some(msg) = msg else { return (id, true, self.depth_of(&key)) };
+        let mut queues = self.queues.lock().unwrap();
+        let q = queues.entry(key).or_default();
+        self.expire(q);
+        if q.items.len() >= self.depth { evict(q); }
+        q.items.push_back(msg);
+        (id, false, q.items.len())
+    }
+
+    fn expire(&self, q: &mut Queue) {
+        let cutoff = now_ms().saturating_sub(self.ttl_ms);
+        while q.items.front().is_some_and(|m| m.queued_at_ms < cutoff) { q.items.pop_front(); q.dropped += 1; }
+    }
+
+    fn depth_of(&self, key: &DeviceKey) -> usize { self.queues.lock().unwrap().get(key).map_or(0, |q| q.items.len()) }
+
+    pub fn info(&self, tenant: &str, device_id: &str) -> QueueInfo {
+        let mut queues = self.queues.lock().unwrap();
+        let Some(q) = queues.get_mut(&(tenant.to_string(), device_id.to_string())) else { return QueueInfo { depth: 0, dropped: 0, oldest_age_ms: None } };
+        self.expire(q);
+        QueueInfo { depth: q.items.len(), dropped: q.dropped, oldest_age_ms: q.items.front().map(|m| now_ms().saturating_sub(m.queued_at_ms)) }
+    }
+
+    pub fn is_online(&self, connection_id: &str) -> bool {
+        self.live.lock().unwrap().get(connection_id).is_some_and(|(_, tx)| !tx.is_closed())
+    }
+
+    /// Closes the connection's socket, if any. Queued deltas stay for the next connection.
+    pub fn detach(&self, connection_id: &str) { self.live.lock().unwrap().remove(connection_id); }
+
+    pub fn remove_tenant(&self, tenant: &str) {
+        self.queues.lock().unwrap().retain(|(t, _), _| t != tenant);
+        self.live.lock().unwrap().retain(|_, ((t, _), _)| t != tenant);
+    }
+
+    /// Removes and returns the device's queue, e.g. to hand it to another region.
+    pub fn take(&self, tenant: &str, device_id: &str) -> Vec<Outbound> {
+        let mut queues = self.queues.lock().unwrap();
+        let Some(q) = queues.get_mut(&(tenant.to_string(), device_id.to_string())) else { return Vec::new() };
+        self.expire(q);
+        q.items.drain(..).collect()
+    }
+
+    /// Puts `items` back ahead of anything queued since, keeping their ids and ages.
+    pub fn restore(&self, tenant: &str, device_id: &str, items: Vec<Outbound>) {
+        let mut queues = self.queues.lock().unwrap();
+        let q = queues.entry((tenant.to_string(), device_id.to_string())).or_default();
+        for m in items.into_iter().rev() { q.items.push_front(m); }
+        self.expire(q);
+        while q.items.len() > self.depth { evict(q); }
+    }
+
+    /// Registers the socket and returns everything queued while the device was away.
+    pub fn attach(&self, connection_id: &str, key: DeviceKey, tx: mpsc::Sender<Outbound>) -> Vec<Outbound> {
+        let mut backlog: Vec<Outbound> = self.queues.lock().unwrap().get_mut(&key).map(|q| { self.expire(q); q.items.drain(..).collect() }).unwrap_or_default();
+        backlog.sort_by_key(|m| m.priority);
+        self.live.lock().unwrap().insert(connection_id.to_string(), (key, tx));
+        backlog
+    }
+}
+
+/// Drops the oldest of the lowest-priority queued deltas.
+fn evict(q: &mut Queue) {
+    let Some(lowest) = q.items.iter().map(|m| m.priority).max() else { return };
+    if let Some(i) = q.items.iter().position(|m| m.priority == lowest) { q.items.remove(i); q.dropped += 1; }
+}
+
+fn device_of(s: &AppState, tenant: &str, connection_id: &str) -> Result<crate::Connection, ApiError> {
+    s.connections.lock().unwrap().get(connection_id).filter(|c| c.tenant == tenant).cloned().ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(connection_id.into())))
+}
+
+/// `?include_deleted=true` adds soft-deleted connections still within retention.
+pub async fn list_connections(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Query(q): Query<ListQuery>) -> Json<Vec<ConnectionInfo>> {
+    let mut conns: Vec<(String, crate::Connection, Deletion)> = s.connections.lock().unwrap().iter().filter(|(_, c)| c.tenant == tenant).map(|(id, c)| (id.clone(), c.clone(), Deletion::default())).collect();
+    if q.include_deleted { conns.extend(s.trash.connections(&tenant)); }
+    let mut out: Vec<ConnectionInfo> = conns.into_iter().map(|(id, c, deletion)| {
+        let queue = s.outbox.info(&tenant, &c.device_id);
+        ConnectionInfo::new(&s, id, c, queue, deletion)
+    }).collect();
+    out.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    Json(out)
+}
+
+pub async fn get_connection(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<ConnectionInfo>, ApiError> {
+    let c = device_of(&s, &tenant, &id)?;
+    let queue = s.outbox.info(&tenant, &c.device_id);
+    Ok(Json(ConnectionInfo::new(&s, id, c, queue, Deletion::default())))
+}
+
+pub async fn post_message(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(id): Path<String>, Json(req): Json<PostMessage>) -> Result<(StatusCode, Json<PostMessageResult>), ApiError> {
+    let c = device_of(&s, &tenant, &id)?;
+    let (id, delivered, queue_depth) = s.outbox.send(&tenant, &c.device_id, req.delta, req.priority);
+    Ok((if delivered { StatusCode::OK } else { StatusCode::ACCEPTED }, Json(PostMessageResult { id, delivered, queue_depth })))
+}
+
+pub async fn ws(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(id): Path<String>, upgrade: WebSocketUpgrade) -> Result<Response, ApiError> {
+    let c = device_of(&s, &tenant, &id)?;
+    Ok(upgrade.on_upgrade(move |socket| pump(s, socket, id, (tenant, c.device_id))))
+}
+
+async fn pump(s: Arc<AppState>, mut socket: WebSocket, connection_id: String, key: DeviceKey) {
+    let (tx, mut rx) = mpsc::channel(s.outbox.depth);
+    let backlog = s.outbox.attach(&connection_id, key.clone(), tx);
+    tracing::info!(%connection_id, device_id = %key.1, drained = backlog.len(), "device attached outbound channel");
+    for m in backlog {
+        if send(&s, &mut socket, &connection_id, &key, &m).await.is_err() { return; }
+    }
+    loop {
+        tokio::select! {
+            out = rx.recv() => match out {
+                Some(m) => if send(&s, &mut socket, &connection_id, &key, &m).await.is_err() { break },
+                None => { let _ = socket.send(Message::Close(None)).await; break }
+            },
+            inc = socket.recv() => match inc {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            },
+        }
+    }
+    let mut live = s.outbox.live.lock().unwrap();
+    if live.get(&connection_id).is_some_and(|(_, tx)| tx.is_closed()) { live.remove(&connection_id); }
+}
+
+/// Sends one message, paced by the connection's bandwidth limits.
+async fn send(s: &AppState, socket: &mut WebSocket, connection_id: &str, (tenant, device_id): &DeviceKey, m: &Outbound) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(m).unwrap_or_default();
+    shaping::pace(s, tenant, connection_id, device_id, text.len() as u64).await;
+    socket.send(Message::Text(text)).await
+}
@@ -0,0 +1,202 @@
+//! Back-pressure for the sync path. Every sync (HTTP, CoAP, QUIC) holds a permit while it runs;
+//! past `SYNC_MAX_IN_FLIGHT` (default 512) gateway-wide or `SYNC_MAX_IN_FLIGHT_PER_CONNECTION`
+//! (default 4) for one connection, syncs are shed with 429, `Retry-After` and a suggested
+//! interval that grows with load from `SYNC_PACING_BASE_MS` (default 100), so well-behaved
+//! clients slow down instead of retrying in a tight loop.
+//!
+//! Syncs carry a `priority` (`high`, `normal`, `low`). When the gateway is at capacity a sync
+//! waits in its priority's lane, bounded by `SYNC_LANE_DEPTH` (default 256) and shed once that
+//! is full. Freed slots go to the lanes by weighted round robin (`SYNC_LANE_WEIGHTS`, default
+//! `8,4,1` for high, normal, low), so safety-critical deltas overtake bulk telemetry without
+//! starving it.
+
+use crate::{api_err, ApiError};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Upper bounds (seconds) of the per-priority sync latency histogram.
+pub const LATENCY_BUCKETS: [f64; 11] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority { High, #[default] Normal, Low }
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    /// Absent or unknown priorities are `normal`.
+    pub fn parse(p: Option<&str>) -> Priority {
+        match p { Some("high") => Priority::High, Some("low") => Priority::Low, _ => Priority::Normal }
+    }
+
+    pub fn as_str(self) -> &'static str { match self { Priority::High => "high", Priority::Normal => "normal", Priority::Low => "low" } }
+
+    fn lane(self) -> usize { self as usize }
+}
+
+/// Waiting syncs per priority, with the weighted round-robin credits of the current round.
+struct Lanes { queues: [VecDeque<oneshot::Sender<()>>; 3], credits: [u32; 3] }
+
+#[derive(Default)]
+struct LaneStats { shed: u64, count: u64, sum_secs: f64, buckets: [u64; LATENCY_BUCKETS.len()] }
+
+pub struct Pressure {
+    limit: usize, per_connection: usize, base_ms: u32, depth: usize, weights: [u32; 3],
+    in_flight: AtomicUsize, by_connection: Mutex<HashMap<String, usize>>, shed: AtomicU64,
+    lanes: Mutex<Lanes>, stats: Mutex<[LaneStats; 3]>,
+}
+
+#[derive(Serialize)]
+pub struct PressureSnapshot {
+    pub level: &'static str, pub in_flight: usize, pub limit: usize, pub utilization: f64, pub per_connection_limit: usize, pub busy_connections: usize, pub suggested_interval_ms: u32, pub shed_total: u64,
+    pub lanes: Vec<LaneSnapshot>,
+}
+
+#[derive(Serialize)]
+pub struct LaneSnapshot { pub priority: Priority, pub weight: u32, pub waiting: usize, pub depth: usize, pub shed_total: u64 }
+
+/// Cumulative latency histogram of one priority, for `/metrics`.
+pub struct LaneLatency { pub priority: Priority, pub count: u64, pub sum_secs: f64, pub buckets: [u64; LATENCY_BUCKETS.len()] }
+
+/// Releases the sync's slots when dropped. A permit still waiting in a lane holds no gateway
+/// slot yet; if one was handed to it meanwhile, dropping passes it on.
+pub struct Permit<'a> { p: &'a Pressure, connection_id: String, slot: bool, pending: Option<oneshot::Receiver<()>> }
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let granted = self.slot || self.pending.as_mut().is_some_and(|rx| { rx.close(); rx.try_recv().is_ok() });
+        if granted { self.p.release_slot(); }
+        let mut conns = self.p.by_connection.lock().unwrap();
+        if let Some(n) = conns.get_mut(&self.connection_id) { *n -= 1; if *n == 0 { conns.remove(&self.connection_id); } }
+    }
+}
+
+impl Lanes {
+    /// Next waiter by weighted round robin; a new round starts once no lane with work has credit.
+    fn next(&mut self, weights: [u32; 3]) -> Option<oneshot::Sender<()>> {
+        if self.queues.iter().all(VecDeque::is_empty) { return None; }
+        if !(0..3).any(|i| self.credits[i] > 0 && !self.queues[i].is_empty()) { self.credits = weights; }
+        let i = (0..3).find(|&i| self.credits[i] > 0 && !self.queues[i].is_empty())?;
+        self.credits[i] -= 1;
+        self.queues[i].pop_front()
+    }
+
+    fn waiting(&self) -> usize { self.queues.iter().map(VecDeque::len).sum() }
+}
+
+impl Pressure {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        let mut weights = [8, 4, 1];
+        if let Ok(w) = std::env::var("SYNC_LANE_WEIGHTS") {
+            match w.split(',').map(|x| x.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>() {
+                Ok(v) if v.len() == 3 && v.iter().all(|x| *x > 0) => weights = [v[0], v[1], v[2]],
+                _ => tracing::warn!("Ignoring SYNC_LANE_WEIGHTS {w:?}: expected three positive integers (high,normal,low)"),
+            }
+        }
+        Pressure {
+            limit: env("SYNC_MAX_IN_FLIGHT", 512).max(1) as usize, per_connection: env("SYNC_MAX_IN_FLIGHT_PER_CONNECTION", 4).max(1) as usize, base_ms: env("SYNC_PACING_BASE_MS", 100) as u32,
+            depth: env("SYNC_LANE_DEPTH", 256) as usize, weights,
+            in_flight: AtomicUsize::new(0), by_connection: Mutex::new(HashMap::new()), shed: AtomicU64::new(0),
+            lanes: Mutex::new(Lanes { queues: Default::default(), credits: weights }), stats: Mutex::new(Default::default()),
+        }
+    }
+
+    fn utilization(&self) -> f64 { self.in_flight.load(Ordering::Acquire) as f64 / self.limit as f64 }
+
+    /// Ten times the base interval at saturation.
+    fn interval_ms(&self) -> u32 { (self.base_ms as f64 * (1.0 + 9.0 * self.utilization().min(1.0))).round() as u32 }
+
+    fn shed(&self, priority: Priority, reason: String) -> ApiError {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+        self.stats.lock().unwrap()[priority.lane()].shed += 1;
+        let ms = self.interval_ms();
+        api_err(StatusCode::TOO_MANY_REQUESTS, "Gateway under back-pressure", Some(reason)).code("backpressure").retry_after(u64::from(ms.div_ceil(1000).max(1))).pacing(ms)
+    }
+
+    /// Takes a slot right away, queues in the priority's lane when the gateway is at capacity
+    /// (`wait`), or sheds.
+    fn enter(&self, connection_id: &str, priority: Priority, wait: bool) -> Result<Permit<'_>, ApiError> {
+        let mut conns = self.by_connection.lock().unwrap();
+        if conns.get(connection_id).is_some_and(|n| *n >= self.per_connection) {
+            drop(conns);
+            return Err(self.shed(priority, format!("connection already has {} syncs in flight", self.per_connection)));
+        }
+        let mut lanes = self.lanes.lock().unwrap();
+        // Queued syncs go first: a newcomer only takes a free slot when nobody is waiting.
+        let pending = if lanes.waiting() == 0 && self.in_flight.load(Ordering::Acquire) < self.limit {
+            self.in_flight.fetch_add(1, Ordering::AcqRel);
+            None
+        } else if wait && lanes.queues[priority.lane()].len() < self.depth {
+            let (tx, rx) = oneshot::channel();
+            lanes.queues[priority.lane()].push_back(tx);
+            Some(rx)
+        } else {
+            drop((lanes, conns));
+            let reason = if wait { format!("{} priority lane is full", priority.as_str()) } else { "gateway sync capacity exhausted".to_string() };
+            return Err(self.shed(priority, reason));
+        };
+        *conns.entry(connection_id.to_string()).or_default() += 1;
+        Ok(Permit { p: self, connection_id: connection_id.to_string(), slot: pending.is_none(), pending })
+    }
+
+    /// Admits a sync, waiting in its lane while the gateway is at capacity.
+    pub async fn admit(&self, connection_id: &str, priority: Priority) -> Result<Permit<'_>, ApiError> {
+        let mut permit = self.enter(connection_id, priority, true)?;
+        if let Some(rx) = permit.pending.as_mut() {
+            // The sender is only dropped after `close`, which this permit alone calls.
+            let _ = rx.await;
+            permit.pending = None;
+            permit.slot = true;
+        }
+        Ok(permit)
+    }
+
+    /// Admits a sync only if a slot is free right now; used where waiting while holding other
+    /// permits could deadlock.
+    pub fn try_admit(&self, connection_id: &str, priority: Priority) -> Result<Permit<'_>, ApiError> { self.enter(connection_id, priority, false) }
+
+    /// Hands a freed slot to the next waiter, or returns it to the pool.
+    fn release_slot(&self) {
+        let mut lanes = self.lanes.lock().unwrap();
+        while let Some(tx) = lanes.next(self.weights) {
+            // A waiter that gave up has closed its receiver; try the next one.
+            if tx.send(()).is_ok() { return; }
+        }
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Records a finished sync's end-to-end latency, lane wait included.
+    pub fn observe(&self, priority: Priority, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let mut stats = self.stats.lock().unwrap();
+        let s = &mut stats[priority.lane()];
+        s.count += 1;
+        s.sum_secs += secs;
+        for (b, le) in s.buckets.iter_mut().zip(LATENCY_BUCKETS) { if secs <= le { *b += 1; } }
+    }
+
+    pub fn latencies(&self) -> Vec<LaneLatency> {
+        let stats = self.stats.lock().unwrap();
+        Priority::ALL.iter().map(|&p| { let s = &stats[p.lane()]; LaneLatency { priority: p, count: s.count, sum_secs: s.sum_secs, buckets: s.buckets } }).collect()
+    }
+
+    pub fn snapshot(&self) -> PressureSnapshot {
+        let utilization = self.utilization();
+        let level = match utilization { u if u >= 1.0 => "saturated", u if u >= 0.8 => "high", u if u >= 0.5 => "elevated", _ => "normal" };
+        let lanes = {
+            let (lanes, stats) = (self.lanes.lock().unwrap(), self.stats.lock().unwrap());
+            Priority::ALL.iter().map(|&p| LaneSnapshot { priority: p, weight: self.weights[p.lane()], waiting: lanes.queues[p.lane()].len(), depth: self.depth, shed_total: stats[p.lane()].shed }).collect()
+        };
+        PressureSnapshot {
+            level, in_flight: self.in_flight.load(Ordering::Acquire), limit: self.limit, utilization, per_connection_limit: self.per_connection,
+            busy_connections: self.by_connection.lock().unwrap().values().filter(|n| **n >= self.per_connection).count(), suggested_interval_ms: self.interval_ms(), shed_total: self.shed.load(Ordering::Relaxed),
+            lanes,
+        }
+    }
+}
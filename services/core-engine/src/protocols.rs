@@ -0,0 +1,486 @@
+//! Device protocol registry. Every protocol the gateway speaks is a `ProtocolPlugin`: it
+//! validates incoming payloads, decodes them into canonical SDF JSON and encodes canonical
+//! documents back into its own framing. `/protocols`, connect negotiation, `/sync` and
+//! `/transform` all resolve protocols here.
+//!
+//! Besides the built-ins, integrators can declare protocols in a JSON file
+//! (`PROTOCOL_PLUGINS_PATH`) or at runtime through `PUT /admin/protocols/:name`. A spec either
+//! describes a declarative envelope or, with the `wasm-plugins` feature, points at a WASM
+//! module implementing the hooks (see [`wasm`]).
+//!
+//! A spec's `latency_ms` / `throughput_mbps` are nominal figures. `/protocols` also reports
+//! what the gateway actually observed for each protocol over a rolling window
+//! (`PROTOCOL_STATS_WINDOW_SECS`, default 300): sync and transform latency percentiles and
+//! inbound throughput.
+//!
+//! A new version of a protocol can be canaried: `PUT /admin/protocols/:name/canary` installs it
+//! next to the stable one with a percentage of `/transform` traffic, per-version request and
+//! error counts are kept, and `POST .../canary/promote` or `DELETE .../canary` finish or roll
+//! back the rollout. Syncs always use the stable version.
+//!
+//! Wire formats are versioned as `name@N`; the bare name is the protocol's current version, one
+//! above its highest registered `name@N` (so `sdf-stream` is also `sdf-stream@2`). Connect
+//! negotiation picks the highest version the device offers of its most preferred protocol, and
+//! a connection on an older version keeps it: its payloads are upgraded to the current format
+//! on decode, before validation and merging. `sdf-stream@1` is built in for legacy firmware;
+//! its frames list objects as `[{"id", "type", "props"}]` with deletions in `removed`.
+
+use crate::{api_err, ApiError, ProtocolInfo};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Samples kept per protocol; older ones fall out early under very high traffic.
+const MAX_SAMPLES: usize = 10_000;
+
+pub trait ProtocolPlugin: Send + Sync {
+    fn info(&self) -> ProtocolInfo;
+    fn validate(&self, payload: &Value) -> Result<(), String>;
+    /// Protocol framing -> canonical SDF JSON.
+    fn decode(&self, payload: &Value) -> Result<Value, String>;
+    /// Canonical SDF JSON -> protocol framing.
+    fn encode(&self, sdf: &Value) -> Result<Value, String>;
+}
+
+/// Declarative protocol: optional envelope field wrapping the SDF document, plus fields every
+/// payload must carry.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PluginSpec {
+    pub name: String,
+    #[serde(default)] pub description: String,
+    #[serde(default)] pub latency_ms: f64,
+    #[serde(default)] pub throughput_mbps: f64,
+    /// Field holding the SDF document; without one the payload is the document itself.
+    pub envelope: Option<String>,
+    /// Static fields added around the document when encoding (e.g. an MQTT topic).
+    #[serde(default)] pub envelope_defaults: Map<String, Value>,
+    #[serde(default)] pub required_fields: Vec<String>,
+    /// Path to a WASM module implementing the hooks; needs the `wasm-plugins` feature.
+    pub wasm: Option<String>,
+    /// Free-form label telling rollouts apart.
+    #[serde(default)] pub version: Option<String>,
+}
+
+struct EnvelopePlugin(PluginSpec);
+
+/// `sdf-stream@1`, spoken by legacy firmware.
+struct SdfStreamV1;
+
+/// `name@N` -> (`name`, `N`); anything else is a bare name.
+pub fn split_version(id: &str) -> (&str, Option<u32>) {
+    match id.rsplit_once('@') {
+        Some((base, v)) if !base.is_empty() => v.parse().map_or((id, None), |v| (base, Some(v))),
+        _ => (id, None),
+    }
+}
+
+/// A v1 frame in the current format: the object list becomes the `objects` map, removals nulls.
+pub fn upgrade_v1(frame: &Value) -> Result<Value, String> {
+    let mut out = frame.as_object().ok_or("sdf-stream@1 frames must be JSON objects")?.clone();
+    let mut objects = Map::new();
+    if let Some(list) = out.remove("objects") {
+        let list = list.as_array().ok_or("objects must be an array")?.clone();
+        for (i, o) in list.into_iter().enumerate() {
+            let Value::Object(mut o) = o else { return Err(format!("objects[{i}] must be an object")) };
+            let Some(Value::String(id)) = o.remove("id") else { return Err(format!("objects[{i}].id must be a string")) };
+            let mut obj = match o.remove("props") {
+                Some(Value::Object(p)) => p,
+                None | Some(Value::Null) => Map::new(),
+                Some(_) => return Err(format!("objects[{i}].props must be an object")),
+            };
+            if let Some(t) = o.remove("type") { obj.insert("type".into(), t); }
+            objects.insert(id, Value::Object(obj));
+        }
+    }
+    if let Some(removed) = out.remove("removed") {
+        for id in removed.as_array().ok_or("removed must be an array")? {
+            objects.insert(id.as_str().ok_or("removed must list object ids")?.into(), Value::Null);
+        }
+    }
+    if !objects.is_empty() { out.insert("objects".into(), Value::Object(objects)); }
+    Ok(Value::Object(out))
+}
+
+/// The reverse of [`upgrade_v1`], for sending current documents to legacy devices.
+fn downgrade_v1(sdf: &Value) -> Result<Value, String> {
+    let mut out = sdf.as_object().ok_or("SDF documents must be JSON objects")?.clone();
+    let Some(objects) = out.remove("objects") else { return Ok(Value::Object(out)) };
+    let objects = objects.as_object().ok_or("objects must be an object")?;
+    let (mut list, mut removed) = (Vec::new(), Vec::new());
+    for (id, o) in objects {
+        match o {
+            Value::Null => removed.push(Value::String(id.clone())),
+            Value::Object(o) => {
+                let mut props = o.clone();
+                let mut entry = serde_json::json!({ "id": id });
+                if let Some(t) = props.remove("type") { entry["type"] = t; }
+                entry["props"] = Value::Object(props);
+                list.push(entry);
+            }
+            _ => return Err(format!("object {id} has no sdf-stream@1 form")),
+        }
+    }
+    out.insert("objects".into(), Value::Array(list));
+    if !removed.is_empty() { out.insert("removed".into(), Value::Array(removed)); }
+    Ok(Value::Object(out))
+}
+
+impl ProtocolPlugin for SdfStreamV1 {
+    fn info(&self) -> ProtocolInfo {
+        ProtocolInfo { name: "sdf-stream@1".into(), description: "Legacy SDF delta frames, upgraded to sdf-stream on decode".into(), latency_ms: 8.0, throughput_mbps: 100.0, version: None, observed: None }
+    }
+    fn validate(&self, payload: &Value) -> Result<(), String> { upgrade_v1(payload).map(drop) }
+    fn decode(&self, payload: &Value) -> Result<Value, String> { upgrade_v1(payload) }
+    fn encode(&self, sdf: &Value) -> Result<Value, String> { downgrade_v1(sdf) }
+}
+
+impl ProtocolPlugin for EnvelopePlugin {
+    fn info(&self) -> ProtocolInfo {
+        ProtocolInfo { name: self.0.name.clone(), description: self.0.description.clone(), latency_ms: self.0.latency_ms, throughput_mbps: self.0.throughput_mbps, version: self.0.version.clone(), observed: None }
+    }
+    fn validate(&self, payload: &Value) -> Result<(), String> {
+        if self.0.envelope.is_none() && self.0.required_fields.is_empty() { return Ok(()); }
+        let obj = payload.as_object().ok_or_else(|| format!("{} payloads must be JSON objects", self.0.name))?;
+        let missing: Vec<&str> = self.0.required_fields.iter().chain(&self.0.envelope).filter(|f| !obj.contains_key(f.as_str())).map(String::as_str).collect();
+        if missing.is_empty() { Ok(()) } else { Err(format!("missing fields {missing:?}")) }
+    }
+    fn decode(&self, payload: &Value) -> Result<Value, String> {
+        self.validate(payload)?;
+        Ok(match &self.0.envelope { Some(f) => payload[f.as_str()].clone(), None => payload.clone() })
+    }
+    fn encode(&self, sdf: &Value) -> Result<Value, String> {
+        let Some(f) = &self.0.envelope else { return Ok(sdf.clone()) };
+        let mut out = self.0.envelope_defaults.clone();
+        out.insert(f.clone(), sdf.clone());
+        Ok(Value::Object(out))
+    }
+}
+
+fn builtins() -> Vec<PluginSpec> {
+    let spec = |name: &str, description: &str, latency_ms, throughput_mbps, envelope: Option<&str>, defaults: Value| PluginSpec {
+        name: name.into(), description: description.into(), latency_ms, throughput_mbps, envelope: envelope.map(Into::into),
+        envelope_defaults: defaults.as_object().cloned().unwrap_or_default(), required_fields: vec![], wasm: None, version: None,
+    };
+    vec![
+        spec("sdf-stream", "SDF delta streaming for spatial data sync", 8.0, 100.0, None, Value::Null),
+        spec("mqtt-bridge", "MQTT to SDF protocol bridge for IoT devices", 15.0, 10.0, Some("payload"), serde_json::json!({ "topic": "alice/sdf" })),
+        spec("grpc-relay", "gRPC relay for microservice communication", 5.0, 500.0, None, Value::Null),
+        spec(crate::quic::PROTOCOL, "SDF deltas over QUIC streams for lossy links (no head-of-line blocking)", 6.0, 100.0, None, Value::Null),
+    ]
+}
+
+pub fn build(spec: PluginSpec) -> Result<Arc<dyn ProtocolPlugin>, String> {
+    match &spec.wasm {
+        #[cfg(feature = "wasm-plugins")]
+        Some(path) => Ok(Arc::new(wasm::WasmPlugin::load(spec.clone(), path)?)),
+        #[cfg(not(feature = "wasm-plugins"))]
+        Some(_) => Err(format!("protocol {} needs the wasm-plugins feature", spec.name)),
+        None => Ok(Arc::new(EnvelopePlugin(spec))),
+    }
+}
+
+pub struct Registry { plugins: RwLock<BTreeMap<String, Arc<dyn ProtocolPlugin>>>, canaries: Mutex<HashMap<String, Canary>>, window: Duration, traffic: Mutex<HashMap<String, VecDeque<Sample>>>, generation: AtomicU64 }
+
+/// Which installed version served a request.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Variant { Stable, Canary }
+
+#[derive(Default)]
+struct Counts { requests: u64, errors: u64 }
+
+struct Canary { plugin: Arc<dyn ProtocolPlugin>, version: String, percent: u8, stable: Counts, canary: Counts }
+
+#[derive(Deserialize)]
+pub struct CanaryRequest { pub percent: u8, pub spec: PluginSpec }
+
+#[derive(Serialize)]
+pub struct VersionStats { version: String, requests: u64, errors: u64, error_rate: f64 }
+
+#[derive(Serialize)]
+pub struct CanaryStatus { protocol: String, percent: u8, stable: VersionStats, canary: VersionStats }
+
+impl Counts {
+    fn stats(&self, version: String) -> VersionStats {
+        VersionStats { version, requests: self.requests, errors: self.errors, error_rate: if self.requests == 0 { 0.0 } else { self.errors as f64 / self.requests as f64 } }
+    }
+}
+
+struct Sample { at: Instant, latency: Duration, bytes: u64 }
+
+/// Measured over the trailing window; throughput is inbound wire bytes over the sampled span.
+#[derive(Clone, Serialize)]
+pub struct Observed { window_secs: u64, samples: usize, p50_ms: f64, p95_ms: f64, p99_ms: f64, throughput_mbps: f64 }
+
+impl Registry {
+    /// Built-ins plus the specs in `PROTOCOL_PLUGINS_PATH`; bad specs are logged and skipped.
+    pub fn from_env() -> Self {
+        let mut specs = builtins();
+        if let Ok(path) = std::env::var("PROTOCOL_PLUGINS_PATH") {
+            match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|t| serde_json::from_str::<Vec<PluginSpec>>(&t).map_err(|e| e.to_string())) {
+                Ok(extra) => specs.extend(extra),
+                Err(e) => tracing::warn!("Ignoring PROTOCOL_PLUGINS_PATH {path}: {e}"),
+            }
+        }
+        let window_secs = std::env::var("PROTOCOL_STATS_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300);
+        let r = Registry { plugins: RwLock::new(BTreeMap::new()), canaries: Mutex::new(HashMap::new()), window: Duration::from_secs(window_secs), traffic: Mutex::new(HashMap::new()), generation: AtomicU64::new(0) };
+        r.plugins.write().unwrap().insert("sdf-stream@1".into(), Arc::new(SdfStreamV1));
+        for spec in specs {
+            let name = spec.name.clone();
+            if let Err(e) = r.register(spec) { tracing::warn!("Skipping protocol {name}: {e}"); }
+        }
+        r
+    }
+
+    pub fn register(&self, spec: PluginSpec) -> Result<ProtocolInfo, String> {
+        if spec.name.is_empty() { return Err("protocol name is empty".into()); }
+        let plugin = build(spec)?;
+        let info = plugin.info();
+        self.plugins.write().unwrap().insert(info.name.clone(), plugin);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        Ok(info)
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.canaries.lock().unwrap().remove(name);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.plugins.write().unwrap().remove(name).is_some()
+    }
+
+    /// Installs `spec` as the canary for an existing protocol, replacing any previous canary.
+    pub fn set_canary(&self, name: &str, mut spec: PluginSpec, percent: u8) -> Result<CanaryStatus, String> {
+        if percent > 100 { return Err("percent must be between 0 and 100".into()); }
+        if !self.plugins.read().unwrap().contains_key(name) { return Err(format!("{name} has no stable version to canary against")); }
+        spec.name = name.into();
+        let version = spec.version.clone().unwrap_or_else(|| "canary".into());
+        let plugin = build(spec)?;
+        self.canaries.lock().unwrap().insert(name.into(), Canary { plugin, version, percent, stable: Counts::default(), canary: Counts::default() });
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        Ok(self.canary_status(name).expect("inserted above"))
+    }
+
+    pub fn canary_status(&self, name: &str) -> Option<CanaryStatus> {
+        let canaries = self.canaries.lock().unwrap();
+        let c = canaries.get(name)?;
+        let stable_version = self.plugins.read().unwrap().get(name).and_then(|p| p.info().version).unwrap_or_else(|| "stable".into());
+        Some(CanaryStatus { protocol: name.into(), percent: c.percent, stable: c.stable.stats(stable_version), canary: c.canary.stats(c.version.clone()) })
+    }
+
+    /// Makes the canary the stable version.
+    pub fn promote(&self, name: &str) -> Option<ProtocolInfo> {
+        let c = self.canaries.lock().unwrap().remove(name)?;
+        self.plugins.write().unwrap().insert(name.into(), c.plugin.clone());
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        Some(c.plugin.info())
+    }
+
+    pub fn rollback(&self, name: &str) -> bool { self.canaries.lock().unwrap().remove(name).is_some() }
+
+    /// Bumped whenever an installed plugin changes, so results computed by an older one can be told apart.
+    pub fn generation(&self) -> u64 { self.generation.load(Ordering::Relaxed) }
+
+    /// `get`, but sends the canary's share of requests to the canary version.
+    pub fn route(&self, name: &str) -> Result<(Arc<dyn ProtocolPlugin>, Variant), ApiError> {
+        let stable = self.get(name)?;
+        let canaries = self.canaries.lock().unwrap();
+        match canaries.get(name) {
+            Some(c) if (uuid::Uuid::new_v4().as_u128() % 100) < c.percent as u128 => Ok((c.plugin.clone(), Variant::Canary)),
+            _ => Ok((stable, Variant::Stable)),
+        }
+    }
+
+    /// Counts a routed request towards its version; a no-op without a canary.
+    pub fn record(&self, name: &str, variant: Variant, ok: bool) {
+        let mut canaries = self.canaries.lock().unwrap();
+        let Some(c) = canaries.get_mut(name) else { return };
+        let counts = if variant == Variant::Canary { &mut c.canary } else { &mut c.stable };
+        counts.requests += 1;
+        if !ok { counts.errors += 1; }
+    }
+
+    pub fn list(&self) -> Vec<ProtocolInfo> {
+        self.plugins.read().unwrap().values().map(|p| {
+            let mut info = p.info();
+            info.observed = self.observed(&info.name);
+            info
+        }).collect()
+    }
+
+    /// Records one request handled in `protocol`.
+    pub fn observe(&self, protocol: &str, latency: Duration, bytes: u64) {
+        let mut traffic = self.traffic.lock().unwrap();
+        let q = traffic.entry(protocol.to_string()).or_default();
+        if q.len() == MAX_SAMPLES { q.pop_front(); }
+        q.push_back(Sample { at: Instant::now(), latency, bytes });
+    }
+
+    fn observed(&self, protocol: &str) -> Option<Observed> {
+        let mut traffic = self.traffic.lock().unwrap();
+        let q = traffic.get_mut(protocol)?;
+        let now = Instant::now();
+        while q.front().is_some_and(|s| now.duration_since(s.at) > self.window) { q.pop_front(); }
+        let oldest = q.front()?.at;
+        let mut latencies: Vec<f64> = q.iter().map(|s| s.latency.as_secs_f64() * 1000.0).collect();
+        latencies.sort_by(f64::total_cmp);
+        let pct = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+        let span = now.duration_since(oldest).as_secs_f64().max(1.0);
+        let bytes: u64 = q.iter().map(|s| s.bytes).sum();
+        Some(Observed { window_secs: self.window.as_secs(), samples: q.len(), p50_ms: pct(0.50), p95_ms: pct(0.95), p99_ms: pct(0.99), throughput_mbps: bytes as f64 * 8.0 / span / 1e6 })
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<dyn ProtocolPlugin>, ApiError> {
+        let plugins = self.plugins.read().unwrap();
+        plugins.get(canonical(&plugins, name)).cloned().ok_or_else(|| {
+            api_err(StatusCode::BAD_REQUEST, "Unsupported protocol", Some(format!("{name}; supported: {}", plugins.keys().cloned().collect::<Vec<_>>().join(", "))))
+        })
+    }
+
+    /// The registered name `id` refers to: `name@N` for the current version is the bare name.
+    pub fn canonical(&self, id: &str) -> String { canonical(&self.plugins.read().unwrap(), id).to_string() }
+
+    /// The highest version the client offers of the first protocol in its preference list that
+    /// the gateway speaks in any offered version.
+    pub fn negotiate(&self, offered: &[String]) -> Result<String, ApiError> {
+        let plugins = self.plugins.read().unwrap();
+        let mut best: Option<(&str, u32)> = None;
+        for id in offered {
+            let (base, v) = split_version(id);
+            if best.is_some_and(|(b, _)| b != base) { continue; }
+            let current = current_version(&plugins, base);
+            let v = v.unwrap_or(current);
+            let known = if v == current { plugins.contains_key(base) } else { plugins.contains_key(&format!("{base}@{v}")) };
+            if known && best.is_none_or(|(_, b)| v > b) { best = Some((base, v)); }
+        }
+        match best {
+            Some((base, v)) if v == current_version(&plugins, base) => Ok(base.to_string()),
+            Some((base, v)) => Ok(format!("{base}@{v}")),
+            None => Err(api_err(StatusCode::BAD_REQUEST, "No supported protocol offered", Some(format!("offered {offered:?}; supported: {}", plugins.keys().cloned().collect::<Vec<_>>().join(", "))))),
+        }
+    }
+
+}
+
+/// One above the highest registered `base@N`.
+fn current_version(plugins: &BTreeMap<String, Arc<dyn ProtocolPlugin>>, base: &str) -> u32 {
+    plugins.keys().filter_map(|k| match split_version(k) { (b, Some(v)) if b == base => Some(v), _ => None }).max().unwrap_or(0) + 1
+}
+
+fn canonical<'a>(plugins: &BTreeMap<String, Arc<dyn ProtocolPlugin>>, id: &'a str) -> &'a str {
+    if plugins.contains_key(id) { return id; }
+    match split_version(id) {
+        (base, Some(v)) if v == current_version(plugins, base) => base,
+        _ => id,
+    }
+}
+
+pub fn invalid(protocol: &str, e: String) -> ApiError {
+    api_err(StatusCode::UNPROCESSABLE_ENTITY, "Invalid payload for protocol", Some(format!("{protocol}: {e}")))
+}
+
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm {
+    //! WASM protocol plugins. A module exports `memory`, `alloc(len: i32) -> i32` and
+    //! `validate`, `decode`, `encode`, each `(ptr: i32, len: i32) -> i64` taking a JSON
+    //! document and returning `(ptr << 32) | len` of a JSON reply, `{"ok": <value>}` or
+    //! `{"err": "<message>"}`. Every call runs in a fresh instance, so plugins are stateless.
+    //!
+    //! A call gets `WASM_PLUGIN_FUEL` units of fuel (default 100M, roughly as many instructions)
+    //! and `WASM_PLUGIN_MEMORY_MB` of linear memory (default 64); a plugin running out of either
+    //! fails the call. Calls made from the async runtime move the worker's other tasks away
+    //! first, so a slow plugin does not stall them.
+
+    use super::{PluginSpec, ProtocolPlugin};
+    use crate::ProtocolInfo;
+    use serde_json::Value;
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+    pub struct WasmPlugin { spec: PluginSpec, engine: Engine, module: Module, fuel: u64, memory_bytes: usize }
+
+    fn env<T: std::str::FromStr>(name: &str, default: T) -> T {
+        std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+
+    impl WasmPlugin {
+        pub fn load(spec: PluginSpec, path: &str) -> Result<Self, String> {
+            let engine = Engine::new(Config::new().consume_fuel(true)).map_err(|e| e.to_string())?;
+            let module = Module::from_file(&engine, path).map_err(|e| format!("loading {path}: {e}"))?;
+            Ok(WasmPlugin { spec, engine, module, fuel: env("WASM_PLUGIN_FUEL", 100_000_000), memory_bytes: env("WASM_PLUGIN_MEMORY_MB", 64usize) << 20 })
+        }
+
+        fn call(&self, hook: &str, input: &Value) -> Result<Value, String> {
+            match Handle::try_current() {
+                Ok(h) if h.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(|| self.run(hook, input)),
+                _ => self.run(hook, input),
+            }
+        }
+
+        fn run(&self, hook: &str, input: &Value) -> Result<Value, String> {
+            let limits = StoreLimitsBuilder::new().memory_size(self.memory_bytes).instances(1).build();
+            let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+            store.limiter(|l| l);
+            store.set_fuel(self.fuel).map_err(|e| e.to_string())?;
+            let instance = Instance::new(&mut store, &self.module, &[]).map_err(|e| e.to_string())?;
+            let memory = instance.get_memory(&mut store, "memory").ok_or("module exports no memory")?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc").map_err(|e| e.to_string())?;
+            let func = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook).map_err(|e| e.to_string())?;
+            let bytes = serde_json::to_vec(input).map_err(|e| e.to_string())?;
+            let len = i32::try_from(bytes.len()).map_err(|_| "payload too large")?;
+            let ptr = alloc.call(&mut store, len).map_err(|e| e.to_string())?;
+            memory.write(&mut store, ptr as usize, &bytes).map_err(|e| e.to_string())?;
+            let packed = func.call(&mut store, (ptr, len)).map_err(|e| format!("{hook} trapped: {}", e.root_cause()))? as u64;
+            let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+            let mut out = vec![0u8; out_len];
+            memory.read(&store, out_ptr, &mut out).map_err(|e| e.to_string())?;
+            let mut reply: Value = serde_json::from_slice(&out).map_err(|e| format!("bad reply from {hook}: {e}"))?;
+            match reply.get("err").and_then(Value::as_str) {
+                Some(err) => Err(err.to_string()),
+                None => Ok(reply["ok"].take()),
+            }
+        }
+    }
+
+    impl ProtocolPlugin for WasmPlugin {
+        fn info(&self) -> ProtocolInfo {
+            ProtocolInfo { name: self.spec.name.clone(), description: self.spec.description.clone(), latency_ms: self.spec.latency_ms, throughput_mbps: self.spec.throughput_mbps, version: self.spec.version.clone(), observed: None }
+        }
+        fn validate(&self, payload: &Value) -> Result<(), String> { self.call("validate", payload).map(drop) }
+        fn decode(&self, payload: &Value) -> Result<Value, String> { self.call("decode", payload) }
+        fn encode(&self, sdf: &Value) -> Result<Value, String> { self.call("encode", sdf) }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn plugin(name: &str, wat: &str) -> WasmPlugin {
+            let path = std::env::temp_dir().join(format!("gw-plugin-{name}-{}.wat", std::process::id()));
+            std::fs::write(&path, wat).unwrap();
+            let spec: PluginSpec = serde_json::from_value(serde_json::json!({ "name": name })).unwrap();
+            let p = WasmPlugin::load(spec, path.to_str().unwrap()).unwrap();
+            std::fs::remove_file(path).unwrap();
+            WasmPlugin { fuel: 1_000_000, memory_bytes: 1 << 20, ..p }
+        }
+
+        #[test]
+        fn a_plugin_that_never_returns_runs_out_of_fuel() {
+            let p = plugin("spin", r#"(module (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 0)
+                (func (export "decode") (param i32 i32) (result i64) (loop (br 0)) i64.const 0))"#);
+            assert!(p.decode(&serde_json::json!({})).unwrap_err().contains("fuel"));
+        }
+
+        #[test]
+        fn a_plugin_cannot_grow_memory_past_the_cap() {
+            let p = plugin("grow", r#"(module (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 0)
+                (func (export "decode") (param i32 i32) (result i64)
+                    (if (i32.eq (memory.grow (i32.const 64)) (i32.const -1)) (then unreachable))
+                    i64.const 0))"#);
+            assert!(p.decode(&serde_json::json!({})).is_err());
+        }
+    }
+}
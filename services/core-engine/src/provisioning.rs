@@ -0,0 +1,259 @@
+//! Device provisioning. `POST /api/v1/devices` pre-registers a device, optionally with the
+//! `protocol` and `region` it is expected to connect with, and returns a one-time
+//! `enrollment_token` valid for `PROVISIONING_TOKEN_TTL_SECS` (default 86400). The device's
+//! first connect presents the token; the gateway then binds a fresh `device_credential`,
+//! returned once in that connect's response, which every later connect presents instead.
+//! A registered device connecting without them is refused with 401 (`invalid_enrollment_token`,
+//! `invalid_device_credential`), one connecting with other attributes than registered with 403
+//! (`device_attribute_mismatch`); registered attributes also fill in a connect that omits them.
+//! Tenants listed in `PROVISIONING_REQUIRED_TENANTS`, or switched with
+//! `PUT /admin/tenants/:tenant/provisioning`, refuse unregistered devices with 403
+//! (`device_not_provisioned`); elsewhere those connect as before. `POST /devices/:device_id/token`
+//! re-arms enrollment for a replaced or reset device, dropping the old credential, and
+//! `DELETE /devices/:device_id` deprovisions it; both close the device's open connections.
+//! Connections migrated in from another region were admitted there and are not checked again.
+
+use crate::admin::token_matches;
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::rbac::{Admin, Configure, Read, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, ConnectRequest, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status { Pending, Enrolled }
+
+struct Device {
+    protocol: Option<String>, region: Option<String>, status: Status,
+    token_hash: Option<String>, token_expires_ms: u64, credential_hash: Option<String>,
+    created_at_ms: u64, enrolled_at_ms: Option<u64>, last_connect_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceInfo {
+    device_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")] protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] region: Option<String>,
+    status: Status, created_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")] token_expires_at_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")] enrolled_at_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")] last_connect_ms: Option<u64>,
+    /// Only in the response that issued it.
+    #[serde(skip_serializing_if = "Option::is_none")] enrollment_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ProvisionRequest { device_id: String, protocol: Option<String>, region: Option<String> }
+
+#[derive(Deserialize, Serialize)]
+pub struct ProvisioningSetting { required: bool }
+
+#[derive(Default)]
+pub struct ProvisioningCounts { pub pending: usize, pub enrolled: usize, pub rejected: u64 }
+
+/// What `admit` let through, settled by `complete` once the connection is open.
+pub enum Admission { Open, Known, Enroll(String) }
+
+type DeviceKey = (String, String);
+
+pub struct Registry { token_ttl_ms: u64, required: Mutex<HashSet<String>>, devices: Mutex<HashMap<DeviceKey, Device>>, rejected: AtomicU64 }
+
+fn digest(secret: &str) -> String { hex::encode(Sha256::digest(secret.as_bytes())) }
+
+fn secret(prefix: &str) -> String { format!("{prefix}_{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple()) }
+
+fn mismatch(field: &str, registered: &str, given: &str) -> ApiError {
+    api_err(StatusCode::FORBIDDEN, "Device attributes do not match its registration", Some(format!("{field} {given} given, {registered} registered"))).code("device_attribute_mismatch")
+}
+
+impl Validate for ProvisionRequest {
+    fn validate(&self, s: &AppState, v: &mut Violations) {
+        v.id("device_id", &self.device_id);
+        if let Some(p) = &self.protocol { v.protocol(s, "protocol", p); }
+        if let Some(r) = &self.region { v.id("region", r); }
+    }
+}
+
+impl Device {
+    fn info(&self, device_id: &str, enrollment_token: Option<String>) -> DeviceInfo {
+        DeviceInfo {
+            device_id: device_id.into(), protocol: self.protocol.clone(), region: self.region.clone(), status: self.status, created_at_ms: self.created_at_ms,
+            token_expires_at_ms: (self.status == Status::Pending).then_some(self.token_expires_ms), enrolled_at_ms: self.enrolled_at_ms, last_connect_ms: self.last_connect_ms, enrollment_token,
+        }
+    }
+
+    /// Arms enrollment with a new token, unbinding any credential; returns the token.
+    fn arm(&mut self, ttl_ms: u64) -> String {
+        let token = secret("det");
+        (self.status, self.token_hash, self.token_expires_ms, self.credential_hash, self.enrolled_at_ms) = (Status::Pending, Some(digest(&token)), now_ms() + ttl_ms, None, None);
+        token
+    }
+
+    /// Checks a connect against the registration, filling in registered attributes it omits.
+    fn admit(&self, req: &mut ConnectRequest) -> Result<Admission, ApiError> {
+        if let Some(p) = &self.protocol {
+            match (&req.protocol, &req.accept_protocols) {
+                (Some(given), _) if given != p => return Err(mismatch("protocol", p, given)),
+                (None, Some(offered)) if !offered.contains(p) => return Err(mismatch("protocol", p, &offered.join(","))),
+                _ => req.protocol = Some(p.clone()),
+            }
+        }
+        if let Some(r) = &self.region {
+            match &req.region {
+                Some(given) if given != r => return Err(mismatch("region", r, given)),
+                _ => req.region = Some(r.clone()),
+            }
+        }
+        let invalid_token = |details: &str| api_err(StatusCode::UNAUTHORIZED, "Invalid enrollment token", Some(details.into())).code("invalid_enrollment_token");
+        match self.status {
+            Status::Pending => {
+                let token = req.enrollment_token.as_deref().ok_or_else(|| invalid_token("the device has not enrolled yet; present its enrollment_token"))?;
+                let hash = digest(token);
+                if !self.token_hash.as_deref().is_some_and(|h| token_matches(&hash, h)) { return Err(invalid_token("the token is not valid for this device")); }
+                if now_ms() >= self.token_expires_ms { return Err(invalid_token("the token has expired; issue a new one")); }
+                Ok(Admission::Enroll(hash))
+            }
+            Status::Enrolled => match (&req.device_credential, &self.credential_hash) {
+                (Some(c), Some(h)) if token_matches(&digest(c), h) => Ok(Admission::Known),
+                _ => Err(api_err(StatusCode::UNAUTHORIZED, "Invalid device credential", Some("present the device_credential issued at enrollment".into())).code("invalid_device_credential")),
+            },
+        }
+    }
+}
+
+impl Registry {
+    pub fn from_env() -> Self {
+        let required = std::env::var("PROVISIONING_REQUIRED_TENANTS").unwrap_or_default().split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect();
+        let ttl_secs = std::env::var("PROVISIONING_TOKEN_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(86_400u64);
+        Registry { token_ttl_ms: ttl_secs.max(1) * 1000, required: Mutex::new(required), devices: Mutex::new(HashMap::new()), rejected: AtomicU64::new(0) }
+    }
+
+    pub fn required(&self, tenant: &str) -> bool { self.required.lock().unwrap().contains(tenant) }
+
+    pub fn counts(&self) -> ProvisioningCounts {
+        let devices = self.devices.lock().unwrap();
+        let enrolled = devices.values().filter(|d| d.status == Status::Enrolled).count();
+        ProvisioningCounts { pending: devices.len() - enrolled, enrolled, rejected: self.rejected.load(Ordering::Relaxed) }
+    }
+
+    /// First half of a connect: checks the device against its registration (or the tenant's
+    /// requirement for one) and returns the request with registered attributes filled in.
+    pub fn admit(&self, s: &AppState, tenant: &str, actor: &str, mut req: ConnectRequest) -> Result<(ConnectRequest, Admission), ApiError> {
+        let result = match self.devices.lock().unwrap().get(&(tenant.to_string(), req.device_id.clone())) {
+            Some(d) => d.admit(&mut req),
+            None if self.required(tenant) => Err(api_err(StatusCode::FORBIDDEN, "Device is not provisioned", Some(format!("register {} with POST /api/v1/devices first", req.device_id))).code("device_not_provisioned")),
+            None => Ok(Admission::Open),
+        };
+        match result {
+            Ok(admission) => Ok((req, admission)),
+            Err(e) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                s.audit.record(actor, "device.connect.denied", Some(tenant), Some(&req.device_id), json!({ "reason": e.body.code, "details": e.body.details }));
+                Err(e)
+            }
+        }
+    }
+
+    /// Second half, once the connection is open: an enrolling device gets its credential. Fails
+    /// only when a concurrent connect used or replaced the token first.
+    pub fn complete(&self, s: &AppState, tenant: &str, actor: &str, device_id: &str, admission: Admission) -> Result<Option<String>, ApiError> {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(d) = devices.get_mut(&(tenant.to_string(), device_id.to_string())) else { return Ok(None) };
+        let now = now_ms();
+        let credential = match admission {
+            Admission::Open => return Ok(None),
+            Admission::Known => None,
+            Admission::Enroll(hash) => {
+                if d.status != Status::Pending || d.token_hash.as_deref() != Some(hash.as_str()) {
+                    return Err(api_err(StatusCode::UNAUTHORIZED, "Invalid enrollment token", Some("the token was used or replaced by another connect".into())).code("invalid_enrollment_token"));
+                }
+                let credential = secret("dcr");
+                (d.status, d.token_hash, d.credential_hash, d.enrolled_at_ms) = (Status::Enrolled, None, Some(digest(&credential)), Some(now));
+                s.audit.record(actor, "device.enroll", Some(tenant), Some(device_id), serde_json::Value::Null);
+                tracing::info!(%tenant, %device_id, "device enrolled");
+                Some(credential)
+            }
+        };
+        d.last_connect_ms = Some(now);
+        Ok(credential)
+    }
+
+    /// Returns how many registrations were removed.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut devices = self.devices.lock().unwrap();
+        let n = devices.len();
+        devices.retain(|(t, _), _| t != tenant);
+        n - devices.len()
+    }
+}
+
+fn close_device(s: &AppState, tenant: &str, device_id: &str) -> usize {
+    let ids: Vec<String> = s.connections.lock().unwrap().iter().filter(|(_, c)| c.tenant == tenant && c.device_id == device_id).map(|(id, _)| id.clone()).collect();
+    ids.iter().filter_map(|id| s.drop_connection(id)).count()
+}
+
+/// The enrollment token is only returned here and by `reissue`.
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Valid(req): Valid<ProvisionRequest>) -> Result<(StatusCode, Json<DeviceInfo>), ApiError> {
+    let info = {
+        let mut devices = s.provisioning.devices.lock().unwrap();
+        let key = (tenant.clone(), req.device_id.clone());
+        if devices.contains_key(&key) { return Err(api_err(StatusCode::CONFLICT, "Device already provisioned", Some(req.device_id)).code("device_exists")); }
+        let mut d = Device { protocol: req.protocol, region: req.region, status: Status::Pending, token_hash: None, token_expires_ms: 0, credential_hash: None, created_at_ms: now_ms(), enrolled_at_ms: None, last_connect_ms: None };
+        let token = d.arm(s.provisioning.token_ttl_ms);
+        let info = d.info(&req.device_id, Some(token));
+        devices.insert(key, d);
+        info
+    };
+    s.audit.record(&actor, "device.provision", Some(&tenant), Some(&info.device_id), json!({ "protocol": info.protocol, "region": info.region }));
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<DeviceInfo>> {
+    let mut out: Vec<DeviceInfo> = s.provisioning.devices.lock().unwrap().iter().filter(|((t, _), _)| *t == tenant).map(|((_, id), d)| d.info(id, None)).collect();
+    out.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    Json(out)
+}
+
+pub async fn get_device(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(device_id): Path<String>) -> Result<Json<DeviceInfo>, ApiError> {
+    let devices = s.provisioning.devices.lock().unwrap();
+    devices.get(&(tenant, device_id.clone())).map(|d| Json(d.info(&device_id, None))).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown device", Some(device_id)))
+}
+
+/// Issues a new enrollment token; the bound credential stops working.
+pub async fn reissue(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(device_id): Path<String>) -> Result<Json<DeviceInfo>, ApiError> {
+    let info = {
+        let mut devices = s.provisioning.devices.lock().unwrap();
+        let d = devices.get_mut(&(tenant.clone(), device_id.clone())).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown device", Some(device_id.clone())))?;
+        let token = d.arm(s.provisioning.token_ttl_ms);
+        d.info(&device_id, Some(token))
+    };
+    let connections_closed = close_device(&s, &tenant, &device_id);
+    s.audit.record(&actor, "device.token.reissue", Some(&tenant), Some(&device_id), json!({ "connections_closed": connections_closed }));
+    Ok(Json(info))
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(device_id): Path<String>) -> Result<StatusCode, ApiError> {
+    if s.provisioning.devices.lock().unwrap().remove(&(tenant.clone(), device_id.clone())).is_none() { return Err(api_err(StatusCode::NOT_FOUND, "Unknown device", Some(device_id))); }
+    let connections_closed = close_device(&s, &tenant, &device_id);
+    s.audit.record(&actor, "device.deprovision", Some(&tenant), Some(&device_id), json!({ "connections_closed": connections_closed }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `PUT /admin/tenants/:tenant/provisioning` with `{"required": true}`.
+pub async fn set_required(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(tenant): Path<String>, Json(req): Json<ProvisioningSetting>) -> Json<ProvisioningSetting> {
+    {
+        let mut required = s.provisioning.required.lock().unwrap();
+        if req.required { required.insert(tenant.clone()); } else { required.remove(&tenant); }
+    }
+    tracing::info!(%tenant, required = req.required, "admin set tenant provisioning requirement");
+    s.audit.record(&actor, "admin.tenant.provisioning", Some(&tenant), None, json!({ "required": req.required }));
+    Json(req)
+}
@@ -0,0 +1,117 @@
+//! QUIC transport for devices on lossy links, listening on UDP `QUIC_ADDR` (disabled when
+//! unset) with the device listener's `DEVICE_TLS_*` certificate, or the `TLS_CERT_PATH`/
+//! `TLS_KEY_PATH` one without it; a client CA enables mTLS exactly as on HTTPS. ALPN is
+//! `alice-sdf/1`.
+//!
+//! Each bidirectional stream carries frames of a 4-byte big-endian length followed by a CBOR
+//! document; every request frame gets one reply frame, and independent requests should use
+//! separate streams so a lost packet only stalls its own stream. Requests are
+//! `{"op": "connect", "api_key": ..., <ConnectRequest fields>}` or
+//! `{"op": "sync", <SyncRequest fields>}`; replies are `{"ok": <response>}` or
+//! `{"status": <http status>, "error": <error body>}`. Devices select this transport by
+//! connecting with `protocol: "sdf-quic"`, which returns a `quic://` endpoint.
+
+use crate::codec::{from_cbor, to_cbor};
+use crate::rbac::Permission;
+use crate::validate::ensure;
+use crate::{api_err, apikeys, tls, ApiError, AppState, ConnectRequest, SyncRequest};
+use axum::http::StatusCode;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Endpoint, RecvStream, SendStream, ServerConfig};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+pub const PROTOCOL: &str = "sdf-quic";
+const ALPN: &[u8] = b"alice-sdf/1";
+const MAX_FRAME: usize = 8 * 1024 * 1024;
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Frame {
+    Connect { api_key: String, #[serde(flatten)] req: ConnectRequest },
+    Sync(SyncRequest),
+}
+
+pub fn public_port() -> u16 {
+    std::env::var("QUIC_ADDR").ok().and_then(|a| a.rsplit(':').next()?.parse().ok()).unwrap_or(4433)
+}
+
+fn server_config() -> Result<ServerConfig, String> {
+    let cfg = tls::config_from_env("DEVICE_").or_else(|| tls::config_from_env("")).ok_or("TLS_CERT_PATH and TLS_KEY_PATH are required")?;
+    let mut tls = tls::server_config(&cfg)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let quic = QuicServerConfig::try_from(tls).map_err(|e| e.to_string())?;
+    Ok(ServerConfig::with_crypto(Arc::new(quic)))
+}
+
+pub async fn serve(s: Arc<AppState>) {
+    let Ok(addr) = std::env::var("QUIC_ADDR") else { return };
+    let endpoint = match server_config().and_then(|c| Endpoint::server(c, addr.parse().map_err(|e| format!("{addr}: {e}"))?).map_err(|e| e.to_string())) {
+        Ok(e) => e,
+        Err(e) => { tracing::error!("QUIC listener disabled: {e}"); return; }
+    };
+    tracing::info!("QUIC listener on udp://{addr}");
+    while let Some(incoming) = endpoint.accept().await {
+        let s = s.clone();
+        tokio::spawn(async move {
+            let conn = match incoming.await { Ok(c) => c, Err(e) => { tracing::debug!("QUIC handshake failed: {e}"); return } };
+            let chain = conn.peer_identity().and_then(|id| id.downcast::<Vec<tokio_rustls::rustls::pki_types::CertificateDer<'static>>>().ok());
+            let peer = match tls::peer_identity(chain.as_deref().map(Vec::as_slice)) {
+                Ok(p) => p,
+                Err(e) => {
+                    tracing::warn!(remote = %conn.remote_address(), "client certificate refused: {e}");
+                    conn.close(quinn::VarInt::from_u32(1), b"client certificate names no device");
+                    return;
+                }
+            };
+            let ip = conn.remote_address().ip();
+            while let Ok((send, recv)) = conn.accept_bi().await {
+                let (s, peer) = (s.clone(), peer.clone());
+                tokio::spawn(async move { stream(&s, peer.as_ref(), ip, send, recv).await });
+            }
+        });
+    }
+}
+
+async fn stream(s: &AppState, peer: Option<&tls::PeerIdentity>, ip: IpAddr, mut send: SendStream, mut recv: RecvStream) {
+    loop {
+        let mut len = [0u8; 4];
+        if recv.read_exact(&mut len).await.is_err() { break; }
+        let len = u32::from_be_bytes(len) as usize;
+        let reply = if len > MAX_FRAME {
+            failure(api_err(StatusCode::PAYLOAD_TOO_LARGE, "Frame too large", Some(format!("limit is {MAX_FRAME} bytes"))))
+        } else {
+            let mut buf = vec![0u8; len];
+            if recv.read_exact(&mut buf).await.is_err() { break; }
+            match handle(s, peer, ip, &buf).await { Ok(v) => json!({ "ok": v }), Err(e) => failure(e) }
+        };
+        let body = to_cbor(&reply);
+        if send.write_all(&(body.len() as u32).to_be_bytes()).await.is_err() || send.write_all(&body).await.is_err() { break; }
+        if len > MAX_FRAME { break; }
+    }
+    let _ = send.finish();
+}
+
+fn failure(e: ApiError) -> Value { json!({ "status": e.status.as_u16(), "error": e.body }) }
+
+fn ok<T: Serialize>(v: T) -> Result<Value, ApiError> { Ok(serde_json::to_value(v).unwrap_or_default()) }
+
+async fn handle(s: &AppState, peer: Option<&tls::PeerIdentity>, ip: IpAddr, buf: &[u8]) -> Result<Value, ApiError> {
+    match from_cbor::<Frame>(buf).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Malformed frame", Some(e)))? {
+        Frame::Connect { api_key, req } => {
+            let (tenant, role, scopes) = apikeys::authenticate(s, &api_key).ok_or_else(|| api_err(StatusCode::UNAUTHORIZED, "Invalid API key", None))?;
+            if !role.allows(Permission::Operate) || scopes.is_some_and(|s| !s.contains(&Permission::Operate)) { return Err(api_err(StatusCode::FORBIDDEN, "Permission denied", Some(format!("role {} lacks Operate", role.as_str()))).code("forbidden")); }
+            ensure(s, &req)?;
+            let actor = format!("key:{}", api_key.chars().take(12).collect::<String>());
+            ok(s.open_connection(&tenant, &actor, peer, Some(ip), req).await?)
+        }
+        Frame::Sync(req) => {
+            ensure(s, &req)?;
+            // As on the CoAP bridge, the connection id is the credential and fixes the tenant.
+            let tenant = s.lookup_connection(&req.connection_id).await.map(|c| c.tenant).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(req.connection_id.clone())))?;
+            ok(s.process_sync(&tenant, peer, req, buf.len()).await?)
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Role-based access control. The api-gateway maps the caller's credential (a user token's
+//! `app_metadata.gateway_role`, `viewer` when absent, or the role stored with an API key) to
+//! `X-Gateway-Role`. The identity headers are only honoured on a hop presenting
+//! `GATEWAY_SHARED_SECRET` (see [`gateway_hop_mw`]); requests without a role get `operator` when
+//! they come with an mTLS client certificate, else `RBAC_DEFAULT_ROLE` (default `viewer`). Handlers declare
+//! what they need with a `Require<P>` extractor; the admin API grants `admin` to callers
+//! holding `ADMIN_TOKEN`. An API key issued with scopes also carries them in
+//! `X-Gateway-Scopes`, and a permission outside them is refused even if the role grants it.
+
+use crate::{api_err, tls::PeerIdentity, ApiError, Tenant};
+use axum::{async_trait, extract::{FromRequestParts, Request}, http::{request::Parts, StatusCode}, middleware::Next, response::{Json, Response}};
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role { Viewer, Operator, TenantAdmin, Admin }
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    /// Read the tenant's gateway state, usage and analytics.
+    Read,
+    /// Connect and sync devices, manage meshes, shadows and uploads.
+    Operate,
+    /// Change the tenant's webhooks and alert rules.
+    Configure,
+    /// Cross-tenant administration.
+    Admin,
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Option<Role> { serde_json::from_value(serde_json::Value::String(s.to_ascii_lowercase())).ok() }
+    pub fn as_str(self) -> &'static str {
+        match self { Role::Viewer => "viewer", Role::Operator => "operator", Role::TenantAdmin => "tenant-admin", Role::Admin => "admin" }
+    }
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Viewer => &[Read],
+            Role::Operator => &[Read, Operate],
+            Role::TenantAdmin => &[Read, Operate, Configure],
+            Role::Admin => &[Read, Operate, Configure, Admin],
+        }
+    }
+    pub fn allows(self, p: Permission) -> bool { self.permissions().contains(&p) }
+}
+
+fn default_role() -> Role {
+    std::env::var("RBAC_DEFAULT_ROLE").ok().and_then(|r| Role::parse(&r)).unwrap_or(Role::Viewer)
+}
+
+/// Carries `GATEWAY_SHARED_SECRET` on hops from the api-gateway and from relaying regions.
+pub const HOP_HEADER: &str = "x-gateway-secret";
+
+/// Set by the api-gateway from the credential it verified; nobody else may set them.
+const IDENTITY_HEADERS: [&str; 5] = ["x-tenant-id", "x-gateway-role", "x-gateway-scopes", "x-gateway-actor", "x-gateway-client-ip"];
+
+/// Applied to every route. A request whose `X-Gateway-Secret` matches `GATEWAY_SHARED_SECRET`
+/// keeps its identity headers; any other has them removed, so a client reaching the engine's
+/// listeners directly is the `default` tenant with the default role. Without the secret
+/// configured no hop is trusted.
+pub async fn gateway_hop_mw(mut req: Request, next: Next) -> Response {
+    let given = req.headers().get(HOP_HEADER).and_then(|h| h.to_str().ok());
+    let trusted = std::env::var("GATEWAY_SHARED_SECRET").ok().filter(|s| !s.is_empty()).zip(given).is_some_and(|(expected, given)| crate::admin::token_matches(given, &expected));
+    let headers = req.headers_mut();
+    headers.remove(HOP_HEADER);
+    if !trusted { for h in IDENTITY_HEADERS { headers.remove(h); } }
+    next.run(req).await
+}
+
+/// The caller's role: set by the admin auth middleware, else forwarded by the api-gateway, else
+/// `operator` for an mTLS device, which may only act as its own device_id.
+pub fn role_of(parts: &Parts) -> Result<Role, ApiError> {
+    if let Some(r) = parts.extensions.get::<Role>() { return Ok(*r); }
+    match parts.headers.get("x-gateway-role").and_then(|h| h.to_str().ok()) {
+        Some(r) => Role::parse(r).ok_or_else(|| api_err(StatusCode::FORBIDDEN, "Unknown role", Some(r.into())).code("forbidden")),
+        None if parts.extensions.get::<PeerIdentity>().is_some() => Ok(Role::Operator),
+        None => Ok(default_role()),
+    }
+}
+
+/// The scopes of the caller's API key, if it was issued with any; `None` means the role decides.
+pub fn scopes_of(parts: &Parts) -> Result<Option<Vec<Permission>>, ApiError> {
+    if parts.extensions.get::<Role>().is_some() { return Ok(None); }
+    let Some(h) = parts.headers.get("x-gateway-scopes").and_then(|h| h.to_str().ok()) else { return Ok(None) };
+    h.split(',').map(str::trim).filter(|p| !p.is_empty())
+        .map(|p| serde_json::from_value(serde_json::Value::String(p.to_ascii_lowercase())).map_err(|_| api_err(StatusCode::FORBIDDEN, "Unknown scope", Some(p.into())).code("forbidden")))
+        .collect::<Result<Vec<_>, _>>().map(Some)
+}
+
+pub trait Perm: Send { const PERMISSION: Permission; }
+pub struct Read;
+pub struct Operate;
+pub struct Configure;
+pub struct Admin;
+impl Perm for Read { const PERMISSION: Permission = Permission::Read; }
+impl Perm for Operate { const PERMISSION: Permission = Permission::Operate; }
+impl Perm for Configure { const PERMISSION: Permission = Permission::Configure; }
+impl Perm for Admin { const PERMISSION: Permission = Permission::Admin; }
+
+/// Rejects the request with 403 unless the caller's role, and its key's scopes if any, grant `P`.
+pub struct Require<P: Perm>(pub Role, PhantomData<P>);
+
+#[async_trait]
+impl<P: Perm, S: Send + Sync> FromRequestParts<S> for Require<P> {
+    type Rejection = ApiError;
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let role = role_of(parts)?;
+        if !role.allows(P::PERMISSION) {
+            return Err(api_err(StatusCode::FORBIDDEN, "Permission denied", Some(format!("role {} lacks {:?}", role.as_str(), P::PERMISSION))).code("forbidden"));
+        }
+        if scopes_of(parts)?.is_some_and(|s| !s.contains(&P::PERMISSION)) {
+            return Err(api_err(StatusCode::FORBIDDEN, "Permission denied", Some(format!("API key is not scoped for {:?}", P::PERMISSION))).code("forbidden"));
+        }
+        Ok(Require(role, PhantomData))
+    }
+}
+
+#[derive(Serialize)]
+pub struct EffectivePermissions {
+    tenant: String, role: Role, permissions: Vec<Permission>,
+    #[serde(skip_serializing_if = "Option::is_none")] scopes: Option<Vec<Permission>>,
+}
+
+/// The caller's key scopes, as an extractor.
+pub struct Scopes(pub Option<Vec<Permission>>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Scopes {
+    type Rejection = ApiError;
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> { scopes_of(parts).map(Scopes) }
+}
+
+/// Reports the effective permissions of any valid credential: its role's, narrowed by its key
+/// scopes. A key scoped without `read` is refused here like anywhere else.
+pub async fn permissions(Tenant(tenant): Tenant, Require(role, _): Require<Read>, Scopes(scopes): Scopes) -> Json<EffectivePermissions> {
+    let permissions = role.permissions().iter().copied().filter(|p| scopes.as_ref().is_none_or(|s| s.contains(p))).collect();
+    Json(EffectivePermissions { tenant, role, permissions, scopes })
+}
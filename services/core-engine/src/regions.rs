@@ -0,0 +1,199 @@
+//! Regional capacity planning and failover. `GET /gateway/regions` counts active connections
+//! and warm standbys per region.
+//!
+//! Every `REGION_PROBE_INTERVAL_SECS` (default 10) each upstream region is probed at
+//! `/health/live`, keeping an EWMA of its round-trip latency (smoothing 0.3). A region turns
+//! `degraded` after `REGION_FAILURE_THRESHOLD` consecutive failed probes (default 3) or once its
+//! latency average passes `REGION_LATENCY_THRESHOLD_MS` (default 750), and `healthy` again
+//! after `REGION_RECOVERY_THRESHOLD` consecutive good probes (default 3). The local region is
+//! always healthy. On degradation every connection in the region is moved to the healthy
+//! region with the lowest latency: the device is sent `{"redirect": {...}}` with its new
+//! endpoint and the tenant gets a `redirect` event. New connects naming a degraded region are
+//! refused with 503 (`region_unavailable`) and the same alternative in `suggested_region`.
+//! `GET /gateway/regions/health` reports each region's status and latency.
+
+use crate::events::now_ms;
+use crate::pressure::Priority;
+use crate::rbac::{Read, Require};
+use crate::{api_err, endpoint_for, ApiError, AppState};
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+const ALPHA: f64 = 0.3;
+
+#[derive(Serialize, Default)]
+pub struct RegionCapacity { pub region: String, pub active_connections: u64, pub standby_sessions: u64 }
+
+pub async fn capacity(State(s): State<Arc<AppState>>, _: Require<Read>) -> Json<Vec<RegionCapacity>> {
+    let mut by_region: BTreeMap<String, RegionCapacity> = BTreeMap::new();
+    for c in s.connections.lock().unwrap().values() {
+        by_region.entry(c.region.clone()).or_insert_with(|| RegionCapacity { region: c.region.clone(), ..Default::default() }).active_connections += 1;
+    }
+    for sb in s.standbys.lock().unwrap().values() {
+        by_region.entry(sb.region.clone()).or_insert_with(|| RegionCapacity { region: sb.region.clone(), ..Default::default() }).standby_sessions += 1;
+    }
+    Json(by_region.into_values().collect())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status { Healthy, Degraded }
+
+#[derive(Clone, Serialize)]
+pub struct RegionHealth {
+    region: String, local: bool, status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")] latency_ms: Option<f64>,
+    consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")] last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] last_probe_ms: Option<u64>,
+    since_ms: u64,
+}
+
+struct Probed { status: Status, latency_ms: Option<f64>, failures: u32, successes: u32, last_error: Option<String>, last_probe_ms: Option<u64>, since_ms: u64 }
+
+impl RegionHealth {
+    pub fn region(&self) -> &str { &self.region }
+    pub fn healthy(&self) -> bool { self.status == Status::Healthy }
+    pub fn latency_ms(&self) -> Option<f64> { self.latency_ms }
+}
+
+pub struct Tracker { interval: Duration, latency_threshold_ms: f64, failure_threshold: u32, recovery_threshold: u32, regions: Mutex<BTreeMap<String, Probed>>, redirects: AtomicU64 }
+
+impl Tracker {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        Tracker {
+            interval: Duration::from_secs(env("REGION_PROBE_INTERVAL_SECS", 10).max(1)), latency_threshold_ms: env("REGION_LATENCY_THRESHOLD_MS", 750) as f64,
+            failure_threshold: env("REGION_FAILURE_THRESHOLD", 3).max(1) as u32, recovery_threshold: env("REGION_RECOVERY_THRESHOLD", 3).max(1) as u32,
+            regions: Mutex::new(BTreeMap::new()), redirects: AtomicU64::new(0),
+        }
+    }
+
+    /// Folds one probe result in; returns the new status when it changed.
+    fn observe(&self, region: &str, result: Result<f64, String>) -> Option<Status> {
+        let mut regions = self.regions.lock().unwrap();
+        let r = regions.entry(region.into()).or_insert_with(|| Probed { status: Status::Healthy, latency_ms: None, failures: 0, successes: 0, last_error: None, last_probe_ms: None, since_ms: now_ms() });
+        r.last_probe_ms = Some(now_ms());
+        let slow = match result {
+            Ok(ms) => {
+                let avg = r.latency_ms.map_or(ms, |avg| avg + ALPHA * (ms - avg));
+                r.latency_ms = Some(avg);
+                r.failures = 0;
+                let slow = avg > self.latency_threshold_ms;
+                r.successes = if slow { 0 } else { r.successes + 1 };
+                r.last_error = slow.then(|| format!("latency {avg:.0} ms over {:.0} ms", self.latency_threshold_ms));
+                slow
+            }
+            Err(e) => { r.failures += 1; r.successes = 0; r.last_error = Some(e); false }
+        };
+        let next = match r.status {
+            Status::Healthy if slow || r.failures >= self.failure_threshold => Status::Degraded,
+            Status::Degraded if r.successes >= self.recovery_threshold => Status::Healthy,
+            s => s,
+        };
+        if next == r.status { return None; }
+        r.status = next;
+        r.since_ms = now_ms();
+        Some(next)
+    }
+
+    fn is_degraded(&self, region: &str) -> bool {
+        self.regions.lock().unwrap().get(region).is_some_and(|r| r.status == Status::Degraded)
+    }
+
+    /// The healthy region with the lowest latency, the local one counting as zero.
+    fn alternative(&self, s: &AppState, avoid: &str) -> Option<String> {
+        let regions = self.regions.lock().unwrap();
+        let mut candidates: Vec<(f64, &str)> = s.relay.upstreams().map(|(r, _)| r.as_str())
+            .filter(|r| *r != avoid && regions.get(*r).is_none_or(|p| p.status == Status::Healthy))
+            .map(|r| (regions.get(r).and_then(|p| p.latency_ms).unwrap_or(f64::MAX), r)).collect();
+        if s.relay.local_region != avoid { candidates.push((0.0, &s.relay.local_region)); }
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(b.1)));
+        candidates.first().map(|(_, r)| r.to_string())
+    }
+
+    /// Refuses connects to a degraded region, suggesting where to go instead.
+    pub fn admit(&self, s: &AppState, region: &str) -> Result<(), ApiError> {
+        if !self.is_degraded(region) { return Ok(()); }
+        let alt = self.alternative(s, region);
+        let details = match &alt { Some(a) => format!("{region} is degraded; connect to {a} instead"), None => format!("{region} is degraded and no healthy region is available") };
+        let e = api_err(StatusCode::SERVICE_UNAVAILABLE, "Region unavailable", Some(details)).code("region_unavailable").retry_after(self.interval.as_secs());
+        Err(match alt { Some(a) => e.suggest_region(a), None => e })
+    }
+
+    pub fn report(&self, s: &AppState) -> Vec<RegionHealth> {
+        let regions = self.regions.lock().unwrap();
+        let mut out = vec![RegionHealth { region: s.relay.local_region.clone(), local: true, status: Status::Healthy, latency_ms: None, consecutive_failures: 0, last_error: None, last_probe_ms: None, since_ms: 0 }];
+        for (region, _) in s.relay.upstreams() {
+            out.push(match regions.get(region) {
+                Some(p) => RegionHealth { region: region.clone(), local: false, status: p.status, latency_ms: p.latency_ms, consecutive_failures: p.failures, last_error: p.last_error.clone(), last_probe_ms: p.last_probe_ms, since_ms: p.since_ms },
+                None => RegionHealth { region: region.clone(), local: false, status: Status::Healthy, latency_ms: None, consecutive_failures: 0, last_error: None, last_probe_ms: None, since_ms: 0 },
+            });
+        }
+        out.sort_by(|a, b| a.region.cmp(&b.region));
+        out
+    }
+
+    pub fn redirects(&self) -> u64 { self.redirects.load(Ordering::Relaxed) }
+}
+
+/// Moves every connection in `region` to the best alternative and tells its device.
+fn steer(s: &AppState, region: &str) {
+    let Some(target) = s.regions.alternative(s, region) else {
+        tracing::warn!(%region, "region degraded with no healthy alternative; connections left in place");
+        return;
+    };
+    let moved: Vec<_> = {
+        let mut conns = s.connections.lock().unwrap();
+        conns.iter_mut().filter(|(_, c)| c.region == region).map(|(id, c)| { c.region = target.clone(); (id.clone(), c.clone()) }).collect()
+    };
+    for (id, conn) in moved {
+        let endpoint = endpoint_for(&target, &conn.protocol);
+        let redirect = serde_json::json!({ "connection_id": id, "device_id": conn.device_id, "from": region, "to": target, "endpoint": endpoint, "reason": "region-degraded" });
+        s.outbox.send(&conn.tenant, &conn.device_id, serde_json::json!({ "redirect": redirect }), Priority::High);
+        s.emit("redirect", &conn.tenant, redirect);
+        s.regions.redirects.fetch_add(1, Ordering::Relaxed);
+        let (shared, id) = (s.shared.clone(), id.clone());
+        tokio::spawn(async move { shared.save_connection(&id, &conn).await });
+    }
+}
+
+pub async fn probe_loop(s: Arc<AppState>) {
+    let mut tick = tokio::time::interval(s.regions.interval);
+    loop {
+        tick.tick().await;
+        let mut set = JoinSet::new();
+        for (region, base) in s.relay.upstreams() {
+            let (http, url, region) = (s.http.clone(), format!("{base}/health/live"), region.clone());
+            set.spawn(async move {
+                let t = Instant::now();
+                let r = match tokio::time::timeout(crate::health::timeout(), http.get(&url).send()).await {
+                    Ok(Ok(r)) if r.status().is_success() => Ok(t.elapsed().as_secs_f64() * 1000.0),
+                    Ok(Ok(r)) => Err(format!("HTTP {}", r.status())),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err("timed out".into()),
+                };
+                (region, r)
+            });
+        }
+        for (region, result) in set.join_all().await {
+            match s.regions.observe(&region, result) {
+                Some(Status::Degraded) => {
+                    tracing::warn!(%region, "region degraded");
+                    steer(&s, &region);
+                }
+                Some(Status::Healthy) => tracing::info!(%region, "region recovered"),
+                None => {}
+            }
+        }
+    }
+}
+
+pub async fn health(State(s): State<Arc<AppState>>, _: Require<Read>) -> Json<Vec<RegionHealth>> {
+    Json(s.regions.report(&s))
+}
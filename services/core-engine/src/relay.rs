@@ -0,0 +1,83 @@
+//! Upstream relay to home-region gateways. A device connecting to a region other than its
+//! home region gets a mirrored connection on the home gateway, and its sync deltas are
+//! forwarded there over a pooled HTTP client, retried through the `relay:<region>` breaker.
+//! Relayed calls authenticate with `GATEWAY_SHARED_SECRET` and act as an `operator` of the tenant.
+//! Every attempt at one connect or sync carries the same `Idempotency-Key`, so a retry after a
+//! lost response is answered from the home gateway's idempotency cache instead of applied twice.
+//!
+//! `GATEWAY_REGION` names this instance's region; `UPSTREAM_REGIONS` maps the others,
+//! e.g. `eu-west-1=https://eu-west-1.gw.internal:8081,ap-northeast-1=https://ap.gw.internal:8081`.
+
+use crate::resilience::{self, Attempt, Breakers, CallError, RetryPolicy};
+use alice_gateway_types::SyncRequest;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const RETRY: RetryPolicy = RetryPolicy { max_attempts: 3, base_backoff: Duration::from_millis(100), max_backoff: Duration::from_secs(2) };
+
+pub struct Relay { pub local_region: String, upstreams: HashMap<String, String>, http: reqwest::Client, breakers: Arc<Breakers> }
+
+#[derive(Deserialize)]
+struct UpstreamConnect { connection_id: String }
+
+impl Relay {
+    pub fn from_env(breakers: Arc<Breakers>) -> Relay {
+        let upstreams = std::env::var("UPSTREAM_REGIONS").unwrap_or_default().split(',')
+            .filter_map(|pair| pair.split_once('=')).map(|(r, u)| (r.trim().to_string(), u.trim().trim_end_matches('/').to_string())).collect();
+        let http = reqwest::Client::builder().pool_max_idle_per_host(32).pool_idle_timeout(Duration::from_secs(90)).timeout(Duration::from_secs(5)).build().expect("relay http client");
+        Relay { local_region: std::env::var("GATEWAY_REGION").unwrap_or_else(|_| "us-east-1".into()), upstreams, http, breakers }
+    }
+
+    /// Upstream regions and their base URLs, for readiness checks.
+    pub fn upstreams(&self) -> impl Iterator<Item = (&String, &String)> { self.upstreams.iter() }
+
+    /// Whether a device homed in `home` must be relayed from this gateway.
+    pub fn relays_to(&self, home: &str) -> bool { home != self.local_region && self.upstreams.contains_key(home) }
+
+    pub async fn register(&self, home: &str, tenant: &str, device_id: &str, protocol: &str) -> Result<String, CallError> {
+        let body = serde_json::json!({ "device_id": device_id, "protocol": protocol, "region": home });
+        let v = self.post_json(home, "/api/v1/gateway/connect", tenant, &body, Some(&uuid::Uuid::new_v4().to_string())).await?;
+        serde_json::from_value::<UpstreamConnect>(v).map(|c| c.connection_id).map_err(|e| CallError::Fatal(e.to_string()))
+    }
+
+    pub async fn forward_sync(&self, home: &str, tenant: &str, upstream_connection_id: &str, req: &SyncRequest) -> Result<serde_json::Value, CallError> {
+        let body = serde_json::to_value(SyncRequest { connection_id: upstream_connection_id.into(), ..req.clone() }).unwrap_or_default();
+        self.post_json(home, "/api/v1/gateway/sync", tenant, &body, Some(&uuid::Uuid::new_v4().to_string())).await
+    }
+
+    /// Hands a migrating connection's state to `region`; returns its connect response.
+    pub async fn migrate(&self, region: &str, tenant: &str, bundle: &serde_json::Value) -> Result<serde_json::Value, CallError> {
+        self.post_json(region, "/api/v1/gateway/connections/import", tenant, bundle, None).await
+    }
+
+    async fn post_json(&self, region: &str, path: &str, tenant: &str, body: &serde_json::Value, idempotency_key: Option<&str>) -> Result<serde_json::Value, CallError> {
+        let base = self.upstreams.get(region).ok_or_else(|| CallError::Fatal(format!("no upstream configured for {region}")))?;
+        let url = format!("{base}{path}");
+        let breaker = self.breakers.get(&format!("relay:{region}"));
+        let secret = std::env::var("GATEWAY_SHARED_SECRET").unwrap_or_default();
+        resilience::call(&breaker, RETRY, |_| async {
+            let mut req = self.http.post(&url).header("x-tenant-id", tenant).header(crate::rbac::HOP_HEADER, &secret).header("x-gateway-role", "operator").header("x-relayed-from", &self.local_region);
+            if let Some(key) = idempotency_key { req = req.header("idempotency-key", key); }
+            match req.json(body).send().await {
+                Ok(r) if r.status().is_success() => match r.json().await { Ok(v) => Attempt::Ok(v), Err(e) => Attempt::Fatal(e.to_string()) },
+                // The home gateway is still running an earlier attempt with this key.
+                Ok(r) if r.status() == reqwest::StatusCode::CONFLICT && r.headers().contains_key(reqwest::header::RETRY_AFTER) => Attempt::Retry("attempt in flight upstream".into()),
+                // A 4xx is the upstream rejecting the request, not an outage.
+                Ok(r) if r.status().is_client_error() => Attempt::Fatal(format!("upstream rejected: HTTP {}", r.status())),
+                Ok(r) => Attempt::Retry(format!("HTTP {}", r.status())),
+                Err(e) => Attempt::Retry(e.to_string()),
+            }
+        }).await
+    }
+}
+
+impl From<CallError> for crate::ApiError {
+    fn from(e: CallError) -> Self {
+        match e {
+            CallError::CircuitOpen(_) => crate::api_err(axum::http::StatusCode::SERVICE_UNAVAILABLE, "Upstream region unavailable", Some(e.message())),
+            _ => crate::api_err(axum::http::StatusCode::BAD_GATEWAY, "Upstream relay failed", Some(e.message())),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Replay protection for `/sync`. A delta's `timestamp` (RFC 3339 or Unix milliseconds) must
+//! fall within `SYNC_MAX_CLOCK_SKEW_SECS` (default 300) of the gateway clock, and its
+//! `sequence` must be strictly greater than the last one accepted on the connection. Both
+//! fields stay optional so older devices keep working; a connection that has sent a sequence
+//! number once must keep sending them.
+
+use crate::{api_err, events::now_ms, ApiError, AppState, SyncRequest};
+use axum::http::StatusCode;
+
+fn max_skew_ms() -> i64 {
+    std::env::var("SYNC_MAX_CLOCK_SKEW_SECS").ok().and_then(|v| v.parse::<i64>().ok()).unwrap_or(300) * 1000
+}
+
+fn parse_ms(ts: &str) -> Option<i64> {
+    ts.parse::<i64>().ok().or_else(|| chrono::DateTime::parse_from_rfc3339(ts).ok().map(|t| t.timestamp_millis()))
+}
+
+fn rejected(status: StatusCode, code: &'static str, details: String) -> ApiError {
+    api_err(status, "Sync rejected", Some(details)).code(code)
+}
+
+/// A sequence number claimed by [`check`]. Dropping it gives the sequence back, so a sync that
+/// fails after the check (quota, shaping, relay, WAL) can be resent unchanged; `commit` keeps it
+/// once the sync has been applied.
+#[must_use]
+pub struct Claim<'a> { s: &'a AppState, connection_id: String, claimed: Option<u64>, previous: Option<u64>, committed: bool }
+
+impl Claim<'_> {
+    pub fn commit(mut self) { self.committed = true; }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if let (false, Some(seq)) = (self.committed, self.claimed) { release(self.s, &self.connection_id, seq, self.previous); }
+    }
+}
+
+/// Validates the timestamp and claims the sequence number until the returned claim is
+/// committed or dropped.
+pub fn check<'a>(s: &'a AppState, req: &SyncRequest) -> Result<Claim<'a>, ApiError> {
+    if let Some(ts) = &req.timestamp {
+        let t = parse_ms(ts).ok_or_else(|| rejected(StatusCode::BAD_REQUEST, "invalid_timestamp", format!("unparseable timestamp {ts:?}")))?;
+        let skew = t - now_ms() as i64;
+        if skew.abs() > max_skew_ms() {
+            return Err(rejected(StatusCode::CONFLICT, "clock_skew", format!("timestamp is {}s outside the allowed window", skew.abs() / 1000)));
+        }
+    }
+    let mut seqs = s.sequences.lock().unwrap();
+    match (req.sequence, seqs.get(&req.connection_id).copied()) {
+        (Some(seq), Some(last)) if seq == last => Err(rejected(StatusCode::CONFLICT, "duplicate_sequence", format!("sequence {seq} already accepted"))),
+        (Some(seq), Some(last)) if seq < last => Err(rejected(StatusCode::CONFLICT, "stale_sequence", format!("sequence {seq} is behind {last}"))),
+        (None, Some(last)) => Err(rejected(StatusCode::BAD_REQUEST, "missing_sequence", format!("connection is sequenced; last accepted {last}"))),
+        (Some(seq), previous) => {
+            seqs.insert(req.connection_id.clone(), seq);
+            Ok(Claim { s, connection_id: req.connection_id.clone(), claimed: Some(seq), previous, committed: false })
+        }
+        (None, None) => Ok(Claim { s, connection_id: req.connection_id.clone(), claimed: None, previous: None, committed: false }),
+    }
+}
+
+/// The last sequence accepted on a connection.
+pub fn last(s: &AppState, connection_id: &str) -> Option<u64> { s.sequences.lock().unwrap().get(connection_id).copied() }
+
+/// Gives back a sequence claimed by `check` for a sync that was rolled back, restoring
+/// `previous`, unless another sync has moved the connection on since.
+fn release(s: &AppState, connection_id: &str, claimed: u64, previous: Option<u64>) {
+    let mut seqs = s.sequences.lock().unwrap();
+    if seqs.get(connection_id) != Some(&claimed) { return; }
+    match previous { Some(p) => { seqs.insert(connection_id.into(), p); } None => { seqs.remove(connection_id); } }
+}
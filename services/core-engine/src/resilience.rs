@@ -0,0 +1,130 @@
+//! Shared resilience layer for outbound calls (webhooks, upstream relay, future brokers):
+//! named circuit breakers with half-open probing plus a retry loop with exponential backoff.
+//!
+//! Breaker thresholds come from `BREAKER_FAILURE_THRESHOLD` (default 5),
+//! `BREAKER_OPEN_SECS` (default 30) and `BREAKER_HALF_OPEN_PROBES` (default 1).
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy { pub max_attempts: u32, pub base_backoff: Duration, pub max_backoff: Duration }
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration { self.base_backoff.saturating_mul(1 << (attempt - 1).min(16)).min(self.max_backoff) }
+}
+
+#[derive(Clone, Copy)]
+pub struct BreakerConfig { pub failure_threshold: u32, pub open_for: Duration, pub half_open_probes: u32 }
+
+impl BreakerConfig {
+    pub fn from_env() -> Self {
+        let num = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        BreakerConfig { failure_threshold: num("BREAKER_FAILURE_THRESHOLD", 5) as u32, open_for: Duration::from_secs(num("BREAKER_OPEN_SECS", 30)), half_open_probes: num("BREAKER_HALF_OPEN_PROBES", 1).max(1) as u32 }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BreakerState { Closed, Open, HalfOpen }
+
+impl BreakerState {
+    pub fn as_gauge(self) -> u8 { match self { BreakerState::Closed => 0, BreakerState::HalfOpen => 1, BreakerState::Open => 2 } }
+}
+
+struct Inner { state: BreakerState, consecutive_failures: u32, opened_at: Option<Instant>, probes_in_flight: u32, successes: u64, failures: u64, rejected: u64 }
+
+pub struct CircuitBreaker { name: String, cfg: BreakerConfig, inner: Mutex<Inner> }
+
+#[derive(Serialize)]
+pub struct BreakerSnapshot { pub name: String, pub state: BreakerState, pub consecutive_failures: u32, pub successes: u64, pub failures: u64, pub rejected: u64 }
+
+impl CircuitBreaker {
+    fn new(name: &str, cfg: BreakerConfig) -> Self {
+        CircuitBreaker { name: name.into(), cfg, inner: Mutex::new(Inner { state: BreakerState::Closed, consecutive_failures: 0, opened_at: None, probes_in_flight: 0, successes: 0, failures: 0, rejected: 0 }) }
+    }
+
+    /// Admits a call; once the open period elapses a limited number of half-open probes are let through.
+    pub fn try_acquire(&self) -> bool {
+        let mut i = self.inner.lock().unwrap();
+        if i.state == BreakerState::Open && i.opened_at.is_some_and(|t| t.elapsed() >= self.cfg.open_for) { i.state = BreakerState::HalfOpen; i.probes_in_flight = 0; }
+        let admitted = match i.state {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen if i.probes_in_flight < self.cfg.half_open_probes => { i.probes_in_flight += 1; true }
+            _ => false,
+        };
+        if !admitted { i.rejected += 1; }
+        admitted
+    }
+
+    pub fn on_success(&self) {
+        let mut i = self.inner.lock().unwrap();
+        if i.state != BreakerState::Closed { tracing::info!(breaker = %self.name, "circuit closed"); }
+        i.state = BreakerState::Closed; i.consecutive_failures = 0; i.opened_at = None; i.probes_in_flight = 0; i.successes += 1;
+    }
+
+    pub fn on_failure(&self) {
+        let mut i = self.inner.lock().unwrap();
+        i.failures += 1;
+        i.consecutive_failures += 1;
+        if i.state == BreakerState::HalfOpen || (i.state == BreakerState::Closed && i.consecutive_failures >= self.cfg.failure_threshold) {
+            tracing::warn!(breaker = %self.name, consecutive_failures = i.consecutive_failures, "circuit opened");
+            i.state = BreakerState::Open; i.opened_at = Some(Instant::now()); i.probes_in_flight = 0;
+        }
+    }
+
+    pub fn reset(&self) {
+        let mut i = self.inner.lock().unwrap();
+        i.state = BreakerState::Closed; i.consecutive_failures = 0; i.opened_at = None; i.probes_in_flight = 0;
+    }
+
+    pub fn snapshot(&self) -> BreakerSnapshot {
+        let i = self.inner.lock().unwrap();
+        BreakerSnapshot { name: self.name.clone(), state: i.state, consecutive_failures: i.consecutive_failures, successes: i.successes, failures: i.failures, rejected: i.rejected }
+    }
+}
+
+/// Named breakers shared by every outbound integration.
+pub struct Breakers { cfg: BreakerConfig, all: Mutex<BTreeMap<String, Arc<CircuitBreaker>>> }
+
+impl Breakers {
+    pub fn new(cfg: BreakerConfig) -> Self { Breakers { cfg, all: Mutex::new(BTreeMap::new()) } }
+    pub fn get(&self, name: &str) -> Arc<CircuitBreaker> {
+        self.all.lock().unwrap().entry(name.to_string()).or_insert_with(|| Arc::new(CircuitBreaker::new(name, self.cfg))).clone()
+    }
+    pub fn find(&self, name: &str) -> Option<Arc<CircuitBreaker>> { self.all.lock().unwrap().get(name).cloned() }
+    pub fn remove(&self, name: &str) { self.all.lock().unwrap().remove(name); }
+    pub fn snapshots(&self) -> Vec<BreakerSnapshot> { self.all.lock().unwrap().values().map(|b| b.snapshot()).collect() }
+}
+
+/// Result of a single attempt. `Fatal` errors (e.g. a 4xx from the peer) are neither retried nor counted against the breaker.
+pub enum Attempt<T> { Ok(T), Retry(String), Fatal(String) }
+
+pub enum CallError { CircuitOpen(String), Exhausted(String), Fatal(String) }
+
+impl CallError {
+    pub fn message(&self) -> String {
+        match self { CallError::CircuitOpen(n) => format!("circuit open for {n}"), CallError::Exhausted(e) | CallError::Fatal(e) => e.clone() }
+    }
+}
+
+pub async fn call<T, F, Fut>(breaker: &CircuitBreaker, policy: RetryPolicy, mut attempt_fn: F) -> Result<T, CallError>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Attempt<T>>,
+{
+    let mut last = String::new();
+    for attempt in 1..=policy.max_attempts {
+        if !breaker.try_acquire() { return Err(CallError::CircuitOpen(breaker.name.clone())); }
+        match attempt_fn(attempt).await {
+            Attempt::Ok(v) => { breaker.on_success(); return Ok(v); }
+            Attempt::Fatal(e) => { breaker.on_success(); return Err(CallError::Fatal(e)); }
+            Attempt::Retry(e) => { breaker.on_failure(); last = e; }
+        }
+        if attempt < policy.max_attempts { tokio::time::sleep(policy.backoff(attempt)).await; }
+    }
+    Err(CallError::Exhausted(last))
+}
@@ -0,0 +1,96 @@
+//! Session resumption. Every connect returns a `resume_token`, an HMAC-signed reference to the
+//! connection. Presenting it on a later connect returns the same `connection_id` instead of a
+//! new session: directly while the connection is still open, or by restoring it (with its
+//! sequence state) if the device closed it less than `RESUME_GRACE_SECS` ago (default 300).
+//! The device's outbox queue is keyed by device and survives either way. Tokens are signed
+//! with `RESUME_TOKEN_SECRET`; replicas sharing state must share it. Without it a random
+//! per-process secret is used and tokens stop working across restarts. A resumed connection
+//! without local snapshot state is seeded from the snapshot archive, when one is configured.
+
+use crate::admin::token_matches;
+use crate::events::now_ms;
+use crate::webhooks::sign;
+use crate::{api_err, endpoint_for, ApiError, AppState, Connection};
+use alice_gateway_types::ConnectResponse;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize)]
+struct Claims { c: String, t: String, d: String }
+
+struct Parked { conn: Connection, sequence: Option<u64>, at_ms: u64 }
+
+pub struct Sessions { secret: String, grace_ms: u64, parked: Mutex<HashMap<String, Parked>> }
+
+impl Sessions {
+    pub fn from_env() -> Self {
+        let secret = std::env::var("RESUME_TOKEN_SECRET").ok().filter(|s| !s.is_empty()).unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+        let grace_secs = std::env::var("RESUME_GRACE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300u64);
+        Sessions { secret, grace_ms: grace_secs * 1000, parked: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn issue(&self, tenant: &str, device_id: &str, connection_id: &str) -> String {
+        let payload = hex::encode(serde_json::to_vec(&Claims { c: connection_id.into(), t: tenant.into(), d: device_id.into() }).unwrap_or_default());
+        let mac = sign(&self.secret, payload.as_bytes());
+        format!("{payload}.{mac}")
+    }
+
+    fn verify(&self, token: &str) -> Option<Claims> {
+        let (payload, mac) = token.split_once('.')?;
+        if !token_matches(mac, &sign(&self.secret, payload.as_bytes())) { return None; }
+        serde_json::from_slice(&hex::decode(payload).ok()?).ok()
+    }
+
+    /// Keeps a device-closed connection resumable for the grace period.
+    pub fn park(&self, connection_id: &str, conn: Connection, sequence: Option<u64>) {
+        if self.grace_ms == 0 { return; }
+        let now = now_ms();
+        let mut parked = self.parked.lock().unwrap();
+        parked.retain(|_, p| now.saturating_sub(p.at_ms) < self.grace_ms);
+        parked.insert(connection_id.into(), Parked { conn, sequence, at_ms: now });
+    }
+
+    /// Drops a parked session that came back some other way (an operator restore).
+    pub fn discard(&self, connection_id: &str) { self.parked.lock().unwrap().remove(connection_id); }
+
+    fn take(&self, connection_id: &str) -> Option<Parked> {
+        self.parked.lock().unwrap().remove(connection_id).filter(|p| now_ms().saturating_sub(p.at_ms) < self.grace_ms)
+    }
+
+    /// Returns how many parked sessions were removed.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut parked = self.parked.lock().unwrap();
+        let n = parked.len();
+        parked.retain(|_, p| p.conn.tenant != tenant);
+        n - parked.len()
+    }
+}
+
+/// Resumes the session behind `token`, or returns `None` when it is gone for good and the
+/// caller should open a new one. A token that is forged or names another device is rejected.
+pub async fn resume(s: &AppState, tenant: &str, actor: &str, device_id: &str, token: &str) -> Result<Option<ConnectResponse>, ApiError> {
+    let claims = s.resume.verify(token).filter(|c| c.t == tenant && c.d == device_id)
+        .ok_or_else(|| api_err(StatusCode::UNAUTHORIZED, "Invalid resume token", Some("the token is not valid for this device".into())).code("invalid_resume_token"))?;
+    let id = claims.c;
+    let conn = match s.lookup_connection(&id).await {
+        Some(c) => c,
+        None => {
+            let Some(p) = s.resume.take(&id) else { return Ok(None) };
+            s.trash.forget_connection(&id);
+            if let Some(seq) = p.sequence { s.sequences.lock().unwrap().insert(id.clone(), seq); }
+            s.shared.save_connection(&id, &p.conn).await;
+            s.connections.lock().unwrap().insert(id.clone(), p.conn.clone());
+            s.emit("connect", tenant, serde_json::json!({ "connection_id": id, "device_id": device_id, "protocol": p.conn.protocol, "region": p.conn.region, "resumed": true }));
+            p.conn
+        }
+    };
+    s.snapshots.restore(tenant, &id).await;
+    s.audit.record(actor, "device.resume", Some(tenant), Some(device_id), serde_json::json!({ "connection_id": id }));
+    tracing::info!(connection_id = %id, %device_id, "session resumed");
+    Ok(Some(ConnectResponse {
+        endpoint: endpoint_for(&conn.region, &conn.protocol), resume_token: Some(s.resume.issue(tenant, device_id, &id)), connection_id: id, device_id: device_id.into(),
+        protocol: conn.protocol, region: conn.region, status: "resumed".into(), standby: None, relayed_to: conn.upstream.map(|u| u.home_region), device_credential: None,
+    }))
+}
@@ -0,0 +1,202 @@
+//! Content-based routing of sync deltas. Admin-configured rules match delta objects on tenant
+//! and object type (globs) and serialized size, and pick what happens to a matching object:
+//! - `forward`: POSTed right away to the rule's URL (HMAC-signed like webhooks, with retry),
+//!   and kept on the normal path
+//! - `priority-queue`: copied into the tenant's priority queue, drained by downstream consumers
+//!   via `POST /api/v1/routing/priority/drain`; kept on the normal path
+//! - `drop`: removed from the delta before the shadow, snapshots and event stream see it
+//!
+//! Rules are tried in ascending `order`; the first match decides. Objects without a `type`
+//! use the type recorded in the device's reported shadow. An encrypted envelope is routed as a
+//! single object of unknown type, so only rules without `object_type` apply to it.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::rbac::{Admin, Operate, Require};
+use crate::resilience::{self, Attempt, CircuitBreaker, RetryPolicy};
+use crate::subscriptions::glob;
+use crate::validate::{Valid, Validate, Violations};
+use crate::webhooks::sign;
+use crate::{api_err, ApiError, AppState, Tenant};
+use alice_gateway_types::Envelope;
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const RETRY: RetryPolicy = RetryPolicy { max_attempts: 3, base_backoff: Duration::from_millis(200), max_backoff: Duration::from_secs(5) };
+const DEFAULT_DRAIN: usize = 100;
+
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Action { Forward { url: String }, PriorityQueue, Drop }
+
+#[derive(Clone, Serialize)]
+pub struct Rule {
+    id: String, name: String, order: i32,
+    #[serde(skip_serializing_if = "Option::is_none")] tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] object_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] min_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")] max_bytes: Option<usize>,
+    action: Action, #[serde(skip)] secret: String, created_at_ms: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RuleRequest { name: String, #[serde(default)] order: i32, tenant: Option<String>, object_type: Option<String>, min_bytes: Option<usize>, max_bytes: Option<usize>, action: Action }
+
+/// The forward signing secret is only returned once, at creation.
+#[derive(Serialize)]
+pub struct CreatedRule { #[serde(flatten)] rule: Rule, #[serde(skip_serializing_if = "Option::is_none")] secret: Option<String> }
+
+#[derive(Clone, Serialize)]
+pub struct Routed { rule_id: String, connection_id: String, device_id: String, object_id: String, object: Value, queued_at_ms: u64 }
+
+#[derive(Deserialize)]
+pub struct DrainQuery { max: Option<usize> }
+
+#[derive(Default, Serialize)]
+pub struct RoutingCounts { pub forwarded: u64, pub queued: u64, pub dropped: u64, pub queue_overflow: u64 }
+
+pub struct Routing {
+    depth: usize,
+    rules: Mutex<Vec<Rule>>,
+    queues: Mutex<HashMap<String, VecDeque<Routed>>>,
+    forwarded: Arc<AtomicU64>, queued: AtomicU64, dropped: AtomicU64, overflow: AtomicU64,
+}
+
+impl Validate for RuleRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check(!self.name.trim().is_empty(), "name", "must not be empty");
+        if let (Some(lo), Some(hi)) = (self.min_bytes, self.max_bytes) { v.check(lo <= hi, "min_bytes", "must not exceed max_bytes"); }
+        if let Action::Forward { url } = &self.action { v.check(url.starts_with("http://") || url.starts_with("https://"), "action.url", "must be an http(s) URL"); }
+    }
+}
+
+impl Rule {
+    fn matches(&self, tenant: &str, object_type: Option<&str>, bytes: usize) -> bool {
+        self.tenant.as_ref().is_none_or(|t| glob(t, tenant))
+            && self.object_type.as_ref().is_none_or(|t| object_type.is_some_and(|o| glob(t, o)))
+            && self.min_bytes.is_none_or(|n| bytes >= n)
+            && self.max_bytes.is_none_or(|n| bytes <= n)
+    }
+}
+
+impl Routing {
+    pub fn from_env() -> Self {
+        let depth = std::env::var("ROUTING_PRIORITY_DEPTH").ok().and_then(|v| v.parse().ok()).unwrap_or(1000usize).max(1);
+        Routing { depth, rules: Mutex::new(Vec::new()), queues: Mutex::new(HashMap::new()), forwarded: Arc::new(AtomicU64::new(0)), queued: AtomicU64::new(0), dropped: AtomicU64::new(0), overflow: AtomicU64::new(0) }
+    }
+
+    pub fn counts(&self) -> RoutingCounts {
+        RoutingCounts { forwarded: self.forwarded.load(Ordering::Relaxed), queued: self.queued.load(Ordering::Relaxed), dropped: self.dropped.load(Ordering::Relaxed), queue_overflow: self.overflow.load(Ordering::Relaxed) }
+    }
+
+    fn enqueue(&self, tenant: &str, item: Routed) {
+        let mut queues = self.queues.lock().unwrap();
+        let q = queues.entry(tenant.into()).or_default();
+        if q.len() == self.depth { q.pop_front(); self.overflow.fetch_add(1, Ordering::Relaxed); }
+        q.push_back(item);
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns how many queued objects were removed.
+    pub fn remove_tenant(&self, tenant: &str) -> usize { self.queues.lock().unwrap().remove(tenant).map_or(0, |q| q.len()) }
+}
+
+/// Runs the rules over a decoded delta's objects; returns the delta with dropped objects removed.
+pub fn apply(s: &AppState, tenant: &str, connection_id: &str, device_id: &str, mut delta: Value) -> Value {
+    let rules = s.routing.rules.lock().unwrap().clone();
+    if rules.is_empty() { return delta; }
+    let Some(objects) = delta.get_mut("objects").and_then(Value::as_object_mut) else { return delta };
+    let known_types: Option<Map<String, Value>> = s.shadows.lock().unwrap().get(&(tenant.to_string(), device_id.to_string())).and_then(|sh| sh.reported.get("objects")?.as_object().cloned());
+    objects.retain(|id, obj| {
+        let object_type = obj.get("type").or_else(|| known_types.as_ref()?.get(id)?.get("type")).and_then(Value::as_str);
+        let bytes = obj.to_string().len();
+        let Some(rule) = rules.iter().find(|r| r.matches(tenant, object_type, bytes)) else { return true };
+        dispatch(s, tenant, rule, || Routed { rule_id: rule.id.clone(), connection_id: connection_id.into(), device_id: device_id.into(), object_id: id.clone(), object: obj.clone(), queued_at_ms: now_ms() })
+    });
+    delta
+}
+
+/// Runs the rules over an envelope, matching on tenant and ciphertext size; returns false when
+/// it is dropped. Queued and forwarded copies carry the envelope under `object`.
+pub fn apply_envelope(s: &AppState, tenant: &str, connection_id: &str, device_id: &str, envelope: &Envelope) -> bool {
+    let rules = s.routing.rules.lock().unwrap();
+    let Some(rule) = rules.iter().find(|r| r.matches(tenant, None, envelope.ciphertext.len())) else { return true };
+    dispatch(s, tenant, rule, || Routed { rule_id: rule.id.clone(), connection_id: connection_id.into(), device_id: device_id.into(), object_id: "envelope".into(), object: json!(envelope), queued_at_ms: now_ms() })
+}
+
+/// Carries out the rule's action; returns whether the object stays on the normal path.
+fn dispatch(s: &AppState, tenant: &str, rule: &Rule, routed: impl FnOnce() -> Routed) -> bool {
+    match &rule.action {
+        Action::Drop => { s.routing.dropped.fetch_add(1, Ordering::Relaxed); false }
+        Action::PriorityQueue => { s.routing.enqueue(tenant, routed()); true }
+        Action::Forward { url } => {
+            let breaker = s.breakers.get(&format!("route:{}", rule.id));
+            tokio::spawn(forward(s.http.clone(), breaker, s.routing.forwarded.clone(), tenant.to_string(), url.clone(), rule.secret.clone(), routed()));
+            true
+        }
+    }
+}
+
+async fn forward(http: reqwest::Client, breaker: Arc<CircuitBreaker>, forwarded: Arc<AtomicU64>, tenant: String, url: String, secret: String, item: Routed) {
+    let body = serde_json::to_vec(&json!({ "tenant": tenant, "rule_id": item.rule_id, "connection_id": item.connection_id, "device_id": item.device_id, "object_id": item.object_id, "object": item.object, "timestamp_ms": item.queued_at_ms })).unwrap_or_default();
+    let signature = format!("sha256={}", sign(&secret, &body));
+    let result = resilience::call(&breaker, RETRY, |_| {
+        let (http, url, body, signature) = (&http, &url, &body, &signature);
+        async move {
+            match http.post(url).header("Content-Type", "application/json").header("X-Alice-Signature", signature).timeout(Duration::from_secs(10)).body(body.clone()).send().await {
+                Ok(r) if r.status().is_success() => Attempt::Ok(()),
+                Ok(r) if r.status().is_client_error() => Attempt::Fatal(format!("HTTP {}", r.status())),
+                Ok(r) => Attempt::Retry(format!("HTTP {}", r.status())),
+                Err(e) => Attempt::Retry(e.to_string()),
+            }
+        }
+    }).await;
+    match result {
+        Ok(()) => { forwarded.fetch_add(1, Ordering::Relaxed); }
+        Err(e) => tracing::warn!(rule = %item.rule_id, object = %item.object_id, "routed object not forwarded: {}", e.message()),
+    }
+}
+
+pub async fn create_rule(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Valid(req): Valid<RuleRequest>) -> (StatusCode, Json<CreatedRule>) {
+    let forwards = matches!(req.action, Action::Forward { .. });
+    let secret = if forwards { hex::encode(uuid::Uuid::new_v4().as_bytes()) } else { String::new() };
+    let rule = Rule { id: uuid::Uuid::new_v4().to_string(), name: req.name, order: req.order, tenant: req.tenant, object_type: req.object_type, min_bytes: req.min_bytes, max_bytes: req.max_bytes, action: req.action, secret: secret.clone(), created_at_ms: now_ms() };
+    {
+        let mut rules = s.routing.rules.lock().unwrap();
+        rules.push(rule.clone());
+        rules.sort_by_key(|r| (r.order, r.created_at_ms));
+    }
+    tracing::info!(rule = %rule.id, name = %rule.name, "admin added routing rule");
+    s.audit.record(&actor, "admin.routing.create", rule.tenant.as_deref(), Some(&rule.id), json!({ "name": rule.name, "action": rule.action }));
+    (StatusCode::CREATED, Json(CreatedRule { rule, secret: forwards.then_some(secret) }))
+}
+
+pub async fn list_rules(State(s): State<Arc<AppState>>, _: Require<Admin>) -> Json<Vec<Rule>> {
+    Json(s.routing.rules.lock().unwrap().clone())
+}
+
+pub async fn remove_rule(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let removed = {
+        let mut rules = s.routing.rules.lock().unwrap();
+        let n = rules.len();
+        rules.retain(|r| r.id != id);
+        n != rules.len()
+    };
+    if !removed { return Err(api_err(StatusCode::NOT_FOUND, "Unknown routing rule", Some(id))); }
+    s.breakers.remove(&format!("route:{id}"));
+    s.audit.record(&actor, "admin.routing.remove", None, Some(&id), Value::Null);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Hands out up to `max` (default 100) queued objects, oldest first, and removes them.
+pub async fn drain(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Query(q): Query<DrainQuery>) -> Json<Vec<Routed>> {
+    let mut queues = s.routing.queues.lock().unwrap();
+    let Some(queue) = queues.get_mut(&tenant) else { return Json(Vec::new()) };
+    let n = q.max.unwrap_or(DEFAULT_DRAIN).min(queue.len());
+    Json(queue.drain(..n).collect())
+}
@@ -0,0 +1,164 @@
+//! Scheduled jobs against registered connections, for backends that want the gateway to pull
+//! device state on a timetable instead of waiting for pushes. Schedules take a cron expression
+//! in UTC: the standard five fields, or six/seven with leading seconds and trailing year.
+//!
+//! Jobs: `sync` sends a pull request (`{"pull": {"schedule_id", "run_id"}}`, or the job's own
+//! `request`) to the device through the outbox, so an offline device gets it on reconnect;
+//! `transform` encodes the device's reported shadow into `target_protocol`. Each run is
+//! kept in a per-schedule history (`SCHEDULE_HISTORY`, default 50) and emitted as a
+//! `schedule-run` event.
+
+use crate::events::now_ms;
+use crate::pressure::Priority;
+use crate::rbac::{Configure, Operate, Read, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    Sync { connection_id: String, #[serde(default, skip_serializing_if = "Option::is_none")] request: Option<Value> },
+    Transform { connection_id: String, target_protocol: String },
+}
+
+impl Job {
+    fn connection_id(&self) -> &str { match self { Job::Sync { connection_id, .. } | Job::Transform { connection_id, .. } => connection_id } }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Run { id: String, trigger: &'static str, started_at_ms: u64, duration_us: u64, status: &'static str, #[serde(skip_serializing_if = "Value::is_null")] output: Value, #[serde(skip_serializing_if = "Option::is_none")] error: Option<String> }
+
+#[derive(Clone, Serialize)]
+pub struct Schedule {
+    pub id: String, #[serde(skip)] pub tenant: String, pub name: Option<String>, pub cron: String, pub job: Job, pub enabled: bool,
+    pub created_at_ms: u64, pub next_run_ms: Option<u64>, pub last_run: Option<Run>,
+    #[serde(skip)] parsed: cron::Schedule, #[serde(skip)] runs: VecDeque<Run>,
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleRequest { name: Option<String>, cron: String, job: Job, enabled: Option<bool> }
+
+/// Five-field expressions get a leading `0` seconds field.
+fn parse_cron(expr: &str) -> Result<cron::Schedule, String> {
+    let expr = if expr.split_whitespace().count() == 5 { format!("0 {expr}") } else { expr.into() };
+    cron::Schedule::from_str(&expr).map_err(|e| e.to_string())
+}
+
+fn next_after(parsed: &cron::Schedule, ms: u64) -> Option<u64> {
+    parsed.after(&DateTime::from_timestamp_millis(ms as i64)?).next().map(|t| t.timestamp_millis() as u64)
+}
+
+impl Validate for ScheduleRequest {
+    fn validate(&self, s: &AppState, v: &mut Violations) {
+        if let Err(e) = parse_cron(&self.cron) { v.check(false, "cron", e); }
+        v.id("job.connection_id", self.job.connection_id());
+        if let Job::Transform { target_protocol, .. } = &self.job { v.protocol(s, "job.target_protocol", target_protocol); }
+    }
+}
+
+fn history() -> usize { std::env::var("SCHEDULE_HISTORY").ok().and_then(|v| v.parse().ok()).unwrap_or(50) }
+
+fn lookup(s: &AppState, tenant: &str, id: &str) -> Result<Schedule, ApiError> {
+    s.schedules.lock().unwrap().get(id).filter(|j| j.tenant == tenant).cloned().ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown schedule", Some(id.into())))
+}
+
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Valid(req): Valid<ScheduleRequest>) -> Result<(StatusCode, Json<Schedule>), ApiError> {
+    let conn_id = req.job.connection_id();
+    if s.lookup_connection(conn_id).await.is_none_or(|c| c.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(conn_id.into()))); }
+    let parsed = parse_cron(&req.cron).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid cron expression", Some(e)))?;
+    let enabled = req.enabled.unwrap_or(true);
+    let created_at_ms = now_ms();
+    let next_run_ms = if enabled { next_after(&parsed, created_at_ms) } else { None };
+    let schedule = Schedule { id: uuid::Uuid::new_v4().to_string(), tenant, name: req.name, cron: req.cron, job: req.job, enabled, created_at_ms, next_run_ms, last_run: None, parsed, runs: VecDeque::new() };
+    s.schedules.lock().unwrap().insert(schedule.id.clone(), schedule.clone());
+    Ok((StatusCode::CREATED, Json(schedule)))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<Schedule>> {
+    Json(s.schedules.lock().unwrap().values().filter(|j| j.tenant == tenant).cloned().collect())
+}
+
+pub async fn get_schedule(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Schedule>, ApiError> {
+    lookup(&s, &tenant, &id).map(Json)
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let mut schedules = s.schedules.lock().unwrap();
+    if schedules.get(&id).is_none_or(|j| j.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown schedule", Some(id))); }
+    schedules.remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Run history, newest first.
+pub async fn runs(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Vec<Run>>, ApiError> {
+    Ok(Json(lookup(&s, &tenant, &id)?.runs.into_iter().rev().collect()))
+}
+
+/// Runs the job now, outside its timetable; the next scheduled run is unaffected.
+pub async fn trigger(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Run>, ApiError> {
+    let schedule = lookup(&s, &tenant, &id)?;
+    Ok(Json(execute(&s, &schedule, "manual").await))
+}
+
+async fn run_job(s: &AppState, schedule: &Schedule, run_id: &str) -> Result<Value, String> {
+    let conn = s.lookup_connection(schedule.job.connection_id()).await.filter(|c| c.tenant == schedule.tenant).ok_or("connection is gone")?;
+    match &schedule.job {
+        Job::Sync { request, .. } => {
+            let message = request.clone().unwrap_or_else(|| json!({ "pull": { "schedule_id": schedule.id, "run_id": run_id } }));
+            let (message_id, delivered, queue_depth) = s.outbox.send(&conn.tenant, &conn.device_id, message, Priority::Normal);
+            Ok(json!({ "message_id": message_id, "delivered": delivered, "queue_depth": queue_depth }))
+        }
+        Job::Transform { target_protocol, .. } => {
+            let reported = s.shadows.lock().unwrap().get(&(conn.tenant.clone(), conn.device_id.clone())).map(|sh| sh.reported.clone()).ok_or("device has not reported state yet")?;
+            let output = s.protocols.get(target_protocol).map_err(|_| format!("protocol {target_protocol} is no longer registered"))?.encode(&reported).map_err(|e| format!("{target_protocol}: {e}"))?;
+            s.stats.lock().unwrap().add(&conn.tenant, [0, 0, 1, 0]);
+            s.shared.incr(&[("total_transforms", 1)]).await;
+            Ok(json!({ "source": conn.protocol, "target": target_protocol, "output": output }))
+        }
+    }
+}
+
+async fn execute(s: &AppState, schedule: &Schedule, trigger: &'static str) -> Run {
+    let (id, started_at_ms, t) = (uuid::Uuid::new_v4().to_string(), now_ms(), Instant::now());
+    let result = run_job(s, schedule, &id).await;
+    let duration_us = t.elapsed().as_micros() as u64;
+    let run = match result {
+        Ok(output) => Run { id, trigger, started_at_ms, duration_us, status: "ok", output, error: None },
+        Err(e) => Run { id, trigger, started_at_ms, duration_us, status: "failed", output: Value::Null, error: Some(e) },
+    };
+    if let Some(j) = s.schedules.lock().unwrap().get_mut(&schedule.id) {
+        j.runs.push_back(run.clone());
+        while j.runs.len() > history() { j.runs.pop_front(); }
+        j.last_run = Some(run.clone());
+    }
+    tracing::info!(schedule = %schedule.id, run = %run.id, trigger, status = run.status, "scheduled job ran");
+    s.emit("schedule-run", &schedule.tenant, json!({ "schedule_id": schedule.id, "run_id": run.id, "trigger": trigger, "status": run.status, "error": run.error }));
+    run
+}
+
+/// Fires due schedules once a second; a run that is still in flight does not delay the next.
+pub async fn run_loop(s: Arc<AppState>) {
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tick.tick().await;
+        let now = now_ms();
+        let due: Vec<Schedule> = s.schedules.lock().unwrap().values_mut().filter(|j| j.enabled && j.next_run_ms.is_some_and(|t| t <= now)).map(|j| {
+            j.next_run_ms = next_after(&j.parsed, now);
+            j.clone()
+        }).collect();
+        for schedule in due {
+            let s = s.clone();
+            tokio::spawn(async move { execute(&s, &schedule, "cron").await; });
+        }
+    }
+}
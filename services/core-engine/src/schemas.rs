@@ -0,0 +1,432 @@
+//! Payload schema registry. Each tenant can register JSON Schemas per protocol under
+//! `/api/v1/schemas/:protocol/versions`, numbered from 1. Once a protocol has a schema, every
+//! `sdf_delta` synced over it and every `/transform` payload from it must match the version the
+//! request names in `schema_version`, or the latest; a mismatch is refused with 422
+//! (`schema_violation`) listing each offending field, an unknown version with 422
+//! (`unknown_schema_version`). Protocols without schemas are not checked.
+//!
+//! A new version must be compatible with the latest under the protocol's mode, set with
+//! `PUT /schemas/:protocol/config`: `backward` (the default: the new schema accepts everything the
+//! previous one did, so consumers upgrade first), `forward` (the previous schema accepts
+//! everything the new one does, so producers upgrade first), `full` (both) or `none`. An
+//! incompatible upload is refused with 409 (`schema_incompatible`) naming each break. The
+//! check is structural, and errs on the side of reporting a break it cannot rule out.
+//!
+//! Schemas use a JSON Schema subset: `type`, `enum`, `const`, `minimum`, `maximum`,
+//! `exclusiveMinimum`, `exclusiveMaximum`, `minLength`, `maxLength`, `properties`, `required`,
+//! `additionalProperties`, `items`, `minItems` and `maxItems`, plus boolean schemas. Annotations
+//! (`title`, `description`, `$schema`, `format`, ...) are kept but not checked; any other
+//! keyword is rejected on upload rather than silently ignored.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::rbac::{Configure, Read, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const MAX_VIOLATIONS: usize = 32;
+const ANNOTATIONS: &[&str] = &["$schema", "$id", "$comment", "title", "description", "default", "examples", "deprecated", "readOnly", "writeOnly", "format"];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind { Null, Boolean, Object, Array, Number, Integer, String }
+
+const KINDS: [Kind; 7] = [Kind::Null, Kind::Boolean, Kind::Object, Kind::Array, Kind::Number, Kind::Integer, Kind::String];
+
+impl Kind {
+    fn parse(s: &str) -> Option<Kind> {
+        KINDS.into_iter().find(|k| k.as_str() == s)
+    }
+    fn as_str(self) -> &'static str {
+        match self { Kind::Null => "null", Kind::Boolean => "boolean", Kind::Object => "object", Kind::Array => "array", Kind::Number => "number", Kind::Integer => "integer", Kind::String => "string" }
+    }
+    fn of(v: &Value) -> Kind {
+        match v {
+            Value::Null => Kind::Null, Value::Bool(_) => Kind::Boolean, Value::Object(_) => Kind::Object, Value::Array(_) => Kind::Array, Value::String(_) => Kind::String,
+            Value::Number(n) => if n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|f| f.fract() == 0.0) { Kind::Integer } else { Kind::Number },
+        }
+    }
+}
+
+fn names(kinds: impl IntoIterator<Item = Kind>) -> String { kinds.into_iter().map(Kind::as_str).collect::<Vec<_>>().join(" or ") }
+
+/// A parsed schema; `Schema::default()` accepts everything.
+#[derive(Default)]
+pub struct Schema {
+    never: bool,
+    types: Option<BTreeSet<Kind>>,
+    enumeration: Option<Vec<Value>>,
+    minimum: Option<f64>, maximum: Option<f64>, exclusive_minimum: Option<f64>, exclusive_maximum: Option<f64>,
+    min_length: Option<u64>, max_length: Option<u64>,
+    properties: BTreeMap<String, Schema>, required: BTreeSet<String>,
+    /// Schema for properties not in `properties`; any value when absent.
+    additional: Option<Box<Schema>>,
+    items: Option<Box<Schema>>, min_items: Option<u64>, max_items: Option<u64>,
+}
+
+/// Problems found, as (field path, message).
+type Findings = Vec<(String, String)>;
+
+fn note(out: &mut Findings, at: &str, message: impl Into<String>) {
+    if out.len() < MAX_VIOLATIONS { out.push((at.into(), message.into())); }
+}
+
+/// The tightest lower (or upper) bound of an inclusive and an exclusive one, as (value, exclusive).
+fn bound(inclusive: Option<f64>, exclusive: Option<f64>, lower: bool) -> Option<(f64, bool)> {
+    match (inclusive, exclusive) {
+        (Some(i), Some(e)) => Some(if (lower && e >= i) || (!lower && e <= i) { (e, true) } else { (i, false) }),
+        (Some(i), None) => Some((i, false)),
+        (None, Some(e)) => Some((e, true)),
+        (None, None) => None,
+    }
+}
+
+/// Whether bound `r` admits everything bound `w` does.
+fn bound_covers(w: Option<(f64, bool)>, r: Option<(f64, bool)>, lower: bool) -> bool {
+    match (w, r) {
+        (_, None) => true,
+        (None, Some(_)) => false,
+        (Some((wv, wx)), Some((rv, rx))) => if wv == rv { wx || !rx } else if lower { rv < wv } else { rv > wv },
+    }
+}
+
+impl Schema {
+    fn parse(v: &Value, at: &str, errs: &mut Findings) -> Schema {
+        let map = match v {
+            Value::Bool(b) => return Schema { never: !b, ..Default::default() },
+            Value::Object(m) => m,
+            _ => { note(errs, at, "must be a schema object or a boolean"); return Schema::default(); }
+        };
+        let mut s = Schema::default();
+        for (k, x) in map {
+            let at = format!("{at}.{k}");
+            let number = |errs: &mut Findings| x.as_f64().or_else(|| { note(errs, &at, "must be a number"); None });
+            let count = |errs: &mut Findings| x.as_u64().or_else(|| { note(errs, &at, "must be a non-negative integer"); None });
+            match k.as_str() {
+                "type" => {
+                    let listed: Vec<&Value> = match x { Value::Array(a) => a.iter().collect(), v => vec![v] };
+                    let kinds: Option<BTreeSet<Kind>> = listed.iter().map(|t| t.as_str().and_then(Kind::parse)).collect();
+                    match kinds { Some(k) if !k.is_empty() => s.types = Some(k), _ => note(errs, &at, format!("must name one or more of {}", names(KINDS))) }
+                }
+                "enum" => match x { Value::Array(a) if !a.is_empty() => s.enumeration = Some(a.clone()), _ => note(errs, &at, "must be a non-empty array") },
+                "const" => s.enumeration = Some(vec![x.clone()]),
+                "minimum" => s.minimum = number(errs),
+                "maximum" => s.maximum = number(errs),
+                "exclusiveMinimum" => s.exclusive_minimum = number(errs),
+                "exclusiveMaximum" => s.exclusive_maximum = number(errs),
+                "minLength" => s.min_length = count(errs),
+                "maxLength" => s.max_length = count(errs),
+                "minItems" => s.min_items = count(errs),
+                "maxItems" => s.max_items = count(errs),
+                "properties" => match x {
+                    Value::Object(props) => s.properties = props.iter().map(|(name, p)| (name.clone(), Schema::parse(p, &format!("{at}.{name}"), errs))).collect(),
+                    _ => note(errs, &at, "must be an object of schemas"),
+                },
+                "required" => match x.as_array().and_then(|a| a.iter().map(|r| r.as_str().map(String::from)).collect::<Option<BTreeSet<_>>>()) {
+                    Some(r) => s.required = r,
+                    None => note(errs, &at, "must be an array of property names"),
+                },
+                "additionalProperties" => s.additional = Some(Box::new(Schema::parse(x, &at, errs))),
+                "items" => s.items = Some(Box::new(Schema::parse(x, &at, errs))),
+                k if ANNOTATIONS.contains(&k) => {}
+                _ => note(errs, &at, "unsupported keyword"),
+            }
+        }
+        s
+    }
+
+    fn accepts_all(&self) -> bool {
+        !self.never && self.types.is_none() && self.enumeration.is_none() && self.properties.is_empty() && self.required.is_empty()
+            && [self.minimum, self.maximum, self.exclusive_minimum, self.exclusive_maximum].iter().all(Option::is_none)
+            && [self.min_length, self.max_length, self.min_items, self.max_items].iter().all(Option::is_none)
+            && self.additional.as_deref().is_none_or(Schema::accepts_all) && self.items.as_deref().is_none_or(Schema::accepts_all)
+    }
+
+    /// The kinds of value the schema can accept; `integer` is implied by `number`.
+    fn kinds(&self) -> BTreeSet<Kind> {
+        let mut k = self.types.clone().unwrap_or_else(|| KINDS.into_iter().collect());
+        if k.contains(&Kind::Number) { k.insert(Kind::Integer); }
+        k
+    }
+
+    fn check(&self, v: &Value, at: &str, out: &mut Findings) {
+        if out.len() >= MAX_VIOLATIONS { return; }
+        if self.never { return note(out, at, "is not allowed"); }
+        if self.types.is_some() && !self.kinds().contains(&Kind::of(v)) {
+            return note(out, at, format!("must be {}", names(self.types.iter().flatten().copied())));
+        }
+        if let Some(values) = &self.enumeration {
+            if !values.contains(v) { return note(out, at, format!("must be one of {}", values.iter().map(Value::to_string).collect::<Vec<_>>().join(", "))); }
+        }
+        match v {
+            Value::Number(n) => {
+                let x = n.as_f64().unwrap_or_default();
+                if let Some((lo, excl)) = bound(self.minimum, self.exclusive_minimum, true) { if x < lo || (excl && x == lo) { note(out, at, format!("must be {} {lo}", if excl { ">" } else { ">=" })); } }
+                if let Some((hi, excl)) = bound(self.maximum, self.exclusive_maximum, false) { if x > hi || (excl && x == hi) { note(out, at, format!("must be {} {hi}", if excl { "<" } else { "<=" })); } }
+            }
+            Value::String(s) => {
+                let len = s.chars().count() as u64;
+                if self.min_length.is_some_and(|m| len < m) || self.max_length.is_some_and(|m| len > m) { note(out, at, format!("length {len} is out of range")); }
+            }
+            Value::Array(items) => {
+                let len = items.len() as u64;
+                if self.min_items.is_some_and(|m| len < m) || self.max_items.is_some_and(|m| len > m) { note(out, at, format!("{len} items is out of range")); }
+                if let Some(schema) = &self.items { for (i, x) in items.iter().enumerate() { schema.check(x, &format!("{at}[{i}]"), out); } }
+            }
+            Value::Object(map) => {
+                for r in self.required.iter().filter(|r| !map.contains_key(*r)) { note(out, &format!("{at}.{r}"), "is required"); }
+                for (k, x) in map {
+                    if let Some(schema) = self.properties.get(k).or(self.additional.as_deref()) { schema.check(x, &format!("{at}.{k}"), out); }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Notes every way a value valid under `self` could be invalid under `r`.
+    fn within(&self, r: &Schema, at: &str, out: &mut Findings) {
+        if self.never || r.accepts_all() { return; }
+        if r.never { return note(out, at, "no longer accepts any value"); }
+        if let Some(values) = &self.enumeration {
+            // An enum spells out every value the schema accepts, so each can be checked directly.
+            let mut rejected = Findings::new();
+            for v in values.iter().filter(|v| { let mut f = Findings::new(); self.check(v, at, &mut f); f.is_empty() }) { r.check(v, at, &mut rejected); }
+            for (field, message) in rejected { note(out, &field, format!("no longer accepts a value it did: {message}")); }
+            return;
+        }
+        if r.enumeration.is_some() { return note(out, at, "is now restricted to an enum"); }
+        let (wk, rk) = (self.kinds(), r.kinds());
+        let dropped: Vec<Kind> = wk.iter().filter(|k| !rk.contains(k)).copied().collect();
+        if !dropped.is_empty() { note(out, at, format!("no longer accepts {}", names(dropped))); }
+        let shared = |k: Kind| wk.contains(&k) && rk.contains(&k);
+        if shared(Kind::Number) || shared(Kind::Integer) {
+            if !bound_covers(bound(self.minimum, self.exclusive_minimum, true), bound(r.minimum, r.exclusive_minimum, true), true) { note(out, at, "minimum was raised"); }
+            if !bound_covers(bound(self.maximum, self.exclusive_maximum, false), bound(r.maximum, r.exclusive_maximum, false), false) { note(out, at, "maximum was lowered"); }
+        }
+        let narrowed = |w_min: Option<u64>, w_max: Option<u64>, r_min: Option<u64>, r_max: Option<u64>| {
+            r_min.is_some_and(|m| m > w_min.unwrap_or(0)) || r_max.is_some_and(|m| w_max.is_none_or(|w| w > m))
+        };
+        if shared(Kind::String) && narrowed(self.min_length, self.max_length, r.min_length, r.max_length) { note(out, at, "allowed length was narrowed"); }
+        if shared(Kind::Array) {
+            if narrowed(self.min_items, self.max_items, r.min_items, r.max_items) { note(out, at, "allowed item count was narrowed"); }
+            let any = Schema::default();
+            self.items.as_deref().unwrap_or(&any).within(r.items.as_deref().unwrap_or(&any), &format!("{at}[]"), out);
+        }
+        if shared(Kind::Object) {
+            let any = Schema::default();
+            let (w_add, r_add) = (self.additional.as_deref().unwrap_or(&any), r.additional.as_deref().unwrap_or(&any));
+            for name in r.required.difference(&self.required) { note(out, &format!("{at}.{name}"), "is now required"); }
+            for (name, rp) in &r.properties { self.properties.get(name).unwrap_or(w_add).within(rp, &format!("{at}.{name}"), out); }
+            for (name, wp) in self.properties.iter().filter(|(name, _)| !r.properties.contains_key(*name)) { wp.within(r_add, &format!("{at}.{name}"), out); }
+            if r_add.never && !w_add.never { note(out, at, "no longer accepts additional properties"); } else { w_add.within(r_add, &format!("{at}.*"), out); }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compatibility { #[default] Backward, Forward, Full, None }
+
+impl Compatibility {
+    fn as_str(self) -> &'static str {
+        match self { Compatibility::Backward => "backward", Compatibility::Forward => "forward", Compatibility::Full => "full", Compatibility::None => "none" }
+    }
+}
+
+struct Version { version: u32, schema: Value, parsed: Arc<Schema>, created_at_ms: u64 }
+
+#[derive(Default)]
+struct Subject { compatibility: Compatibility, next_version: u32, versions: Vec<Version> }
+
+#[derive(Serialize)]
+pub struct VersionInfo { protocol: String, version: u32, schema: Value, created_at_ms: u64 }
+
+#[derive(Serialize)]
+pub struct SubjectInfo { protocol: String, compatibility: Compatibility, #[serde(skip_serializing_if = "Option::is_none")] latest_version: Option<u32>, versions: Vec<u32> }
+
+#[derive(Deserialize)]
+pub struct SchemaUpload { schema: Value }
+
+#[derive(Deserialize, Serialize)]
+pub struct SubjectConfig { compatibility: Compatibility }
+
+type SubjectKey = (String, String);
+
+#[derive(Default)]
+pub struct Schemas { subjects: Mutex<HashMap<SubjectKey, Subject>>, violations: AtomicU64 }
+
+impl Validate for SchemaUpload {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        let mut errs = Findings::new();
+        Schema::parse(&self.schema, "schema", &mut errs);
+        for (field, message) in errs { v.check(false, field, message); }
+    }
+}
+
+impl Version {
+    fn info(&self, protocol: &str) -> VersionInfo { VersionInfo { protocol: protocol.into(), version: self.version, schema: self.schema.clone(), created_at_ms: self.created_at_ms } }
+}
+
+impl Subject {
+    fn info(&self, protocol: &str) -> SubjectInfo {
+        SubjectInfo { protocol: protocol.into(), compatibility: self.compatibility, latest_version: self.versions.last().map(|v| v.version), versions: self.versions.iter().map(|v| v.version).collect() }
+    }
+
+    /// `version`, or the latest when `None`.
+    fn find(&self, version: Option<u32>) -> Option<&Version> {
+        match version { Some(n) => self.versions.iter().find(|v| v.version == n), None => self.versions.last() }
+    }
+}
+
+/// An error listing `found` as violations, with `details` naming the schema.
+fn rejection(found: Findings, status: StatusCode, error: &str, code: &'static str, details: String) -> ApiError {
+    let mut v = Violations::default();
+    for (field, message) in found { v.check(false, field, message); }
+    let mut e = v.into_error(status, error, code);
+    e.body.details = Some(details);
+    e
+}
+
+fn unknown_version(protocol: &str, version: Option<u32>) -> ApiError {
+    let details = match version { Some(v) => format!("{protocol} has no schema version {v}"), None => format!("{protocol} has no schema") };
+    api_err(StatusCode::UNPROCESSABLE_ENTITY, "Unknown schema version", Some(details)).code("unknown_schema_version")
+}
+
+impl Schemas {
+    pub fn violations(&self) -> u64 { self.violations.load(Ordering::Relaxed) }
+
+    pub fn version_count(&self) -> usize { self.subjects.lock().unwrap().values().map(|s| s.versions.len()).sum() }
+
+    /// The schema a payload of `protocol` must match: `Ok(None)` when the protocol has none.
+    fn resolve(&self, tenant: &str, protocol: &str, version: Option<u32>) -> Result<Option<(u32, Arc<Schema>)>, ApiError> {
+        let subjects = self.subjects.lock().unwrap();
+        match subjects.get(&(tenant.to_string(), protocol.to_string())).filter(|s| !s.versions.is_empty()) {
+            Some(subject) => subject.find(version).map(|v| Some((v.version, v.parsed.clone()))).ok_or_else(|| unknown_version(protocol, version)),
+            None if version.is_some() => Err(unknown_version(protocol, version)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every way `payload` (reported under `field`) breaks the schema in force, as messages;
+    /// for `/transform/validate`.
+    pub fn problems(&self, tenant: &str, protocol: &str, version: Option<u32>, payload: &Value, field: &str) -> Vec<String> {
+        match self.resolve(tenant, protocol, version) {
+            Ok(None) => Vec::new(),
+            Ok(Some((_, schema))) => { let mut out = Findings::new(); schema.check(payload, field, &mut out); out.into_iter().map(|(f, m)| format!("{f} {m}")).collect() }
+            Err(e) => vec![e.body.details.unwrap_or_default()],
+        }
+    }
+
+    /// Refuses `payload` (reported under `field`) unless it matches the schema in force.
+    pub fn enforce(&self, tenant: &str, protocol: &str, version: Option<u32>, payload: &Value, field: &str) -> Result<(), ApiError> {
+        let Some((version, schema)) = self.resolve(tenant, protocol, version)? else { return Ok(()) };
+        let mut found = Findings::new();
+        schema.check(payload, field, &mut found);
+        if found.is_empty() { return Ok(()); }
+        self.violations.fetch_add(1, Ordering::Relaxed);
+        Err(rejection(found, StatusCode::UNPROCESSABLE_ENTITY, "Payload does not match its schema", "schema_violation", format!("{protocol} schema version {version}")))
+    }
+
+    /// Returns how many schema versions were removed.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut subjects = self.subjects.lock().unwrap();
+        let n: usize = subjects.values().map(|s| s.versions.len()).sum();
+        subjects.retain(|(t, _), _| t != tenant);
+        n - subjects.values().map(|s| s.versions.len()).sum::<usize>()
+    }
+}
+
+fn known_protocol(s: &AppState, protocol: &str) -> Result<(), ApiError> {
+    let mut v = Violations::default();
+    v.protocol(s, "protocol", protocol);
+    if v.is_empty() { return Ok(()); }
+    Err(v.into_error(StatusCode::BAD_REQUEST, "Validation failed", "validation_failed"))
+}
+
+fn parse_version(version: &str) -> Result<Option<u32>, ApiError> {
+    if version == "latest" { return Ok(None); }
+    version.parse().map(Some).map_err(|_| api_err(StatusCode::BAD_REQUEST, "Invalid version", Some("expected a version number or latest".into())))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<SubjectInfo>> {
+    let mut out: Vec<SubjectInfo> = s.schemas.subjects.lock().unwrap().iter().filter(|((t, _), _)| *t == tenant).map(|((_, p), subject)| subject.info(p)).collect();
+    out.sort_by(|a, b| a.protocol.cmp(&b.protocol));
+    Json(out)
+}
+
+/// Registers a new version; uploading the latest schema again returns it unchanged (200).
+pub async fn register(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(protocol): Path<String>, Valid(req): Valid<SchemaUpload>) -> Result<(StatusCode, Json<VersionInfo>), ApiError> {
+    known_protocol(&s, &protocol)?;
+    let parsed = Schema::parse(&req.schema, "schema", &mut Findings::new());
+    let info = {
+        let mut subjects = s.schemas.subjects.lock().unwrap();
+        let subject = subjects.entry((tenant.clone(), protocol.clone())).or_default();
+        if let Some(latest) = subject.versions.last() {
+            if latest.schema == req.schema { return Ok((StatusCode::OK, Json(latest.info(&protocol)))); }
+            let mut breaks = Findings::new();
+            let mode = subject.compatibility;
+            if matches!(mode, Compatibility::Backward | Compatibility::Full) { latest.parsed.within(&parsed, "payload", &mut breaks); }
+            if matches!(mode, Compatibility::Forward | Compatibility::Full) { parsed.within(&latest.parsed, "payload", &mut breaks); }
+            if !breaks.is_empty() {
+                return Err(rejection(breaks, StatusCode::CONFLICT, "Schema is not compatible with the latest version", "schema_incompatible", format!("{} check against version {}", mode.as_str(), latest.version)));
+            }
+        }
+        subject.next_version += 1;
+        let version = Version { version: subject.next_version, schema: req.schema, parsed: Arc::new(parsed), created_at_ms: now_ms() };
+        let info = version.info(&protocol);
+        subject.versions.push(version);
+        info
+    };
+    tracing::info!(%tenant, %protocol, version = info.version, "schema registered");
+    s.audit.record(&actor, "schema.register", Some(&tenant), Some(&protocol), json!({ "version": info.version }));
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+pub async fn versions(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(protocol): Path<String>) -> Result<Json<Vec<VersionInfo>>, ApiError> {
+    let subjects = s.schemas.subjects.lock().unwrap();
+    let subject = subjects.get(&(tenant, protocol.clone())).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "No schemas for protocol", Some(protocol.clone())))?;
+    Ok(Json(subject.versions.iter().map(|v| v.info(&protocol)).collect()))
+}
+
+/// `:version` is a number or `latest`.
+pub async fn get_version(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path((protocol, version)): Path<(String, String)>) -> Result<Json<VersionInfo>, ApiError> {
+    let wanted = parse_version(&version)?;
+    let subjects = s.schemas.subjects.lock().unwrap();
+    subjects.get(&(tenant, protocol.clone())).and_then(|subject| subject.find(wanted)).map(|v| Json(v.info(&protocol)))
+        .ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown schema version", Some(format!("{protocol} version {version}"))))
+}
+
+/// Later uploads are checked against whichever version is then the latest; numbers are not reused.
+pub async fn remove_version(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path((protocol, version)): Path<(String, u32)>) -> Result<StatusCode, ApiError> {
+    let removed = s.schemas.subjects.lock().unwrap().get_mut(&(tenant.clone(), protocol.clone())).and_then(|sub| {
+        let i = sub.versions.iter().position(|v| v.version == version)?;
+        Some(sub.versions.remove(i))
+    });
+    if removed.is_none() { return Err(api_err(StatusCode::NOT_FOUND, "Unknown schema version", Some(format!("{protocol} version {version}")))); }
+    s.audit.record(&actor, "schema.delete", Some(&tenant), Some(&protocol), json!({ "version": version }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Removes every version and the compatibility setting; the protocol stops being checked.
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(protocol): Path<String>) -> Result<StatusCode, ApiError> {
+    let Some(subject) = s.schemas.subjects.lock().unwrap().remove(&(tenant.clone(), protocol.clone())) else { return Err(api_err(StatusCode::NOT_FOUND, "No schemas for protocol", Some(protocol))); };
+    s.audit.record(&actor, "schema.delete", Some(&tenant), Some(&protocol), json!({ "versions": subject.versions.len() }));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_config(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(protocol): Path<String>) -> Json<SubjectConfig> {
+    Json(SubjectConfig { compatibility: s.schemas.subjects.lock().unwrap().get(&(tenant, protocol)).map(|s| s.compatibility).unwrap_or_default() })
+}
+
+/// May precede the protocol's first schema.
+pub async fn set_config(State(s): State<Arc<AppState>>, _: Require<Configure>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(protocol): Path<String>, Json(req): Json<SubjectConfig>) -> Result<Json<SubjectConfig>, ApiError> {
+    known_protocol(&s, &protocol)?;
+    s.schemas.subjects.lock().unwrap().entry((tenant.clone(), protocol.clone())).or_default().compatibility = req.compatibility;
+    s.audit.record(&actor, "schema.config", Some(&tenant), Some(&protocol), json!({ "compatibility": req.compatibility }));
+    Ok(Json(req))
+}
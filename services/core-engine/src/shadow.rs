@@ -0,0 +1,129 @@
+//! Device shadows: the last-known `reported` SDF state (merged from every sync delta) next to
+//! the `desired` state set by backends, readable while the device is offline. Both documents
+//! are updated with JSON merge-patch semantics (RFC 7386): `null` removes a key.
+
+use crate::events::now_ms;
+use crate::pressure::Priority;
+use crate::rbac::{Operate, Read, Require};
+use crate::spatial;
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Clone, Serialize)]
+pub struct Shadow {
+    pub device_id: String, pub reported: Value, pub desired: Value, pub delta: Value, pub version: u64, pub updated_at_ms: u64,
+    /// Last change per key of `reported.objects`, for the object query's `modified_since`.
+    #[serde(skip)] pub objects_modified_ms: HashMap<String, u64>,
+    /// Bounds of `reported.objects`, for bounding-box queries and stream filters.
+    #[serde(skip)] pub index: spatial::Index,
+}
+
+/// The documents that move with a device migrating to another region.
+#[derive(Serialize, Deserialize)]
+pub struct ShadowState { pub reported: Value, pub desired: Value }
+
+#[derive(Deserialize)]
+pub struct ShadowUpdate { desired: Value, version: Option<u64> }
+
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(p) = patch else { *target = patch.clone(); return };
+    if !target.is_object() { *target = Value::Object(Map::new()); }
+    let t = target.as_object_mut().expect("target coerced to object above");
+    for (k, v) in p {
+        if v.is_null() { t.remove(k); } else { merge_patch(t.entry(k.clone()).or_insert(Value::Null), v); }
+    }
+}
+
+/// Desired keys whose value differs from what the device last reported.
+fn diff(desired: &Value, reported: &Value) -> Value {
+    match (desired, reported) {
+        (Value::Object(d), Value::Object(r)) => {
+            let out: Map<String, Value> = d.iter().filter_map(|(k, dv)| {
+                let sub = match r.get(k) { Some(rv) => diff(dv, rv), None => dv.clone() };
+                (!sub.is_null()).then(|| (k.clone(), sub))
+            }).collect();
+            if out.is_empty() { Value::Null } else { Value::Object(out) }
+        }
+        (d, r) if d == r => Value::Null,
+        (d, _) => d.clone(),
+    }
+}
+
+impl Shadow {
+    fn new(device_id: &str) -> Self {
+        Shadow { device_id: device_id.into(), reported: Value::Object(Map::new()), desired: Value::Object(Map::new()), delta: Value::Null, version: 0, updated_at_ms: now_ms(), objects_modified_ms: HashMap::new(), index: spatial::Index::from_env() }
+    }
+    fn touch(&mut self) { self.delta = diff(&self.desired, &self.reported); self.version += 1; self.updated_at_ms = now_ms(); }
+}
+
+/// Folds an incoming sync delta into the device's reported state.
+pub fn apply_reported(s: &AppState, tenant: &str, device_id: &str, delta: &Value) {
+    apply_reported_all(s, tenant, &[(device_id, delta)]);
+}
+
+/// Applies several devices' deltas under one lock, so readers see all of them or none.
+pub fn apply_reported_all(s: &AppState, tenant: &str, deltas: &[(&str, &Value)]) {
+    let mut shadows = s.shadows.lock().unwrap();
+    for (device_id, delta) in deltas {
+        let sh = shadows.entry((tenant.to_string(), device_id.to_string())).or_insert_with(|| Shadow::new(device_id));
+        report(sh, delta);
+    }
+}
+
+fn report(sh: &mut Shadow, delta: &Value) {
+    merge_patch(&mut sh.reported, delta);
+    sh.touch();
+    let now = sh.updated_at_ms;
+    match delta.get("objects") {
+        Some(Value::Object(objects)) => for (id, v) in objects {
+            if v.is_null() { sh.objects_modified_ms.remove(id); } else { sh.objects_modified_ms.insert(id.clone(), now); }
+            sh.index.update(id, sh.reported.get("objects").and_then(|o| o.get(id)).and_then(Value::as_object));
+        },
+        None if delta.is_object() => {}
+        // `objects` (or the whole document) was replaced outright.
+        _ => {
+            sh.objects_modified_ms = sh.reported.get("objects").and_then(Value::as_object).map(|o| o.keys().map(|id| (id.clone(), now)).collect()).unwrap_or_default();
+            sh.index.rebuild(&sh.reported);
+        }
+    }
+}
+
+pub fn export(s: &AppState, tenant: &str, device_id: &str) -> Option<ShadowState> {
+    s.shadows.lock().unwrap().get(&(tenant.to_string(), device_id.to_string())).map(|sh| ShadowState { reported: sh.reported.clone(), desired: sh.desired.clone() })
+}
+
+/// Replaces the device's shadow with migrated documents; every object counts as modified now.
+pub fn import(s: &AppState, tenant: &str, device_id: &str, state: ShadowState) {
+    let mut sh = Shadow::new(device_id);
+    (sh.reported, sh.desired) = (state.reported, state.desired);
+    sh.touch();
+    sh.objects_modified_ms = sh.reported.get("objects").and_then(Value::as_object).map(|o| o.keys().map(|id| (id.clone(), sh.updated_at_ms)).collect()).unwrap_or_default();
+    sh.index.rebuild(&sh.reported);
+    s.shadows.lock().unwrap().insert((tenant.to_string(), device_id.to_string()), sh);
+}
+
+pub async fn get_shadow(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(device_id): Path<String>) -> Result<Json<Shadow>, ApiError> {
+    s.shadows.lock().unwrap().get(&(tenant, device_id.clone())).cloned().map(Json).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "No shadow for device", Some(device_id)))
+}
+
+/// Merges into `desired`; a supplied `version` must match the current one (optimistic concurrency).
+pub async fn put_shadow(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(device_id): Path<String>, Json(req): Json<ShadowUpdate>) -> Result<Json<Shadow>, ApiError> {
+    if !req.desired.is_object() { return Err(api_err(StatusCode::BAD_REQUEST, "desired must be an object", None)); }
+    let shadow = {
+        let mut shadows = s.shadows.lock().unwrap();
+        let sh = shadows.entry((tenant.clone(), device_id.clone())).or_insert_with(|| Shadow::new(&device_id));
+        if let Some(v) = req.version.filter(|v| *v != sh.version) {
+            return Err(api_err(StatusCode::CONFLICT, "Shadow version mismatch", Some(format!("expected {}, got {v}", sh.version))));
+        }
+        merge_patch(&mut sh.desired, &req.desired);
+        sh.touch();
+        sh.clone()
+    };
+    s.emit("shadow-update", &tenant, serde_json::json!({ "device_id": device_id, "version": shadow.version, "delta": shadow.delta }));
+    if !shadow.delta.is_null() { s.outbox.send(&tenant, &device_id, shadow.delta.clone(), Priority::Normal); }
+    Ok(Json(shadow))
+}
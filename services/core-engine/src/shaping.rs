@@ -0,0 +1,223 @@
+//! Bandwidth shaping for metered links: token buckets on bytes, one per connection and one per
+//! tenant. Sync payloads (HTTP, CoAP, QUIC) spend from both as they arrive, and so does every
+//! message pushed down a device's `/connections/:id/ws` socket.
+//!
+//! A sync that would overdraw a bucket is held until the bytes are available, up to
+//! `BANDWIDTH_MAX_DELAY_MS` (default 1000); past that it is refused with 429
+//! (`bandwidth_exceeded`) and `Retry-After`. Outbound messages are only ever delayed. Buckets
+//! hold `BANDWIDTH_BURST_SECS` (default 2) of traffic, so short bursts pass at full speed.
+//!
+//! Default rates come from `BANDWIDTH_CONNECTION_BYTES_PER_SEC` and
+//! `BANDWIDTH_TENANT_BYTES_PER_SEC` (unset = unlimited); `PUT /admin/tenants/:tenant/bandwidth` sets
+//! both rates for a tenant, replacing the defaults. A bucket that starts throttling emits one
+//! `bandwidth-throttled` event, again only once it has refilled. Connections report their
+//! bucket as `bandwidth`.
+
+use crate::audit::Actor;
+use crate::rbac::{Admin, Require};
+use crate::{api_err, ApiError, AppState};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Utilization is measured over this window.
+const WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct Limits { pub connection_bytes_per_sec: Option<u64>, pub tenant_bytes_per_sec: Option<u64> }
+
+impl Limits {
+    pub fn from_env() -> Self {
+        let num = |k: &str| std::env::var(k).ok().and_then(|v| v.parse().ok()).filter(|r| *r > 0);
+        Limits { connection_bytes_per_sec: num("BANDWIDTH_CONNECTION_BYTES_PER_SEC"), tenant_bytes_per_sec: num("BANDWIDTH_TENANT_BYTES_PER_SEC") }
+    }
+}
+
+struct Bucket { rate: u64, tokens: f64, refilled: Instant, throttled: bool, throttled_total: u64, window_start: Instant, window_bytes: u64, previous_window_bytes: u64 }
+
+/// Bandwidth use of a connection and of its tenant, as shown on the connection resource.
+#[derive(Serialize)]
+pub struct Bandwidth {
+    #[serde(skip_serializing_if = "Option::is_none")] connection: Option<Utilization>,
+    #[serde(skip_serializing_if = "Option::is_none")] tenant: Option<Utilization>,
+}
+
+#[derive(Serialize)]
+pub struct Utilization { limit_bytes_per_sec: u64, bytes_per_sec: f64, utilization: f64, available_bytes: u64, throttled: bool, throttled_total: u64 }
+
+impl Bucket {
+    fn new(rate: u64, burst_secs: f64) -> Self {
+        let now = Instant::now();
+        Bucket { rate, tokens: rate as f64 * burst_secs, refilled: now, throttled: false, throttled_total: 0, window_start: now, window_bytes: 0, previous_window_bytes: 0 }
+    }
+
+    fn refill(&mut self, rate: u64, burst_secs: f64) {
+        let now = Instant::now();
+        let capacity = rate as f64 * burst_secs;
+        if rate != self.rate { self.rate = rate; self.tokens = self.tokens.min(capacity); }
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * rate as f64).min(capacity);
+        self.refilled = now;
+        if self.tokens >= capacity { self.throttled = false; }
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= WINDOW {
+            self.previous_window_bytes = if elapsed >= WINDOW * 2 { 0 } else { self.window_bytes };
+            (self.window_start, self.window_bytes) = (now, 0);
+        }
+    }
+
+    /// How long `bytes` would have to wait for tokens.
+    fn wait(&self, bytes: u64) -> Duration {
+        Duration::from_secs_f64(((bytes as f64 - self.tokens) / self.rate as f64).max(0.0))
+    }
+
+    /// Counts a throttled transfer; returns whether that starts a throttling episode.
+    fn throttle(&mut self) -> bool {
+        self.throttled_total += 1;
+        !std::mem::replace(&mut self.throttled, true)
+    }
+
+    /// Spends `bytes`, going into debt if need be; returns whether throttling just started.
+    fn spend(&mut self, bytes: u64) -> bool {
+        let starts = self.tokens < bytes as f64 && self.throttle();
+        self.tokens -= bytes as f64;
+        self.window_bytes += bytes;
+        starts
+    }
+
+    /// Sliding-window estimate: the current window plus the overlapping part of the last one.
+    fn utilization(&self, burst_secs: f64) -> Utilization {
+        let frac = (Instant::now().duration_since(self.window_start).as_secs_f64() / WINDOW.as_secs_f64()).min(1.0);
+        let bytes_per_sec = (self.previous_window_bytes as f64 * (1.0 - frac) + self.window_bytes as f64) / WINDOW.as_secs_f64();
+        Utilization {
+            limit_bytes_per_sec: self.rate, bytes_per_sec, utilization: bytes_per_sec / self.rate as f64,
+            available_bytes: self.tokens.clamp(0.0, self.rate as f64 * burst_secs) as u64, throttled: self.throttled, throttled_total: self.throttled_total,
+        }
+    }
+}
+
+pub struct ShapingCounts { pub throttled: u64, pub rejected: u64, pub delay_ms: u64 }
+
+pub struct Shaper {
+    defaults: Limits,
+    burst_secs: f64,
+    max_delay: Duration,
+    overrides: Mutex<HashMap<String, Limits>>,
+    connections: Mutex<HashMap<String, Bucket>>,
+    tenants: Mutex<HashMap<String, Bucket>>,
+    throttled: AtomicU64,
+    rejected: AtomicU64,
+    delay_ms: AtomicU64,
+}
+
+/// A bucket that started throttling on this reservation.
+struct Started { scope: &'static str, rate: u64 }
+
+/// How long to wait for the reserved bytes; `admitted` is false when the transfer was refused
+/// and nothing was reserved.
+struct Reservation { wait: Duration, admitted: bool, started: Vec<Started> }
+
+impl Shaper {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        Shaper {
+            defaults: Limits::from_env(), burst_secs: env("BANDWIDTH_BURST_SECS", 2).max(1) as f64, max_delay: Duration::from_millis(env("BANDWIDTH_MAX_DELAY_MS", 1000)),
+            overrides: Mutex::default(), connections: Mutex::default(), tenants: Mutex::default(),
+            throttled: AtomicU64::new(0), rejected: AtomicU64::new(0), delay_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn limits(&self, tenant: &str) -> Limits { self.overrides.lock().unwrap().get(tenant).copied().unwrap_or(self.defaults) }
+
+    /// Reserves `bytes` from the connection's and tenant's buckets. With `refuse`, a wait beyond
+    /// `max_delay` reserves nothing.
+    fn reserve(&self, tenant: &str, connection_id: &str, bytes: u64, refuse: bool) -> Reservation {
+        let limits = self.limits(tenant);
+        let mut conns = self.connections.lock().unwrap();
+        let mut tenants = self.tenants.lock().unwrap();
+        let mut buckets: Vec<(&'static str, &mut Bucket)> = Vec::new();
+        if let Some(rate) = limits.connection_bytes_per_sec {
+            let b = conns.entry(connection_id.to_string()).or_insert_with(|| Bucket::new(rate, self.burst_secs));
+            b.refill(rate, self.burst_secs);
+            buckets.push(("connection", b));
+        }
+        if let Some(rate) = limits.tenant_bytes_per_sec {
+            let b = tenants.entry(tenant.to_string()).or_insert_with(|| Bucket::new(rate, self.burst_secs));
+            b.refill(rate, self.burst_secs);
+            buckets.push(("tenant", b));
+        }
+        let wait = buckets.iter().map(|(_, b)| b.wait(bytes)).max().unwrap_or_default();
+        let admitted = !refuse || wait <= self.max_delay;
+        let started = buckets.into_iter().filter_map(|(scope, b)| {
+            let starts = if admitted { b.spend(bytes) } else { !b.wait(bytes).is_zero() && b.throttle() };
+            starts.then_some(Started { scope, rate: b.rate })
+        }).collect();
+        Reservation { wait, admitted, started }
+    }
+
+    pub fn forget(&self, connection_id: &str) { self.connections.lock().unwrap().remove(connection_id); }
+
+    /// The buckets a connection currently spends from; `None` when neither is limited.
+    pub fn bandwidth(&self, tenant: &str, connection_id: &str) -> Option<Bandwidth> {
+        let limits = self.limits(tenant);
+        let view = |buckets: &Mutex<HashMap<String, Bucket>>, key: &str, rate: Option<u64>| {
+            let rate = rate?;
+            let mut buckets = buckets.lock().unwrap();
+            let b = buckets.get_mut(key)?;
+            b.refill(rate, self.burst_secs);
+            Some(b.utilization(self.burst_secs))
+        };
+        let bw = Bandwidth {
+            connection: view(&self.connections, connection_id, limits.connection_bytes_per_sec),
+            tenant: view(&self.tenants, tenant, limits.tenant_bytes_per_sec),
+        };
+        (bw.connection.is_some() || bw.tenant.is_some()).then_some(bw)
+    }
+
+    pub fn counts(&self) -> ShapingCounts {
+        ShapingCounts { throttled: self.throttled.load(Ordering::Relaxed), rejected: self.rejected.load(Ordering::Relaxed), delay_ms: self.delay_ms.load(Ordering::Relaxed) }
+    }
+}
+
+/// Charges `bytes` to the connection and its tenant and waits them out; `Err(wait)` when
+/// `refuse` turned the transfer away.
+async fn shape(s: &AppState, tenant: &str, connection_id: &str, device_id: &str, bytes: u64, path: &'static str, refuse: bool) -> Result<(), Duration> {
+    let Reservation { wait, admitted, started } = s.shaping.reserve(tenant, connection_id, bytes, refuse);
+    for st in started {
+        s.shaping.throttled.fetch_add(1, Ordering::Relaxed);
+        tracing::info!(%tenant, %connection_id, scope = st.scope, rate = st.rate, "bandwidth throttling started");
+        s.emit("bandwidth-throttled", tenant, serde_json::json!({
+            "connection_id": connection_id, "device_id": device_id, "scope": st.scope, "bytes_per_sec": st.rate, "path": path, "delay_ms": wait.as_millis() as u64, "rejected": !admitted,
+        }));
+    }
+    if !admitted {
+        s.shaping.rejected.fetch_add(1, Ordering::Relaxed);
+        return Err(wait);
+    }
+    if !wait.is_zero() {
+        s.shaping.delay_ms.fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+        tokio::time::sleep(wait).await;
+    }
+    Ok(())
+}
+
+/// Charges an inbound sync payload, holding it while the buckets refill; 429 past the delay cap.
+pub async fn admit(s: &AppState, tenant: &str, connection_id: &str, device_id: &str, bytes: u64) -> Result<(), ApiError> {
+    shape(s, tenant, connection_id, device_id, bytes, "sync", true).await.map_err(|wait| {
+        api_err(StatusCode::TOO_MANY_REQUESTS, "Bandwidth limit exceeded", Some(format!("{bytes} bytes exceed the connection or tenant bandwidth allowance"))).code("bandwidth_exceeded").retry_after(wait.as_secs().max(1))
+    })
+}
+
+/// Paces an outbound message; never refuses.
+pub async fn pace(s: &AppState, tenant: &str, connection_id: &str, device_id: &str, bytes: u64) {
+    let _ = shape(s, tenant, connection_id, device_id, bytes, "ws", false).await;
+}
+
+pub async fn set_limits(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(tenant): Path<String>, Json(l): Json<Limits>) -> Json<Limits> {
+    s.shaping.overrides.lock().unwrap().insert(tenant.clone(), l);
+    tracing::info!(%tenant, "admin set tenant bandwidth limits");
+    s.audit.record(&actor, "admin.tenant.bandwidth", Some(&tenant), None, serde_json::to_value(l).unwrap_or_default());
+    Json(l)
+}
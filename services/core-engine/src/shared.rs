@@ -0,0 +1,139 @@
+//! Cross-replica state. `STATE_BACKEND=redis` (built with the `redis-state` feature, using
+//! `REDIS_URL`) shares the connection registry, stats counters and idempotency cache between
+//! core-engine replicas; the default `local` backend keeps everything in-process.
+//!
+//! Each replica still serves from its in-memory registry; the shared backend is written
+//! through on every change and consulted when a connection id is not known locally. A
+//! connection another replica owns is cached for `FOREIGN_CONNECTION_TTL_MS` (default 5000)
+//! before it is looked up again, so its disconnect reaches every replica within that time.
+
+use crate::Connection;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[async_trait]
+pub trait StateBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn save_connection(&self, id: &str, c: &Connection);
+    async fn load_connection(&self, id: &str) -> Option<Connection>;
+    async fn delete_connection(&self, id: &str);
+    async fn incr(&self, counters: &[(&str, u64)]);
+    /// Cluster-wide counters, or None when only the local counters exist.
+    async fn counters(&self) -> Option<HashMap<String, u64>>;
+    async fn idempotency_get(&self, key: &str) -> Option<Vec<u8>>;
+    async fn idempotency_put(&self, key: &str, value: &[u8], ttl: Duration);
+    /// Stores `value` only if the key is free; false when it is already taken.
+    async fn idempotency_reserve(&self, key: &str, value: &[u8], ttl: Duration) -> bool;
+    async fn idempotency_release(&self, key: &str);
+    async fn ping(&self) -> Result<(), String> { Ok(()) }
+}
+
+pub fn foreign_ttl() -> Duration {
+    Duration::from_millis(std::env::var("FOREIGN_CONNECTION_TTL_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(5000))
+}
+
+pub async fn from_env() -> Arc<dyn StateBackend> {
+    match std::env::var("STATE_BACKEND").as_deref() {
+        #[cfg(feature = "redis-state")]
+        Ok("redis") => Arc::new(redis_backend::RedisBackend::connect(&std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://redis:6379".into())).await.expect("redis connect")),
+        Ok("local") | Err(_) => Arc::new(LocalBackend::default()),
+        Ok(other) => { tracing::warn!("Unknown STATE_BACKEND {other:?}, falling back to local"); Arc::new(LocalBackend::default()) }
+    }
+}
+
+/// Single-replica backend: the registry and counters already live in AppState, so only the
+/// idempotency cache is kept here.
+#[derive(Default)]
+pub struct LocalBackend { idempotency: Mutex<HashMap<String, (Instant, Vec<u8>)>> }
+
+#[async_trait]
+impl StateBackend for LocalBackend {
+    fn name(&self) -> &'static str { "local" }
+    async fn save_connection(&self, _: &str, _: &Connection) {}
+    async fn load_connection(&self, _: &str) -> Option<Connection> { None }
+    async fn delete_connection(&self, _: &str) {}
+    async fn incr(&self, _: &[(&str, u64)]) {}
+    async fn counters(&self) -> Option<HashMap<String, u64>> { None }
+    async fn idempotency_get(&self, key: &str) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let mut cache = self.idempotency.lock().unwrap();
+        cache.retain(|_, (expires, _)| *expires > now);
+        cache.get(key).map(|(_, v)| v.clone())
+    }
+    async fn idempotency_put(&self, key: &str, value: &[u8], ttl: Duration) {
+        self.idempotency.lock().unwrap().insert(key.into(), (Instant::now() + ttl, value.to_vec()));
+    }
+    async fn idempotency_reserve(&self, key: &str, value: &[u8], ttl: Duration) -> bool {
+        let now = Instant::now();
+        let mut cache = self.idempotency.lock().unwrap();
+        if cache.get(key).is_some_and(|(expires, _)| *expires > now) { return false; }
+        cache.insert(key.into(), (now + ttl, value.to_vec()));
+        true
+    }
+    async fn idempotency_release(&self, key: &str) { self.idempotency.lock().unwrap().remove(key); }
+}
+
+#[cfg(feature = "redis-state")]
+mod redis_backend {
+    use super::StateBackend;
+    use crate::Connection;
+    use async_trait::async_trait;
+    use redis::{aio::ConnectionManager, AsyncCommands};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    pub struct RedisBackend { conn: ConnectionManager }
+
+    impl RedisBackend {
+        pub async fn connect(url: &str) -> redis::RedisResult<Self> {
+            Ok(RedisBackend { conn: ConnectionManager::new(redis::Client::open(url)?).await? })
+        }
+    }
+
+    fn log_err<T>(op: &str, r: redis::RedisResult<T>) -> Option<T> {
+        r.map_err(|e| tracing::warn!("redis {op} failed: {e}")).ok()
+    }
+
+    #[async_trait]
+    impl StateBackend for RedisBackend {
+        fn name(&self) -> &'static str { "redis" }
+        async fn ping(&self) -> Result<(), String> {
+            redis::cmd("PING").query_async::<String>(&mut self.conn.clone()).await.map(drop).map_err(|e| e.to_string())
+        }
+        async fn save_connection(&self, id: &str, c: &Connection) {
+            let Ok(json) = serde_json::to_string(c) else { return };
+            log_err("SET", self.conn.clone().set::<_, _, ()>(format!("gw:conn:{id}"), json).await);
+        }
+        async fn load_connection(&self, id: &str) -> Option<Connection> {
+            let json: Option<String> = log_err("GET", self.conn.clone().get(format!("gw:conn:{id}")).await)?;
+            serde_json::from_str(&json?).ok()
+        }
+        async fn delete_connection(&self, id: &str) {
+            log_err("DEL", self.conn.clone().del::<_, ()>(format!("gw:conn:{id}")).await);
+        }
+        async fn incr(&self, counters: &[(&str, u64)]) {
+            let mut pipe = redis::pipe();
+            for (field, by) in counters { pipe.hincr("gw:stats", *field, *by).ignore(); }
+            log_err("HINCRBY", pipe.query_async::<()>(&mut self.conn.clone()).await);
+        }
+        async fn counters(&self) -> Option<HashMap<String, u64>> {
+            log_err("HGETALL", self.conn.clone().hgetall("gw:stats").await)
+        }
+        async fn idempotency_get(&self, key: &str) -> Option<Vec<u8>> {
+            log_err("GET", self.conn.clone().get(format!("gw:idem:{key}")).await).flatten()
+        }
+        async fn idempotency_put(&self, key: &str, value: &[u8], ttl: Duration) {
+            log_err("SET EX", self.conn.clone().set_ex::<_, _, ()>(format!("gw:idem:{key}"), value, ttl.as_secs().max(1)).await);
+        }
+        async fn idempotency_reserve(&self, key: &str, value: &[u8], ttl: Duration) -> bool {
+            let set = redis::cmd("SET").arg(format!("gw:idem:{key}")).arg(value).arg("NX").arg("EX").arg(ttl.as_secs().max(1)).query_async::<Option<String>>(&mut self.conn.clone()).await;
+            // With Redis unreachable the request runs unprotected rather than not at all.
+            log_err("SET NX", set).is_none_or(|ok| ok.is_some())
+        }
+        async fn idempotency_release(&self, key: &str) {
+            log_err("DEL", self.conn.clone().del::<_, ()>(format!("gw:idem:{key}")).await);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Synthetic load for capacity testing (feature `simulate`). `POST /api/v1/simulate` starts a
+//! job that connects `devices` virtual devices in the caller's tenant and has each sync a
+//! `payload_bytes` delta `syncs_per_sec` times a second for `duration_secs`, going through the
+//! same connect and sync pipelines as real traffic (quotas, pressure, events, usage). The job
+//! result reports achieved throughput and the connect and sync latency distributions. Virtual
+//! devices are named `sim-<job>-<n>` and disconnected when the run ends or is cancelled.
+
+use crate::audit::Actor;
+use crate::jobs::{Job, JobCtx, Jobs};
+use crate::rbac::{Admin, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{AppState, Tenant};
+use alice_gateway_types::{ConnectRequest, SyncRequest};
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+
+const MAX_DEVICES: u32 = 10_000;
+const MAX_DURATION_SECS: u64 = 3600;
+const MAX_PAYLOAD_BYTES: usize = 1024 * 1024;
+/// Latency buckets grow by 5%, so percentiles are accurate to within that.
+const BUCKET_GROWTH: f64 = 1.05;
+const BUCKETS: usize = 512;
+
+fn default_rate() -> f64 { 1.0 }
+fn default_payload() -> usize { 256 }
+
+#[derive(Deserialize)]
+pub struct SimulateRequest {
+    devices: u32, duration_secs: u64,
+    #[serde(default = "default_rate")] syncs_per_sec: f64,
+    #[serde(default = "default_payload")] payload_bytes: usize,
+    /// Connects per second while ramping up; all devices connect at once when absent.
+    ramp_per_sec: Option<f64>,
+    protocol: Option<String>, region: Option<String>,
+}
+
+impl Validate for SimulateRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check((1..=MAX_DEVICES).contains(&self.devices), "devices", format!("must be between 1 and {MAX_DEVICES}"));
+        v.check((1..=MAX_DURATION_SECS).contains(&self.duration_secs), "duration_secs", format!("must be between 1 and {MAX_DURATION_SECS}"));
+        v.check(self.syncs_per_sec > 0.0 && self.syncs_per_sec <= 1000.0, "syncs_per_sec", "must be above 0 and at most 1000");
+        v.check(self.payload_bytes <= MAX_PAYLOAD_BYTES, "payload_bytes", format!("must be at most {MAX_PAYLOAD_BYTES}"));
+        v.check(self.ramp_per_sec.is_none_or(|r| r > 0.0), "ramp_per_sec", "must be above 0");
+    }
+}
+
+/// Log-bucketed latency histogram in microseconds; fixed size however long the run.
+struct Histogram { counts: Vec<u64>, n: u64, sum_us: f64, max_us: f64 }
+
+impl Histogram {
+    fn new() -> Self { Histogram { counts: vec![0; BUCKETS], n: 0, sum_us: 0.0, max_us: 0.0 } }
+
+    fn record(&mut self, d: Duration) {
+        let us = d.as_secs_f64() * 1e6;
+        let bucket = if us <= 1.0 { 0 } else { (us.ln() / BUCKET_GROWTH.ln()).ceil() as usize };
+        self.counts[bucket.min(BUCKETS - 1)] += 1;
+        self.n += 1;
+        self.sum_us += us;
+        self.max_us = self.max_us.max(us);
+    }
+
+    fn merge(&mut self, o: &Histogram) {
+        for (a, b) in self.counts.iter_mut().zip(&o.counts) { *a += b; }
+        self.n += o.n;
+        self.sum_us += o.sum_us;
+        self.max_us = self.max_us.max(o.max_us);
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, in milliseconds.
+    fn quantile_ms(&self, q: f64) -> f64 {
+        let rank = ((self.n as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, c) in self.counts.iter().enumerate() {
+            seen += c;
+            if seen >= rank { return (BUCKET_GROWTH.powi(i as i32) / 1000.0).min(self.max_us / 1000.0); }
+        }
+        self.max_us / 1000.0
+    }
+
+    fn summary(&self) -> Value {
+        if self.n == 0 { return json!({ "count": 0 }); }
+        json!({
+            "count": self.n, "mean_ms": self.sum_us / self.n as f64 / 1000.0,
+            "p50_ms": self.quantile_ms(0.50), "p95_ms": self.quantile_ms(0.95), "p99_ms": self.quantile_ms(0.99), "max_ms": self.max_us / 1000.0,
+        })
+    }
+}
+
+/// What one virtual device saw.
+struct DeviceRun { connect: Histogram, sync: Histogram, connect_failed: u64, synced: u64, sync_failed: u64, bytes: u64, errors: HashMap<String, u64> }
+
+impl DeviceRun {
+    fn error(&mut self, e: &crate::ApiError) { *self.errors.entry(format!("{} {}", e.status.as_u16(), e.body.error)).or_default() += 1; }
+}
+
+pub async fn start(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Tenant(tenant): Tenant, Valid(req): Valid<SimulateRequest>) -> (StatusCode, Json<Job>) {
+    let (s2, t2) = (s.clone(), tenant.clone());
+    let job = Jobs::submit(&s, &tenant, "simulate", req.duration_secs, move |ctx| run(s2, ctx, t2, req));
+    s.audit.record(&actor, "simulate.start", Some(&tenant), Some(&job.id), json!({ "job_id": job.id }));
+    (StatusCode::ACCEPTED, Json(job))
+}
+
+async fn run(s: Arc<AppState>, ctx: JobCtx, tenant: String, req: SimulateRequest) -> Result<Value, String> {
+    let ctx = Arc::new(ctx);
+    let payload = json!({ "objects": { "sim": { "data": "x".repeat(req.payload_bytes) } } });
+    let period = Duration::from_secs_f64(1.0 / req.syncs_per_sec);
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(req.duration_secs);
+    tracing::info!(job = ctx.id(), devices = req.devices, duration_secs = req.duration_secs, "simulation started");
+
+    let mut devices = Vec::with_capacity(req.devices as usize);
+    for n in 0..req.devices {
+        if let Some(ramp) = req.ramp_per_sec {
+            if n > 0 { tokio::time::sleep(Duration::from_secs_f64(1.0 / ramp)).await; }
+        }
+        if ctx.cancelled() || Instant::now() >= deadline { break; }
+        let connect = ConnectRequest { device_id: format!("sim-{}-{n}", &ctx.id()[..8]), protocol: req.protocol.clone(), region: req.region.clone(), ..Default::default() };
+        devices.push(tokio::spawn(device(s.clone(), ctx.clone(), tenant.clone(), connect, payload.clone(), period, deadline)));
+    }
+    let progress = {
+        let (ctx, secs) = (ctx.clone(), req.duration_secs);
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(1));
+            loop { tick.tick().await; ctx.progress(started.elapsed().as_secs().min(secs), secs); }
+        })
+    };
+
+    let mut total = DeviceRun { connect: Histogram::new(), sync: Histogram::new(), connect_failed: 0, synced: 0, sync_failed: 0, bytes: 0, errors: HashMap::new() };
+    for d in devices {
+        let Ok(d) = d.await else { total.connect_failed += 1; continue };
+        total.connect.merge(&d.connect);
+        total.sync.merge(&d.sync);
+        total.connect_failed += d.connect_failed;
+        total.synced += d.synced;
+        total.sync_failed += d.sync_failed;
+        total.bytes += d.bytes;
+        for (e, c) in d.errors { *total.errors.entry(e).or_default() += c; }
+    }
+    progress.abort();
+    let elapsed = started.elapsed().as_secs_f64();
+    tracing::info!(job = ctx.id(), syncs = total.synced, elapsed_secs = elapsed, "simulation finished");
+    Ok(json!({
+        "devices": req.devices, "elapsed_secs": elapsed, "target_syncs_per_sec": req.syncs_per_sec * req.devices as f64,
+        "achieved_syncs_per_sec": total.synced as f64 / elapsed, "achieved_bytes_per_sec": total.bytes as f64 / elapsed,
+        "connects": { "succeeded": total.connect.n, "failed": total.connect_failed, "latency": total.connect.summary() },
+        "syncs": { "succeeded": total.synced, "failed": total.sync_failed, "latency": total.sync.summary() },
+        "errors": total.errors,
+    }))
+}
+
+/// One virtual device: connect, sync on a fixed period until the deadline, disconnect.
+async fn device(s: Arc<AppState>, ctx: Arc<JobCtx>, tenant: String, connect: ConnectRequest, payload: Value, period: Duration, deadline: Instant) -> DeviceRun {
+    let mut run = DeviceRun { connect: Histogram::new(), sync: Histogram::new(), connect_failed: 0, synced: 0, sync_failed: 0, bytes: 0, errors: HashMap::new() };
+    let t = Instant::now();
+    let conn = match s.open_connection(&tenant, "simulate", None, None, connect).await {
+        Ok(c) => { run.connect.record(t.elapsed()); c }
+        Err(e) => { run.connect_failed += 1; run.error(&e); return run; }
+    };
+    let wire_bytes = payload.to_string().len();
+    let mut tick = tokio::time::interval(period);
+    // Ticks missed while a sync was slow are skipped, so the achieved rate shows the shortfall.
+    tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    while Instant::now() < deadline && !ctx.cancelled() {
+        tick.tick().await;
+        let t = Instant::now();
+        let req = SyncRequest { connection_id: conn.connection_id.clone(), sdf_delta: Some(payload.clone()), ..Default::default() };
+        match s.process_sync(&tenant, None, req, wire_bytes).await {
+            Ok(_) => { run.sync.record(t.elapsed()); run.synced += 1; run.bytes += wire_bytes as u64; }
+            Err(e) => { run.sync_failed += 1; run.error(&e); }
+        }
+    }
+    s.drop_connection(&conn.connection_id);
+    run
+}
@@ -0,0 +1,286 @@
+//! Per-connection delta log with compaction. Every sync delta is appended to its connection's
+//! log; compaction folds the pending deltas into the connection's snapshot (JSON merge-patch,
+//! like the shadow) and drops them. A log is compacted once it holds `COMPACT_MAX_DELTAS`
+//! deltas (default 1000) or its oldest delta is `COMPACT_MAX_AGE_SECS` old (default 300).
+//! `POST /connections/:id/compact` forces it; `GET /connections/:id/snapshot` reads the result.
+//! With archival configured (see `archive`), each compaction is also uploaded off-box; with the
+//! write-ahead log on (see `wal`), it also becomes the connection's WAL checkpoint.
+//!
+//! Folded deltas are kept for replay, the newest `REPLAY_HISTORY_DELTAS` per connection
+//! (default 1000, 0 keeps none). `POST /connections/:id/replay?from=&to=` (Unix ms, inclusive)
+//! merges the deltas synced in that window into an empty document, oldest first, and returns the
+//! result with warnings for every step that loses or contradicts data: a delta that is not an
+//! object and replaces the whole document, a deletion of a missing key, a key switching between
+//! object and value, and sequence gaps or regressions. Deltas older than the kept history come
+//! from the archive when there is one; deltas stored nowhere are counted in `missing_deltas`.
+//! Replaying the whole history also reports whether it reproduces the current state.
+
+use crate::archive::{Archive, Archived};
+use crate::events::now_ms;
+use crate::keystore::Keystore;
+use crate::rbac::{Operate, Read, Require};
+use crate::shadow::merge_patch;
+use crate::wal::{Checkpoint, Wal};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const TICK: Duration = Duration::from_secs(10);
+const MAX_WARNINGS: usize = 1000;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry { pub at_ms: u64, pub sequence: Option<u64>, pub delta: Value }
+
+struct DeltaLog {
+    tenant: String, device_id: String, snapshot: Value, version: u64, taken_at_ms: Option<u64>, last_sequence: Option<u64>, folded: u64, pending: Vec<Entry>,
+    /// The newest folded deltas, for replay.
+    history: VecDeque<Entry>,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    connection_id: String, device_id: String, version: u64, taken_at_ms: Option<u64>,
+    /// Sequence of the last delta folded in, when the device sends sequences.
+    last_sequence: Option<u64>, deltas_folded: u64, pending_deltas: usize, state: Value,
+}
+
+pub struct Snapshots { max_deltas: usize, max_age_ms: u64, history: usize, logs: Mutex<HashMap<String, DeltaLog>>, pub archive: Option<Arc<Archive>>, wal: Arc<Wal> }
+
+impl DeltaLog {
+    fn new(tenant: &str, device_id: &str, snapshot: Value, version: u64, taken_at_ms: Option<u64>, last_sequence: Option<u64>, folded: u64) -> Self {
+        DeltaLog { tenant: tenant.into(), device_id: device_id.into(), snapshot, version, taken_at_ms, last_sequence, folded, pending: Vec::new(), history: VecDeque::new() }
+    }
+
+    /// Folds the pending deltas in and returns them; empty if there were none.
+    fn compact(&mut self) -> Vec<Entry> {
+        if self.pending.is_empty() { return Vec::new(); }
+        let folded = std::mem::take(&mut self.pending);
+        for e in &folded {
+            merge_patch(&mut self.snapshot, &e.delta);
+            self.last_sequence = e.sequence.or(self.last_sequence);
+            self.folded += 1;
+        }
+        self.version += 1;
+        self.taken_at_ms = Some(now_ms());
+        folded
+    }
+
+    fn archived(&self, connection_id: &str, deltas: Vec<Entry>) -> Archived {
+        Archived {
+            tenant: self.tenant.clone(), connection_id: connection_id.into(), device_id: self.device_id.clone(), version: self.version, taken_at_ms: self.taken_at_ms,
+            last_sequence: self.last_sequence, deltas_folded: self.folded, state: self.snapshot.clone(), sealed: None, deltas, sealed_deltas: None,
+        }
+    }
+
+    fn view(&self, connection_id: &str) -> Snapshot {
+        Snapshot { connection_id: connection_id.into(), device_id: self.device_id.clone(), version: self.version, taken_at_ms: self.taken_at_ms, last_sequence: self.last_sequence, deltas_folded: self.folded, pending_deltas: self.pending.len(), state: self.snapshot.clone() }
+    }
+}
+
+impl Snapshots {
+    pub fn from_env(keys: Arc<Keystore>, wal: Arc<Wal>) -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        Snapshots {
+            max_deltas: env("COMPACT_MAX_DELTAS", 1000).max(1) as usize, max_age_ms: env("COMPACT_MAX_AGE_SECS", 300) * 1000, history: env("REPLAY_HISTORY_DELTAS", 1000) as usize,
+            logs: Mutex::new(HashMap::new()), archive: Archive::from_env(keys).map(Arc::new), wal,
+        }
+    }
+
+    /// Compacts the log, checkpoints the WAL and uploads the result when archival is on.
+    fn compact(&self, connection_id: &str, log: &mut DeltaLog) -> bool {
+        let folded = log.compact();
+        if folded.is_empty() { return false; }
+        self.wal.checkpoint(connection_id, Checkpoint { version: log.version, taken_at_ms: log.taken_at_ms, last_sequence: log.last_sequence, folded: log.folded, state: &log.snapshot });
+        if let Some(archive) = self.archive.clone() {
+            let rec = log.archived(connection_id, folded.clone());
+            tokio::spawn(async move { archive.put(rec).await });
+        }
+        log.history.extend(folded);
+        while log.history.len() > self.history { log.history.pop_front(); }
+        true
+    }
+
+    /// Appends a decoded delta, compacting right away once the count threshold is reached.
+    pub fn append(&self, tenant: &str, connection_id: &str, device_id: &str, sequence: Option<u64>, delta: &Value) {
+        let mut logs = self.logs.lock().unwrap();
+        let log = logs.entry(connection_id.into()).or_insert_with(|| DeltaLog::new(tenant, device_id, Value::Object(Map::new()), 0, None, None, 0));
+        log.pending.push(Entry { at_ms: now_ms(), sequence, delta: delta.clone() });
+        if log.pending.len() >= self.max_deltas { self.compact(connection_id, log); }
+    }
+
+    pub fn forget(&self, connection_id: &str) { self.logs.lock().unwrap().remove(connection_id); }
+
+    /// Seeds a connection's log from a WAL checkpoint during startup recovery.
+    pub fn recover(&self, tenant: &str, connection_id: &str, device_id: &str, c: Checkpoint) {
+        self.logs.lock().unwrap().insert(connection_id.into(), DeltaLog::new(tenant, device_id, c.state.clone(), c.version, c.taken_at_ms, c.last_sequence, c.folded));
+    }
+
+    /// Seeds a connection without local state from its latest archived snapshot.
+    pub async fn restore(&self, tenant: &str, connection_id: &str) {
+        let Some(archive) = &self.archive else { return };
+        if self.logs.lock().unwrap().contains_key(connection_id) { return; }
+        let Some(rec) = archive.latest(tenant, connection_id).await else { return };
+        tracing::info!(%connection_id, version = rec.version, "restored snapshot from archive");
+        self.logs.lock().unwrap().entry(connection_id.into()).or_insert(DeltaLog::new(&rec.tenant, &rec.device_id, rec.state, rec.version, rec.taken_at_ms, rec.last_sequence, rec.deltas_folded));
+    }
+}
+
+/// Compacts logs whose oldest pending delta has aged past the limit.
+pub async fn compact_loop(s: Arc<AppState>) {
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tick.tick().await;
+        let cutoff = now_ms().saturating_sub(s.snapshots.max_age_ms);
+        let mut compacted = 0;
+        for (id, log) in s.snapshots.logs.lock().unwrap().iter_mut() {
+            if log.pending.first().is_some_and(|e| e.at_ms <= cutoff) && s.snapshots.compact(id, log) { compacted += 1; }
+        }
+        if compacted > 0 { tracing::debug!(compacted, "compacted delta logs by age"); }
+    }
+}
+
+fn with_log<T>(s: &AppState, tenant: &str, id: &str, f: impl FnOnce(&mut DeltaLog) -> T) -> Result<T, ApiError> {
+    if s.connections.lock().unwrap().get(id).is_none_or(|c| c.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(id.into()))); }
+    let mut logs = s.snapshots.logs.lock().unwrap();
+    logs.get_mut(id).filter(|l| l.tenant == tenant).map(f).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "No deltas synced on this connection yet", Some(id.into())))
+}
+
+pub async fn get_snapshot(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Snapshot>, ApiError> {
+    with_log(&s, &tenant, &id, |log| log.view(&id)).map(Json)
+}
+
+pub async fn compact(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Snapshot>, ApiError> {
+    with_log(&s, &tenant, &id, |log| { s.snapshots.compact(&id, log); log.view(&id) }).map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct ReplayQuery { from: Option<u64>, to: Option<u64> }
+
+#[derive(Serialize)]
+pub struct ReplayWarning {
+    /// Position of the delta in the replay, from 0.
+    index: usize, at_ms: u64, #[serde(skip_serializing_if = "Option::is_none")] sequence: Option<u64>, code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")] path: Option<String>, message: String,
+}
+
+#[derive(Serialize)]
+pub struct Replay {
+    connection_id: String, device_id: String, from: Option<u64>, to: Option<u64>, deltas_replayed: usize, from_memory: usize, from_archive: usize,
+    first_at_ms: Option<u64>, last_at_ms: Option<u64>, last_sequence: Option<u64>,
+    /// Deltas synced before the oldest stored one, so no longer replayable.
+    missing_deltas: u64,
+    /// Whether the replay reproduces the current state; only when the whole history was replayed.
+    #[serde(skip_serializing_if = "Option::is_none")] matches_current: Option<bool>,
+    state: Value, warnings: Vec<ReplayWarning>, warnings_omitted: usize,
+}
+
+/// Records what merging `patch` into `target` at `path` would lose or contradict.
+fn inspect(target: &Value, patch: &Value, path: &str, out: &mut Vec<(&'static str, String, String)>) {
+    let Value::Object(p) = patch else {
+        if path.is_empty() { out.push(("replaces_document", "/".into(), "delta is not an object and replaces the whole document".into())); }
+        return;
+    };
+    let t = target.as_object();
+    for (k, v) in p {
+        let at = format!("{path}/{k}");
+        let existing = t.and_then(|t| t.get(k));
+        match (existing, v) {
+            (None, Value::Null) => out.push(("delete_missing", at, "deletes a key that does not exist".into())),
+            (Some(e), v) if !v.is_null() && e.is_object() != v.is_object() => {
+                out.push(("type_change", at.clone(), format!("{} replaced by {}", kind(e), kind(v))));
+                if v.is_object() { inspect(&Value::Null, v, &at, out); }
+            }
+            (e, v) if v.is_object() => inspect(e.unwrap_or(&Value::Null), v, &at, out),
+            _ => {}
+        }
+    }
+}
+
+fn kind(v: &Value) -> &'static str {
+    match v { Value::Object(_) => "object", Value::Array(_) => "array", Value::String(_) => "string", Value::Number(_) => "number", Value::Bool(_) => "bool", Value::Null => "null" }
+}
+
+/// Replays the connection's deltas in the window against an empty document. Changes nothing.
+pub async fn replay(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>, Query(q): Query<ReplayQuery>) -> Result<Json<Replay>, ApiError> {
+    let Some(device_id) = s.connections.lock().unwrap().get(&id).filter(|c| c.tenant == tenant).map(|c| c.device_id.clone()) else {
+        return Err(api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(id)));
+    };
+    if q.from.zip(q.to).is_some_and(|(f, t)| f > t) { return Err(api_err(StatusCode::BAD_REQUEST, "Invalid replay window", Some("from is after to".into()))); }
+    // Index (in sync order, from 0) of the oldest delta held in memory, and those deltas.
+    let (first_local, local, current) = {
+        let logs = s.snapshots.logs.lock().unwrap();
+        match logs.get(&id).filter(|l| l.tenant == tenant) {
+            Some(l) => {
+                let mut current = l.snapshot.clone();
+                for e in &l.pending { merge_patch(&mut current, &e.delta); }
+                (l.folded - l.history.len() as u64, l.history.iter().chain(&l.pending).cloned().collect::<Vec<_>>(), Some(current))
+            }
+            None => (0, Vec::new(), None),
+        }
+    };
+    let mut warnings = Vec::new();
+    let mut older: Vec<Entry> = Vec::new();
+    let reaches_back = |e: Option<&Entry>| q.from.is_none_or(|f| e.is_none_or(|e| e.at_ms > f));
+    if first_local > 0 && reaches_back(local.first()) {
+        if let Some(archive) = &s.snapshots.archive {
+            match archive.history(&tenant, &id).await {
+                Ok(versions) => {
+                    let mut indexed: Vec<(u64, Entry)> = versions.into_iter().flat_map(|v| {
+                        let start = v.deltas_folded.saturating_sub(v.deltas.len() as u64);
+                        v.deltas.into_iter().enumerate().map(move |(i, e)| (start + i as u64, e))
+                    }).filter(|(i, _)| *i < first_local).collect();
+                    indexed.sort_by_key(|(i, _)| *i);
+                    indexed.dedup_by_key(|(i, _)| *i);
+                    // Only an unbroken run up to the in-memory history is usable.
+                    let mut next = first_local;
+                    while indexed.last().is_some_and(|(i, _)| *i + 1 == next) { next -= 1; older.push(indexed.pop().expect("checked above").1); }
+                    older.reverse();
+                }
+                Err(e) => warnings.push(ReplayWarning { index: 0, at_ms: now_ms(), sequence: None, code: "archive_unavailable", path: None, message: e }),
+            }
+        }
+    }
+    let first_stored = first_local - older.len() as u64;
+    // Each delta tagged with whether it came from the archive.
+    let all: Vec<(bool, Entry)> = older.into_iter().map(|e| (true, e)).chain(local.into_iter().map(|e| (false, e))).collect();
+    let missing_deltas = if reaches_back(all.first().map(|(_, e)| e)) { first_stored } else { 0 };
+    let whole = q.from.is_none() && q.to.is_none() && missing_deltas == 0;
+
+    let mut state = Value::Object(Map::new());
+    let (mut replayed, mut from_archive) = (0, 0);
+    let (mut first_at_ms, mut last_at_ms, mut last_sequence) = (None, None, None::<u64>);
+    let mut found = Vec::new();
+    for (archived, e) in all.iter().filter(|(_, e)| q.from.is_none_or(|f| e.at_ms >= f) && q.to.is_none_or(|t| e.at_ms <= t)) {
+        let index = replayed;
+        let mut warn = |code, path, message| warnings.push(ReplayWarning { index, at_ms: e.at_ms, sequence: e.sequence, code, path, message });
+        match (last_sequence, e.sequence) {
+            (Some(last), Some(seq)) if seq <= last => warn("sequence_regression", None, format!("sequence {seq} follows {last}")),
+            (Some(last), Some(seq)) if seq > last + 1 => warn("sequence_gap", None, format!("{} sequence numbers skipped after {last}", seq - last - 1)),
+            (Some(last), None) => warn("sequence_missing", None, format!("no sequence after {last}")),
+            _ => {}
+        }
+        inspect(&state, &e.delta, "", &mut found);
+        for (code, path, message) in found.drain(..) { warn(code, Some(path), message); }
+        merge_patch(&mut state, &e.delta);
+        first_at_ms = first_at_ms.or(Some(e.at_ms));
+        last_at_ms = Some(e.at_ms);
+        last_sequence = e.sequence.or(last_sequence);
+        replayed += 1;
+        from_archive += usize::from(*archived);
+    }
+    if missing_deltas > 0 {
+        warnings.insert(0, ReplayWarning { index: 0, at_ms: first_at_ms.unwrap_or_else(now_ms), sequence: None, code: "history_truncated", path: None, message: format!("{missing_deltas} earlier deltas are no longer stored") });
+    }
+    let warnings_omitted = warnings.len().saturating_sub(MAX_WARNINGS);
+    warnings.truncate(MAX_WARNINGS);
+    let matches_current = current.filter(|_| whole).map(|c| c == state);
+    tracing::debug!(connection_id = %id, replayed, warnings = warnings.len(), "replayed delta log");
+    Ok(Json(Replay {
+        connection_id: id, device_id, from: q.from, to: q.to, deltas_replayed: replayed, from_memory: replayed - from_archive, from_archive,
+        first_at_ms, last_at_ms, last_sequence, missing_deltas, matches_current, state, warnings, warnings_omitted,
+    }))
+}
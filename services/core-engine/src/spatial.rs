@@ -0,0 +1,90 @@
+//! Per-device spatial index over the reported SDF objects, kept up to date as deltas merge, so
+//! bounding-box object queries and the streams' `bbox` filter don't scan every object.
+//!
+//! It is a uniform grid over the first two axes (`SPATIAL_CELL_SIZE`, default 10 units): an
+//! object is filed under every cell its footprint overlaps, and a query only visits the cells
+//! its box covers, then checks each candidate exactly (all shared axes, as the object query
+//! always did). Objects covering more than `SPATIAL_MAX_CELLS` cells (default 64), or without
+//! two usable axes, go on a short `wide` list that every query checks. A cell size near the
+//! typical object extent keeps both the cells visited and the candidates per cell low;
+//! `GET /connections/:id/objects/index` reports occupancy for tuning.
+
+use crate::objects::Aabb;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+type Cell = (i64, i64);
+
+#[derive(Clone)]
+pub struct Index { cell_size: f64, max_cells: u64, boxes: HashMap<String, Aabb>, cells: HashMap<Cell, HashSet<String>>, wide: HashSet<String> }
+
+#[derive(Serialize)]
+pub struct IndexStats { pub cell_size: f64, pub objects: usize, pub unbounded: usize, pub cells: usize, pub wide: usize, pub max_per_cell: usize, pub mean_per_cell: f64 }
+
+impl Index {
+    pub fn from_env() -> Self {
+        let cell_size = std::env::var("SPATIAL_CELL_SIZE").ok().and_then(|v| v.parse().ok()).filter(|c: &f64| c.is_finite() && *c > 0.0).unwrap_or(10.0);
+        let max_cells = std::env::var("SPATIAL_MAX_CELLS").ok().and_then(|v| v.parse().ok()).unwrap_or(64u64).max(1);
+        Index { cell_size, max_cells, boxes: HashMap::new(), cells: HashMap::new(), wide: HashSet::new() }
+    }
+
+    /// The cells `b`'s footprint overlaps, or `None` when it belongs on the wide list (or, for
+    /// a query, covers more cells than are worth visiting).
+    fn cells_of(&self, b: &Aabb, max: u64) -> Option<Vec<Cell>> {
+        let (Some(min), Some(maxc)) = (b.min.get(..2), b.max.get(..2)) else { return None };
+        if min.iter().chain(maxc).any(|v| !v.is_finite()) { return None; }
+        let lo: Vec<f64> = min.iter().map(|v| (v / self.cell_size).floor()).collect();
+        let hi: Vec<f64> = maxc.iter().map(|v| (v / self.cell_size).floor()).collect();
+        let count = (hi[0] - lo[0] + 1.0) * (hi[1] - lo[1] + 1.0);
+        if count > max as f64 { return None; }
+        let (x0, y0, x1, y1) = (lo[0] as i64, lo[1] as i64, hi[0] as i64, hi[1] as i64);
+        Some((x0..=x1).flat_map(|x| (y0..=y1).map(move |y| (x, y))).collect())
+    }
+
+    /// Files `id` under its current bounds; `None` (removed, or no bounds) drops it.
+    pub fn update(&mut self, id: &str, object: Option<&Map<String, Value>>) {
+        if let Some(old) = self.boxes.remove(id) {
+            match self.cells_of(&old, self.max_cells) {
+                Some(cells) => for c in cells {
+                    if let Some(ids) = self.cells.get_mut(&c) { ids.remove(id); if ids.is_empty() { self.cells.remove(&c); } }
+                },
+                None => { self.wide.remove(id); }
+            }
+        }
+        let Some(b) = object.and_then(Aabb::of) else { return };
+        match self.cells_of(&b, self.max_cells) {
+            Some(cells) => for c in cells { self.cells.entry(c).or_default().insert(id.to_string()); },
+            None => { self.wide.insert(id.to_string()); }
+        }
+        self.boxes.insert(id.to_string(), b);
+    }
+
+    /// Re-indexes every object of a reported document.
+    pub fn rebuild(&mut self, reported: &Value) {
+        (self.boxes, self.cells, self.wide) = Default::default();
+        for (id, v) in reported.get("objects").and_then(Value::as_object).into_iter().flatten() { self.update(id, v.as_object()); }
+    }
+
+    pub fn bounds(&self, id: &str) -> Option<&Aabb> { self.boxes.get(id) }
+
+    /// Ids of the objects intersecting `q`, in order.
+    pub fn query(&self, q: &Aabb) -> BTreeSet<&str> {
+        let candidates: Box<dyn Iterator<Item = &String>> = match self.cells_of(q, self.cells.len() as u64) {
+            Some(cells) => Box::new(cells.into_iter().filter_map(|c| self.cells.get(&c)).flatten().chain(&self.wide)),
+            // Covering more cells than are occupied: walking the occupied ones is cheaper.
+            None => Box::new(self.boxes.keys()),
+        };
+        candidates.filter(|id| self.boxes.get(*id).is_some_and(|b| q.intersects(b))).map(String::as_str).collect()
+    }
+
+    /// `objects` is how many objects the document holds, for the share without bounds.
+    pub fn stats(&self, objects: usize) -> IndexStats {
+        let per_cell = self.cells.values().map(HashSet::len);
+        let filed: usize = per_cell.clone().sum();
+        IndexStats {
+            cell_size: self.cell_size, objects: self.boxes.len(), unbounded: objects.saturating_sub(self.boxes.len()), cells: self.cells.len(), wide: self.wide.len(),
+            max_per_cell: per_cell.max().unwrap_or(0), mean_per_cell: if self.cells.is_empty() { 0.0 } else { filed as f64 / self.cells.len() as f64 },
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Warm standby sessions: a high-priority device can pre-register in a secondary
+//! region at connect time, so failover is a single round trip with a pre-shared token.
+//! A token only works for the tenant it was issued to; any other sees an unknown standby.
+//! Standbys lapse `STANDBY_TTL_SECS` (default 3600) after registration; a device that wants to
+//! stay covered reconnects for a fresh one.
+
+use crate::rbac::{Operate, Require};
+use crate::{api_err, endpoint_for, ApiError, AppState, ConnectResponse, Tenant};
+use alice_gateway_types::StandbyInfo;
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub struct Standby { pub tenant: String, pub connection_id: String, pub region: String, pub created_at: Instant }
+
+#[derive(Deserialize)]
+pub struct FailoverRequest { resume_token: String }
+
+fn ttl() -> Duration {
+    Duration::from_secs(std::env::var("STANDBY_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(3600))
+}
+
+/// Pre-creates the standby session keyed by a fresh resume token, dropping lapsed ones.
+pub fn register(s: &AppState, tenant: &str, connection_id: &str, protocol: &str, region: String) -> StandbyInfo {
+    let resume_token = uuid::Uuid::new_v4().to_string();
+    let info = StandbyInfo { endpoint: endpoint_for(&region, protocol), region: region.clone(), resume_token: resume_token.clone() };
+    let mut standbys = s.standbys.lock().unwrap();
+    let ttl = ttl();
+    standbys.retain(|_, sb| sb.created_at.elapsed() < ttl);
+    standbys.insert(resume_token, Standby { tenant: tenant.into(), connection_id: connection_id.into(), region, created_at: Instant::now() });
+    info
+}
+
+/// Promotes a standby session to the active connection, keeping the original connection_id.
+pub async fn failover(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Json(req): Json<FailoverRequest>) -> Result<Json<ConnectResponse>, ApiError> {
+    let sb = {
+        let mut standbys = s.standbys.lock().unwrap();
+        if standbys.get(&req.resume_token).is_none_or(|sb| sb.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown standby", None)); }
+        standbys.remove(&req.resume_token).expect("checked above")
+    };
+    if sb.created_at.elapsed() >= ttl() {
+        return Err(api_err(StatusCode::GONE, "Standby expired", Some("reconnect to register a new standby".into())).code("standby_expired"));
+    }
+    let conn = {
+        let mut conns = s.connections.lock().unwrap();
+        let conn = conns.get_mut(&sb.connection_id).ok_or_else(|| api_err(StatusCode::GONE, "Connection closed", Some(sb.connection_id.clone())).code("connection_gone"))?;
+        conn.region = sb.region.clone();
+        conn.clone()
+    };
+    s.shared.save_connection(&sb.connection_id, &conn).await;
+    tracing::info!(connection_id = %sb.connection_id, region = %sb.region, standby_age_ms = sb.created_at.elapsed().as_millis() as u64, "standby promoted");
+    let resume_token = Some(s.resume.issue(&conn.tenant, &conn.device_id, &sb.connection_id));
+    Ok(Json(ConnectResponse { connection_id: sb.connection_id, device_id: conn.device_id, endpoint: endpoint_for(&sb.region, &conn.protocol), protocol: conn.protocol, region: sb.region, status: "failed-over".into(), standby: None, relayed_to: conn.upstream.map(|u| u.home_region), resume_token, device_credential: None }))
+}
@@ -0,0 +1,212 @@
+//! Live event streams for dashboards and backends: `GET /events` (server-sent events) and
+//! `GET /events/ws` (WebSocket). Besides the webhook kinds, streams can carry `delta` — every
+//! accepted sync delta — which is why subscriptions filter server-side:
+//!
+//! - `topics`: comma-separated hub topic patterns relative to the tenant, e.g. `+/sensor` or
+//!   `dev-42/#` (default: `#`)
+//! - `kinds`: comma-separated event kinds (default: all)
+//! - `device`: comma-separated device_id globs (`*`, `?`); events without a device are dropped
+//! - `type` / `bbox`: keep only delta objects of these types / intersecting the box (as in the
+//!   object query). Matching uses the object's merged state, so partial updates still match;
+//!   removals always pass. Non-object keys are dropped once either filter is set.
+//! - `min_change`: suppress numeric leaves (and numeric arrays, element-wise) that moved less
+//!   than this since the last value sent on this subscription
+//!
+//! `buffer` (events) and `overflow` (`drop-oldest`, `drop-newest` or `disconnect`) set how the
+//! hub treats the subscription when it falls behind; a gap arrives as a `lagged` event.
+//!
+//! A WebSocket client may send the same filters as a JSON object at any time to replace them;
+//! `buffer` and `overflow` stay as they were at connect.
+//!
+//! Browser consoles may take the same stream over gRPC-Web instead (see [`crate::grpc_web`]).
+
+use crate::events::Event;
+use crate::hub::{Overflow, Pattern, Subscription, MAX_BUFFER};
+use crate::objects::Aabb;
+use crate::rbac::{Read, Require};
+use crate::webhooks::{EVENT_KINDS, STREAM_ONLY_KINDS};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Query, State},
+    http::StatusCode,
+    response::{sse::{self, KeepAlive, Sse}, Response},
+};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Default, Deserialize)]
+pub struct FilterSpec {
+    pub(crate) topics: Option<String>, pub(crate) buffer: Option<usize>, pub(crate) overflow: Option<String>, pub(crate) kinds: Option<String>,
+    pub(crate) device: Option<String>, pub(crate) r#type: Option<String>, pub(crate) bbox: Option<String>, pub(crate) min_change: Option<f64>,
+}
+
+#[derive(Default)]
+struct Filter { topics: Vec<Pattern>, buffer: Option<usize>, overflow: Overflow, kinds: Vec<String>, devices: Vec<String>, types: Vec<String>, bbox: Option<Aabb>, min_change: Option<f64> }
+
+fn list(v: Option<String>) -> Vec<String> {
+    v.map(|v| v.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect()).unwrap_or_default()
+}
+
+impl FilterSpec {
+    fn parse(self) -> Result<Filter, ApiError> {
+        let kinds = list(self.kinds);
+        if let Some(bad) = kinds.iter().find(|k| !STREAM_ONLY_KINDS.contains(&k.as_str()) && !EVENT_KINDS.contains(&k.as_str())) {
+            return Err(api_err(StatusCode::BAD_REQUEST, "Unknown event kind", Some(format!("{bad}; supported: {}, {}", STREAM_ONLY_KINDS.join(", "), EVENT_KINDS.join(", ")))));
+        }
+        let bbox = self.bbox.as_deref().map(Aabb::parse_query).transpose().map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid bbox", Some(e)))?;
+        if self.min_change.is_some_and(|t| t.is_nan() || t < 0.0) { return Err(api_err(StatusCode::BAD_REQUEST, "Invalid min_change", Some("must be a non-negative number".into()))); }
+        let mut topics = list(self.topics).iter().map(|t| Pattern::parse(t)).collect::<Result<Vec<_>, _>>().map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid topic pattern", Some(e)))?;
+        if topics.is_empty() { topics.push(Pattern::all()); }
+        if self.buffer.is_some_and(|b| b == 0 || b > MAX_BUFFER) { return Err(api_err(StatusCode::BAD_REQUEST, "Invalid buffer", Some(format!("must be 1-{MAX_BUFFER} events")))); }
+        let overflow = match self.overflow.as_deref() {
+            None => Overflow::default(),
+            Some(o) => Overflow::parse(o).ok_or_else(|| api_err(StatusCode::BAD_REQUEST, "Invalid overflow", Some(format!("{o}; supported: drop-oldest, drop-newest, disconnect"))))?,
+        };
+        Ok(Filter { topics, buffer: self.buffer, overflow, kinds, devices: list(self.device), types: list(self.r#type), bbox, min_change: self.min_change })
+    }
+}
+
+pub(crate) fn glob(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti, mut star, mut mark) = (0, 0, None, 0);
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) { pi += 1; ti += 1; }
+        else if pi < p.len() && p[pi] == '*' { star = Some(pi); mark = ti; pi += 1; }
+        else if let Some(sp) = star { pi = sp + 1; mark += 1; ti = mark; }
+        else { return false; }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+fn moved(prev: &Value, next: &Value, threshold: f64) -> bool {
+    match (prev, next) {
+        (Value::Number(a), Value::Number(b)) => (a.as_f64().unwrap_or(0.0) - b.as_f64().unwrap_or(0.0)).abs() >= threshold,
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() && a.iter().chain(b).all(Value::is_number) => a.iter().zip(b).any(|(x, y)| moved(x, y, threshold)),
+        _ => true,
+    }
+}
+
+/// A subscription's filter plus the last value it sent per (device, JSON pointer), for `min_change`.
+struct Subscriber { tenant: String, filter: Filter, last_sent: HashMap<(String, String), Value> }
+
+impl Subscriber {
+    fn admit(&mut self, s: &AppState, ev: &Event) -> Option<Event> {
+        let f = &self.filter;
+        if ev.tenant != self.tenant || (!f.kinds.is_empty() && !f.kinds.contains(&ev.kind)) { return None; }
+        let device = ev.data.get("device_id").and_then(Value::as_str);
+        if !f.devices.is_empty() && !device.is_some_and(|d| f.devices.iter().any(|g| glob(g, d))) { return None; }
+        if ev.kind != "delta" { return Some(ev.clone()); }
+        let device = device?.to_string();
+        let mut delta = ev.data.get("delta")?.clone();
+        if !f.types.is_empty() || f.bbox.is_some() { delta = self.objects_only(s, &device, &delta)?; }
+        if let Some(t) = f.min_change {
+            if !self.damp(&device, String::new(), &mut delta, t) { return None; }
+        }
+        let mut ev = ev.clone();
+        ev.data["delta"] = delta;
+        Some(ev)
+    }
+
+    fn objects_only(&self, s: &AppState, device: &str, delta: &Value) -> Option<Value> {
+        let shadows = s.shadows.lock().unwrap();
+        let sh = shadows.get(&(self.tenant.clone(), device.to_string()));
+        let merged = sh.and_then(|sh| sh.reported.get("objects")?.as_object());
+        let kept: Map<String, Value> = delta.get("objects")?.as_object()?.iter().filter(|(id, v)| {
+            let Some(obj) = merged.and_then(|m| m.get(*id)).or(Some(*v)).and_then(Value::as_object) else { return v.is_null() };
+            let typed = self.filter.types.is_empty() || obj.get("type").and_then(Value::as_str).is_some_and(|t| self.filter.types.iter().any(|x| x == t));
+            let inside = self.filter.bbox.as_ref().is_none_or(|b| match sh.and_then(|sh| sh.index.bounds(id)) {
+                Some(o) => b.intersects(o),
+                None => Aabb::of(obj).is_some_and(|o| b.intersects(&o)),
+            });
+            v.is_null() || (typed && inside)
+        }).map(|(id, v)| (id.clone(), v.clone())).collect();
+        (!kept.is_empty()).then(|| json!({ "objects": kept }))
+    }
+
+    /// Drops leaves that moved less than `threshold`; returns whether anything is left.
+    fn damp(&mut self, device: &str, path: String, v: &mut Value, threshold: f64) -> bool {
+        match v {
+            Value::Object(m) => {
+                m.retain(|k, x| self.damp(device, format!("{path}/{k}"), x, threshold));
+                !m.is_empty()
+            }
+            leaf => {
+                let key = (device.to_string(), path);
+                if self.last_sent.get(&key).is_some_and(|prev| !moved(prev, leaf, threshold)) { return false; }
+                self.last_sent.insert(key, leaf.clone());
+                true
+            }
+        }
+    }
+
+    fn subscribe(&self, s: &AppState, consumer: &'static str) -> Subscription {
+        s.hub.subscribe(Some(&self.tenant), consumer, &self.filter.topics, self.filter.overflow, self.filter.buffer)
+    }
+
+    /// Next admitted event; a lagging receiver is told how many events it missed.
+    async fn next(&mut self, s: &AppState, rx: &mut Subscription) -> Option<Value> {
+        loop {
+            match rx.recv().await {
+                Ok(ev) => if let Some(ev) = self.admit(s, &ev) { return serde_json::to_value(ev).ok() },
+                Err(RecvError::Lagged(n)) => return Some(json!({ "kind": "lagged", "skipped": n })),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// The tenant's admitted events under `spec`, until the hub drops the subscription.
+pub(crate) fn events(s: Arc<AppState>, tenant: String, spec: FilterSpec, consumer: &'static str) -> Result<impl Stream<Item = Value>, ApiError> {
+    let sub = Subscriber { tenant, filter: spec.parse()?, last_sent: HashMap::new() };
+    let rx = sub.subscribe(&s, consumer);
+    Ok(futures_util::stream::unfold((s, rx, sub), |(s, mut rx, mut sub)| async move {
+        let ev = sub.next(&s, &mut rx).await?;
+        Some((ev, (s, rx, sub)))
+    }))
+}
+
+pub async fn sse(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Query(spec): Query<FilterSpec>) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, ApiError> {
+    let stream = events(s, tenant, spec, "sse")?.map(|ev| {
+        let kind = ev["kind"].as_str().unwrap_or_default().to_string();
+        Ok(sse::Event::default().event(kind).data(ev.to_string()))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+pub async fn ws(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Query(spec): Query<FilterSpec>, upgrade: WebSocketUpgrade) -> Result<Response, ApiError> {
+    let sub = Subscriber { tenant, filter: spec.parse()?, last_sent: HashMap::new() };
+    Ok(upgrade.on_upgrade(move |socket| pump(s, socket, sub)))
+}
+
+async fn pump(s: Arc<AppState>, mut socket: WebSocket, mut sub: Subscriber) {
+    let mut rx = sub.subscribe(&s, "ws");
+    loop {
+        tokio::select! {
+            ev = sub.next(&s, &mut rx) => match ev {
+                Some(ev) => if socket.send(Message::Text(ev.to_string())).await.is_err() { break },
+                None => { let _ = socket.send(Message::Close(None)).await; break }
+            },
+            inc = socket.recv() => match inc {
+                Some(Ok(Message::Text(t))) => {
+                    let reply = match serde_json::from_str::<FilterSpec>(&t).map_err(|e| api_err(StatusCode::BAD_REQUEST, "Malformed filter", Some(e.to_string()))).and_then(FilterSpec::parse) {
+                        Ok(filter) => {
+                            rx.set_patterns(&filter.topics);
+                            sub.filter = Filter { buffer: sub.filter.buffer, overflow: sub.filter.overflow, ..filter };
+                            sub.last_sent.clear();
+                            json!({ "kind": "filter-updated" })
+                        }
+                        Err(e) => json!({ "kind": "filter-rejected", "error": e.body.error, "details": e.body.details }),
+                    };
+                    if socket.send(Message::Text(reply.to_string())).await.is_err() { break }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            },
+        }
+    }
+}
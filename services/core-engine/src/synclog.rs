@@ -0,0 +1,77 @@
+//! Per-sync history for analytics: one record per sync attempt (accepted or rejected) in a
+//! ring buffer of `SYNC_HISTORY_CAPACITY` records (default 100 000) shared by all tenants.
+//! `GET /syncs/export?from=&to=` (Unix ms, inclusive) streams the tenant's records as NDJSON,
+//! oldest first; send `Accept-Encoding: gzip` for a compressed stream.
+
+use crate::events::now_ms;
+use crate::rbac::{Read, Require};
+use crate::{ApiError, AppState, SyncResponse, Tenant};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const LINES_PER_CHUNK: usize = 500;
+
+#[derive(Clone, Serialize)]
+pub struct SyncRecord {
+    timestamp_ms: u64, #[serde(skip_serializing_if = "Option::is_none")] sync_id: Option<String>, tenant: String, connection_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")] device_id: Option<String>, bytes: u64, objects: u32, latency_ms: f64,
+    /// `synced`, `relayed`, `rejected` (4xx) or `failed` (5xx).
+    outcome: &'static str, status: u16, #[serde(skip_serializing_if = "Option::is_none")] error: Option<String>,
+}
+
+pub struct SyncLog { capacity: usize, records: Mutex<VecDeque<SyncRecord>> }
+
+#[derive(Deserialize)]
+pub struct ExportQuery { from: Option<u64>, to: Option<u64> }
+
+impl SyncLog {
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("SYNC_HISTORY_CAPACITY").ok().and_then(|v| v.parse().ok()).unwrap_or(100_000);
+        SyncLog { capacity, records: Mutex::new(VecDeque::new()) }
+    }
+
+    pub fn record(&self, tenant: &str, connection_id: &str, device_id: Option<String>, bytes: u64, latency: Duration, result: &Result<SyncResponse, ApiError>) {
+        if self.capacity == 0 { return; }
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let (sync_id, objects, outcome, status, error) = match result {
+            Ok(r) => (Some(r.sync_id.clone()), r.objects_synced, if r.status == "relayed" { "relayed" } else { "synced" }, 200, None),
+            Err(e) => (None, 0, if e.status.is_server_error() { "failed" } else { "rejected" }, e.status.as_u16(), Some(e.body.code.map(String::from).unwrap_or_else(|| e.body.error.clone()))),
+        };
+        let rec = SyncRecord { timestamp_ms: now_ms(), sync_id, tenant: tenant.into(), connection_id: connection_id.into(), device_id, bytes, objects, latency_ms, outcome, status, error };
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity { records.pop_front(); }
+        records.push_back(rec);
+    }
+
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut records = self.records.lock().unwrap();
+        let n = records.len();
+        records.retain(|r| r.tenant != tenant);
+        n - records.len()
+    }
+}
+
+pub async fn export(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Query(q): Query<ExportQuery>) -> Response {
+    let (from, to) = (q.from.unwrap_or(0), q.to.unwrap_or(u64::MAX));
+    let records: Vec<SyncRecord> = s.sync_log.records.lock().unwrap().iter().filter(|r| r.tenant == tenant && (from..=to).contains(&r.timestamp_ms)).cloned().collect();
+    let body = futures_util::stream::iter(records).chunks(LINES_PER_CHUNK).map(|chunk| {
+        let mut buf = Vec::new();
+        for r in chunk {
+            let _ = serde_json::to_writer(&mut buf, &r);
+            buf.push(b'\n');
+        }
+        Ok::<_, Infallible>(Bytes::from(buf))
+    });
+    let filename = format!("attachment; filename=\"syncs-{from}-{}.ndjson\"", q.to.unwrap_or_else(now_ms));
+    ([(header::CONTENT_TYPE, "application/x-ndjson".to_string()), (header::CONTENT_DISPOSITION, filename)], Body::from_stream(body)).into_response()
+}
@@ -0,0 +1,248 @@
+//! High-frequency telemetry ingestion behind a pluggable time-series backend.
+//!
+//! `TELEMETRY_BACKEND` selects the store: `memory` (default, bounded ring buffer),
+//! `clickhouse` (HTTP interface at `CLICKHOUSE_URL`) or `timescale` (`TIMESCALE_URL`,
+//! requires the `timescale` feature). Schemas live in `database/migrations/007_telemetry.sql`
+//! and in the ClickHouse DDL below. The `timescale` backend buckets with `date_bin`, so it
+//! also runs on plain PostgreSQL 14+ where the extension is not installed.
+
+use crate::events::now_ms;
+use crate::rbac::{Operate, Read, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use async_trait::async_trait;
+use axum::{extract::{Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+const MEMORY_CAPACITY: usize = 1_000_000;
+const MAX_BUCKETS: u64 = 10_000;
+/// Widest rollup bucket, a year.
+const MAX_BUCKET_SECS: u64 = 366 * 86_400;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Point { pub device_id: String, pub metric: String, pub value: f64, pub timestamp_ms: Option<u64> }
+
+#[derive(Deserialize)]
+pub struct RollupQuery { pub metric: String, pub device_id: Option<String>, pub from: Option<u64>, pub to: Option<u64>, pub bucket_secs: Option<u64> }
+
+#[derive(Serialize, Deserialize)]
+pub struct Bucket { pub bucket_start_ms: u64, pub count: u64, pub min: f64, pub max: f64, pub avg: f64 }
+
+#[derive(Serialize)]
+pub struct IngestResponse { accepted: usize, backend: &'static str }
+
+/// Resolved rollup window handed to the backend.
+pub struct Window<'a> { pub tenant: &'a str, pub metric: &'a str, pub device_id: Option<&'a str>, pub from_ms: u64, pub to_ms: u64, pub bucket_ms: u64 }
+
+#[async_trait]
+pub trait TelemetryStore: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn write(&self, tenant: &str, points: &[Point]) -> Result<(), String>;
+    async fn rollup(&self, w: &Window<'_>) -> Result<Vec<Bucket>, String>;
+    /// Deletes all of the tenant's points; returns how many went.
+    async fn remove_tenant(&self, tenant: &str) -> Result<u64, String>;
+    /// Readiness probe; stores without an external dependency are always up.
+    async fn ping(&self) -> Result<(), String> { Ok(()) }
+}
+
+pub async fn from_env() -> Arc<dyn TelemetryStore> {
+    let backend = std::env::var("TELEMETRY_BACKEND").unwrap_or_else(|_| "memory".into());
+    match backend.as_str() {
+        "clickhouse" => Arc::new(ClickHouseStore { url: std::env::var("CLICKHOUSE_URL").unwrap_or_else(|_| "http://clickhouse:8123".into()), http: reqwest::Client::new() }),
+        #[cfg(feature = "timescale")]
+        "timescale" => Arc::new(timescale::TimescaleStore::connect(&std::env::var("TIMESCALE_URL").expect("TIMESCALE_URL must be set")).await.expect("timescale connect")),
+        other => {
+            if other != "memory" { tracing::warn!("Unknown TELEMETRY_BACKEND {other:?}, falling back to memory"); }
+            Arc::new(MemoryStore::default())
+        }
+    }
+}
+
+impl Validate for Vec<Point> {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check(!self.is_empty() && self.len() <= 10_000, "points", "must contain 1-10000 points");
+        for (i, p) in self.iter().enumerate() {
+            v.id(format!("[{i}].device_id"), &p.device_id);
+            v.id(format!("[{i}].metric"), &p.metric);
+            v.check(p.value.is_finite(), format!("[{i}].value"), "must be a finite number");
+        }
+    }
+}
+
+pub async fn ingest(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Valid(mut points): Valid<Vec<Point>>) -> Result<(StatusCode, Json<IngestResponse>), ApiError> {
+    let now = now_ms();
+    for p in points.iter_mut() { p.timestamp_ms.get_or_insert(now); }
+    s.telemetry.write(&tenant, &points).await.map_err(|e| api_err(StatusCode::SERVICE_UNAVAILABLE, "Telemetry backend unavailable", Some(e)))?;
+    Ok((StatusCode::ACCEPTED, Json(IngestResponse { accepted: points.len(), backend: s.telemetry.name() })))
+}
+
+pub async fn rollup(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Query(q): Query<RollupQuery>) -> Result<Json<Vec<Bucket>>, ApiError> {
+    let to_ms = q.to.unwrap_or_else(now_ms);
+    let from_ms = q.from.unwrap_or(to_ms.saturating_sub(3_600_000));
+    let bucket_secs = q.bucket_secs.unwrap_or(60);
+    if !(1..=MAX_BUCKET_SECS).contains(&bucket_secs) || from_ms >= to_ms {
+        return Err(api_err(StatusCode::BAD_REQUEST, "Invalid rollup window", Some(format!("require from < to and bucket_secs between 1 and {MAX_BUCKET_SECS}"))));
+    }
+    let bucket_ms = bucket_secs * 1000;
+    if (to_ms - from_ms) / bucket_ms > MAX_BUCKETS { return Err(api_err(StatusCode::BAD_REQUEST, "Too many buckets", Some(format!("at most {MAX_BUCKETS} buckets per query")))); }
+    let w = Window { tenant: &tenant, metric: &q.metric, device_id: q.device_id.as_deref(), from_ms, to_ms, bucket_ms };
+    s.telemetry.rollup(&w).await.map(Json).map_err(|e| api_err(StatusCode::SERVICE_UNAVAILABLE, "Telemetry backend unavailable", Some(e)))
+}
+
+/// Bounded in-process store; the oldest points are evicted past `MEMORY_CAPACITY`. Points are
+/// indexed by (tenant, metric) and then by time, so a rollup only visits its own window.
+#[derive(Default)]
+pub struct MemoryStore { inner: Mutex<MemorySeries> }
+
+/// A point's place in its series: timestamp, then arrival number to keep equal timestamps apart.
+type SeriesKey = (u64, u64);
+
+#[derive(Default)]
+struct MemorySeries {
+    series: HashMap<(String, String), BTreeMap<SeriesKey, (String, f64)>>,
+    /// Arrival order, for eviction.
+    arrivals: VecDeque<((String, String), SeriesKey)>,
+    next: u64,
+}
+
+#[async_trait]
+impl TelemetryStore for MemoryStore {
+    fn name(&self) -> &'static str { "memory" }
+    async fn write(&self, tenant: &str, points: &[Point]) -> Result<(), String> {
+        let mut m = self.inner.lock().unwrap();
+        for p in points {
+            if m.arrivals.len() == MEMORY_CAPACITY {
+                let (series, key) = m.arrivals.pop_front().expect("capacity is non-zero");
+                if let Some(s) = m.series.get_mut(&series) {
+                    s.remove(&key);
+                    if s.is_empty() { m.series.remove(&series); }
+                }
+            }
+            let series = (tenant.to_string(), p.metric.clone());
+            let key = (p.timestamp_ms.unwrap_or(0), m.next);
+            m.next += 1;
+            m.series.entry(series.clone()).or_default().insert(key, (p.device_id.clone(), p.value));
+            m.arrivals.push_back((series, key));
+        }
+        Ok(())
+    }
+    async fn rollup(&self, w: &Window<'_>) -> Result<Vec<Bucket>, String> {
+        let mut agg: BTreeMap<u64, Bucket> = BTreeMap::new();
+        let m = self.inner.lock().unwrap();
+        let Some(series) = m.series.get(&(w.tenant.to_string(), w.metric.to_string())) else { return Ok(Vec::new()) };
+        for (&(ts, _), &(ref device_id, value)) in series.range((w.from_ms, 0)..(w.to_ms, 0)) {
+            if w.device_id.is_some_and(|d| d != device_id) { continue; }
+            let start = ts / w.bucket_ms * w.bucket_ms;
+            let b = agg.entry(start).or_insert(Bucket { bucket_start_ms: start, count: 0, min: f64::MAX, max: f64::MIN, avg: 0.0 });
+            b.count += 1; b.min = b.min.min(value); b.max = b.max.max(value); b.avg += value;
+        }
+        Ok(agg.into_values().map(|mut b| { b.avg /= b.count as f64; b }).collect())
+    }
+    async fn remove_tenant(&self, tenant: &str) -> Result<u64, String> {
+        let mut m = self.inner.lock().unwrap();
+        let n = m.series.iter().filter(|((t, _), _)| t == tenant).map(|(_, s)| s.len() as u64).sum();
+        m.series.retain(|(t, _), _| t != tenant);
+        m.arrivals.retain(|((t, _), _)| t != tenant);
+        Ok(n)
+    }
+}
+
+/// ClickHouse over its HTTP interface. Expected table:
+///
+/// ```sql
+/// CREATE TABLE telemetry (ts_ms Int64, tenant String, device_id String, metric LowCardinality(String), value Float64)
+/// ENGINE = MergeTree ORDER BY (tenant, metric, ts_ms);
+/// ```
+pub struct ClickHouseStore { url: String, http: reqwest::Client }
+
+#[derive(Serialize)]
+struct ClickHouseRow<'a> { ts_ms: u64, tenant: &'a str, device_id: &'a str, metric: &'a str, value: f64 }
+
+#[async_trait]
+impl TelemetryStore for ClickHouseStore {
+    fn name(&self) -> &'static str { "clickhouse" }
+    async fn ping(&self) -> Result<(), String> {
+        let r = self.http.get(&self.url).query(&[("query", "SELECT 1")]).send().await.map_err(|e| e.to_string())?;
+        if r.status().is_success() { Ok(()) } else { Err(format!("HTTP {}", r.status())) }
+    }
+    async fn write(&self, tenant: &str, points: &[Point]) -> Result<(), String> {
+        let mut body = String::new();
+        for p in points {
+            let row = ClickHouseRow { ts_ms: p.timestamp_ms.unwrap_or(0), tenant, device_id: &p.device_id, metric: &p.metric, value: p.value };
+            body.push_str(&serde_json::to_string(&row).map_err(|e| e.to_string())?);
+            body.push('\n');
+        }
+        let r = self.http.post(&self.url).query(&[("query", "INSERT INTO telemetry FORMAT JSONEachRow")]).body(body).send().await.map_err(|e| e.to_string())?;
+        if r.status().is_success() { Ok(()) } else { Err(r.text().await.unwrap_or_default()) }
+    }
+    async fn rollup(&self, w: &Window<'_>) -> Result<Vec<Bucket>, String> {
+        let sql = "SELECT intDiv(ts_ms, {bucket:Int64}) * {bucket:Int64} AS bucket_start_ms, count() AS count, min(value) AS min, max(value) AS max, avg(value) AS avg \
+                   FROM telemetry WHERE tenant = {tenant:String} AND metric = {metric:String} AND ts_ms >= {from:Int64} AND ts_ms < {to:Int64} \
+                   AND ({device:String} = '' OR device_id = {device:String}) GROUP BY bucket_start_ms ORDER BY bucket_start_ms FORMAT JSONEachRow";
+        let params = [
+            ("param_bucket", w.bucket_ms.to_string()), ("param_tenant", w.tenant.to_string()), ("param_metric", w.metric.to_string()),
+            ("param_from", w.from_ms.to_string()), ("param_to", w.to_ms.to_string()), ("param_device", w.device_id.unwrap_or_default().to_string()),
+            ("output_format_json_quote_64bit_integers", "0".to_string()),
+        ];
+        let r = self.http.post(&self.url).query(&params).body(sql).send().await.map_err(|e| e.to_string())?;
+        if !r.status().is_success() { return Err(r.text().await.unwrap_or_default()); }
+        let text = r.text().await.map_err(|e| e.to_string())?;
+        text.lines().filter(|l| !l.is_empty()).map(|l| serde_json::from_str(l).map_err(|e| e.to_string())).collect()
+    }
+    async fn remove_tenant(&self, tenant: &str) -> Result<u64, String> {
+        let run = |sql: &'static str| self.http.post(&self.url).query(&[("param_tenant", tenant)]).body(sql).send();
+        let r = run("SELECT count() FROM telemetry WHERE tenant = {tenant:String} FORMAT TabSeparated").await.map_err(|e| e.to_string())?;
+        if !r.status().is_success() { return Err(r.text().await.unwrap_or_default()); }
+        let n = r.text().await.map_err(|e| e.to_string())?.trim().parse().map_err(|e| format!("count: {e}"))?;
+        let r = run("DELETE FROM telemetry WHERE tenant = {tenant:String}").await.map_err(|e| e.to_string())?;
+        if r.status().is_success() { Ok(n) } else { Err(r.text().await.unwrap_or_default()) }
+    }
+}
+
+#[cfg(feature = "timescale")]
+mod timescale {
+    use super::{Bucket, Point, TelemetryStore, Window};
+    use async_trait::async_trait;
+    use tokio_postgres::{Client, NoTls};
+
+    pub struct TimescaleStore { client: Client }
+
+    impl TimescaleStore {
+        pub async fn connect(url: &str) -> Result<Self, tokio_postgres::Error> {
+            let (client, conn) = tokio_postgres::connect(url, NoTls).await?;
+            tokio::spawn(async move { if let Err(e) = conn.await { tracing::error!("timescale connection error: {e}"); } });
+            Ok(Self { client })
+        }
+    }
+
+    #[async_trait]
+    impl TelemetryStore for TimescaleStore {
+        fn name(&self) -> &'static str { "timescale" }
+        async fn ping(&self) -> Result<(), String> { self.client.simple_query("SELECT 1").await.map(drop).map_err(|e| e.to_string()) }
+        async fn write(&self, tenant: &str, points: &[Point]) -> Result<(), String> {
+            let ts: Vec<f64> = points.iter().map(|p| p.timestamp_ms.unwrap_or(0) as f64 / 1000.0).collect();
+            let devices: Vec<&str> = points.iter().map(|p| p.device_id.as_str()).collect();
+            let metrics: Vec<&str> = points.iter().map(|p| p.metric.as_str()).collect();
+            let values: Vec<f64> = points.iter().map(|p| p.value).collect();
+            self.client.execute(
+                "INSERT INTO telemetry_points (time, tenant, device_id, metric, value) \
+                 SELECT to_timestamp(t), $1, d, m, v FROM UNNEST($2::float8[], $3::text[], $4::text[], $5::float8[]) AS u(t, d, m, v)",
+                &[&tenant, &ts, &devices, &metrics, &values],
+            ).await.map(|_| ()).map_err(|e| e.to_string())
+        }
+        async fn rollup(&self, w: &Window<'_>) -> Result<Vec<Bucket>, String> {
+            let rows = self.client.query(
+                "SELECT (extract(epoch FROM date_bin(make_interval(secs => $2), time, 'epoch'::timestamptz)) * 1000)::int8 AS b, count(*)::int8, min(value), max(value), avg(value) \
+                 FROM telemetry_points WHERE tenant = $1 AND metric = $3 AND time >= to_timestamp($4) AND time < to_timestamp($5) \
+                 AND ($6::text IS NULL OR device_id = $6) GROUP BY b ORDER BY b",
+                &[&w.tenant, &(w.bucket_ms as f64 / 1000.0), &w.metric, &(w.from_ms as f64 / 1000.0), &(w.to_ms as f64 / 1000.0), &w.device_id],
+            ).await.map_err(|e| e.to_string())?;
+            Ok(rows.iter().map(|r| Bucket { bucket_start_ms: r.get::<_, i64>(0) as u64, count: r.get::<_, i64>(1) as u64, min: r.get(2), max: r.get(3), avg: r.get(4) }).collect())
+        }
+        async fn remove_tenant(&self, tenant: &str) -> Result<u64, String> {
+            self.client.execute("DELETE FROM telemetry_points WHERE tenant = $1", &[&tenant]).await.map_err(|e| e.to_string())
+        }
+    }
+}
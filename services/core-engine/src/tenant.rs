@@ -0,0 +1,21 @@
+//! Tenant identity. The api-gateway authenticates the caller and forwards the tenant
+//! as `X-Tenant-Id` (honoured only from a trusted hop, see `rbac::gateway_hop_mw`); requests
+//! reaching the engine directly fall back to `default`.
+
+use axum::{async_trait, extract::FromRequestParts, http::{request::Parts, HeaderMap}};
+use std::convert::Infallible;
+
+pub struct Tenant(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tenant {
+    type Rejection = Infallible;
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Tenant(of(&parts.headers).to_string()))
+    }
+}
+
+/// The tenant a request is for, for code running before extractors.
+pub fn of(headers: &HeaderMap) -> &str {
+    headers.get("x-tenant-id").and_then(|h| h.to_str().ok()).filter(|t| !t.is_empty()).unwrap_or("default")
+}
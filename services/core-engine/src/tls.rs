@@ -0,0 +1,109 @@
+//! Optional rustls TLS termination. `TLS_CERT_PATH`/`TLS_KEY_PATH` enable TLS; adding
+//! `TLS_CLIENT_CA_PATH` switches to mTLS, where every client must present a certificate
+//! signed by that CA and its subject CN (or, lacking one, its first DNS or URI subject
+//! alternative name) is the only device_id it may act as; a certificate naming neither is
+//! refused at the handshake. Connections offer
+//! `h2` and `http/1.1` over ALPN, so browsers stream events over HTTP/2. The internal and device
+//! listeners read the same settings under their own prefix (`INTERNAL_TLS_*`, `DEVICE_TLS_*`).
+
+use crate::{api_err, ApiError};
+use axum::{extract::Request, http::StatusCode, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{pki_types::{CertificateDer, PrivateKeyDer}, server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+use tower::ServiceExt;
+
+pub struct TlsConfig { pub cert_path: String, pub key_path: String, pub client_ca_path: Option<String> }
+
+/// Identity of an mTLS-authenticated peer, attached to every request on that connection.
+#[derive(Clone)]
+pub struct PeerIdentity { pub common_name: String }
+
+impl PeerIdentity {
+    pub fn authorize(&self, device_id: &str) -> Result<(), ApiError> {
+        if self.common_name == device_id { return Ok(()); }
+        Err(api_err(StatusCode::FORBIDDEN, "Device identity mismatch", Some(format!("client certificate is issued to {}", self.common_name))))
+    }
+}
+
+/// Reads `{prefix}TLS_CERT_PATH`, `{prefix}TLS_KEY_PATH` and `{prefix}TLS_CLIENT_CA_PATH`.
+pub fn config_from_env(prefix: &str) -> Option<TlsConfig> {
+    let var = |name: &str| std::env::var(format!("{prefix}{name}")).ok().filter(|v| !v.is_empty());
+    Some(TlsConfig { cert_path: var("TLS_CERT_PATH")?, key_path: var("TLS_KEY_PATH")?, client_ca_path: var("TLS_CLIENT_CA_PATH") })
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let f = std::fs::File::open(path).map_err(|e| format!("{path}: {e}"))?;
+    rustls_pemfile::certs(&mut BufReader::new(f)).collect::<Result<_, _>>().map_err(|e| format!("{path}: {e}"))
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let f = std::fs::File::open(path).map_err(|e| format!("{path}: {e}"))?;
+    rustls_pemfile::private_key(&mut BufReader::new(f)).map_err(|e| format!("{path}: {e}"))?.ok_or_else(|| format!("{path}: no private key found"))
+}
+
+pub fn server_config(cfg: &TlsConfig) -> Result<ServerConfig, String> {
+    let builder = ServerConfig::builder();
+    let builder = match &cfg.client_ca_path {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for c in load_certs(ca)? { roots.add(c).map_err(|e| format!("{ca}: {e}"))?; }
+            builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(|e| e.to_string())?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut sc = builder.with_single_cert(load_certs(&cfg.cert_path)?, load_key(&cfg.key_path)?).map_err(|e| e.to_string())?;
+    sc.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(sc)
+}
+
+/// The device_id a client certificate is issued to: its subject CN, else its first DNS or URI
+/// subject alternative name.
+pub fn device_identity(der: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der.as_ref()).ok()?;
+    let cn = cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()).filter(|cn| !cn.trim().is_empty());
+    let san = || cert.subject_alternative_name().ok().flatten()?.value.general_names.iter().find_map(|n| match n {
+        GeneralName::DNSName(s) | GeneralName::URI(s) if !s.trim().is_empty() => Some(*s),
+        _ => None,
+    });
+    cn.or_else(san).map(str::to_string)
+}
+
+/// The identity of a connection's verified client chain; no chain means no mTLS. A chain whose
+/// leaf names no device is an error, never an anonymous peer.
+pub fn peer_identity(chain: Option<&[CertificateDer<'_>]>) -> Result<Option<PeerIdentity>, String> {
+    let Some(leaf) = chain.and_then(|c| c.first()) else { return Ok(None) };
+    device_identity(leaf).map(|common_name| Some(PeerIdentity { common_name })).ok_or_else(|| "client certificate has no subject CN or DNS/URI SAN".into())
+}
+
+pub async fn serve(listener: TcpListener, app: Router, cfg: &TlsConfig) {
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(cfg).expect("invalid TLS configuration")));
+    loop {
+        let (tcp, addr) = match listener.accept().await {
+            Ok(c) => c,
+            Err(e) => { tracing::warn!("accept failed: {e}"); continue; }
+        };
+        let (acceptor, app) = (acceptor.clone(), app.clone());
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(tcp).await {
+                Ok(s) => s,
+                Err(e) => { tracing::debug!(%addr, "TLS handshake failed: {e}"); return; }
+            };
+            let peer = match peer_identity(stream.get_ref().1.peer_certificates()) {
+                Ok(p) => p,
+                Err(e) => { tracing::warn!(%addr, "client certificate refused: {e}"); return; }
+            };
+            let svc = hyper::service::service_fn(move |mut req: Request<hyper::body::Incoming>| {
+                if let Some(p) = &peer { req.extensions_mut().insert(p.clone()); }
+                app.clone().oneshot(req)
+            });
+            if let Err(e) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), svc).await {
+                tracing::debug!(%addr, "connection closed: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,145 @@
+//! Transactional multi-connection sync: `POST /api/v1/gateway/sync/transaction` applies the
+//! deltas of several connections all together or not at all, in two phases. Prepare runs every
+//! check of the plain sync path (connection, migration, replay, decoding, quota) for every sync
+//! and claims their sequence numbers without touching any state; if one fails, the claimed
+//! sequences are released and nothing is applied. Commit then folds all deltas into the shadows
+//! under one lock, so readers never see half a transaction, followed by the per-sync effects
+//! (routing, snapshots, CoAP, `delta` events carrying the `transaction_id`, stats, usage).
+//! Connections relayed to their home region cannot take part, since the remote apply could not
+//! be rolled back. Encrypted envelopes commit like plain deltas, minus the shadow update.
+
+use crate::rbac::{Operate, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::pressure::Priority;
+use crate::{api_err, envelope, protocols, replay, routing, shadow, tls, usage, ApiError, AppState, Tenant};
+use alice_gateway_types::{Envelope, ErrorBody, SyncRequest, SyncResponse, SyncTransactionRequest, SyncTransactionResponse, TransactionResult, MAX_TRANSACTION};
+use axum::{extract::{Extension, State}, http::StatusCode, response::Json};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+
+struct Prepared<'a> { connection_id: String, device_id: String, protocol: String, sequence: Option<u64>, claim: replay::Claim<'a>, delta: Option<Value>, envelope: Option<Envelope>, bytes: u64 }
+
+impl Validate for SyncTransactionRequest {
+    fn validate(&self, _: &AppState, v: &mut Violations) {
+        v.check((1..=MAX_TRANSACTION).contains(&self.syncs.len()), "syncs", format!("must hold 1 to {MAX_TRANSACTION} syncs"));
+        let mut seen = HashSet::new();
+        for (i, sync) in self.syncs.iter().enumerate() {
+            v.check(seen.insert(&sync.connection_id), format!("syncs[{i}].connection_id"), "each connection may appear once per transaction");
+            if let Some(p) = &sync.priority { v.one_of(format!("syncs[{i}].priority"), p, crate::validate::PRIORITIES); }
+            if let Some(e) = &sync.envelope {
+                v.check(sync.sdf_delta.is_none(), format!("syncs[{i}].sdf_delta"), "must not be set together with envelope");
+                envelope::validate(e, &format!("syncs[{i}].envelope"), v);
+            }
+        }
+    }
+}
+
+fn error_body(e: &ApiError) -> ErrorBody {
+    ErrorBody { error: e.body.error.clone(), code: e.body.code.map(Into::into), details: e.body.details.clone(), violations: Vec::new(), suggested_interval_ms: e.body.hints.as_ref().and_then(|h| h.suggested_interval_ms), suggested_region: e.body.hints.as_ref().and_then(|h| h.suggested_region.clone()) }
+}
+
+/// Phase one for a single sync: everything that can fail, with the sequence as the only claim.
+async fn prepare<'a>(s: &'a AppState, tenant: &str, peer: Option<&tls::PeerIdentity>, req: &SyncRequest) -> Result<Prepared<'a>, ApiError> {
+    let conn = s.lookup_connection(&req.connection_id).await.filter(|c| c.tenant == tenant).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(req.connection_id.clone())))?;
+    if let Some(peer) = peer { peer.authorize(&conn.device_id)?; }
+    if conn.upstream.is_some() {
+        return Err(api_err(StatusCode::CONFLICT, "Relayed connections cannot join a transaction", Some("sync this connection on its own".into())).code("relayed"));
+    }
+    if s.migrating.lock().unwrap().contains(&req.connection_id) {
+        return Err(api_err(StatusCode::CONFLICT, "Connection is migrating", Some("reconnect to the endpoint returned by the migration".into())).code("migrating").retry_after(1));
+    }
+    envelope::check(s, tenant, req)?;
+    if let Some(d) = &req.sdf_delta { s.schemas.enforce(tenant, &conn.protocol, req.schema_version, d, "sdf_delta")?; }
+    let delta = match &req.sdf_delta {
+        Some(d) => Some(s.protocols.get(&conn.protocol)?.decode(d).map_err(|e| protocols::invalid(&conn.protocol, e))?),
+        None => None,
+    };
+    let claim = replay::check(s, req)?;
+    let bytes = match (&req.sdf_delta, &req.envelope) {
+        (Some(d), _) => d.to_string().len() as u64,
+        (None, Some(e)) => serde_json::to_string(e).map_or(0, |e| e.len() as u64),
+        (None, None) => 0,
+    };
+    Ok(Prepared { connection_id: req.connection_id.clone(), device_id: conn.device_id, protocol: conn.protocol, sequence: req.sequence, claim, delta, envelope: req.envelope.clone(), bytes })
+}
+
+pub async fn sync_transaction(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, peer: Option<Extension<tls::PeerIdentity>>, Valid(req): Valid<SyncTransactionRequest>) -> Json<SyncTransactionResponse> {
+    let started = Instant::now();
+    let transaction_id = uuid::Uuid::new_v4().to_string();
+    let peer = peer.as_ref().map(|Extension(p)| p);
+
+    // Phase one: hold a back-pressure slot for every connection and prepare every sync. Slots are
+    // taken without waiting in a lane, since holding some while queueing for others could
+    // deadlock two transactions.
+    let mut permits = Vec::with_capacity(req.syncs.len());
+    let mut outcomes = Vec::with_capacity(req.syncs.len());
+    for sync in &req.syncs {
+        let outcome = match s.pressure.try_admit(&sync.connection_id, Priority::parse(sync.priority.as_deref())) {
+            Ok(p) => { permits.push(p); prepare(&s, &tenant, peer, sync).await }
+            Err(e) => Err(e),
+        };
+        outcomes.push(outcome);
+    }
+    let mut billed = None;
+    if outcomes.iter().all(Result::is_ok) {
+        let total = outcomes.iter().flatten().map(|p| p.bytes).sum();
+        match usage::reserve(&s, &tenant, req.syncs.len() as u64, total) {
+            Ok(r) => billed = Some(r),
+            Err(e) => outcomes[0] = Err(e),
+        }
+    }
+
+    if outcomes.iter().any(Result::is_err) {
+        let mut results = Vec::with_capacity(outcomes.len());
+        for (sync, outcome) in req.syncs.iter().zip(outcomes) {
+            let (status, err) = match outcome {
+                Ok(_) => {
+                    // Dropping the prepared sync gives its sequence back.
+                    ("aborted", api_err(StatusCode::CONFLICT, "Transaction rolled back", Some("another sync in the transaction failed".into())).code("transaction_aborted"))
+                }
+                Err(e) => {
+                    s.emit("sync-failure", &tenant, json!({ "connection_id": sync.connection_id, "reason": e.body.code.unwrap_or("transaction"), "transaction_id": transaction_id }));
+                    ("failed", e)
+                }
+            };
+            results.push(TransactionResult { connection_id: sync.connection_id.clone(), status: status.into(), sync_id: None, objects_synced: 0, error: Some(error_body(&err)) });
+            let device_id = s.connections.lock().unwrap().get(&sync.connection_id).map(|c| c.device_id.clone());
+            s.sync_log.record(&tenant, &sync.connection_id, device_id, 0, started.elapsed(), &Err(err));
+        }
+        tracing::info!(transaction = %transaction_id, syncs = results.len(), "sync transaction rolled back");
+        return Json(SyncTransactionResponse { transaction_id, status: "rolled_back".into(), results, latency_ms: started.elapsed().as_secs_f64() * 1000.0 });
+    }
+
+    // Phase two: nothing below can fail.
+    let mut prepared: Vec<Prepared> = outcomes.into_iter().flatten().collect();
+    for p in &mut prepared {
+        p.delta = p.delta.take().map(|d| routing::apply(&s, &tenant, &p.connection_id, &p.device_id, d));
+    }
+    let reported: Vec<(&str, &Value)> = prepared.iter().filter_map(|p| Some((p.device_id.as_str(), p.delta.as_ref()?))).collect();
+    shadow::apply_reported_all(&s, &tenant, &reported);
+    let mut results = Vec::with_capacity(prepared.len());
+    let total_bytes: u64 = prepared.iter().map(|p| p.bytes).sum();
+    for p in prepared {
+        if let Some(delta) = &p.delta {
+            s.snapshots.append(&tenant, &p.connection_id, &p.device_id, p.sequence, delta);
+            s.coap.publish(&tenant, &p.device_id, delta);
+            s.emit("delta", &tenant, json!({ "connection_id": p.connection_id, "device_id": p.device_id, "sequence": p.sequence, "delta": delta, "transaction_id": transaction_id }));
+        }
+        let sealed = p.envelope.as_ref().is_some_and(|e| envelope::commit(&s, &tenant, &p.connection_id, &p.device_id, p.sequence, e, envelope::Origin::Transaction(&transaction_id)));
+        s.protocols.observe(&p.protocol, started.elapsed(), p.bytes);
+        let objects_synced = p.delta.as_ref().map_or(u32::from(sealed), |d| d.get("objects").and_then(|o| o.as_object()).or(d.as_object()).map_or(1, |o| o.len()) as u32);
+        let resp = SyncResponse { sync_id: uuid::Uuid::new_v4().to_string(), status: "synced".into(), objects_synced, sdf_bytes_transferred: p.bytes, latency_ms: started.elapsed().as_secs_f64() * 1000.0, delivery_id: None };
+        results.push(TransactionResult { connection_id: p.connection_id.clone(), status: "committed".into(), sync_id: Some(resp.sync_id.clone()), objects_synced, error: None });
+        s.sync_log.record(&tenant, &p.connection_id, Some(p.device_id), p.bytes, started.elapsed(), &Ok(resp));
+        p.claim.commit();
+    }
+    if let Some(b) = billed { b.commit(); }
+    let n = results.len() as u64;
+    s.stats.lock().unwrap().add(&tenant, [0, n, 0, total_bytes]);
+    s.shared.incr(&[("total_syncs", n), ("bytes_relayed", total_bytes)]).await;
+    drop(permits);
+    for sync in &req.syncs { s.pressure.observe(Priority::parse(sync.priority.as_deref()), started.elapsed()); }
+    Json(SyncTransactionResponse { transaction_id, status: "committed".into(), results, latency_ms: started.elapsed().as_secs_f64() * 1000.0 })
+}
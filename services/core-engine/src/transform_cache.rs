@@ -0,0 +1,55 @@
+//! LRU cache of successful transform outputs, keyed by the routed pipeline and a SHA-256 of the
+//! payload, so repeated identical payloads (firmware heartbeats) skip decode/encode. Sized by
+//! `TRANSFORM_CACHE_SIZE` (default 10000 entries, 0 disables) with entries expiring after
+//! `TRANSFORM_CACHE_TTL_SECS` (default 60). Entries from a replaced plugin are never served:
+//! the key carries the registry generation.
+
+use lru::LruCache;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type Key = (u64, String, [u8; 32]);
+
+pub struct TransformCache { entries: Option<Mutex<LruCache<Key, (Instant, Value)>>>, ttl: Duration, hits: AtomicU64, misses: AtomicU64 }
+
+pub struct CacheSnapshot { pub entries: usize, pub hits: u64, pub misses: u64 }
+
+impl TransformCache {
+    pub fn from_env() -> Self {
+        let env = |k: &str, d: u64| std::env::var(k).ok().and_then(|v| v.parse().ok()).unwrap_or(d);
+        let entries = NonZeroUsize::new(env("TRANSFORM_CACHE_SIZE", 10_000) as usize).map(|n| Mutex::new(LruCache::new(n)));
+        TransformCache { entries, ttl: Duration::from_secs(env("TRANSFORM_CACHE_TTL_SECS", 60)), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    /// `route` names each hop as served (e.g. `json>sdf-binary@canary`); `None` when caching is off.
+    pub fn key(&self, generation: u64, route: String, payload: &Value) -> Option<Key> {
+        self.entries.as_ref()?;
+        let digest = Sha256::digest(serde_json::to_vec(payload).unwrap_or_default());
+        Some((generation, route, digest.into()))
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Value> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        let hit = match entries.get(key) {
+            Some((at, v)) if at.elapsed() < self.ttl => Some(v.clone()),
+            Some(_) => { entries.pop(key); None }
+            None => None,
+        };
+        let counter = if hit.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        hit
+    }
+
+    pub fn put(&self, key: Key, output: Value) {
+        if let Some(entries) = &self.entries { entries.lock().unwrap().put(key, (Instant::now(), output)); }
+    }
+
+    pub fn snapshot(&self) -> CacheSnapshot {
+        let entries = self.entries.as_ref().map_or(0, |e| e.lock().unwrap().len());
+        CacheSnapshot { entries, hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) }
+    }
+}
@@ -0,0 +1,163 @@
+//! Soft delete for connections and meshes. `DELETE /connections/:id` and `DELETE /mesh/:id`
+//! move the resource here, with its state, for `DELETE_RETENTION_SECS` (default 604800, a
+//! week); `POST /connections/:id/restore` and `POST /mesh/:id/restore` put it back under the
+//! same id until then, after which it is purged. Listings (`GET /connections`, `GET /mesh`)
+//! include deleted resources, marked `"state": "deleted"`, with `?include_deleted=true`.
+//!
+//! A deleted connection is also parked for session resumption as before; whichever of a
+//! device resume or an operator restore comes first wins, and the other finds nothing.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::mesh::Mesh;
+use crate::outbox::ConnectionInfo;
+use crate::rbac::{Operate, Read, Require};
+use crate::{api_err, ApiError, AppState, Connection, Tenant};
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceState { #[default] Active, Deleted }
+
+#[derive(Default, Deserialize)]
+pub struct ListQuery { #[serde(default)] pub include_deleted: bool }
+
+/// When and by whom a listed resource was deleted; empty for active ones.
+#[derive(Default, Serialize)]
+pub struct Deletion {
+    pub state: ResourceState,
+    #[serde(skip_serializing_if = "Option::is_none")] pub deleted_at_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub deleted_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub purge_at_ms: Option<u64>,
+}
+
+struct Deleted<T> { item: T, at_ms: u64, by: String }
+
+struct DeletedConnection { conn: Connection, sequence: Option<u64> }
+
+#[derive(Serialize)]
+pub struct MeshListing { #[serde(flatten)] mesh: Mesh, #[serde(flatten)] deletion: Deletion }
+
+pub struct Trash { retention_ms: u64, connections: Mutex<HashMap<String, Deleted<DeletedConnection>>>, meshes: Mutex<HashMap<String, Deleted<Mesh>>> }
+
+impl<T> Deleted<T> {
+    fn deletion(&self, retention_ms: u64) -> Deletion {
+        Deletion { state: ResourceState::Deleted, deleted_at_ms: Some(self.at_ms), deleted_by: Some(self.by.clone()), purge_at_ms: Some(self.at_ms + retention_ms) }
+    }
+}
+
+/// Drops entries past the retention window.
+fn purge<T>(map: &mut HashMap<String, Deleted<T>>, retention_ms: u64) {
+    let now = now_ms();
+    map.retain(|_, d| now.saturating_sub(d.at_ms) < retention_ms);
+}
+
+impl Trash {
+    pub fn from_env() -> Self {
+        let secs = std::env::var("DELETE_RETENTION_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(604_800u64);
+        Trash { retention_ms: secs * 1000, connections: Mutex::new(HashMap::new()), meshes: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn put_connection(&self, id: &str, conn: Connection, sequence: Option<u64>, by: &str) {
+        if self.retention_ms == 0 { return; }
+        let mut conns = self.connections.lock().unwrap();
+        purge(&mut conns, self.retention_ms);
+        conns.insert(id.into(), Deleted { item: DeletedConnection { conn, sequence }, at_ms: now_ms(), by: by.into() });
+    }
+
+    /// Forgets a deleted connection that came back some other way (a device resume).
+    pub fn forget_connection(&self, id: &str) { self.connections.lock().unwrap().remove(id); }
+
+    fn take_connection(&self, tenant: &str, id: &str) -> Option<DeletedConnection> {
+        let mut conns = self.connections.lock().unwrap();
+        purge(&mut conns, self.retention_ms);
+        if conns.get(id).is_none_or(|d| d.item.conn.tenant != tenant) { return None; }
+        conns.remove(id).map(|d| d.item)
+    }
+
+    /// The tenant's deleted connections as (id, connection, deletion).
+    pub fn connections(&self, tenant: &str) -> Vec<(String, Connection, Deletion)> {
+        let mut conns = self.connections.lock().unwrap();
+        purge(&mut conns, self.retention_ms);
+        conns.iter().filter(|(_, d)| d.item.conn.tenant == tenant).map(|(id, d)| (id.clone(), d.item.conn.clone(), d.deletion(self.retention_ms))).collect()
+    }
+
+    /// Returns how many deleted connections and meshes were purged.
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut conns = self.connections.lock().unwrap();
+        let mut meshes = self.meshes.lock().unwrap();
+        let n = conns.len() + meshes.len();
+        conns.retain(|_, d| d.item.conn.tenant != tenant);
+        meshes.retain(|_, d| d.item.tenant != tenant);
+        n - conns.len() - meshes.len()
+    }
+
+    pub fn count(&self) -> usize {
+        let mut conns = self.connections.lock().unwrap();
+        let mut meshes = self.meshes.lock().unwrap();
+        purge(&mut conns, self.retention_ms);
+        purge(&mut meshes, self.retention_ms);
+        conns.len() + meshes.len()
+    }
+}
+
+/// Puts a deleted connection back under its id, with its sequence state.
+pub async fn restore_connection(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<ConnectionInfo>, ApiError> {
+    if s.connections.lock().unwrap().contains_key(&id) { return Err(api_err(StatusCode::CONFLICT, "Connection is active", Some(id)).code("not_deleted")); }
+    let d = s.trash.take_connection(&tenant, &id).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown deleted connection", Some(format!("{id} was not deleted in the last {}s", s.trash.retention_ms / 1000))))?;
+    s.resume.discard(&id);
+    if let Some(seq) = d.sequence { s.sequences.lock().unwrap().insert(id.clone(), seq); }
+    s.shared.save_connection(&id, &d.conn).await;
+    s.connections.lock().unwrap().insert(id.clone(), d.conn.clone());
+    s.snapshots.restore(&tenant, &id).await;
+    s.emit("connect", &tenant, serde_json::json!({ "connection_id": id, "device_id": d.conn.device_id, "protocol": d.conn.protocol, "region": d.conn.region, "restored": true }));
+    s.audit.record(&actor, "device.restore", Some(&tenant), Some(&d.conn.device_id), serde_json::json!({ "connection_id": id }));
+    tracing::info!(connection_id = %id, device_id = %d.conn.device_id, "connection restored");
+    let queue = s.outbox.info(&tenant, &d.conn.device_id);
+    Ok(Json(ConnectionInfo::new(&s, id, d.conn, queue, Deletion::default())))
+}
+
+pub async fn list_meshes(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Query(q): Query<ListQuery>) -> Json<Vec<MeshListing>> {
+    let mut out: Vec<MeshListing> = s.meshes.lock().unwrap().values().filter(|m| m.tenant == tenant).map(|m| MeshListing { mesh: m.clone(), deletion: Deletion::default() }).collect();
+    if q.include_deleted {
+        let mut meshes = s.trash.meshes.lock().unwrap();
+        purge(&mut meshes, s.trash.retention_ms);
+        out.extend(meshes.values().filter(|d| d.item.tenant == tenant).map(|d| MeshListing { mesh: d.item.clone(), deletion: d.deletion(s.trash.retention_ms) }));
+    }
+    out.sort_by(|a, b| a.mesh.mesh_id.cmp(&b.mesh.mesh_id));
+    Json(out)
+}
+
+pub async fn delete_mesh(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let mesh = {
+        let mut meshes = s.meshes.lock().unwrap();
+        if meshes.get(&id).is_none_or(|m| m.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown mesh", Some(id))); }
+        meshes.remove(&id).expect("checked above")
+    };
+    s.emit("mesh-change", &tenant, serde_json::json!({ "mesh_id": id, "state": ResourceState::Deleted }));
+    s.audit.record(&actor, "mesh.delete", Some(&tenant), Some(&id), serde_json::json!({ "devices": mesh.devices }));
+    if s.trash.retention_ms > 0 {
+        let mut meshes = s.trash.meshes.lock().unwrap();
+        purge(&mut meshes, s.trash.retention_ms);
+        meshes.insert(id, Deleted { item: mesh, at_ms: now_ms(), by: actor });
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn restore_mesh(State(s): State<Arc<AppState>>, _: Require<Operate>, Actor(actor): Actor, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Mesh>, ApiError> {
+    let mesh = {
+        let mut meshes = s.trash.meshes.lock().unwrap();
+        purge(&mut meshes, s.trash.retention_ms);
+        if meshes.get(&id).is_none_or(|d| d.item.tenant != tenant) {
+            return Err(api_err(StatusCode::NOT_FOUND, "Unknown deleted mesh", Some(format!("{id} was not deleted in the last {}s", s.trash.retention_ms / 1000))));
+        }
+        meshes.remove(&id).expect("checked above").item
+    };
+    s.meshes.lock().unwrap().insert(id.clone(), mesh.clone());
+    s.emit("mesh-change", &tenant, serde_json::json!({ "mesh_id": id, "state": ResourceState::Active, "restored": true }));
+    s.audit.record(&actor, "mesh.restore", Some(&tenant), Some(&id), serde_json::Value::Null);
+    Ok(Json(mesh))
+}
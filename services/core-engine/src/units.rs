@@ -0,0 +1,361 @@
+//! Units and coordinate frames in transforms. Device payloads mix millimeters and meters, NED
+//! and Y-up; a tenant's transform rules (`/gateway/transform/rules`) say what a route's payloads
+//! are in and what the target expects, and the transformer converts the canonical SDF between
+//! the source decode and the first encode.
+//!
+//! A rule matches by `source_protocol` and/or `target_protocol` (the more specific rule wins) and
+//! declares `expect` (the `units` and `frame` assumed when a payload does not say) and `target`
+//! (what to convert to). A payload declares its own with top-level `units` / `frame` keys, which
+//! an object may override for itself; a declaration always wins over `expect`. Converted, the
+//! document carries the target `units` and `frame` and its objects none.
+//!
+//! Per object, `position`, `bounds.min` / `bounds.max` and `size` are lengths: scaled and moved
+//! to the target axes (bounds re-sorted where an axis flips). `scale` is moved to the target axes
+//! and `rotation`, a quaternion `[x, y, z, w]`, is re-based into the target frame (for a change
+//! of handedness the vector part is mirrored as well). Units: `mm`, `cm`, `m`, `km`, `in`, `ft`.
+//! Frames are named by where their x, y and z axes point: built in are `enu`, `ned`, `nwu`,
+//! `y-up` (right-handed, Y up, Z south, as glTF) and `y-up-lh` (left-handed, Z north, as Unity);
+//! a rule may define more under `frames`, e.g. `{"lab": ["north", "up", "east"]}`.
+//!
+//! A payload is refused with 422 rather than guessed at: geometry with no units or frame from
+//! either the payload or `expect` (`ambiguous_units`, `ambiguous_frame`), a 2-D coordinate that
+//! needs an axis change (`ambiguous_frame`), an unknown unit or frame (`unknown_unit`,
+//! `unknown_frame`) or geometry that is not numeric (`invalid_geometry`).
+
+use crate::rbac::{Configure, Read, Require};
+use crate::validate::{Valid, Validate, Violations};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// Each unit in micrometers, so metric conversions stay exact.
+const UNITS: &[(&str, f64)] = &[("mm", 1e3), ("cm", 1e4), ("m", 1e6), ("km", 1e9), ("in", 25_400.0), ("ft", 304_800.0)];
+const DIRECTIONS: &[&str] = &["east", "north", "up", "west", "south", "down"];
+const FRAMES: &[(&str, [&str; 3])] = &[
+    ("enu", ["east", "north", "up"]),
+    ("ned", ["north", "east", "down"]),
+    ("nwu", ["north", "west", "up"]),
+    ("y-up", ["east", "up", "south"]),
+    ("y-up-lh", ["east", "up", "north"]),
+];
+const MAX_RULES: usize = 100;
+
+/// Units and frame, either side of a conversion.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Basis {
+    #[serde(default, skip_serializing_if = "Option::is_none")] units: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] frame: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Rule {
+    id: String, #[serde(skip)] tenant: String,
+    #[serde(skip_serializing_if = "Option::is_none")] source_protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] target_protocol: Option<String>,
+    expect: Basis, target: Basis, #[serde(skip_serializing_if = "BTreeMap::is_empty")] frames: BTreeMap<String, [String; 3]>,
+    created_at_ms: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RuleRequest {
+    source_protocol: Option<String>, target_protocol: Option<String>,
+    #[serde(default)] expect: Basis, target: Basis, #[serde(default)] frames: BTreeMap<String, [String; 3]>,
+}
+
+/// A frame's axes as (ENU axis, sign) for its x, y and z.
+type Axes = [(usize, f64); 3];
+
+fn axes(dirs: &[impl AsRef<str>; 3]) -> Option<Axes> {
+    let mut out = [(0, 1.0); 3];
+    for (i, d) in dirs.iter().enumerate() {
+        let k = DIRECTIONS.iter().position(|x| *x == d.as_ref())?;
+        out[i] = (k % 3, if k < 3 { 1.0 } else { -1.0 });
+    }
+    // Every ENU axis exactly once, or it is no frame at all.
+    let mut seen = [false; 3];
+    for (a, _) in out { if std::mem::replace(&mut seen[a], true) { return None; } }
+    Some(out)
+}
+
+fn unit(name: &str) -> Option<f64> { UNITS.iter().find(|(u, _)| *u == name).map(|(_, um)| *um) }
+
+impl Validate for RuleRequest {
+    fn validate(&self, s: &AppState, v: &mut Violations) {
+        if let Some(p) = &self.source_protocol { v.protocol(s, "source_protocol", p); }
+        if let Some(p) = &self.target_protocol { v.protocol(s, "target_protocol", p); }
+        v.check(self.target.units.is_some() || self.target.frame.is_some(), "target", "must name units, a frame or both");
+        for (name, dirs) in &self.frames {
+            v.id(format!("frames.{name}"), name);
+            v.check(FRAMES.iter().all(|(f, _)| f != name), format!("frames.{name}"), "shadows a built-in frame");
+            v.check(axes(dirs).is_some(), format!("frames.{name}"), format!("must point x, y and z along three different axes of {}", DIRECTIONS.join(", ")));
+        }
+        for (field, b) in [("expect", &self.expect), ("target", &self.target)] {
+            if let Some(u) = &b.units { v.check(unit(u).is_some(), format!("{field}.units"), format!("must be one of {}", UNITS.iter().map(|(u, _)| *u).collect::<Vec<_>>().join(", "))); }
+            if let Some(f) = &b.frame { v.check(FRAMES.iter().any(|(n, _)| n == f) || self.frames.contains_key(f), format!("{field}.frame"), "unknown frame"); }
+        }
+    }
+}
+
+fn refused(code: &'static str, details: String) -> ApiError {
+    api_err(StatusCode::UNPROCESSABLE_ENTITY, "Payload units or frame rejected", Some(details)).code(code)
+}
+
+impl Rule {
+    fn frame(&self, name: &str) -> Result<Axes, ApiError> {
+        let dirs = FRAMES.iter().find(|(n, _)| *n == name).map(|(_, d)| axes(d)).or_else(|| self.frames.get(name).map(axes)).flatten();
+        dirs.ok_or_else(|| refused("unknown_frame", format!("unknown frame {name:?}")))
+    }
+
+    fn matches(&self, source: &str, target: &str) -> bool {
+        self.source_protocol.as_deref().is_none_or(|p| p == source) && self.target_protocol.as_deref().is_none_or(|p| p == target)
+    }
+
+    fn specificity(&self) -> u8 { u8::from(self.source_protocol.is_some()) * 2 + u8::from(self.target_protocol.is_some()) }
+
+    /// Identifies the rule's effect in transform cache keys.
+    pub fn fingerprint(&self) -> String {
+        let mut h = DefaultHasher::new();
+        serde_json::to_string(self).unwrap_or_default().hash(&mut h);
+        format!("units:{:016x}", h.finish())
+    }
+
+    /// Converts every object in the document to the rule's target units and frame.
+    pub fn apply(&self, sdf: &mut Value) -> Result<(), ApiError> {
+        let Some(doc) = sdf.as_object_mut() else { return Ok(()) };
+        let declared = Basis { units: str_field(doc, "units", "")?, frame: str_field(doc, "frame", "")? };
+        if let Some(Value::Object(objects)) = doc.get_mut("objects") {
+            for (id, obj) in objects.iter_mut() {
+                let Some(obj) = obj.as_object_mut() else { continue };
+                let here = Basis { units: str_field(obj, "units", id)?.or(declared.units.clone()), frame: str_field(obj, "frame", id)?.or(declared.frame.clone()) };
+                self.convert(id, obj, &here)?;
+            }
+        }
+        if let Some(u) = &self.target.units { doc.insert("units".into(), u.clone().into()); }
+        if let Some(f) = &self.target.frame { doc.insert("frame".into(), f.clone().into()); }
+        Ok(())
+    }
+
+    fn convert(&self, id: &str, obj: &mut Map<String, Value>, declared: &Basis) -> Result<(), ApiError> {
+        if obj.get("bounds").is_some_and(|b| !b.is_object()) {
+            return Err(refused("invalid_geometry", format!("object {id:?}: bounds must be an object with min and max")));
+        }
+        let has = |k: &str| obj.contains_key(k);
+        let lengths = ["position", "bounds", "size"].into_iter().any(has);
+        let oriented = lengths || ["rotation", "scale"].into_iter().any(has);
+        let factor = match (&self.target.units, lengths) {
+            (Some(to), true) => {
+                let from = declared.units.as_ref().or(self.expect.units.as_ref())
+                    .ok_or_else(|| refused("ambiguous_units", format!("object {id:?} has geometry but neither it, the payload nor the rule says its units")))?;
+                let from_um = unit(from).ok_or_else(|| refused("unknown_unit", format!("object {id:?}: unknown unit {from:?}")))?;
+                Some((from_um, unit(to).expect("validated on creation")))
+            }
+            _ => None,
+        };
+        let rebase = match (&self.target.frame, oriented) {
+            (Some(to), true) => {
+                let from = declared.frame.as_ref().or(self.expect.frame.as_ref())
+                    .ok_or_else(|| refused("ambiguous_frame", format!("object {id:?} has geometry but neither it, the payload nor the rule says its frame")))?;
+                let (f, t) = (self.frame(from)?, self.frame(to)?);
+                (f != t).then_some((f, t))
+            }
+            _ => None,
+        };
+        let scale = |v: f64| factor.map_or(v, |(from, to)| v * from / to);
+        let point = |key: &str, v: &mut Value, signed: bool, len: bool| -> Result<(), ApiError> {
+            let mut c = coords(id, key, v)?;
+            if len { c.iter_mut().for_each(|x| *x = scale(*x)); }
+            if let Some((f, t)) = rebase {
+                let c3: [f64; 3] = c.as_slice().try_into().map_err(|_| refused("ambiguous_frame", format!("object {id:?}: {key} has {} components; a frame change needs 3", c.len())))?;
+                c = swap(c3, f, t, signed).to_vec();
+            }
+            // Adding 0.0 turns the -0.0 a flipped zero becomes back into 0.0.
+            *v = Value::Array(c.into_iter().map(|x| Value::from(x + 0.0)).collect());
+            Ok(())
+        };
+        if let Some(v) = obj.get_mut("position") { point("position", v, true, true)?; }
+        if let Some(v) = obj.get_mut("size") { point("size", v, false, true)?; }
+        if let Some(v) = obj.get_mut("scale") { point("scale", v, false, false)?; }
+        if let Some(Value::Object(b)) = obj.get_mut("bounds") {
+            for k in ["min", "max"] { if let Some(v) = b.get_mut(k) { point(&format!("bounds.{k}"), v, true, true)?; } }
+            // A flipped axis turns min into max.
+            if let (Some(Value::Array(lo)), Some(Value::Array(hi))) = (b.get("min").cloned(), b.get("max").cloned()) {
+                let (lo, hi): (Vec<Value>, Vec<Value>) = lo.iter().zip(&hi).map(|(a, b)| {
+                    let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+                    (Value::from(a.min(b)), Value::from(a.max(b)))
+                }).unzip();
+                b.insert("min".into(), Value::Array(lo));
+                b.insert("max".into(), Value::Array(hi));
+            }
+        }
+        if let (Some(v), Some((f, t))) = (obj.get_mut("rotation"), rebase) {
+            let q = coords(id, "rotation", v)?;
+            let [x, y, z, w]: [f64; 4] = q.as_slice().try_into().map_err(|_| refused("invalid_geometry", format!("object {id:?}: rotation must be a quaternion [x, y, z, w]")))?;
+            let handedness = det(f) * det(t);
+            let [x, y, z] = swap([x, y, z], f, t, true).map(|c| c * handedness);
+            *v = Value::Array([x, y, z, w].into_iter().map(Value::from).collect());
+        }
+        obj.remove("units");
+        obj.remove("frame");
+        Ok(())
+    }
+}
+
+fn str_field(m: &Map<String, Value>, key: &str, id: &str) -> Result<Option<String>, ApiError> {
+    match m.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) if id.is_empty() => Err(refused("invalid_geometry", format!("{key} must be a string"))),
+        Some(_) => Err(refused("invalid_geometry", format!("object {id:?}: {key} must be a string"))),
+    }
+}
+
+fn coords(id: &str, key: &str, v: &Value) -> Result<Vec<f64>, ApiError> {
+    v.as_array().and_then(|a| a.iter().map(Value::as_f64).collect::<Option<Vec<_>>>())
+        .ok_or_else(|| refused("invalid_geometry", format!("object {id:?}: {key} must be an array of numbers")))
+}
+
+/// Re-expresses a vector given in frame `f` in frame `t`; unsigned for extents like size and scale.
+fn swap(v: [f64; 3], f: Axes, t: Axes, signed: bool) -> [f64; 3] {
+    t.map(|(axis, sign)| {
+        let (i, (_, from_sign)) = f.iter().enumerate().find(|(_, (a, _))| *a == axis).expect("frames cover every axis");
+        if signed { v[i] * sign * from_sign } else { v[i] }
+    })
+}
+
+/// +1 for a right-handed frame, -1 for a left-handed one.
+fn det(f: Axes) -> f64 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, (a, s)) in f.iter().enumerate() { m[i][*a] = *s; }
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+#[derive(Default)]
+pub struct Rules { rules: Mutex<HashMap<String, Rule>> }
+
+impl Rules {
+    /// The tenant's most specific rule for this route, if any.
+    pub fn find(&self, tenant: &str, source: &str, target: &str) -> Option<Rule> {
+        self.rules.lock().unwrap().values().filter(|r| r.tenant == tenant && r.matches(source, target)).max_by_key(|r| r.specificity()).cloned()
+    }
+
+    pub fn remove_tenant(&self, tenant: &str) -> usize {
+        let mut rules = self.rules.lock().unwrap();
+        let n = rules.len();
+        rules.retain(|_, r| r.tenant != tenant);
+        n - rules.len()
+    }
+}
+
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Valid(req): Valid<RuleRequest>) -> Result<(StatusCode, Json<Rule>), ApiError> {
+    let mut rules = s.unit_rules.rules.lock().unwrap();
+    let mine: Vec<&Rule> = rules.values().filter(|r| r.tenant == tenant).collect();
+    if mine.iter().any(|r| r.source_protocol == req.source_protocol && r.target_protocol == req.target_protocol) {
+        return Err(api_err(StatusCode::CONFLICT, "Transform rule exists", Some("a rule for this source and target already exists; delete it first".into())).code("conflict"));
+    }
+    if mine.len() >= MAX_RULES { return Err(api_err(StatusCode::CONFLICT, "Too many transform rules", Some(format!("at most {MAX_RULES} per tenant"))).code("limit_exceeded")); }
+    let rule = Rule {
+        id: uuid::Uuid::new_v4().to_string(), tenant, source_protocol: req.source_protocol, target_protocol: req.target_protocol,
+        expect: req.expect, target: req.target, frames: req.frames, created_at_ms: crate::events::now_ms(),
+    };
+    rules.insert(rule.id.clone(), rule.clone());
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<Rule>> {
+    let mut rules: Vec<Rule> = s.unit_rules.rules.lock().unwrap().values().filter(|r| r.tenant == tenant).cloned().collect();
+    rules.sort_by(|a, b| a.id.cmp(&b.id));
+    Json(rules)
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let mut rules = s.unit_rules.rules.lock().unwrap();
+    if rules.get(&id).is_none_or(|r| r.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown transform rule", Some(id))); }
+    rules.remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule(expect: (Option<&str>, Option<&str>), target: (Option<&str>, Option<&str>)) -> Rule {
+        let basis = |(units, frame): (Option<&str>, Option<&str>)| Basis { units: units.map(Into::into), frame: frame.map(Into::into) };
+        Rule {
+            id: "r".into(), tenant: "acme".into(), source_protocol: None, target_protocol: None,
+            expect: basis(expect), target: basis(target), frames: BTreeMap::new(), created_at_ms: 0,
+        }
+    }
+
+    fn object(doc: &Value) -> &Value { &doc["objects"]["o1"] }
+
+    #[test]
+    fn millimeters_become_meters() {
+        let mut doc = json!({ "units": "mm", "objects": { "o1": { "position": [1000, 2500, -3], "size": [10, 20, 30], "bounds": { "min": [0, 0, 0], "max": [500, 500, 500] } } } });
+        assert!(rule((None, None), (Some("m"), None)).apply(&mut doc).is_ok());
+        assert_eq!(object(&doc)["position"], json!([1.0, 2.5, -0.003]));
+        assert_eq!(object(&doc)["size"], json!([0.01, 0.02, 0.03]));
+        assert_eq!(object(&doc)["bounds"]["max"], json!([0.5, 0.5, 0.5]));
+        assert_eq!(doc["units"], "m");
+    }
+
+    #[test]
+    fn enu_to_ned_swaps_east_and_north_and_flips_up() {
+        let mut doc = json!({ "objects": { "o1": {
+            "position": [1, 2, 3], "size": [1, 2, 3], "bounds": { "min": [0, 0, 0], "max": [1, 2, 3] },
+            // 90 degrees about up.
+            "rotation": [0, 0, std::f64::consts::FRAC_1_SQRT_2, std::f64::consts::FRAC_1_SQRT_2],
+        } } });
+        assert!(rule((None, Some("enu")), (None, Some("ned"))).apply(&mut doc).is_ok());
+        let o = object(&doc);
+        assert_eq!(o["position"], json!([2.0, 1.0, -3.0]));
+        assert_eq!(o["size"], json!([2.0, 1.0, 3.0]));
+        // Down is the flipped up axis, so the box's min and max trade places along it.
+        assert_eq!(o["bounds"], json!({ "min": [0.0, 0.0, -3.0], "max": [2.0, 1.0, 0.0] }));
+        // The same turn, now about down: -90 degrees.
+        assert_eq!(o["rotation"], json!([0.0, 0.0, -std::f64::consts::FRAC_1_SQRT_2, std::f64::consts::FRAC_1_SQRT_2]));
+        assert_eq!(doc["frame"], "ned");
+    }
+
+    #[test]
+    fn ned_to_enu_undoes_enu_to_ned() {
+        let original = json!({ "frame": "enu", "objects": { "o1": { "position": [1.5, -2.0, 3.0], "rotation": [0.1, 0.2, 0.3, 0.9] } } });
+        let mut doc = original.clone();
+        assert!(rule((None, None), (None, Some("ned"))).apply(&mut doc).is_ok());
+        assert!(rule((None, None), (None, Some("enu"))).apply(&mut doc).is_ok());
+        assert_eq!(doc, original);
+    }
+
+    #[test]
+    fn y_up_to_left_handed_y_up_mirrors_the_quaternion() {
+        let mut doc = json!({ "frame": "y-up", "objects": { "o1": { "position": [1, 2, 3], "rotation": [0.1, 0.2, 0.3, 0.9] } } });
+        assert_eq!(det(axes(&["east", "up", "south"]).unwrap()), 1.0);
+        assert_eq!(det(axes(&["east", "up", "north"]).unwrap()), -1.0);
+        assert!(rule((None, None), (None, Some("y-up-lh"))).apply(&mut doc).is_ok());
+        assert_eq!(object(&doc)["position"], json!([1.0, 2.0, -3.0]));
+        assert_eq!(object(&doc)["rotation"], json!([-0.1, -0.2, 0.3, 0.9]));
+    }
+
+    #[test]
+    fn an_object_may_declare_its_own_units() {
+        let mut doc = json!({ "units": "m", "objects": { "o1": { "units": "cm", "position": [100, 0, 0] } } });
+        assert!(rule((None, None), (Some("mm"), None)).apply(&mut doc).is_ok());
+        assert_eq!(object(&doc)["position"], json!([1000.0, 0.0, 0.0]));
+        assert!(object(&doc).get("units").is_none());
+    }
+
+    #[test]
+    fn geometry_that_cannot_be_converted_is_refused() {
+        let code = |mut doc: Value, r: Rule| r.apply(&mut doc).err().map(|e| e.body.code).unwrap();
+        let to_m = || rule((None, None), (Some("m"), None));
+        assert_eq!(code(json!({ "units": "mm", "objects": { "o1": { "bounds": [0, 1] } } }), to_m()), Some("invalid_geometry"));
+        assert_eq!(code(json!({ "units": "mm", "objects": { "o1": { "bounds": "big" } } }), to_m()), Some("invalid_geometry"));
+        assert_eq!(code(json!({ "objects": { "o1": { "position": [1, 2, 3] } } }), to_m()), Some("ambiguous_units"));
+        assert_eq!(code(json!({ "units": "yd", "objects": { "o1": { "position": [1, 2, 3] } } }), to_m()), Some("unknown_unit"));
+        assert_eq!(code(json!({ "frame": "enu", "objects": { "o1": { "position": [1, 2] } } }), rule((None, None), (None, Some("ned")))), Some("ambiguous_frame"));
+    }
+}
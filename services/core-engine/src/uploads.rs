@@ -0,0 +1,207 @@
+//! Resumable chunked upload for SDF snapshots too large for a single `/sync` body.
+//! `POST /sync/uploads` declares the size and SHA-256 of the whole snapshot, each chunk is
+//! `PUT` by index with its own `X-Chunk-SHA256`, and `POST .../complete` verifies the assembled
+//! file. `GET` on the upload lists the received chunks so an interrupted client can resume.
+//! Chunks are written straight to `UPLOAD_DIR`; a tenant may have `UPLOAD_MAX_PER_TENANT`
+//! (default 4) uploads in progress at once.
+//!
+//! On completion the snapshot, a document in the connection's protocol, is applied like a
+//! `/sync` of that size (decoding, routing, shadow, snapshots, events, usage) and its file is
+//! deleted; the upload then reports the `sync_id`. Upload records, finished or not, expire after
+//! `UPLOAD_TTL_SECS`, and a background sweep drops them along with files nothing refers to.
+
+use crate::events::now_ms;
+use crate::rbac::{self, Operate, Require};
+use crate::{api_err, tls, ApiError, AppState, Tenant};
+use alice_gateway_types::SyncRequest;
+use axum::{body::Bytes, extract::{Extension, Path, State}, http::{HeaderMap, StatusCode}, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::io::{BufReader, Read, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+pub const MAX_CHUNK: usize = 32 * 1024 * 1024;
+const MIN_CHUNK: u64 = 256 * 1024;
+const DEFAULT_CHUNK: u64 = 8 * 1024 * 1024;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Serialize)]
+pub struct Upload {
+    pub upload_id: String,
+    #[serde(skip)] pub tenant: String,
+    pub connection_id: String,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub sha256: String,
+    pub received: BTreeSet<u64>,
+    pub status: String,
+    pub expires_at_ms: u64,
+    /// The sync the completed snapshot was applied as.
+    #[serde(skip_serializing_if = "Option::is_none")] pub sync_id: Option<String>,
+}
+
+impl Upload {
+    fn chunk_count(&self) -> u64 { self.total_size.div_ceil(self.chunk_size) }
+    fn chunk_len(&self, index: u64) -> u64 { self.chunk_size.min(self.total_size - index * self.chunk_size) }
+}
+
+#[derive(Deserialize)]
+pub struct StartUpload { connection_id: String, total_size: u64, sha256: String, chunk_size: Option<u64> }
+
+fn upload_dir() -> PathBuf { std::env::var("UPLOAD_DIR").unwrap_or_else(|_| "/tmp/alice-uploads".into()).into() }
+fn max_bytes() -> u64 { std::env::var("UPLOAD_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(2 * 1024 * 1024 * 1024) }
+fn ttl_ms() -> u64 { std::env::var("UPLOAD_TTL_SECS").ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(86_400) * 1000 }
+fn max_per_tenant() -> usize { std::env::var("UPLOAD_MAX_PER_TENANT").ok().and_then(|v| v.parse().ok()).unwrap_or(4) }
+fn file_for(id: &str) -> PathBuf { upload_dir().join(format!("{id}.sdf")) }
+
+fn owned(s: &AppState, tenant: &str, id: &str) -> Result<Upload, ApiError> {
+    s.uploads.lock().unwrap().get(id).filter(|u| u.tenant == tenant).cloned().ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown upload", Some(id.into())))
+}
+
+/// Drops expired uploads and their files, then files older than the TTL that no upload refers
+/// to (left behind by a restart).
+fn sweep(s: &AppState) {
+    let now = now_ms();
+    let expired: Vec<String> = s.uploads.lock().unwrap().iter().filter(|(_, u)| u.status != "applying" && u.expires_at_ms < now).map(|(id, _)| id.clone()).collect();
+    for id in expired {
+        s.uploads.lock().unwrap().remove(&id);
+        let _ = std::fs::remove_file(file_for(&id));
+    }
+    let Ok(dir) = std::fs::read_dir(upload_dir()) else { return };
+    let stale = SystemTime::now() - Duration::from_millis(ttl_ms());
+    for entry in dir.flatten() {
+        let path = entry.path();
+        let Some(id) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".sdf")) else { continue };
+        if s.uploads.lock().unwrap().contains_key(id) || entry.metadata().and_then(|m| m.modified()).is_ok_and(|m| m > stale) { continue; }
+        tracing::info!(file = %path.display(), "removing orphaned upload file");
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+/// Background task expiring uploads.
+pub async fn sweep_loop(s: Arc<AppState>) {
+    let mut tick = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        tick.tick().await;
+        let s = s.clone();
+        let _ = tokio::task::spawn_blocking(move || sweep(&s)).await;
+    }
+}
+
+/// Drops every upload owned by a tenant, including completed snapshots. Returns how many.
+pub fn remove_tenant(s: &AppState, tenant: &str) -> usize {
+    let ids: Vec<String> = s.uploads.lock().unwrap().iter().filter(|(_, u)| u.tenant == tenant).map(|(id, _)| id.clone()).collect();
+    for id in &ids {
+        s.uploads.lock().unwrap().remove(id);
+        let _ = std::fs::remove_file(file_for(id));
+    }
+    ids.len()
+}
+
+pub async fn start(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Json(req): Json<StartUpload>) -> Result<(StatusCode, Json<Upload>), ApiError> {
+    if s.lookup_connection(&req.connection_id).await.is_none_or(|c| c.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown connection", Some(req.connection_id))); }
+    if req.total_size == 0 || req.total_size > max_bytes() { return Err(api_err(StatusCode::PAYLOAD_TOO_LARGE, "Invalid snapshot size", Some(format!("must be 1..={} bytes", max_bytes())))); }
+    if req.sha256.len() != 64 || hex::decode(&req.sha256).is_err() { return Err(api_err(StatusCode::BAD_REQUEST, "sha256 must be 64 hex characters", None)); }
+    let chunk_size = req.chunk_size.unwrap_or(DEFAULT_CHUNK);
+    if !(MIN_CHUNK..=MAX_CHUNK as u64).contains(&chunk_size) { return Err(api_err(StatusCode::BAD_REQUEST, "Invalid chunk_size", Some(format!("must be {MIN_CHUNK}..={MAX_CHUNK} bytes")))); }
+    crate::usage::check(&s, &tenant, req.total_size)?;
+    let upload_id = uuid::Uuid::new_v4().to_string();
+    tokio::fs::create_dir_all(upload_dir()).await.map_err(|e| api_err(StatusCode::INTERNAL_SERVER_ERROR, "Upload storage unavailable", Some(e.to_string())))?;
+    let f = tokio::fs::File::create(file_for(&upload_id)).await.map_err(|e| api_err(StatusCode::INTERNAL_SERVER_ERROR, "Upload storage unavailable", Some(e.to_string())))?;
+    f.set_len(req.total_size).await.map_err(|e| api_err(StatusCode::INSUFFICIENT_STORAGE, "Cannot reserve snapshot space", Some(e.to_string())))?;
+    let up = Upload { upload_id: upload_id.clone(), tenant, connection_id: req.connection_id, total_size: req.total_size, chunk_size, sha256: req.sha256.to_lowercase(), received: BTreeSet::new(), status: "in_progress".into(), expires_at_ms: now_ms() + ttl_ms(), sync_id: None };
+    {
+        let mut uploads = s.uploads.lock().unwrap();
+        let active = uploads.values().filter(|u| u.tenant == up.tenant && u.status != "completed").count();
+        if active >= max_per_tenant() {
+            drop(uploads);
+            let _ = std::fs::remove_file(file_for(&upload_id));
+            return Err(api_err(StatusCode::TOO_MANY_REQUESTS, "Too many uploads in progress", Some(format!("at most {} per tenant; complete or abort one first", max_per_tenant()))).code("upload_limit"));
+        }
+        uploads.insert(upload_id, up.clone());
+    }
+    Ok((StatusCode::CREATED, Json(up)))
+}
+
+pub async fn status(State(s): State<Arc<AppState>>, _: Require<rbac::Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Upload>, ApiError> {
+    owned(&s, &tenant, &id).map(Json)
+}
+
+/// Writes one chunk at its offset. Re-sending a chunk overwrites it, so retries are safe.
+pub async fn put_chunk(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path((id, index)): Path<(String, u64)>, headers: HeaderMap, body: Bytes) -> Result<StatusCode, ApiError> {
+    let up = owned(&s, &tenant, &id)?;
+    if up.status != "in_progress" { return Err(api_err(StatusCode::CONFLICT, "Upload already completed", None)); }
+    if index >= up.chunk_count() { return Err(api_err(StatusCode::BAD_REQUEST, "Chunk index out of range", Some(format!("upload has {} chunks", up.chunk_count())))); }
+    if body.len() as u64 != up.chunk_len(index) { return Err(api_err(StatusCode::BAD_REQUEST, "Wrong chunk length", Some(format!("expected {} bytes, got {}", up.chunk_len(index), body.len())))); }
+    let expected = headers.get("x-chunk-sha256").and_then(|h| h.to_str().ok()).ok_or_else(|| api_err(StatusCode::BAD_REQUEST, "Missing X-Chunk-SHA256", None))?;
+    let actual = hex::encode(Sha256::digest(&body));
+    if !actual.eq_ignore_ascii_case(expected) { return Err(api_err(StatusCode::UNPROCESSABLE_ENTITY, "Chunk checksum mismatch", Some(format!("computed {actual}")))); }
+    let io = |e: std::io::Error| api_err(StatusCode::INTERNAL_SERVER_ERROR, "Chunk write failed", Some(e.to_string()));
+    let mut f = tokio::fs::OpenOptions::new().write(true).open(file_for(&id)).await.map_err(io)?;
+    f.seek(SeekFrom::Start(index * up.chunk_size)).await.map_err(io)?;
+    f.write_all(&body).await.map_err(io)?;
+    f.flush().await.map_err(io)?;
+    if let Some(u) = s.uploads.lock().unwrap().get_mut(&id) { u.received.insert(index); }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Verifies every chunk arrived and the assembled snapshot matches the declared checksum, then
+/// applies it to the connection. On failure the upload stays in progress, so chunks can be
+/// re-sent and completion retried.
+pub async fn complete(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, peer: Option<Extension<tls::PeerIdentity>>, Path(id): Path<String>) -> Result<Json<Upload>, ApiError> {
+    let up = owned(&s, &tenant, &id)?;
+    if up.status == "completed" { return Ok(Json(up)); }
+    let missing: Vec<u64> = (0..up.chunk_count()).filter(|i| !up.received.contains(i)).take(20).collect();
+    if !missing.is_empty() { return Err(api_err(StatusCode::CONFLICT, "Upload incomplete", Some(format!("missing chunks {missing:?}")))); }
+    {
+        let mut uploads = s.uploads.lock().unwrap();
+        let u = uploads.get_mut(&id).ok_or_else(|| api_err(StatusCode::NOT_FOUND, "Unknown upload", Some(id.clone())))?;
+        match u.status.as_str() {
+            "in_progress" => u.status = "applying".into(),
+            "completed" => return Ok(Json(u.clone())),
+            _ => return Err(api_err(StatusCode::CONFLICT, "Upload is being applied", None).retry_after(1)),
+        }
+    }
+    let result = apply(&s, &tenant, peer.as_ref().map(|Extension(p)| p), &up).await;
+    let done = {
+        let mut uploads = s.uploads.lock().unwrap();
+        let Some(u) = uploads.get_mut(&id) else { return result.map(|_| Json(up)) };
+        match result {
+            Ok(sync_id) => { u.status = "completed".into(); u.sync_id = Some(sync_id); u.expires_at_ms = now_ms() + ttl_ms(); }
+            Err(e) => { u.status = "in_progress".into(); return Err(e); }
+        }
+        u.clone()
+    };
+    let _ = tokio::fs::remove_file(file_for(&id)).await;
+    tracing::info!(upload_id = %id, connection_id = %done.connection_id, bytes = done.total_size, "SDF snapshot upload applied");
+    Ok(Json(done))
+}
+
+/// Checks the assembled file and syncs it to the upload's connection; returns the sync id.
+async fn apply(s: &AppState, tenant: &str, peer: Option<&tls::PeerIdentity>, up: &Upload) -> Result<String, ApiError> {
+    let path = file_for(&up.upload_id);
+    let expected = up.sha256.clone();
+    let read = tokio::task::spawn_blocking(move || -> std::io::Result<Result<Value, ApiError>> {
+        let mut f = std::fs::File::open(&path)?;
+        let (mut h, mut buf) = (Sha256::new(), vec![0u8; 1 << 20]);
+        loop { match f.read(&mut buf)? { 0 => break, n => h.update(&buf[..n]) } }
+        let digest = hex::encode(h.finalize());
+        if digest != expected { return Ok(Err(api_err(StatusCode::UNPROCESSABLE_ENTITY, "Snapshot checksum mismatch", Some(format!("computed {digest}"))))); }
+        Ok(serde_json::from_reader(BufReader::new(std::fs::File::open(&path)?)).map_err(|e| api_err(StatusCode::UNPROCESSABLE_ENTITY, "Snapshot is not a JSON document", Some(e.to_string()))))
+    }).await.expect("snapshot read task panicked");
+    let doc = read.map_err(|e| api_err(StatusCode::INTERNAL_SERVER_ERROR, "Snapshot read failed", Some(e.to_string())))??;
+    let req = SyncRequest { connection_id: up.connection_id.clone(), sdf_delta: Some(doc), ..Default::default() };
+    s.process_sync(tenant, peer, req, up.total_size as usize).await.map(|r| r.sync_id)
+}
+
+pub async fn abort(State(s): State<Arc<AppState>>, _: Require<Operate>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    owned(&s, &tenant, &id)?;
+    s.uploads.lock().unwrap().remove(&id);
+    let _ = tokio::fs::remove_file(file_for(&id)).await;
+    Ok(StatusCode::NO_CONTENT)
+}
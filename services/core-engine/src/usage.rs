@@ -0,0 +1,146 @@
+//! Per-tenant billing usage in hourly buckets (sync operations and bytes relayed), with
+//! soft/hard quotas enforced on the sync path and an export endpoint for the billing pipeline.
+//!
+//! Default quotas come from `QUOTA_{SOFT,HARD}_{SYNCS,BYTES}_PER_HOUR` (unset = unlimited);
+//! per-tenant overrides are set through `PUT /admin/tenants/:tenant/quota`.
+
+use crate::audit::Actor;
+use crate::events::now_ms;
+use crate::rbac::{Admin, Read, Require};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+const HOUR_MS: u64 = 3_600_000;
+const RETENTION_HOURS: u64 = 24 * 90;
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct Quota { pub soft_syncs_per_hour: Option<u64>, pub hard_syncs_per_hour: Option<u64>, pub soft_bytes_per_hour: Option<u64>, pub hard_bytes_per_hour: Option<u64> }
+
+impl Quota {
+    pub fn from_env() -> Self {
+        let num = |k: &str| std::env::var(k).ok().and_then(|v| v.parse().ok());
+        Quota {
+            soft_syncs_per_hour: num("QUOTA_SOFT_SYNCS_PER_HOUR"), hard_syncs_per_hour: num("QUOTA_HARD_SYNCS_PER_HOUR"),
+            soft_bytes_per_hour: num("QUOTA_SOFT_BYTES_PER_HOUR"), hard_bytes_per_hour: num("QUOTA_HARD_BYTES_PER_HOUR"),
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct Bucket { pub hour_start_ms: u64, pub syncs: u64, pub bytes_relayed: u64, #[serde(skip)] warned: bool }
+
+#[derive(Default)]
+pub struct TenantUsage { pub quota: Option<Quota>, pub buckets: BTreeMap<u64, Bucket> }
+
+#[derive(Deserialize)]
+pub struct UsageQuery { from: Option<u64>, to: Option<u64>, format: Option<String> }
+
+#[derive(Serialize)]
+pub struct UsageReport { tenant: String, from_ms: u64, to_ms: u64, total_syncs: u64, total_bytes_relayed: u64, buckets: Vec<Bucket> }
+
+fn hour_of(ms: u64) -> u64 { ms / HOUR_MS * HOUR_MS }
+
+/// Syncs counted against the current hour before they run. Dropping it refunds the count, so
+/// syncs that fail are not billed; `commit` keeps it.
+#[must_use]
+pub struct Reservation<'a> { s: &'a AppState, tenant: String, hour: u64, syncs: u64, bytes: u64, committed: bool }
+
+impl Reservation<'_> {
+    pub fn commit(mut self) { self.committed = true; }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.committed { return; }
+        let mut usage = self.s.usage.lock().unwrap();
+        if let Some(b) = usage.get_mut(&self.tenant).and_then(|u| u.buckets.get_mut(&self.hour)) {
+            b.syncs = b.syncs.saturating_sub(self.syncs);
+            b.bytes_relayed = b.bytes_relayed.saturating_sub(self.bytes);
+        }
+    }
+}
+
+/// Rejects with 429 once a hard cap for the current hour would be exceeded, without counting
+/// anything; for an early check on work that is counted later.
+pub fn check(s: &AppState, tenant: &str, bytes: u64) -> Result<(), ApiError> { admit(s, tenant, 1, bytes, false).map(drop) }
+
+/// Checks the hard caps and counts `syncs` under the same lock, so concurrent syncs cannot
+/// all pass the check and then overshoot the cap together. Crossing a soft limit emits a
+/// single `quota-warning` event per hour.
+pub fn reserve<'a>(s: &'a AppState, tenant: &str, syncs: u64, bytes: u64) -> Result<Reservation<'a>, ApiError> {
+    let hour = admit(s, tenant, syncs, bytes, true)?;
+    Ok(Reservation { s, tenant: tenant.to_string(), hour, syncs, bytes, committed: false })
+}
+
+/// Returns the hour the syncs fall in.
+fn admit(s: &AppState, tenant: &str, syncs: u64, bytes: u64, count: bool) -> Result<u64, ApiError> {
+    let now = now_ms();
+    let hour = hour_of(now);
+    let warn = {
+        let mut usage = s.usage.lock().unwrap();
+        let u = usage.entry(tenant.to_string()).or_default();
+        let q = u.quota.unwrap_or(s.default_quota);
+        let b = u.buckets.entry(hour).or_insert_with(|| Bucket { hour_start_ms: hour, ..Default::default() });
+        let (syncs, total_bytes) = (b.syncs + syncs, b.bytes_relayed + bytes);
+        if q.hard_syncs_per_hour.is_some_and(|cap| syncs > cap) || q.hard_bytes_per_hour.is_some_and(|cap| total_bytes > cap) {
+            let retry = (hour + HOUR_MS - now).div_ceil(1000);
+            return Err(api_err(StatusCode::TOO_MANY_REQUESTS, "Quota exceeded", Some(format!("hourly hard cap reached for tenant {tenant}"))).retry_after(retry));
+        }
+        if !count { return Ok(hour); }
+        b.syncs = syncs;
+        b.bytes_relayed = total_bytes;
+        let over_soft = q.soft_syncs_per_hour.is_some_and(|l| syncs > l) || q.soft_bytes_per_hour.is_some_and(|l| total_bytes > l);
+        let warn = over_soft && !b.warned;
+        b.warned |= over_soft;
+        let cutoff = hour.saturating_sub(RETENTION_HOURS * HOUR_MS);
+        u.buckets.retain(|h, _| *h >= cutoff);
+        warn.then_some((syncs, total_bytes))
+    };
+    if let Some((syncs, bytes_relayed)) = warn {
+        tracing::warn!(%tenant, syncs, bytes_relayed, "tenant crossed soft quota");
+        s.emit("quota-warning", tenant, serde_json::json!({ "hour_start_ms": hour, "syncs": syncs, "bytes_relayed": bytes_relayed }));
+    }
+    Ok(hour)
+}
+
+/// Syncs and bytes billed to `tenant` in the hourly buckets overlapping `from_ms..to_ms`.
+pub fn totals(s: &AppState, tenant: &str, from_ms: u64, to_ms: u64) -> (u64, u64) {
+    let usage = s.usage.lock().unwrap();
+    let buckets = usage.get(tenant).into_iter().flat_map(|u| u.buckets.range(hour_of(from_ms)..to_ms).map(|(_, b)| b));
+    buckets.fold((0, 0), |(syncs, bytes), b| (syncs + b.syncs, bytes + b.bytes_relayed))
+}
+
+/// Drops the tenant's usage buckets and quota override; returns how many buckets went.
+pub fn remove_tenant(s: &AppState, tenant: &str) -> usize { s.usage.lock().unwrap().remove(tenant).map_or(0, |u| u.buckets.len()) }
+
+/// `GET /api/v1/tenants/:id/usage?from=&to=&format=csv|json`; CSV is also chosen by `Accept: text/csv`.
+pub async fn export(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(caller): Tenant, Path(id): Path<String>, Query(q): Query<UsageQuery>, headers: HeaderMap) -> Result<Response, ApiError> {
+    if caller != id { return Err(api_err(StatusCode::FORBIDDEN, "Cannot read another tenant's usage", None)); }
+    let to_ms = q.to.unwrap_or_else(now_ms);
+    let from_ms = q.from.unwrap_or(to_ms.saturating_sub(24 * HOUR_MS));
+    if from_ms >= to_ms { return Err(api_err(StatusCode::BAD_REQUEST, "Invalid range", Some("require from < to".into()))); }
+    let buckets: Vec<Bucket> = s.usage.lock().unwrap().get(&id).map(|u| u.buckets.range(hour_of(from_ms)..to_ms).map(|(_, b)| b.clone()).collect()).unwrap_or_default();
+    let csv = q.format.as_deref() == Some("csv") || (q.format.is_none() && headers.get(header::ACCEPT).and_then(|h| h.to_str().ok()).is_some_and(|a| a.contains("text/csv")));
+    if csv {
+        let mut out = String::from("tenant,hour_start_ms,syncs,bytes_relayed\n");
+        for b in &buckets { let _ = writeln!(out, "{id},{},{},{}", b.hour_start_ms, b.syncs, b.bytes_relayed); }
+        return Ok(([(header::CONTENT_TYPE, "text/csv")], out).into_response());
+    }
+    let report = UsageReport { total_syncs: buckets.iter().map(|b| b.syncs).sum(), total_bytes_relayed: buckets.iter().map(|b| b.bytes_relayed).sum(), tenant: id, from_ms, to_ms, buckets };
+    Ok(Json(report).into_response())
+}
+
+pub async fn set_quota(State(s): State<Arc<AppState>>, _: Require<Admin>, Actor(actor): Actor, Path(tenant): Path<String>, Json(q): Json<Quota>) -> Json<Quota> {
+    s.usage.lock().unwrap().entry(tenant.clone()).or_default().quota = Some(q);
+    tracing::info!(%tenant, "admin set tenant quota");
+    s.audit.record(&actor, "admin.tenant.quota", Some(&tenant), None, serde_json::to_value(q).unwrap_or_default());
+    Json(q)
+}
@@ -0,0 +1,77 @@
+//! Request validation and body limits. Request structs implement `Validate`, collecting every
+//! violation rather than stopping at the first; the `Valid<T>` JSON extractor (and
+//! `Negotiated<T>` on the codec paths) rejects with a 400 listing them all under `violations`.
+//! Body sizes are capped globally by `BODY_LIMIT_BYTES` (default 1 MiB) with larger per-route
+//! limits for the streaming paths.
+
+use crate::{api_err, ApiError, AppState};
+use axum::{
+    async_trait,
+    extract::{DefaultBodyLimit, FromRequest, Request},
+    http::StatusCode,
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+
+pub const TOPOLOGIES: &[&str] = &["full-mesh", "star", "ring", "line"];
+pub const PRIORITIES: &[&str] = &["low", "normal", "high"];
+const MAX_ID_LEN: usize = 128;
+
+#[derive(Serialize)]
+pub struct Violation { field: String, message: String }
+
+#[derive(Default)]
+pub struct Violations(Vec<Violation>);
+
+impl Violations {
+    pub fn check(&mut self, ok: bool, field: impl Into<String>, message: impl Into<String>) {
+        if !ok { self.0.push(Violation { field: field.into(), message: message.into() }); }
+    }
+    /// 1-128 characters of `[A-Za-z0-9._:-]`, starting with an alphanumeric.
+    pub fn id(&mut self, field: impl Into<String>, v: &str) {
+        let ok = v.len() <= MAX_ID_LEN && v.starts_with(|c: char| c.is_ascii_alphanumeric()) && v.chars().all(|c| c.is_ascii_alphanumeric() || "._:-".contains(c));
+        self.check(ok, field, "must be 1-128 characters of A-Z a-z 0-9 . _ : - starting with a letter or digit");
+    }
+    pub fn one_of(&mut self, field: impl Into<String>, v: &str, allowed: &[&str]) {
+        self.check(allowed.contains(&v), field, format!("must be one of {}", allowed.join(", ")));
+    }
+    pub fn protocol(&mut self, s: &AppState, field: impl Into<String>, v: &str) {
+        let known = s.protocols.list().into_iter().map(|p| p.name).collect::<Vec<_>>();
+        self.check(s.protocols.get(v).is_ok(), field, format!("unsupported protocol; supported: {}", known.join(", ")));
+    }
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+    /// An error with `status` and `code` listing every violation.
+    pub fn into_error(self, status: StatusCode, error: &str, code: &'static str) -> ApiError {
+        api_err(status, error, None).code(code).violations(self.0)
+    }
+}
+
+pub trait Validate {
+    fn validate(&self, s: &AppState, v: &mut Violations);
+}
+
+pub fn ensure<T: Validate>(s: &AppState, t: &T) -> Result<(), ApiError> {
+    let mut v = Violations::default();
+    t.validate(s, &mut v);
+    if v.is_empty() { return Ok(()); }
+    Err(v.into_error(StatusCode::BAD_REQUEST, "Validation failed", "validation_failed"))
+}
+
+/// JSON body that passed `Validate`.
+pub struct Valid<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned + Validate + Send> FromRequest<Arc<AppState>> for Valid<T> {
+    type Rejection = ApiError;
+    async fn from_request(req: Request, s: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let Json(t) = Json::<T>::from_request(req, s).await.map_err(|e| api_err(e.status(), "Malformed body", Some(e.body_text())))?;
+        ensure(s, &t)?;
+        Ok(Valid(t))
+    }
+}
+
+/// `DefaultBodyLimit` from an env var holding a byte count.
+pub fn body_limit(var: &str, default: usize) -> DefaultBodyLimit {
+    DefaultBodyLimit::max(std::env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default))
+}
@@ -0,0 +1,249 @@
+//! API versions. `/api/v1` and `/api/v2` serve the same routes from the same handlers; v2
+//! differs only in the shape of what it returns:
+//!
+//! * JSON responses are enveloped: `{"data": ..., "meta": {"api_version": "v2", ...},
+//!   "links": {"self": ...}}`, errors `{"error": {<the v1 error body>}, "meta": ..., "links": ...}`.
+//!   Other bodies (negotiated CBOR/protobuf, CSV and NDJSON exports, event streams, WebSockets)
+//!   and empty responses are the same as in v1.
+//! * List endpoints are cursor-paginated. Items come in a fixed key order (ids, or time then id
+//!   for logs), `?limit=` per page (default 50, max 500); `meta.next_cursor` and `links.next`
+//!   continue after the last item, and are absent on the last page. The cursor names that item's
+//!   key rather than a position, so items added or removed between pages never shift the rest.
+//!   A cursor that does not decode is refused with 400 (`invalid_cursor`). The connection object
+//!   query keeps its own cursor, now surfaced the same way.
+//!
+//! v1 responses announce their deprecation once `API_V1_DEPRECATED_AT` (Unix seconds) is set:
+//! `Deprecation: @<secs>` (RFC 9745), `Sunset` from `API_V1_SUNSET_AT` when set, and a `Link`
+//! to the same path under `/api/v2` with `rel="successor-version"`. Requests per version are
+//! counted in `gateway_api_requests_total` to track migration.
+
+use crate::{api_err, AppState};
+use axum::{
+    body::{to_bytes, Body, HttpBody},
+    extract::{OriginalUri, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::{from_fn, from_fn_with_state, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+    Router,
+};
+use base64::Engine;
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering as Cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const MAX_ENVELOPED_BODY: usize = 64 * 1024 * 1024;
+const DEFAULT_PAGE: usize = 50;
+const MAX_PAGE: usize = 500;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Version { V1, V2 }
+
+pub struct Versions { deprecated_at: Option<u64>, sunset_at: Option<u64>, requests: [AtomicU64; 2] }
+
+impl Versions {
+    pub fn from_env() -> Self {
+        let secs = |k: &str| std::env::var(k).ok().and_then(|v| v.parse().ok());
+        Versions { deprecated_at: secs("API_V1_DEPRECATED_AT"), sunset_at: secs("API_V1_SUNSET_AT"), requests: Default::default() }
+    }
+
+    /// Requests served per version, v1 first.
+    pub fn requests(&self) -> [(&'static str, u64); 2] {
+        [("v1", self.requests[0].load(Ordering::Relaxed)), ("v2", self.requests[1].load(Ordering::Relaxed))]
+    }
+}
+
+/// How a v2 list endpoint pages.
+#[derive(Clone, Copy)]
+enum Paging {
+    /// The handler returns every item; pages follow the order of these fields.
+    Keyed(&'static [&'static str]),
+    /// The handler pages itself with `cursor` and `next_cursor`; items are under this field.
+    Native(&'static str),
+}
+
+/// Marks a response the pagination layer already enveloped.
+#[derive(Clone)]
+struct Enveloped;
+
+impl Version {
+    /// A list endpoint whose items are ordered by `key` in v2.
+    pub fn list(self, route: MethodRouter<Arc<AppState>>, key: &'static [&'static str]) -> MethodRouter<Arc<AppState>> {
+        self.paged(route, Paging::Keyed(key))
+    }
+
+    /// A list endpoint with its own cursor, its items under `field`.
+    pub fn cursor(self, route: MethodRouter<Arc<AppState>>, field: &'static str) -> MethodRouter<Arc<AppState>> {
+        self.paged(route, Paging::Native(field))
+    }
+
+    fn paged(self, route: MethodRouter<Arc<AppState>>, paging: Paging) -> MethodRouter<Arc<AppState>> {
+        match self {
+            Version::V1 => route,
+            Version::V2 => route.layer(from_fn(move |req: Request, next: Next| paginate(paging, req, next))),
+        }
+    }
+
+    /// Adds the version's response handling to its routes.
+    pub fn apply(self, state: &Arc<AppState>, routes: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
+        match self {
+            Version::V1 => routes.layer(from_fn_with_state(state.clone(), deprecation_mw)),
+            Version::V2 => routes.layer(from_fn_with_state(state.clone(), envelope_mw)),
+        }
+    }
+}
+
+fn original(req: &Request) -> String {
+    let uri = req.extensions().get::<OriginalUri>().map_or(req.uri(), |OriginalUri(u)| u);
+    uri.path_and_query().map_or_else(|| uri.path().to_string(), ToString::to_string)
+}
+
+fn meta(extra: Map<String, Value>) -> Value {
+    let mut m = Map::from_iter([("api_version".to_string(), json!("v2"))]);
+    m.extend(extra);
+    Value::Object(m)
+}
+
+fn json_response(mut parts: axum::http::response::Parts, body: &Value) -> Response {
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Response::from_parts(parts, Body::from(body.to_string()))
+}
+
+/// A complete JSON body, or the response back untouched when it is anything else (streamed,
+/// another media type, empty).
+async fn json_body(resp: Response) -> Result<(axum::http::response::Parts, Value), Response> {
+    let is_json = resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(|ct| ct.starts_with("application/json"));
+    if !is_json || resp.body().size_hint().exact().is_none_or(|n| n == 0 || n > MAX_ENVELOPED_BODY as u64) { return Err(resp); }
+    let (parts, body) = resp.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_ENVELOPED_BODY).await else { return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()) };
+    match serde_json::from_slice(&bytes) {
+        Ok(v) => Ok((parts, v)),
+        Err(_) => Err(Response::from_parts(parts, Body::from(bytes))),
+    }
+}
+
+async fn envelope_mw(State(s): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    s.versions.requests[1].fetch_add(1, Ordering::Relaxed);
+    let self_link = original(&req);
+    let resp = next.run(req).await;
+    if resp.extensions().get::<Enveloped>().is_some() { return resp; }
+    let (parts, body) = match json_body(resp).await { Ok(r) => r, Err(resp) => return resp };
+    let slot = if parts.status.is_success() { "data" } else { "error" };
+    json_response(parts, &json!({ slot: body, "meta": meta(Map::new()), "links": { "self": self_link } }))
+}
+
+async fn deprecation_mw(State(s): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let v = &s.versions;
+    v.requests[0].fetch_add(1, Ordering::Relaxed);
+    let Some(at) = v.deprecated_at else { return next.run(req).await };
+    let successor = original(&req).replacen("/api/v1", "/api/v2", 1);
+    let mut resp = next.run(req).await;
+    let h = resp.headers_mut();
+    h.insert("deprecation", HeaderValue::from_str(&format!("@{at}")).expect("a number is a valid header"));
+    if let Ok(link) = HeaderValue::from_str(&format!("<{successor}>; rel=\"successor-version\"")) { h.append(header::LINK, link); }
+    if let Some(sunset) = v.sunset_at {
+        h.insert("sunset", HeaderValue::from_str(&crate::conditional::http_date(sunset * 1000)).expect("http date is a valid header"));
+    }
+    resp
+}
+
+/// Orders JSON values: numbers numerically, strings lexically, other kinds by type.
+fn compare(a: &Value, b: &Value) -> Cmp {
+    let rank = |v: &Value| match v { Value::Null => 0, Value::Bool(_) => 1, Value::Number(_) => 2, Value::String(_) => 3, _ => 4 };
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64().partial_cmp(&y.as_f64()).unwrap_or(Cmp::Equal),
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+        _ => rank(a).cmp(&rank(b)).then_with(|| a.to_string().cmp(&b.to_string())),
+    }
+}
+
+fn compare_keys(a: &[Value], b: &[Value]) -> Cmp {
+    a.iter().zip(b).map(|(x, y)| compare(x, y)).find(|c| c.is_ne()).unwrap_or(Cmp::Equal)
+}
+
+fn encode_cursor(key: &[Value]) -> String { base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Value::from(key.to_vec()).to_string()) }
+
+fn decode_cursor(cursor: &str) -> Option<Vec<Value>> {
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    match serde_json::from_slice(&bytes).ok()? { Value::Array(key) => Some(key), _ => None }
+}
+
+/// Splits `cursor` and `limit` out of a raw query string, returning them and the rest.
+fn page_params(query: &str) -> (Option<String>, Option<String>, Vec<String>) {
+    let (mut cursor, mut limit, mut rest) = (None, None, Vec::new());
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some(("cursor", v)) => cursor = Some(v.to_string()),
+            Some(("limit", v)) => limit = Some(v.to_string()),
+            _ => rest.push(pair.to_string()),
+        }
+    }
+    (cursor, limit, rest)
+}
+
+fn link(path: &str, rest: &[String], extra: &[String]) -> String {
+    let query: Vec<&str> = rest.iter().chain(extra).map(String::as_str).collect();
+    if query.is_empty() { path.to_string() } else { format!("{path}?{}", query.join("&")) }
+}
+
+async fn paginate(paging: Paging, mut req: Request, next: Next) -> Response {
+    if req.method() != Method::GET { return next.run(req).await; }
+    let self_link = original(&req);
+    let path = self_link.split('?').next().unwrap_or_default().to_string();
+    let (cursor, limit, rest) = page_params(req.uri().query().unwrap_or_default());
+    let (parts, body) = match paging {
+        Paging::Native(field) => {
+            let resp = next.run(req).await;
+            let (parts, mut body) = match json_body(resp).await { Ok(r) if r.0.status.is_success() => r, Ok((parts, body)) => return json_response(parts, &body), Err(resp) => return resp };
+            let Some(obj) = body.as_object_mut() else { return json_response(parts, &body) };
+            let items = obj.remove(field).unwrap_or(Value::Array(Vec::new()));
+            let next_cursor = obj.remove("next_cursor").filter(|c| !c.is_null());
+            let mut links = json!({ "self": self_link });
+            let mut extra = std::mem::take(obj);
+            extra.insert("count".into(), json!(items.as_array().map_or(0, Vec::len)));
+            if let Some(c) = next_cursor.as_ref().and_then(Value::as_str) {
+                let mut params = vec![format!("cursor={c}")];
+                params.extend(limit.map(|l| format!("limit={l}")));
+                links["next"] = json!(link(&path, &rest, &params));
+            }
+            extra.insert("next_cursor".into(), next_cursor.unwrap_or(Value::Null));
+            (parts, json!({ "data": items, "meta": meta(extra), "links": links }))
+        }
+        Paging::Keyed(fields) => {
+            let limit = match limit.map(|l| l.parse::<usize>()) {
+                None => DEFAULT_PAGE,
+                Some(Ok(l)) => l.clamp(1, MAX_PAGE),
+                Some(Err(_)) => return api_err(StatusCode::BAD_REQUEST, "Invalid limit", Some(format!("expected 1..={MAX_PAGE}"))).into_response(),
+            };
+            let after = match cursor.as_deref().map(decode_cursor) {
+                None => None,
+                Some(Some(key)) if key.len() == fields.len() => Some(key),
+                Some(_) => return api_err(StatusCode::BAD_REQUEST, "Invalid cursor", cursor).code("invalid_cursor").into_response(),
+            };
+            // The handler sees the query without the paging parameters, and lists everything.
+            let stripped = link(req.uri().path(), &rest, &[]);
+            if let Ok(uri) = stripped.parse() { *req.uri_mut() = uri; }
+            let resp = next.run(req).await;
+            let (parts, body) = match json_body(resp).await { Ok(r) if r.0.status.is_success() => r, Ok((parts, body)) => return json_response(parts, &body), Err(resp) => return resp };
+            let Value::Array(items) = body else { return json_response(parts, &body) };
+            let total = items.len();
+            let mut keyed: Vec<(Vec<Value>, Value)> = items.into_iter().map(|item| (fields.iter().map(|f| item.get(*f).cloned().unwrap_or(Value::Null)).collect(), item)).collect();
+            keyed.sort_by(|a, b| compare_keys(&a.0, &b.0));
+            if let Some(after) = &after { keyed.retain(|(k, _)| compare_keys(k, after).is_gt()); }
+            let more = keyed.len() > limit;
+            keyed.truncate(limit);
+            let next_cursor = keyed.last().filter(|_| more).map(|(k, _)| encode_cursor(k));
+            let mut links = json!({ "self": self_link });
+            if let Some(c) = &next_cursor { links["next"] = json!(link(&path, &rest, &[format!("cursor={c}"), format!("limit={limit}")])); }
+            let data: Vec<Value> = keyed.into_iter().map(|(_, item)| item).collect();
+            let extra = Map::from_iter([("count".to_string(), json!(data.len())), ("total".to_string(), json!(total)), ("limit".to_string(), json!(limit)), ("next_cursor".to_string(), json!(next_cursor))]);
+            (parts, json!({ "data": data, "meta": meta(extra), "links": links }))
+        }
+    };
+    let mut resp = json_response(parts, &body);
+    resp.extensions_mut().insert(Enveloped);
+    resp
+}
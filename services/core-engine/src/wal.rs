@@ -0,0 +1,357 @@
+//! Write-ahead log for SDF mutations, so a crash mid-merge cannot lose or half-apply a delta.
+//! With `WAL_DIR` set, every connection gets an append-only `<connection_id>.wal`: an `open`
+//! record with the connection, then one `delta` record per sync delta, written before the delta
+//! is merged into the shadow and the connection's delta log. A sync whose record cannot be
+//! written is refused with 503 `wal_unavailable`. Compaction rewrites the file atomically as
+//! the `open` record plus a `checkpoint` of the compacted snapshot, so logs stay short.
+//!
+//! Each line is `<checksum>\t<json>`; `WAL_FSYNC` decides when records reach disk: `always`
+//! (before the sync is answered), `interval` (every `WAL_FSYNC_INTERVAL_MS`, default 1000; the
+//! default) or `never` (left to the OS). On startup every log is replayed before traffic is
+//! served, restoring the connection, its shadow, its snapshot and its last sequence. A torn or
+//! corrupt tail is truncated at the last good record; a file without a readable `open` record is
+//! renamed to `.corrupt`. The report is logged and served at `GET /admin/recovery`.
+//!
+//! Each log has its own lock, held for the write (and fsync) of that connection's records only,
+//! so connections do not wait on each other's disk flushes.
+
+use crate::events::now_ms;
+use crate::rbac::{Admin, Require};
+use crate::{api_err, ApiError, AppState, Connection};
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fsync { Always, Interval, Never }
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Record {
+    Open { connection: Connection },
+    Delta { at_ms: u64, #[serde(default, skip_serializing_if = "Option::is_none")] sequence: Option<u64>, delta: Value },
+    Checkpoint { version: u64, taken_at_ms: Option<u64>, last_sequence: Option<u64>, folded: u64, state: Value },
+}
+
+/// A compacted snapshot, as checkpointed by the delta log.
+pub struct Checkpoint<'a> { pub version: u64, pub taken_at_ms: Option<u64>, pub last_sequence: Option<u64>, pub folded: u64, pub state: &'a Value }
+
+struct Log { file: File, connection: Connection }
+
+/// A connection's log, empty until its first record is written.
+type Slot = Arc<Mutex<Option<Log>>>;
+
+#[derive(Clone, Serialize)]
+pub struct CorruptFile { file: String, error: String }
+
+#[derive(Clone, Default, Serialize)]
+pub struct Report {
+    recovered_at_ms: u64, duration_ms: f64, files: usize, connections_restored: usize, checkpoints: usize, deltas_replayed: usize,
+    torn_records_truncated: usize, corrupt_files: Vec<CorruptFile>,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryView {
+    enabled: bool, #[serde(skip_serializing_if = "Option::is_none")] dir: Option<String>, fsync: Fsync,
+    #[serde(skip_serializing_if = "Option::is_none")] recovery: Option<Report>, open_logs: usize, records_appended: u64, append_failures: u64,
+}
+
+pub struct Wal {
+    dir: Option<PathBuf>,
+    fsync: Fsync,
+    interval: Duration,
+    logs: Mutex<HashMap<String, Slot>>,
+    /// Connections with records written since the last fsync, under `interval`.
+    dirty: Mutex<HashSet<String>>,
+    report: Mutex<Option<Report>>,
+    appended: AtomicU64,
+    failures: AtomicU64,
+}
+
+fn checksum(json: &str) -> String { hex::encode(&Sha256::digest(json.as_bytes())[..8]) }
+
+fn line(rec: &Record) -> String {
+    let json = serde_json::to_string(rec).unwrap_or_default();
+    format!("{}\t{json}\n", checksum(&json))
+}
+
+fn parse(line: &str) -> Result<Record, String> {
+    let (sum, json) = line.split_once('\t').ok_or("missing checksum")?;
+    if checksum(json) != sum { return Err("checksum mismatch".into()); }
+    serde_json::from_str(json).map_err(|e| e.to_string())
+}
+
+impl Wal {
+    pub fn from_env() -> Self {
+        let fsync = match std::env::var("WAL_FSYNC").as_deref() { Ok("always") => Fsync::Always, Ok("never") => Fsync::Never, _ => Fsync::Interval };
+        let interval = Duration::from_millis(std::env::var("WAL_FSYNC_INTERVAL_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(1000u64).max(10));
+        let dir = std::env::var("WAL_DIR").ok().filter(|d| !d.is_empty()).map(PathBuf::from);
+        Wal { dir, fsync, interval, logs: Mutex::default(), dirty: Mutex::default(), report: Mutex::default(), appended: AtomicU64::new(0), failures: AtomicU64::new(0) }
+    }
+
+    fn path(&self, connection_id: &str) -> Option<PathBuf> { self.dir.as_ref().map(|d| d.join(format!("{connection_id}.wal"))) }
+
+    fn slot(&self, connection_id: &str) -> Option<Slot> { self.logs.lock().unwrap().get(connection_id).cloned() }
+
+    /// Appends `rec`. A record that fails to write or sync is cut off again, so the failed sync
+    /// can be retried without its torn remains hiding every later record from recovery.
+    fn write(&self, connection_id: &str, file: &mut File, rec: &Record) -> std::io::Result<()> {
+        let len = file.seek(SeekFrom::End(0))?;
+        let written = file.write_all(line(rec).as_bytes()).and_then(|()| match self.fsync {
+            Fsync::Always => file.sync_data(),
+            Fsync::Interval => { self.dirty.lock().unwrap().insert(connection_id.to_string()); Ok(()) }
+            Fsync::Never => Ok(()),
+        });
+        if written.is_err() {
+            if let Err(e) = file.set_len(len).and_then(|()| file.seek(SeekFrom::Start(len))) { tracing::error!(%connection_id, "WAL rollback failed: {e}"); }
+        }
+        written
+    }
+
+    /// Logs a delta ahead of merging it; the connection's log is started on its first delta. On
+    /// failure nothing is logged, and the sync gives back its sequence and usage reservation as
+    /// it unwinds, so the 503's `Retry-After` can be honoured with the same request.
+    pub fn append(&self, s: &AppState, connection_id: &str, sequence: Option<u64>, delta: &Value) -> Result<(), ApiError> {
+        let Some(path) = self.path(connection_id) else { return Ok(()) };
+        let slot = self.logs.lock().unwrap().entry(connection_id.to_string()).or_default().clone();
+        let mut log = slot.lock().unwrap();
+        let result = (|| {
+            let log = match &mut *log {
+                Some(log) => log,
+                None => {
+                    let connection = s.connections.lock().unwrap().get(connection_id).cloned().ok_or_else(|| std::io::Error::other("connection is not local"))?;
+                    let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&path)?;
+                    self.write(connection_id, &mut file, &Record::Open { connection: connection.clone() })?;
+                    log.insert(Log { file, connection })
+                }
+            };
+            self.write(connection_id, &mut log.file, &Record::Delta { at_ms: now_ms(), sequence, delta: delta.clone() })
+        })();
+        match result {
+            Ok(()) => { self.appended.fetch_add(1, Ordering::Relaxed); Ok(()) }
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                tracing::error!(%connection_id, "WAL append failed: {e}");
+                Err(api_err(StatusCode::SERVICE_UNAVAILABLE, "Write-ahead log unavailable", Some(e.to_string())).code("wal_unavailable").retry_after(1))
+            }
+        }
+    }
+
+    /// Replaces the connection's log with its compacted snapshot, atomically.
+    pub fn checkpoint(&self, connection_id: &str, c: Checkpoint) {
+        let Some(path) = self.path(connection_id) else { return };
+        let Some(slot) = self.slot(connection_id) else { return };
+        let mut log = slot.lock().unwrap();
+        let Some(log) = log.as_mut() else { return };
+        let tmp = path.with_extension("wal.tmp");
+        let rec = Record::Checkpoint { version: c.version, taken_at_ms: c.taken_at_ms, last_sequence: c.last_sequence, folded: c.folded, state: c.state.clone() };
+        let written = (|| {
+            let mut file = File::create(&tmp)?;
+            file.write_all(format!("{}{}", line(&Record::Open { connection: log.connection.clone() }), line(&rec)).as_bytes())?;
+            file.sync_all()?;
+            std::fs::rename(&tmp, &path)?;
+            OpenOptions::new().append(true).open(&path)
+        })();
+        match written {
+            Ok(file) => log.file = file,
+            Err(e) => tracing::warn!(%connection_id, "WAL checkpoint failed, keeping the full log: {e}"),
+        }
+    }
+
+    /// Deletes a connection's log once the connection is gone.
+    pub fn remove(&self, connection_id: &str) {
+        if self.logs.lock().unwrap().remove(connection_id).is_none() { return; }
+        self.dirty.lock().unwrap().remove(connection_id);
+        if let Some(path) = self.path(connection_id) { let _ = std::fs::remove_file(path); }
+    }
+
+    /// Replays every log in `WAL_DIR` into the state; run before the listener is bound.
+    pub fn recover(&self, s: &AppState) {
+        let Some(dir) = &self.dir else { return };
+        let started = Instant::now();
+        let mut report = Report { recovered_at_ms: now_ms(), ..Report::default() };
+        if let Err(e) = std::fs::create_dir_all(dir) { tracing::error!(dir = %dir.display(), "WAL directory unavailable: {e}"); }
+        let paths: Vec<PathBuf> = std::fs::read_dir(dir).into_iter().flatten().flatten().map(|e| e.path()).collect();
+        // A checkpoint interrupted before its rename; the log it was replacing is still whole.
+        for tmp in paths.iter().filter(|p| p.extension().is_some_and(|x| x == "tmp")) { let _ = std::fs::remove_file(tmp); }
+        for path in paths.iter().filter(|p| p.extension().is_some_and(|x| x == "wal")) {
+            report.files += 1;
+            if let Err(error) = self.replay(s, path, &mut report) {
+                tracing::error!(file = %path.display(), "unrecoverable WAL file: {error}");
+                let _ = std::fs::rename(path, path.with_extension("wal.corrupt"));
+                report.corrupt_files.push(CorruptFile { file: path.display().to_string(), error });
+            }
+        }
+        report.duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+        tracing::info!(files = report.files, connections = report.connections_restored, checkpoints = report.checkpoints, deltas = report.deltas_replayed,
+            torn = report.torn_records_truncated, corrupt = report.corrupt_files.len(), duration_ms = report.duration_ms, "WAL recovery complete");
+        *self.report.lock().unwrap() = Some(report);
+    }
+
+    fn replay(&self, s: &AppState, path: &Path, report: &mut Report) -> Result<(), String> {
+        let connection_id = path.file_stem().and_then(|n| n.to_str()).ok_or("unnamed file")?.to_string();
+        let (log, records) = load(path, report)?;
+        let connection = log.connection.clone();
+        let (tenant, device_id) = (connection.tenant.clone(), connection.device_id.clone());
+        s.connections.lock().unwrap().entry(connection_id.clone()).or_insert(connection);
+        self.logs.lock().unwrap().insert(connection_id.clone(), Arc::new(Mutex::new(Some(log))));
+        let mut last_sequence = None;
+        for rec in records {
+            match rec {
+                Record::Open { .. } => return Err("second open record".into()),
+                Record::Checkpoint { version, taken_at_ms, last_sequence: seq, folded, state } => {
+                    crate::shadow::apply_reported(s, &tenant, &device_id, &state);
+                    s.snapshots.recover(&tenant, &connection_id, &device_id, Checkpoint { version, taken_at_ms, last_sequence: seq, folded, state: &state });
+                    last_sequence = seq.or(last_sequence);
+                    report.checkpoints += 1;
+                }
+                Record::Delta { sequence, delta, .. } => {
+                    crate::shadow::apply_reported(s, &tenant, &device_id, &delta);
+                    s.snapshots.append(&tenant, &connection_id, &device_id, sequence, &delta);
+                    last_sequence = sequence.or(last_sequence);
+                    report.deltas_replayed += 1;
+                }
+            }
+        }
+        if let Some(seq) = last_sequence { s.sequences.lock().unwrap().insert(connection_id, seq); }
+        report.connections_restored += 1;
+        Ok(())
+    }
+
+    pub fn counts(&self) -> (u64, u64) { (self.appended.load(Ordering::Relaxed), self.failures.load(Ordering::Relaxed)) }
+
+    fn open_logs(&self) -> usize {
+        let slots: Vec<Slot> = self.logs.lock().unwrap().values().cloned().collect();
+        slots.iter().filter(|l| l.lock().unwrap().is_some()).count()
+    }
+}
+
+/// Reads a log up to its last intact record, truncating anything after it, and reopens it for
+/// appending. Returns the log and the records following its `open` record.
+fn load(path: &Path, report: &mut Report) -> Result<(Log, Vec<Record>), String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let (mut records, mut good_len, mut buf) = (Vec::new(), 0u64, String::new());
+    loop {
+        buf.clear();
+        match reader.read_line(&mut buf) {
+            Ok(0) => break,
+            // A record without its newline was cut off mid-write.
+            Ok(_) if buf.ends_with('\n') => match parse(buf.trim_end_matches('\n')) {
+                Ok(rec) => { records.push(rec); good_len += buf.len() as u64; }
+                Err(_) => break,
+            },
+            _ => break,
+        }
+    }
+    let total = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+    let mut records = records.into_iter();
+    let Some(Record::Open { connection }) = records.next() else { return Err("no open record".into()) };
+    if good_len < total {
+        tracing::warn!(file = %path.display(), kept_bytes = good_len, dropped_bytes = total - good_len, "truncating torn WAL tail");
+        OpenOptions::new().write(true).open(path).and_then(|f| f.set_len(good_len)).map_err(|e| e.to_string())?;
+        report.torn_records_truncated += 1;
+    }
+    let file = OpenOptions::new().append(true).open(path).map_err(|e| e.to_string())?;
+    Ok((Log { file, connection }, records.collect()))
+}
+
+/// Flushes logs written since the last tick, under `WAL_FSYNC=interval`.
+pub async fn fsync_loop(s: Arc<AppState>) {
+    if s.wal.dir.is_none() || s.wal.fsync != Fsync::Interval { return; }
+    let mut tick = tokio::time::interval(s.wal.interval);
+    loop {
+        tick.tick().await;
+        let dirty: Vec<String> = s.wal.dirty.lock().unwrap().drain().collect();
+        if dirty.is_empty() { continue; }
+        let files: Vec<File> = dirty.iter().filter_map(|id| s.wal.slot(id)?.lock().unwrap().as_ref()?.file.try_clone().ok()).collect();
+        let _ = tokio::task::spawn_blocking(move || for f in files { if let Err(e) = f.sync_data() { tracing::warn!("WAL fsync failed: {e}"); } }).await;
+    }
+}
+
+pub async fn recovery(State(s): State<Arc<AppState>>, _: Require<Admin>) -> Json<RecoveryView> {
+    let (records_appended, append_failures) = s.wal.counts();
+    Json(RecoveryView {
+        enabled: s.wal.dir.is_some(), dir: s.wal.dir.as_ref().map(|d| d.display().to_string()), fsync: s.wal.fsync,
+        recovery: s.wal.report.lock().unwrap().clone(), open_logs: s.wal.open_logs(), records_appended, append_failures,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_log() -> PathBuf { std::env::temp_dir().join(format!("wal-{}.wal", uuid::Uuid::new_v4().simple())) }
+
+    fn open_record() -> String {
+        line(&Record::Open { connection: Connection { tenant: "acme".into(), device_id: "dev-1".into(), protocol: "sdf-stream".into(), region: "us-east-1".into(), upstream: None } })
+    }
+
+    fn delta(n: u64) -> String { line(&Record::Delta { at_ms: n, sequence: Some(n), delta: json!({ "objects": { "o1": { "n": n } } }) }) }
+
+    fn sequences(records: &[Record]) -> Vec<u64> {
+        records.iter().filter_map(|r| match r { Record::Delta { sequence, .. } => *sequence, _ => None }).collect()
+    }
+
+    #[test]
+    fn an_intact_log_loads_whole() {
+        let path = temp_log();
+        std::fs::write(&path, format!("{}{}{}", open_record(), delta(1), delta(2))).unwrap();
+        let mut report = Report::default();
+        let (log, records) = load(&path, &mut report).unwrap();
+        assert_eq!(log.connection.device_id, "dev-1");
+        assert_eq!(sequences(&records), [1, 2]);
+        assert_eq!(report.torn_records_truncated, 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_torn_tail_is_truncated_and_the_log_stays_appendable() {
+        let path = temp_log();
+        let intact = format!("{}{}", open_record(), delta(1));
+        let torn = delta(2);
+        std::fs::write(&path, format!("{intact}{}", &torn[..torn.len() / 2])).unwrap();
+        let mut report = Report::default();
+        let (mut log, records) = load(&path, &mut report).unwrap();
+        assert_eq!(sequences(&records), [1]);
+        assert_eq!(report.torn_records_truncated, 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), intact.len() as u64);
+
+        log.file.write_all(delta(3).as_bytes()).unwrap();
+        let (_, records) = load(&path, &mut Report::default()).unwrap();
+        assert_eq!(sequences(&records), [1, 3]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_checksum_mismatch_ends_the_log_at_the_last_good_record() {
+        let path = temp_log();
+        let intact = format!("{}{}", open_record(), delta(1));
+        let tampered = delta(2).replace("\"n\":2", "\"n\":9");
+        std::fs::write(&path, format!("{intact}{tampered}{}", delta(3))).unwrap();
+        assert!(parse(tampered.trim_end()).is_err_and(|e| e.contains("checksum")));
+        let mut report = Report::default();
+        let (_, records) = load(&path, &mut report).unwrap();
+        assert_eq!(sequences(&records), [1]);
+        assert_eq!(report.torn_records_truncated, 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), intact.len() as u64);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_log_without_an_open_record_is_refused() {
+        let path = temp_log();
+        std::fs::write(&path, delta(1)).unwrap();
+        assert_eq!(load(&path, &mut Report::default()).err().as_deref(), Some("no open record"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
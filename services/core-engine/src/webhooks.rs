@@ -0,0 +1,229 @@
+//! Tenant webhooks: HMAC-signed JSON delivery of lifecycle events with retry and
+//! exponential backoff, plus a bounded per-webhook delivery log. A webhook may narrow its
+//! events further with `topics`, hub patterns relative to the tenant (e.g. `+/$connect`).
+//!
+//! Webhook URLs must be `https://` and reach public addresses only: loopback, private,
+//! link-local (cloud metadata), CGNAT and other special-use ranges are refused when the webhook
+//! is created and again on delivery, when the resolver only hands the client public addresses,
+//! so a name that is later re-pointed inward is not followed; redirects are not followed either.
+//! `WEBHOOK_ALLOW_INSECURE=true` lifts both rules for local development. A 4xx response other
+//! than 408 and 429 is final: it is neither retried nor counted against the breaker.
+
+use crate::events::{now_ms, Event};
+use crate::hub::{Overflow, Pattern};
+use crate::rbac::{Configure, Read, Require};
+use crate::resilience::{self, Attempt, CallError, RetryPolicy};
+use crate::{api_err, ApiError, AppState, Tenant};
+use axum::{extract::{Path, State}, http::StatusCode, response::Json};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+/// High-volume kinds (per sync, per transform) that only reach the streaming endpoints and sinks.
+pub const STREAM_ONLY_KINDS: &[&str] = &["delta", "transform"];
+pub const EVENT_KINDS: &[&str] = &["connect", "disconnect", "sync-failure", "mesh-change", "mesh-degraded", "mesh-healed", "mesh-partitioned", "mesh-rejoined", "alert-fired", "alert-resolved", "shadow-update", "quota-warning", "schedule-run", "anomaly-detected", "anomaly-cleared", "redirect", "bandwidth-throttled", "duplicate-offender", "maintenance-scheduled", "maintenance-started", "maintenance-ended"];
+const RETRY: RetryPolicy = RetryPolicy { max_attempts: 5, base_backoff: Duration::from_millis(500), max_backoff: Duration::from_secs(30) };
+const DELIVERY_LOG_LEN: usize = 100;
+
+pub struct Webhook { pub id: String, pub tenant: String, pub url: String, pub events: Vec<String>, pub topics: Vec<Pattern>, pub secret: String, pub created_at_ms: u64, pub deliveries: VecDeque<Delivery> }
+
+#[derive(Clone, Serialize)]
+pub struct Delivery { pub event_id: String, pub event: String, pub attempt: u32, pub status_code: Option<u16>, pub error: Option<String>, pub delivered: bool, pub timestamp_ms: u64 }
+
+#[derive(Deserialize)]
+pub struct WebhookRequest { url: String, events: Option<Vec<String>>, topics: Option<Vec<String>> }
+
+#[derive(Serialize)]
+pub struct WebhookInfo { id: String, url: String, events: Vec<String>, #[serde(skip_serializing_if = "Vec::is_empty")] topics: Vec<String>, created_at_ms: u64, #[serde(skip_serializing_if = "Option::is_none")] secret: Option<String> }
+
+impl Webhook {
+    fn info(&self, with_secret: bool) -> WebhookInfo {
+        WebhookInfo { id: self.id.clone(), url: self.url.clone(), events: self.events.clone(), topics: self.topics.iter().map(|p| p.relative()).collect(), created_at_ms: self.created_at_ms, secret: with_secret.then(|| self.secret.clone()) }
+    }
+    /// `STREAM_ONLY_KINDS` are never delivered to webhooks.
+    fn wants(&self, ev: &Event) -> bool {
+        let kind = if self.events.is_empty() { EVENT_KINDS.contains(&ev.kind.as_str()) } else { self.events.contains(&ev.kind) };
+        kind && (self.topics.is_empty() || self.topics.iter().any(|p| ev.topics.iter().any(|t| p.matches(t))))
+    }
+}
+
+/// The client webhooks are delivered with, and the rules their URLs must meet.
+pub struct Egress { client: reqwest::Client, allow_insecure: bool }
+
+impl Egress {
+    pub fn from_env() -> Self {
+        let allow_insecure = std::env::var("WEBHOOK_ALLOW_INSECURE").is_ok_and(|v| v == "true");
+        let builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+        let builder = if allow_insecure { builder } else { builder.dns_resolver(Arc::new(PublicOnly)) };
+        Egress { client: builder.build().expect("webhook HTTP client"), allow_insecure }
+    }
+
+    /// Checks that `url` may be delivered to, resolving its host.
+    pub async fn check(&self, url: &str) -> Result<(), String> {
+        let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        match url.scheme() {
+            "https" => {}
+            "http" if self.allow_insecure => {}
+            _ => return Err(if self.allow_insecure { "url must be http(s)" } else { "url must be https" }.into()),
+        }
+        if self.allow_insecure { return Ok(()); }
+        let host = url.host_str().ok_or("url has no host")?;
+        match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) if !public(ip) => Err(format!("{ip} is not a public address")),
+            Ok(_) => Ok(()),
+            Err(_) => public_addrs(host).await.map(drop),
+        }
+    }
+}
+
+/// Resolves names for the webhook client, refusing any that reach a non-public address.
+struct PublicOnly;
+
+impl reqwest::dns::Resolve for PublicOnly {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = public_addrs(&host).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+async fn public_addrs(host: &str) -> Result<Vec<SocketAddr>, String> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await.map_err(|e| format!("{host} does not resolve: {e}"))?.collect();
+    if let Some(a) = addrs.iter().find(|a| !public(a.ip())) { return Err(format!("{host} resolves to {}, which is not a public address", a.ip())); }
+    if addrs.is_empty() { return Err(format!("{host} does not resolve")); }
+    Ok(addrs)
+}
+
+/// Whether `ip` is globally routable: not loopback, private, link-local, CGNAT, multicast,
+/// documentation or otherwise reserved.
+fn public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            !(v4.is_unspecified() || v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_broadcast() || v4.is_multicast() || v4.is_documentation()
+                || a == 0 || (a == 100 && (64..128).contains(&b)) || (a == 192 && b == 0 && (c == 0 || c == 2)) || (a == 198 && (18..20).contains(&b)) || a >= 240)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => public(v4.into()),
+            None => {
+                let first = v6.segments()[0];
+                !(v6.is_unspecified() || v6.is_loopback() || v6.is_multicast() || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80 || (first == 0x2001 && v6.segments()[1] == 0x0db8)
+                    || (first == 0x0064 && v6.segments()[1] == 0xff9b))
+            }
+        },
+    }
+}
+
+/// The secret is only returned once, at creation.
+pub async fn create(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Json(req): Json<WebhookRequest>) -> Result<(StatusCode, Json<WebhookInfo>), ApiError> {
+    if let Err(e) = s.webhook_egress.check(&req.url).await {
+        return Err(api_err(StatusCode::BAD_REQUEST, "Invalid webhook url", Some(e)));
+    }
+    let events = req.events.unwrap_or_default();
+    if let Some(bad) = events.iter().find(|e| !EVENT_KINDS.contains(&e.as_str())) {
+        return Err(api_err(StatusCode::BAD_REQUEST, "Unknown event type", Some(bad.clone())));
+    }
+    let topics = req.topics.unwrap_or_default().iter().map(|t| Pattern::parse(t).map(|p| p.within(&tenant))).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| api_err(StatusCode::BAD_REQUEST, "Invalid topic pattern", Some(e)))?;
+    let wh = Webhook { id: uuid::Uuid::new_v4().to_string(), tenant, url: req.url, events, topics, secret: uuid::Uuid::new_v4().simple().to_string(), created_at_ms: now_ms(), deliveries: VecDeque::new() };
+    let info = wh.info(true);
+    s.webhooks.lock().unwrap().insert(wh.id.clone(), wh);
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+pub async fn list(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant) -> Json<Vec<WebhookInfo>> {
+    Json(s.webhooks.lock().unwrap().values().filter(|w| w.tenant == tenant).map(|w| w.info(false)).collect())
+}
+
+pub async fn remove(State(s): State<Arc<AppState>>, _: Require<Configure>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    let mut hooks = s.webhooks.lock().unwrap();
+    if hooks.get(&id).is_none_or(|w| w.tenant != tenant) { return Err(api_err(StatusCode::NOT_FOUND, "Unknown webhook", None)); }
+    hooks.remove(&id);
+    s.breakers.remove(&format!("webhook:{id}"));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn deliveries(State(s): State<Arc<AppState>>, _: Require<Read>, Tenant(tenant): Tenant, Path(id): Path<String>) -> Result<Json<Vec<Delivery>>, ApiError> {
+    let hooks = s.webhooks.lock().unwrap();
+    match hooks.get(&id) {
+        Some(w) if w.tenant == tenant => Ok(Json(w.deliveries.iter().cloned().collect())),
+        _ => Err(api_err(StatusCode::NOT_FOUND, "Unknown webhook", None)),
+    }
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Background task: routes every emitted event to the tenant's matching webhooks.
+pub async fn dispatch(s: Arc<AppState>) {
+    let mut rx = s.hub.subscribe(None, "webhooks", &[Pattern::all()], Overflow::DropOldest, None);
+    loop {
+        let ev = match rx.recv().await {
+            Ok(ev) => ev,
+            Err(RecvError::Lagged(n)) => { tracing::warn!(skipped = n, "webhook dispatcher lagged"); continue; }
+            Err(RecvError::Closed) => return,
+        };
+        let targets: Vec<(String, String, String)> = s.webhooks.lock().unwrap().values()
+            .filter(|w| w.tenant == ev.tenant && w.wants(&ev))
+            .map(|w| (w.id.clone(), w.url.clone(), w.secret.clone())).collect();
+        // Deltas are stream-only, so a tracked delivery never has a webhook destination.
+        if let Some(d) = &ev.delivery { s.deliveries.forget(d, "webhooks"); }
+        for (id, url, secret) in targets { tokio::spawn(deliver(s.clone(), id, url, secret, ev.clone())); }
+    }
+}
+
+fn log_delivery(s: &AppState, id: &str, d: Delivery) -> bool {
+    let mut hooks = s.webhooks.lock().unwrap();
+    let Some(w) = hooks.get_mut(id) else { return false };
+    if w.deliveries.len() == DELIVERY_LOG_LEN { w.deliveries.pop_front(); }
+    w.deliveries.push_back(d);
+    true
+}
+
+async fn deliver(s: Arc<AppState>, id: String, url: String, secret: String, ev: Event) {
+    let body = serde_json::to_vec(&ev).unwrap_or_default();
+    let signature = format!("sha256={}", sign(&secret, &body));
+    let breaker = s.breakers.get(&format!("webhook:{id}"));
+    // A literal address never reaches the resolver, so the URL is checked again here as well.
+    if let Err(e) = s.webhook_egress.check(&url).await {
+        log_delivery(&s, &id, Delivery { event_id: ev.id.clone(), event: ev.kind.clone(), attempt: 0, status_code: None, error: Some(e), delivered: false, timestamp_ms: now_ms() });
+        return;
+    }
+    let result = resilience::call(&breaker, RETRY, |attempt| {
+        let (s, id, url, body, signature, ev) = (&s, &id, &url, &body, &signature, &ev);
+        async move {
+            let res = s.webhook_egress.client.post(url).header("Content-Type", "application/json").header("X-Alice-Event", &ev.kind).header("X-Alice-Signature", signature)
+                .timeout(Duration::from_secs(10)).body(body.clone()).send().await;
+            let (status_code, error) = match res {
+                Ok(r) if r.status().is_success() => (Some(r.status().as_u16()), None),
+                Ok(r) => (Some(r.status().as_u16()), Some(format!("HTTP {}", r.status()))),
+                // The cause (e.g. a refused address) is in the source chain.
+                Err(e) => (None, Some(std::iter::successors(Some(&e as &dyn std::error::Error), |e| e.source()).map(ToString::to_string).collect::<Vec<_>>().join(": "))),
+            };
+            let d = Delivery { event_id: ev.id.clone(), event: ev.kind.clone(), attempt, status_code, error: error.clone(), delivered: error.is_none(), timestamp_ms: now_ms() };
+            if !log_delivery(s, id, d) { return Attempt::Fatal("webhook removed".into()); }
+            match (error, status_code) {
+                (None, _) => Attempt::Ok(()),
+                (Some(e), Some(400..=499)) if !matches!(status_code, Some(408 | 429)) => Attempt::Fatal(e),
+                (Some(e), _) => Attempt::Retry(e),
+            }
+        }
+    }).await;
+    match result {
+        Ok(()) => {}
+        Err(CallError::CircuitOpen(_)) => {
+            log_delivery(&s, &id, Delivery { event_id: ev.id.clone(), event: ev.kind.clone(), attempt: 0, status_code: None, error: Some("circuit open".into()), delivered: false, timestamp_ms: now_ms() });
+        }
+        Err(e) => tracing::warn!(webhook = %id, event = %ev.id, "webhook delivery abandoned: {}", e.message()),
+    }
+}